@@ -0,0 +1,2 @@
+ALTER TABLE signer_data ADD COLUMN coin_withdrawn BOOLEAN DEFAULT FALSE;
+ALTER TABLE signer_data ADD COLUMN withdrawal_txid TEXT;
@@ -0,0 +1,17 @@
+ALTER TABLE signer_data ADD COLUMN deposit_status TEXT;
+
+-- Deposits made before the status was stored. Rows without a token are transfer addresses and have no deposit status.
+UPDATE signer_data SET deposit_status = 'BACKUP_SIGNED'
+    WHERE token_id IS NOT NULL AND statechain_id IN (SELECT statechain_id FROM backup_transaction);
+
+UPDATE signer_data SET deposit_status = 'FUNDED'
+    WHERE token_id IS NOT NULL AND deposit_status IS NULL AND funding_txid IS NOT NULL;
+
+UPDATE signer_data SET deposit_status = 'AWAITING_FUNDS'
+    WHERE token_id IS NOT NULL AND deposit_status IS NULL AND p2tr_agg_address IS NOT NULL;
+
+UPDATE signer_data SET deposit_status = 'SERVER_INITIALISED'
+    WHERE token_id IS NOT NULL AND deposit_status IS NULL AND statechain_id IS NOT NULL;
+
+UPDATE signer_data SET deposit_status = 'KEYS_GENERATED'
+    WHERE token_id IS NOT NULL AND deposit_status IS NULL;
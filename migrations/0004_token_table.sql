@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS token (
+
+    token_id TEXT NOT NULL UNIQUE,
+
+    fee INT,
+    lightning_invoice TEXT,
+    btc_payment_address TEXT,
+
+    confirmed BOOLEAN DEFAULT FALSE,
+    spent BOOLEAN DEFAULT FALSE,
+
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS watch_event (
+
+    statechain_id TEXT NOT NULL,
+    event TEXT NOT NULL,
+    txid TEXT,
+    -- Chain tip when the event was recorded, or confirmation height of the spending transaction (0 in the mempool)
+    block_height INT NOT NULL,
+    message TEXT,
+
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,20 @@
+ALTER TABLE signer_data ADD COLUMN status TEXT;
+
+-- Derive the status of existing statecoins from the flags and the deposit progress
+UPDATE signer_data
+SET status = CASE
+    WHEN coin_withdrawn THEN 'WITHDRAWN'
+    WHEN coin_sent THEN 'TRANSFERRED'
+    WHEN statechain_id IN (
+        SELECT statechain_id FROM watch_event
+        WHERE event IN ('SPENT_BY_PREVIOUS_BACKUP', 'SPENT_UNEXPECTEDLY') AND block_height > 0) THEN 'EXPIRED'
+    WHEN statechain_id IN (
+        SELECT statechain_id FROM watch_event
+        WHERE event IN ('BACKUP_BROADCAST', 'SPENT_BY_BACKUP')) THEN 'BACKUP_BROADCAST'
+    WHEN deposit_status IN ('FUNDED', 'BACKUP_SIGNED') THEN 'CONFIRMED'
+    WHEN deposit_status = 'FUNDED_UNCONFIRMED' THEN 'IN_MEMPOOL'
+    WHEN deposit_status IN ('SERVER_INITIALISED', 'AWAITING_FUNDS') THEN 'INITIALISED'
+    -- Received statecoins have no deposit status
+    WHEN deposit_status IS NULL AND funding_txid IS NOT NULL THEN 'CONFIRMED'
+END
+WHERE statechain_id IS NOT NULL;
@@ -0,0 +1,13 @@
+-- Wallet key, encrypted with an Argon2id key derived from the passphrase.
+-- Once a row exists, signer_seed.seed, signer_data.client_seckey_share and signer_data.auth_seckey hold nonce || AES-256-GCM ciphertext.
+-- Plaintext wallets are encrypted in place by the encrypt-wallet command, which needs the new passphrase.
+CREATE TABLE IF NOT EXISTS wallet_encryption (
+
+    kdf_salt BLOB NOT NULL,
+    kdf_memory_kib INT NOT NULL,
+    kdf_iterations INT NOT NULL,
+    kdf_parallelism INT NOT NULL,
+    encrypted_key BLOB NOT NULL,
+
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
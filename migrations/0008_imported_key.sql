@@ -0,0 +1,7 @@
+-- Secret keys that cannot be derived from the seed, encrypted like the other secrets: nonce || AES-256-GCM ciphertext, with the public key as associated data.
+-- Derived secret keys are no longer stored. signer_data.client_seckey_share and signer_data.auth_seckey are emptied once they are checked against the seed.
+CREATE TABLE IF NOT EXISTS imported_key (
+    public_key BLOB PRIMARY KEY,
+    encrypted_seckey BLOB NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
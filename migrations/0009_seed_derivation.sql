@@ -0,0 +1,4 @@
+-- How the master key is derived from signer_seed.seed. Seeds stored by earlier versions are LEGACY: the entropy itself is the BIP32 seed.
+-- BIP39 seeds use the standard seed of the mnemonic and of bip39_passphrase, stored encrypted like the seed, or NULL if it is empty.
+ALTER TABLE signer_seed ADD COLUMN derivation TEXT NOT NULL DEFAULT 'LEGACY';
+ALTER TABLE signer_seed ADD COLUMN bip39_passphrase BLOB;
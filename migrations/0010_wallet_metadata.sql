@@ -0,0 +1,6 @@
+-- Properties of the wallet as a whole. `network` is the network the wallet was created for; the client refuses to open it on another one.
+CREATE TABLE IF NOT EXISTS wallet_metadata (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,11 @@
+-- Accounts of the wallet seed: the account index is the third level of the derivation paths, e.g. m/86h/1h/<account_index>h.
+-- Every key belongs to one account. The keys stored by earlier versions are all in account 0.
+CREATE TABLE IF NOT EXISTS account (
+    account_index INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO account (account_index, name) VALUES (0, 'default');
+
+ALTER TABLE signer_data ADD COLUMN account_index INT NOT NULL DEFAULT 0;
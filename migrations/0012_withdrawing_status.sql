@@ -0,0 +1,8 @@
+-- 0006 marked every withdrawn statecoin as WITHDRAWN, including those whose withdrawal transaction was only broadcast.
+-- Those are WITHDRAWING until watch sees the withdrawal transaction confirm.
+UPDATE signer_data
+SET status = 'WITHDRAWING'
+WHERE status = 'WITHDRAWN'
+AND statechain_id NOT IN (
+    SELECT statechain_id FROM watch_event
+    WHERE event = 'SPENT_BY_WITHDRAWAL' AND block_height > 0);
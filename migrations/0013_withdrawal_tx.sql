@@ -0,0 +1,4 @@
+-- The signed withdrawal transaction, broadcast again by watch if it leaves the mempool
+ALTER TABLE signer_data ADD COLUMN withdrawal_tx BLOB;
+-- Authenticates withdraw/complete, which watch sends once the withdrawal transaction is confirmed
+ALTER TABLE signer_data ADD COLUMN withdrawal_signed_statechain_id TEXT;
@@ -0,0 +1,10 @@
+-- New backup transaction co-signed by the server for a transfer whose message has not been accepted yet.
+-- The server counts each signature, so a retry of the transfer reuses it instead of asking for another one.
+CREATE TABLE IF NOT EXISTS pending_transfer (
+    statechain_id TEXT PRIMARY KEY,
+    recipient_address TEXT NOT NULL,
+    backup_tx BLOB NOT NULL,
+    client_public_nonce BLOB NOT NULL,
+    blinding_factor BLOB NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,10 @@
+-- Transfer message being received, saved before the server rotates its key share.
+-- The server no longer serves the message once it has, so a rerun completes the transfer from this row.
+CREATE TABLE IF NOT EXISTS pending_receive (
+    auth_pubkey BLOB PRIMARY KEY,
+    statechain_id TEXT NOT NULL,
+    transfer_msg BLOB NOT NULL,
+    t2 BLOB NOT NULL,
+    server_pubkey BLOB,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
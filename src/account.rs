@@ -0,0 +1,140 @@
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, Row};
+
+use crate::{error::CError, wallet_metadata};
+
+/// Key of the selected account in the wallet metadata
+const SELECTED_ACCOUNT_KEY: &str = "account";
+
+/// Hardened derivation indexes are below 2^31
+const MAX_ACCOUNT_INDEX: u32 = (1 << 31) - 1;
+
+/// Account of the wallet seed. New addresses and deposits use the keys of the selected account.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub index: u32,
+    pub name: String,
+    pub selected: bool,
+}
+
+/// Adds an account with the next free index
+pub async fn create_account(pool: &sqlx::Pool<Sqlite>, name: &str) -> Result<Account, CError> {
+
+    if name.is_empty() {
+        return Err(CError::UserInput("The account name cannot be empty".to_string()));
+    }
+
+    if get_account_index(pool, name).await?.is_some() {
+        return Err(CError::UserInput(format!("Account {} already exists", name)));
+    }
+
+    let row = sqlx::query("SELECT MAX(account_index) FROM account")
+        .fetch_one(pool)
+        .await?;
+
+    let index = match row.try_get::<Option<u32>, _>(0)? {
+        Some(index) if index >= MAX_ACCOUNT_INDEX => return Err(CError::UserInput("No account index left".to_string())),
+        Some(index) => index + 1,
+        None => 0,
+    };
+
+    insert_account(pool, index, name).await?;
+
+    Ok(Account {
+        index,
+        name: name.to_string(),
+        selected: false,
+    })
+}
+
+async fn insert_account(pool: &sqlx::Pool<Sqlite>, index: u32, name: &str) -> Result<(), CError> {
+
+    let _ = sqlx::query("INSERT INTO account (account_index, name) VALUES ($1, $2)")
+        .bind(index)
+        .bind(name)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Creates the accounts below `count` that do not exist yet, e.g. before restoring them. They are named after their index,
+/// with a suffix if an account already has that name.
+pub async fn create_missing_accounts(pool: &sqlx::Pool<Sqlite>, count: u32) -> Result<(), CError> {
+
+    let existing = list_accounts(pool).await?;
+
+    for index in 0..count.min(MAX_ACCOUNT_INDEX + 1) {
+        if !existing.iter().any(|account| account.index == index) {
+
+            let mut name = format!("account-{}", index);
+            let mut suffix = 1;
+
+            while get_account_index(pool, &name).await?.is_some() {
+                suffix += 1;
+                name = format!("account-{}-{}", index, suffix);
+            }
+
+            insert_account(pool, index, &name).await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn list_accounts(pool: &sqlx::Pool<Sqlite>) -> Result<Vec<Account>, CError> {
+
+    let rows = sqlx::query("SELECT account_index, name FROM account ORDER BY account_index")
+        .fetch_all(pool)
+        .await?;
+
+    let selected = get_selected_account(pool).await?;
+
+    let mut accounts = Vec::<Account>::new();
+
+    for row in rows {
+        let index = row.try_get::<u32, _>("account_index")?;
+        accounts.push(Account {
+            index,
+            name: row.try_get::<String, _>("name")?,
+            selected: index == selected,
+        });
+    }
+
+    Ok(accounts)
+}
+
+async fn get_account_index(pool: &sqlx::Pool<Sqlite>, name: &str) -> Result<Option<u32>, CError> {
+
+    let row = sqlx::query("SELECT account_index FROM account WHERE name = $1")
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+
+    match row {
+        Some(row) => Ok(Some(row.try_get::<u32, _>("account_index")?)),
+        None => Ok(None),
+    }
+}
+
+/// Index of the selected account, 0 if none has been selected
+pub async fn get_selected_account(pool: &sqlx::Pool<Sqlite>) -> Result<u32, CError> {
+    match wallet_metadata::get_value(pool, SELECTED_ACCOUNT_KEY).await? {
+        Some(index) => index.parse::<u32>().map_err(|_| CError::Database(format!("Invalid selected account: {}", index))),
+        None => Ok(0),
+    }
+}
+
+pub async fn select_account(pool: &sqlx::Pool<Sqlite>, name: &str) -> Result<Account, CError> {
+
+    let index = get_account_index(pool, name).await?
+        .ok_or(CError::UserInput(format!("Account {} not found", name)))?;
+
+    wallet_metadata::set_value(pool, SELECTED_ACCOUNT_KEY, &index.to_string()).await?;
+
+    Ok(Account {
+        index,
+        name: name.to_string(),
+        selected: true,
+    })
+}
@@ -0,0 +1,183 @@
+use std::{str::FromStr, time::Duration};
+
+use async_trait::async_trait;
+use bitcoin::{Address, Amount, Transaction, Txid};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use serde_json::{json, Value};
+
+use crate::{chain::{ChainBackend, AddressBalance, HistoryItem, Utxo, FALLBACK_FEE_RATE}, error::CError};
+
+/// `scantxoutset` walks the whole UTXO set and can take a while
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// RPC_INVALID_ADDRESS_OR_KEY, returned by `getrawtransaction` for a transaction bitcoind does not know
+const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcResponse {
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ScanUnspent {
+    txid: Txid,
+    vout: u32,
+    /// BTC
+    amount: f64,
+    height: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ScanResult {
+    unspents: Vec<ScanUnspent>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SmartFee {
+    /// BTC per kvB
+    feerate: Option<f64>,
+}
+
+/// Backend for the bitcoind JSON-RPC interface.
+/// Addresses are looked up with `scantxoutset`, so no wallet is needed, but only confirmed outputs are seen
+/// and the history of an address is limited to the transactions of its unspent outputs.
+/// `get_transaction` of confirmed transactions requires bitcoind to run with `-txindex`.
+pub struct BitcoindBackend {
+    http_client: reqwest::Client,
+    url: String,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl BitcoindBackend {
+
+    pub fn new(url: &str, user: Option<&str>, password: Option<&str>) -> Result<Self, CError> {
+        let http_client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| CError::Chain(e.to_string()))?;
+
+        Ok(BitcoindBackend {
+            http_client,
+            url: url.to_string(),
+            user: user.map(|user| user.to_string()),
+            password: password.map(|password| password.to_string()),
+        })
+    }
+
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T, CError> {
+        self.call_unless(method, params, None).await?
+            .ok_or(CError::Chain(format!("bitcoind {} returned no result", method)))
+    }
+
+    /// Like `call`, but `None` if bitcoind answers with the error `none_on_error`
+    async fn call_unless<T: DeserializeOwned>(&self, method: &str, params: Value, none_on_error: Option<i64>) -> Result<Option<T>, CError> {
+
+        let payload = json!({
+            "jsonrpc": "1.0",
+            "id": "mercury",
+            "method": method,
+            "params": params,
+        });
+
+        let mut request = self.http_client.post(&self.url).json(&payload);
+
+        if let Some(user) = &self.user {
+            request = request.basic_auth(user, self.password.as_ref());
+        }
+
+        let response = request.send().await.map_err(|e| CError::Chain(e.to_string()))?;
+        let status = response.status();
+        let value = response.text().await.map_err(|e| CError::Chain(e.to_string()))?;
+
+        // bitcoind answers RPC errors with a 500 status and a JSON body, anything else without one
+        let response: RpcResponse = serde_json::from_str(&value)
+            .map_err(|_| CError::Chain(format!("bitcoind {} returned status {}: {}", method, status.as_u16(), value)))?;
+
+        if let Some(error) = response.error {
+            if Some(error.code) == none_on_error {
+                return Ok(None);
+            }
+            return Err(CError::Chain(format!("bitcoind {} failed ({}): {}", method, error.code, error.message)));
+        }
+
+        serde_json::from_value(response.result.unwrap_or(Value::Null))
+            .map(Some)
+            .map_err(|e| CError::Chain(format!("failed to parse bitcoind {} response: {}", method, e)))
+    }
+
+    async fn scan_address(&self, address: &Address) -> Result<Vec<Utxo>, CError> {
+
+        let descriptor = format!("addr({})", address);
+
+        let result: ScanResult = self.call("scantxoutset", json!(["start", [descriptor]])).await?;
+
+        result.unspents.into_iter().map(|unspent| {
+            let value = Amount::from_btc(unspent.amount).map_err(|e| CError::Chain(e.to_string()))?;
+            Ok(Utxo {
+                txid: unspent.txid,
+                vout: unspent.vout,
+                value: value.to_sat(),
+                height: unspent.height,
+            })
+        }).collect()
+    }
+}
+
+#[async_trait]
+impl ChainBackend for BitcoindBackend {
+
+    async fn get_address_balance(&self, address: &Address) -> Result<AddressBalance, CError> {
+        let utxos = self.scan_address(address).await?;
+        Ok(AddressBalance { confirmed: utxos.iter().map(|utxo| utxo.value).sum(), unconfirmed: 0 })
+    }
+
+    async fn get_address_history(&self, address: &Address) -> Result<Vec<HistoryItem>, CError> {
+        let mut history: Vec<HistoryItem> = self.scan_address(address).await?
+            .into_iter()
+            .map(|utxo| HistoryItem { txid: utxo.txid, height: utxo.height })
+            .collect();
+        history.sort_by_key(|item| item.txid);
+        history.dedup_by_key(|item| item.txid);
+        Ok(history)
+    }
+
+    async fn list_unspent(&self, address: &Address) -> Result<Vec<Utxo>, CError> {
+        self.scan_address(address).await
+    }
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<Transaction, CError> {
+        let tx_hex: String = self.call("getrawtransaction", json!([txid.to_string(), false])).await?;
+        Ok(bitcoin::consensus::deserialize(&hex::decode(tx_hex)?)?)
+    }
+
+    /// Without `-txindex`, confirmed transactions are not found either
+    async fn find_transaction(&self, txid: &Txid) -> Result<Option<Transaction>, CError> {
+        let tx_hex: Option<String> = self.call_unless("getrawtransaction", json!([txid.to_string(), false]), Some(RPC_INVALID_ADDRESS_OR_KEY)).await?;
+        tx_hex.map(|tx_hex| Ok(bitcoin::consensus::deserialize(&hex::decode(tx_hex)?)?)).transpose()
+    }
+
+    async fn broadcast(&self, raw_tx: &[u8]) -> Result<Txid, CError> {
+        let txid: String = self.call("sendrawtransaction", json!([hex::encode(raw_tx)])).await?;
+        Txid::from_str(&txid).map_err(|e| CError::Chain(format!("invalid txid {}: {}", txid, e)))
+    }
+
+    async fn get_tip_height(&self) -> Result<u32, CError> {
+        self.call("getblockcount", json!([])).await
+    }
+
+    async fn estimate_fee_rate(&self, blocks: usize) -> Result<u64, CError> {
+        let smart_fee: SmartFee = self.call("estimatesmartfee", json!([blocks])).await?;
+        match smart_fee.feerate {
+            Some(fee_rate_btc_per_kvb) => Ok(((fee_rate_btc_per_kvb * 100000.0).ceil() as u64).max(FALLBACK_FEE_RATE)),
+            None => Ok(FALLBACK_FEE_RATE),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use bitcoin::{Network, Address, Transaction, Txid};
+use secp256k1_zkp::Secp256k1;
+use sqlx::Sqlite;
+
+use crate::{chain::{ChainBackend, Utxo}, encryption::WalletCipher, error::CError, send_backup, transaction, wallet::{self, StatecoinStatus}};
+
+/// Broadcasts the latest backup transaction of the statecoin with a CPFP child spending its anchor output,
+/// so that both confirm at `fee_rate_sats_per_byte`.
+/// The anchor alone cannot pay for the bump, so the child also spends the backup output
+/// and sends what is left after the fee back to the backup address.
+pub async fn execute(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, chain: &dyn ChainBackend, statechain_id: &str, fee_rate_sats_per_byte: u64, network: Network) -> Result<Txid, CError> {
+
+    let statecoin = wallet::get_statecoin(pool, statechain_id, network).await?;
+
+    if statecoin.coin_sent {
+        return Err(CError::UserInput(format!("Statecoin {} has been sent, its latest backup transaction belongs to the new owner", statechain_id)));
+    }
+
+    if statecoin.coin_withdrawn {
+        return Err(CError::UserInput(format!("Statecoin {} has already been withdrawn", statechain_id)));
+    }
+
+    wallet::check_transition(pool, statechain_id, StatecoinStatus::BackupBroadcast).await?;
+
+    let backup_txs = transaction::get_backup_transactions(pool, statechain_id).await?;
+
+    let latest_backup_tx = match backup_txs.last() {
+        Some(backup_tx) => backup_tx,
+        None => return Err(CError::UserInput(format!("No backup transaction found for statecoin {}", statechain_id))),
+    };
+
+    let parent: Transaction = bitcoin::consensus::deserialize(&hex::decode(&latest_backup_tx.tx)?)?;
+    let parent_txid = parent.txid();
+
+    let backup_address = Address::p2tr(&Secp256k1::new(), statecoin.client_pubkey.x_only_public_key().0, None, network);
+
+    let anchor_vout = transaction::anchor_vout(&parent, &backup_address.script_pubkey())
+        .ok_or(CError::UserInput(format!("Latest backup transaction of statecoin {} has no anchor output", statechain_id)))?;
+
+    // The child is only relayed if its parent is in the mempool
+    if let Err(err) = chain.broadcast(&bitcoin::consensus::encode::serialize(&parent)).await {
+        if chain.get_transaction(&parent_txid).await.is_err() {
+            return Err(err);
+        }
+    }
+
+    wallet::update_status(pool, statechain_id, StatecoinStatus::BackupBroadcast).await?;
+
+    let list_utxo = [0, anchor_vout].iter().map(|vout| {
+        let utxo = Utxo { txid: parent_txid, vout: *vout, value: parent.output[*vout as usize].value, height: 0 };
+        (utxo, backup_address.clone())
+    }).collect();
+
+    let list_utxo = send_backup::get_address_info(pool, cipher, list_utxo, network).await?;
+
+    let child_vsize = send_backup::sweep_vsize(&list_utxo, &backup_address)?;
+
+    let parent_output_value: u64 = parent.output.iter().map(|output| output.value).sum();
+    let parent_fee = statecoin.amount.saturating_sub(parent_output_value);
+
+    // The child pays what the parent is missing for the package to reach the fee rate, and at least its own fee
+    let package_fee = (parent.vsize() as u64 + child_vsize) * fee_rate_sats_per_byte;
+    let child_fee = package_fee.saturating_sub(parent_fee).max(child_vsize * fee_rate_sats_per_byte);
+
+    send_backup::send_all_funds_with_fee(chain, &list_utxo, &backup_address, child_fee).await
+}
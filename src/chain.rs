@@ -0,0 +1,110 @@
+use std::{fmt, str::FromStr};
+
+use async_trait::async_trait;
+use bitcoin::{Address, Transaction, Txid};
+use serde::{Serialize, Deserialize};
+
+use crate::{bitcoind::BitcoindBackend, config::Config, electrum::ElectrumBackend, error::CError, esplora::EsploraBackend};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressBalance {
+    pub confirmed: u64,
+    pub unconfirmed: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HistoryItem {
+    pub txid: Txid,
+    /// Confirmation height, 0 if the transaction is in the mempool
+    pub height: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    pub txid: Txid,
+    pub vout: u32,
+    pub value: u64,
+    /// Confirmation height, 0 if the transaction is in the mempool
+    pub height: u32,
+}
+
+/// Fee rate used when the backend has no estimate, e.g. on regtest
+pub const FALLBACK_FEE_RATE: u64 = 1;
+
+/// Number of confirmations of a transaction confirmed at `height`, 0 for a transaction in the mempool
+pub fn confirmations(tip_height: u32, height: u32) -> u32 {
+    if height == 0 || height > tip_height {
+        0
+    } else {
+        tip_height - height + 1
+    }
+}
+
+/// Source of blockchain data and transaction relay.
+/// Fee rates are in sats per virtual byte.
+#[async_trait]
+pub trait ChainBackend: Send + Sync {
+
+    async fn get_address_balance(&self, address: &Address) -> Result<AddressBalance, CError>;
+
+    async fn get_address_history(&self, address: &Address) -> Result<Vec<HistoryItem>, CError>;
+
+    async fn list_unspent(&self, address: &Address) -> Result<Vec<Utxo>, CError>;
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<Transaction, CError>;
+
+    /// Like `get_transaction`, but `None` if the backend answers that it does not know the transaction.
+    /// Any other failure is an error, so that an unreachable backend is not taken for a missing transaction.
+    async fn find_transaction(&self, txid: &Txid) -> Result<Option<Transaction>, CError>;
+
+    async fn broadcast(&self, raw_tx: &[u8]) -> Result<Txid, CError>;
+
+    async fn get_tip_height(&self) -> Result<u32, CError>;
+
+    /// Fee rate for the transaction to confirm within `blocks` blocks
+    async fn estimate_fee_rate(&self, blocks: usize) -> Result<u64, CError>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChainBackendKind {
+    Electrum,
+    Esplora,
+    Bitcoind,
+}
+
+impl FromStr for ChainBackendKind {
+    type Err = CError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "electrum" => Ok(ChainBackendKind::Electrum),
+            "esplora" => Ok(ChainBackendKind::Esplora),
+            "bitcoind" => Ok(ChainBackendKind::Bitcoind),
+            _ => Err(CError::Config(format!("Unknown chain backend: {} (expected electrum, esplora or bitcoind)", s))),
+        }
+    }
+}
+
+impl fmt::Display for ChainBackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainBackendKind::Electrum => write!(f, "electrum"),
+            ChainBackendKind::Esplora => write!(f, "esplora"),
+            ChainBackendKind::Bitcoind => write!(f, "bitcoind"),
+        }
+    }
+}
+
+/// Connects to the backend selected in the config
+pub fn connect(config: &Config) -> Result<Box<dyn ChainBackend>, CError> {
+    match config.chain_backend {
+        ChainBackendKind::Electrum => Ok(Box::new(ElectrumBackend::new(&config.electrum_server)?)),
+        ChainBackendKind::Esplora => Ok(Box::new(EsploraBackend::new(&config.esplora_url)?)),
+        ChainBackendKind::Bitcoind => Ok(Box::new(BitcoindBackend::new(
+            &config.bitcoind_rpc_url,
+            config.bitcoind_rpc_user.as_deref(),
+            config.bitcoind_rpc_password.as_deref(),
+        )?)),
+    }
+}
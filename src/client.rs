@@ -0,0 +1,310 @@
+use std::{path::Path, time::Duration};
+
+use bitcoin::{Network, Address, Txid};
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, migrate::MigrateDatabase, SqlitePool};
+
+use crate::{account::{self, Account}, bump_backup, chain::{self, ChainBackend, Utxo}, config::Config, deposit::{self, DepositInfo, DepositProgress}, encryption::{self, KdfParams, WalletCipher}, error::CError, key_derivation::{self, SeedDerivation, WalletSeed}, key_store, restore::{self, RestoreReport}, send_backup, server::StatechainServer, statecoin_info::{self, StatecoinInfo}, token::{self, Token}, transfer_receiver::{self, TransferReceiveReport}, transfer_sender, verify_backups::{self, StatecoinBackupReport}, wallet, wallet_metadata, watch::{self, WatchEvent, WatchPolicy}, withdraw::{self, WithdrawResult}};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Balance {
+    pub address: String,
+    pub balance: u64,
+    pub unconfirmed_balance: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WalletBalance {
+    pub statecoins: Vec<Balance>,
+    pub backup_addresses: Vec<Balance>,
+}
+
+/// Entry point of the library. Owns the wallet database, the network, the blockchain backend and the statechain server client.
+pub struct MercuryClient {
+    pool: sqlx::Pool<Sqlite>,
+    network: Network,
+    chain: Box<dyn ChainBackend>,
+    server: StatechainServer,
+    deposit_timeout: Duration,
+    min_confirmations: u32,
+    backup_anchor: bool,
+    watch_interval: Duration,
+    watch_policy: WatchPolicy,
+    /// Set once the wallet is unlocked with its passphrase. Needed by the commands that read the seed or a secret key.
+    cipher: Option<WalletCipher>,
+}
+
+impl MercuryClient {
+
+    /// Opens (or creates) the wallet database, runs the migrations and connects to the blockchain backend.
+    pub async fn new(config: &Config) -> Result<Self, CError> {
+
+        // Built before the pool is opened, so that their errors do not leave it open
+        let chain = chain::connect(config)?;
+
+        let server = StatechainServer::new(&config.statechain_entity)?;
+
+        let db_path = config.database_file.as_str();
+
+        if !Sqlite::database_exists(db_path).await.unwrap_or(false) {
+            // The database of a named wallet lives in the wallets directory
+            if let Some(dir) = Path::new(db_path).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir).map_err(|e| CError::Config(format!("Cannot create the directory {}: {}", dir.display(), e)))?;
+            }
+            Sqlite::create_database(db_path).await?;
+        }
+
+        let pool = SqlitePool::connect(db_path).await?;
+
+        if let Err(err) = sqlx::migrate!("./migrations").run(&pool).await {
+            pool.close().await;
+            return Err(err.into());
+        }
+
+        if let Err(err) = wallet_metadata::check_network(&pool, config.network).await {
+            pool.close().await;
+            return Err(err);
+        }
+
+        Ok(MercuryClient {
+            pool,
+            network: config.network,
+            chain,
+            server,
+            deposit_timeout: Duration::from_secs(config.deposit_timeout),
+            min_confirmations: config.min_confirmations,
+            backup_anchor: config.backup_anchor,
+            watch_interval: Duration::from_secs(config.watch_interval),
+            watch_policy: WatchPolicy { safety_margin: config.watch_safety_margin },
+            cipher: None,
+        })
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub async fn close(self) {
+        self.pool.close().await;
+    }
+
+    fn cipher(&self) -> Result<&WalletCipher, CError> {
+        self.cipher.as_ref().ok_or(CError::UserInput("The wallet is locked. Unlock it with its passphrase first".to_string()))
+    }
+
+    /// Keeps the wallet unlocked with `cipher`. The secret keys stored by earlier versions are removed first.
+    async fn set_cipher(&mut self, cipher: WalletCipher) -> Result<(), CError> {
+        key_store::migrate_stored_secrets(&self.pool, &cipher, self.network).await?;
+        self.cipher = Some(cipher);
+        Ok(())
+    }
+
+    /// Creates a new encrypted wallet and leaves it unlocked. `bip39_passphrase` is the optional passphrase of the mnemonic.
+    pub async fn create_wallet(&mut self, passphrase: &str, bip39_passphrase: &str) -> Result<(), CError> {
+        let cipher = encryption::create_wallet(&self.pool, passphrase, KdfParams::default(), bip39_passphrase).await?;
+        self.set_cipher(cipher).await
+    }
+
+    /// Encrypts a wallet created before encryption was supported and leaves it unlocked
+    pub async fn encrypt_wallet(&mut self, passphrase: &str) -> Result<(), CError> {
+        let cipher = encryption::encrypt_wallet(&self.pool, passphrase, KdfParams::default()).await?;
+        self.set_cipher(cipher).await
+    }
+
+    pub async fn unlock(&mut self, passphrase: &str) -> Result<(), CError> {
+        let cipher = encryption::unlock(&self.pool, passphrase).await?;
+        self.set_cipher(cipher).await
+    }
+
+    /// Whether the wallet has been created. Tells whether `restore` needs the passphrase of the wallet or a new one.
+    pub async fn wallet_exists(&self) -> Result<bool, CError> {
+        encryption::wallet_exists(&self.pool).await
+    }
+
+    /// Creates the wallet from `seed`, or unlocks it if it exists, and recovers its statecoins from the server.
+    /// Every account of the wallet is scanned, with at least the first `account_count` accounts.
+    pub async fn restore(&mut self, seed: &WalletSeed, passphrase: &str, gap_limit: u32, account_count: u32) -> Result<RestoreReport, CError> {
+        let cipher = restore::open_wallet(&self.pool, seed, passphrase, KdfParams::default()).await?;
+        self.set_cipher(cipher).await?;
+        account::create_missing_accounts(&self.pool, account_count).await?;
+        restore::execute(&self.pool, self.cipher()?, self.chain.as_ref(), &self.server, gap_limit, self.network).await
+    }
+
+    pub async fn change_passphrase(&self, passphrase: &str, new_passphrase: &str) -> Result<(), CError> {
+        encryption::change_passphrase(&self.pool, passphrase, new_passphrase, KdfParams::default()).await
+    }
+
+    async fn fee_rate_or_estimate(&self, fee_rate: Option<u64>) -> Result<u64, CError> {
+        match fee_rate {
+            Some(fee_rate) => Ok(fee_rate),
+            None => self.chain.estimate_fee_rate(1).await,
+        }
+    }
+
+    /// Adds an account to the wallet seed. Its keys are derived at the next account index.
+    pub async fn create_account(&self, name: &str) -> Result<Account, CError> {
+        account::create_account(&self.pool, name).await
+    }
+
+    pub async fn list_accounts(&self) -> Result<Vec<Account>, CError> {
+        account::list_accounts(&self.pool).await
+    }
+
+    /// Selects the account of the new addresses and deposits
+    pub async fn select_account(&self, name: &str) -> Result<Account, CError> {
+        account::select_account(&self.pool, name).await
+    }
+
+    /// The mnemonic of the wallet and how its keys are derived from it, which a restore must be given
+    pub async fn show_mnemonic(&self) -> Result<(String, SeedDerivation), CError> {
+        let seed = key_derivation::get_seed(&self.pool, self.cipher()?).await?;
+        Ok((seed.mnemonic()?.to_string(), seed.derivation))
+    }
+
+    /// `on_progress` is told what the deposit is waiting for
+    pub async fn deposit(&self, token_id: uuid::Uuid, amount: u64, on_progress: &mut dyn FnMut(DepositProgress)) -> Result<String, CError> {
+        deposit::execute(&self.pool, self.cipher()?, self.chain.as_ref(), &self.server, token_id, amount, self.network, self.min_confirmations, self.deposit_timeout, self.backup_anchor, on_progress).await
+    }
+
+    /// Requests a deposit token from the server. It must be paid before it can be used by `deposit`.
+    pub async fn new_token(&self) -> Result<Token, CError> {
+        token::new_token(&self.pool, &self.server).await
+    }
+
+    /// Waits until the token is paid
+    pub async fn wait_for_token_payment(&self, token_id: uuid::Uuid) -> Result<Token, CError> {
+        token::wait_for_payment(&self.pool, &self.server, &token_id, self.deposit_timeout).await?;
+        token::get_token(&self.pool, &token_id).await?
+            .ok_or(CError::Database(format!("Token {} not found", token_id)))
+    }
+
+    pub async fn list_tokens(&self) -> Result<Vec<Token>, CError> {
+        token::list_tokens(&self.pool).await
+    }
+
+    /// Continues an interrupted or timed out deposit
+    pub async fn deposit_resume(&self, statechain_id: &str, on_progress: &mut dyn FnMut(DepositProgress)) -> Result<String, CError> {
+        deposit::resume(&self.pool, self.cipher()?, self.chain.as_ref(), &self.server, statechain_id, self.network, self.min_confirmations, self.deposit_timeout, self.backup_anchor, on_progress).await
+    }
+
+    /// Reports the progress of a deposit, including the confirmations of its deposit transaction
+    pub async fn deposit_status(&self, statechain_id: &str) -> Result<DepositInfo, CError> {
+        deposit::info(&self.pool, self.chain.as_ref(), statechain_id, self.network).await
+    }
+
+    /// Abandons a deposit that has not been funded
+    pub async fn deposit_cancel(&self, statechain_id: &str) -> Result<(), CError> {
+        deposit::cancel(&self.pool, self.cipher()?, self.chain.as_ref(), &self.server, statechain_id, self.network).await
+    }
+
+    pub async fn get_balance(&self) -> Result<WalletBalance, CError> {
+
+        let (agg_addresses, backup_addresses) = wallet::get_all_addresses(&self.pool, self.network).await?;
+
+        Ok(WalletBalance {
+            statecoins: self.get_balances(&agg_addresses).await?,
+            backup_addresses: self.get_balances(&backup_addresses).await?,
+        })
+    }
+
+    async fn get_balances(&self, addresses: &Vec<Address>) -> Result<Vec<Balance>, CError> {
+
+        let mut balances = Vec::<Balance>::new();
+
+        for address in addresses {
+            let balance_res = self.chain.get_address_balance(address).await?;
+            balances.push(Balance {
+                address: address.to_string(),
+                balance: balance_res.confirmed,
+                unconfirmed_balance: balance_res.unconfirmed,
+            });
+        }
+
+        Ok(balances)
+    }
+
+    /// Statecoins of the wallet with their status and backup transaction locktime
+    pub async fn list_statecoins(&self) -> Result<Vec<StatecoinInfo>, CError> {
+        statecoin_info::list(&self.pool, self.chain.as_ref(), self.network).await
+    }
+
+    pub async fn statecoin_info(&self, statechain_id: &str) -> Result<StatecoinInfo, CError> {
+        statecoin_info::info(&self.pool, self.chain.as_ref(), statechain_id, self.network).await
+    }
+
+    pub async fn broadcast_backup_transaction(&self, statechain_id: &str) -> Result<Txid, CError> {
+        deposit::broadcast_backup_tx(&self.pool, self.chain.as_ref(), statechain_id).await
+    }
+
+    /// Checks the stored backup transactions of one statecoin, or of all of them
+    pub async fn verify_backups(&self, statechain_id: Option<&str>) -> Result<Vec<StatecoinBackupReport>, CError> {
+        verify_backups::execute(&self.pool, statechain_id, self.network).await
+    }
+
+    /// Broadcasts the backup transaction with a CPFP child paying `fee_rate` for both
+    pub async fn bump_backup(&self, statechain_id: &str, fee_rate: Option<u64>) -> Result<Txid, CError> {
+        let fee_rate = self.fee_rate_or_estimate(fee_rate).await?;
+        bump_backup::execute(&self.pool, self.cipher()?, self.chain.as_ref(), statechain_id, fee_rate, self.network).await
+    }
+
+    /// Watchtower: broadcasts backup transactions when they are due and reports spends of the funding outpoints.
+    /// Runs until an error occurs, or checks the current tip only if `once` is set.
+    /// `on_update` receives each event as it is recorded and the blockchain backend errors that are retried.
+    pub async fn watch(&self, once: bool, on_update: &mut dyn FnMut(Result<&WatchEvent, &CError>)) -> Result<Vec<WatchEvent>, CError> {
+        watch::execute(&self.pool, self.chain.as_ref(), &self.server, self.watch_policy, self.watch_interval, once, self.network, on_update).await
+    }
+
+    /// Events recorded by the watchtower for one statecoin, or for all of them
+    pub async fn watch_events(&self, statechain_id: Option<&str>) -> Result<Vec<WatchEvent>, CError> {
+        watch::get_events(&self.pool, statechain_id).await
+    }
+
+    /// Sends the funds of every backup address to `address`
+    pub async fn send_backup(&self, address: &str, fee_rate: Option<u64>) -> Result<Txid, CError> {
+
+        let to_address = wallet::parse_address(address, self.network)?;
+
+        let fee_rate = self.fee_rate_or_estimate(fee_rate).await?;
+
+        let (_, backup_addresses) = wallet::get_all_addresses(&self.pool, self.network).await?;
+
+        let mut list_unspent = Vec::<(Utxo, Address)>::new();
+
+        for address in backup_addresses {
+            let address_utxos = self.chain.list_unspent(&address).await?;
+            for utxo in address_utxos {
+                list_unspent.push((utxo, address.clone()));
+            }
+        }
+
+        if list_unspent.is_empty() {
+            return Err(CError::UserInput("No backup funds to send".to_string()));
+        }
+
+        let list_utxo = send_backup::get_address_info(&self.pool, self.cipher()?, list_unspent, self.network).await?;
+
+        send_backup::send_all_funds(self.chain.as_ref(), &list_utxo, &to_address, fee_rate).await
+    }
+
+    pub async fn new_transfer_address(&self) -> Result<String, CError> {
+        let address_data = key_derivation::get_new_address(&self.pool, self.cipher()?, None, None, self.network).await?;
+        Ok(address_data.transfer_address)
+    }
+
+    pub async fn transfer_send(&self, recipient_address: &str, statechain_id: &str) -> Result<Txid, CError> {
+        transfer_sender::execute(&self.pool, self.cipher()?, self.chain.as_ref(), &self.server, recipient_address, statechain_id, self.network, self.backup_anchor).await
+    }
+
+    pub async fn transfer_receive(&self) -> Result<TransferReceiveReport, CError> {
+        transfer_receiver::execute(&self.pool, self.cipher()?, self.chain.as_ref(), &self.server, self.network).await
+    }
+
+    pub async fn withdraw(&self, statechain_id: &str, address: &str, fee_rate: Option<u64>) -> Result<WithdrawResult, CError> {
+
+        let to_address = wallet::parse_address(address, self.network)?;
+
+        let fee_rate = self.fee_rate_or_estimate(fee_rate).await?;
+
+        withdraw::execute(&self.pool, self.cipher()?, self.chain.as_ref(), &self.server, statechain_id, &to_address, fee_rate, self.network).await
+    }
+}
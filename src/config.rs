@@ -0,0 +1,267 @@
+use std::{env, path::Path, str::FromStr};
+
+use bitcoin::Network;
+use serde::{Serialize, Deserialize};
+
+use crate::{chain::ChainBackendKind, error::CError, wallets};
+
+/// Config file read when no other path is given. It is optional.
+pub const DEFAULT_CONFIG_FILE: &str = "Settings.toml";
+
+/// Environment variable holding the path of the config file
+pub const CONFIG_FILE_ENV: &str = "MERCURY_CONFIG";
+
+const NETWORK_ENV: &str = "MERCURY_NETWORK";
+const CHAIN_BACKEND_ENV: &str = "MERCURY_CHAIN_BACKEND";
+const ELECTRUM_SERVER_ENV: &str = "MERCURY_ELECTRUM_SERVER";
+const ESPLORA_URL_ENV: &str = "MERCURY_ESPLORA_URL";
+const BITCOIND_RPC_URL_ENV: &str = "MERCURY_BITCOIND_RPC_URL";
+const BITCOIND_RPC_USER_ENV: &str = "MERCURY_BITCOIND_RPC_USER";
+const BITCOIND_RPC_PASSWORD_ENV: &str = "MERCURY_BITCOIND_RPC_PASSWORD";
+const STATECHAIN_ENTITY_ENV: &str = "MERCURY_STATECHAIN_ENTITY";
+const DATABASE_FILE_ENV: &str = "MERCURY_DATABASE_FILE";
+const WALLETS_DIR_ENV: &str = "MERCURY_WALLETS_DIR";
+const WALLET_ENV: &str = "MERCURY_WALLET";
+const DEPOSIT_TIMEOUT_ENV: &str = "MERCURY_DEPOSIT_TIMEOUT";
+const MIN_CONFIRMATIONS_ENV: &str = "MERCURY_MIN_CONFIRMATIONS";
+const BACKUP_ANCHOR_ENV: &str = "MERCURY_BACKUP_ANCHOR";
+const WATCH_INTERVAL_ENV: &str = "MERCURY_WATCH_INTERVAL";
+const WATCH_SAFETY_MARGIN_ENV: &str = "MERCURY_WATCH_SAFETY_MARGIN";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub network: Network,
+    /// Blockchain backend used to query addresses and broadcast transactions
+    pub chain_backend: ChainBackendKind,
+    /// Electrum server URL, e.g. tcp://127.0.0.1:50001
+    pub electrum_server: String,
+    /// Esplora API URL, e.g. http://127.0.0.1:3000
+    pub esplora_url: String,
+    /// bitcoind JSON-RPC URL, e.g. http://127.0.0.1:38332
+    pub bitcoind_rpc_url: String,
+    pub bitcoind_rpc_user: Option<String>,
+    pub bitcoind_rpc_password: Option<String>,
+    /// Statechain server URL, e.g. http://127.0.0.1:8000
+    pub statechain_entity: String,
+    /// Path of the SQLite wallet database. When a named wallet is used, it is the database of that wallet in `wallets_dir`.
+    pub database_file: String,
+    /// Directory of the named wallets, one database per wallet
+    pub wallets_dir: String,
+    /// Named wallet to use. If unset, `database_file` if it is set explicitly, otherwise the wallet selected with `select-wallet`.
+    pub wallet: Option<String>,
+    /// Seconds a deposit waits for its funding transaction, or a token for its payment, before giving up
+    pub deposit_timeout: u64,
+    /// Confirmations of the deposit transaction required before the backup transaction is signed
+    pub min_confirmations: u32,
+    /// Add an anchor output to new backup transactions so they can be fee bumped with `bump-backup`
+    pub backup_anchor: bool,
+    /// Seconds between two chain tip checks in `watch` mode
+    pub watch_interval: u64,
+    /// Blocks the watchtower keeps between broadcasting a backup transaction and the moment the backup transaction
+    /// of the previous owner becomes final. If unset, backup transactions are broadcast as soon as they are final.
+    pub watch_safety_margin: Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            network: Network::Signet,
+            chain_backend: ChainBackendKind::Electrum,
+            electrum_server: "tcp://127.0.0.1:50001".to_string(),
+            esplora_url: "http://127.0.0.1:3000".to_string(),
+            bitcoind_rpc_url: "http://127.0.0.1:38332".to_string(),
+            bitcoind_rpc_user: None,
+            bitcoind_rpc_password: None,
+            statechain_entity: "http://127.0.0.1:8000".to_string(),
+            database_file: "wallet.db".to_string(),
+            wallets_dir: "wallets".to_string(),
+            wallet: None,
+            deposit_timeout: 3600,
+            min_confirmations: 1,
+            backup_anchor: false,
+            watch_interval: 60,
+            watch_safety_margin: None,
+        }
+    }
+}
+
+/// Partial configuration. Each source (file, environment, command line) only sets the values it knows about.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConfigOverrides {
+    pub network: Option<String>,
+    pub chain_backend: Option<String>,
+    pub electrum_server: Option<String>,
+    pub esplora_url: Option<String>,
+    pub bitcoind_rpc_url: Option<String>,
+    pub bitcoind_rpc_user: Option<String>,
+    pub bitcoind_rpc_password: Option<String>,
+    pub statechain_entity: Option<String>,
+    pub database_file: Option<String>,
+    pub wallets_dir: Option<String>,
+    pub wallet: Option<String>,
+    pub deposit_timeout: Option<u64>,
+    pub min_confirmations: Option<u32>,
+    pub backup_anchor: Option<bool>,
+    pub watch_interval: Option<u64>,
+    pub watch_safety_margin: Option<u32>,
+}
+
+impl ConfigOverrides {
+
+    pub fn from_file(path: &str) -> Result<Self, CError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| CError::Config(format!("Failed to read config file {}: {}", path, e)))?;
+
+        ConfigOverrides::parse_toml(&contents, &format!("config file {}", path))
+    }
+
+    /// Contents of a config file
+    pub fn from_toml(contents: &str) -> Result<Self, CError> {
+        ConfigOverrides::parse_toml(contents, "config")
+    }
+
+    fn parse_toml(contents: &str, source: &str) -> Result<Self, CError> {
+        toml::from_str(contents)
+            .map_err(|e| CError::Config(format!("Failed to parse {}: {}", source, e)))
+    }
+
+    pub fn from_env() -> Result<Self, CError> {
+        Ok(ConfigOverrides {
+            network: env::var(NETWORK_ENV).ok(),
+            chain_backend: env::var(CHAIN_BACKEND_ENV).ok(),
+            electrum_server: env::var(ELECTRUM_SERVER_ENV).ok(),
+            esplora_url: env::var(ESPLORA_URL_ENV).ok(),
+            bitcoind_rpc_url: env::var(BITCOIND_RPC_URL_ENV).ok(),
+            bitcoind_rpc_user: env::var(BITCOIND_RPC_USER_ENV).ok(),
+            bitcoind_rpc_password: env::var(BITCOIND_RPC_PASSWORD_ENV).ok(),
+            statechain_entity: env::var(STATECHAIN_ENTITY_ENV).ok(),
+            database_file: env::var(DATABASE_FILE_ENV).ok(),
+            wallets_dir: env::var(WALLETS_DIR_ENV).ok(),
+            wallet: env::var(WALLET_ENV).ok(),
+            deposit_timeout: env::var(DEPOSIT_TIMEOUT_ENV).ok().map(|value| parse_seconds(&value)).transpose()?,
+            min_confirmations: env::var(MIN_CONFIRMATIONS_ENV).ok().map(|value| parse_confirmations(&value)).transpose()?,
+            backup_anchor: env::var(BACKUP_ANCHOR_ENV).ok().map(|value| parse_bool(&value)).transpose()?,
+            watch_interval: env::var(WATCH_INTERVAL_ENV).ok().map(|value| parse_seconds(&value)).transpose()?,
+            watch_safety_margin: env::var(WATCH_SAFETY_MARGIN_ENV).ok().map(|value| parse_blocks(&value)).transpose()?,
+        })
+    }
+}
+
+pub fn parse_network(network: &str) -> Result<Network, CError> {
+    match network {
+        "mainnet" => Ok(Network::Bitcoin),
+        _ => Network::from_str(network).map_err(|_| CError::Config(format!("Unknown network: {}", network))),
+    }
+}
+
+pub fn parse_seconds(value: &str) -> Result<u64, CError> {
+    value.parse::<u64>().map_err(|_| CError::Config(format!("Invalid number of seconds: {}", value)))
+}
+
+pub fn parse_confirmations(value: &str) -> Result<u32, CError> {
+    value.parse::<u32>().map_err(|_| CError::Config(format!("Invalid number of confirmations: {}", value)))
+}
+
+pub fn parse_blocks(value: &str) -> Result<u32, CError> {
+    value.parse::<u32>().map_err(|_| CError::Config(format!("Invalid number of blocks: {}", value)))
+}
+
+pub fn parse_bool(value: &str) -> Result<bool, CError> {
+    value.parse::<bool>().map_err(|_| CError::Config(format!("Invalid boolean: {} (expected true or false)", value)))
+}
+
+impl Config {
+
+    fn apply(&mut self, overrides: ConfigOverrides) -> Result<(), CError> {
+        if let Some(network) = overrides.network {
+            self.network = parse_network(&network)?;
+        }
+        if let Some(chain_backend) = overrides.chain_backend {
+            self.chain_backend = ChainBackendKind::from_str(&chain_backend)?;
+        }
+        if let Some(electrum_server) = overrides.electrum_server {
+            self.electrum_server = electrum_server;
+        }
+        if let Some(esplora_url) = overrides.esplora_url {
+            self.esplora_url = esplora_url;
+        }
+        if let Some(bitcoind_rpc_url) = overrides.bitcoind_rpc_url {
+            self.bitcoind_rpc_url = bitcoind_rpc_url;
+        }
+        if let Some(bitcoind_rpc_user) = overrides.bitcoind_rpc_user {
+            self.bitcoind_rpc_user = Some(bitcoind_rpc_user);
+        }
+        if let Some(bitcoind_rpc_password) = overrides.bitcoind_rpc_password {
+            self.bitcoind_rpc_password = Some(bitcoind_rpc_password);
+        }
+        if let Some(statechain_entity) = overrides.statechain_entity {
+            self.statechain_entity = statechain_entity;
+        }
+        if let Some(database_file) = overrides.database_file {
+            self.database_file = database_file;
+        }
+        if let Some(wallets_dir) = overrides.wallets_dir {
+            self.wallets_dir = wallets_dir;
+        }
+        if let Some(wallet) = overrides.wallet {
+            self.wallet = Some(wallet);
+        }
+        if let Some(deposit_timeout) = overrides.deposit_timeout {
+            self.deposit_timeout = deposit_timeout;
+        }
+        if let Some(min_confirmations) = overrides.min_confirmations {
+            self.min_confirmations = min_confirmations;
+        }
+        if let Some(backup_anchor) = overrides.backup_anchor {
+            self.backup_anchor = backup_anchor;
+        }
+        if let Some(watch_interval) = overrides.watch_interval {
+            self.watch_interval = watch_interval;
+        }
+        if let Some(watch_safety_margin) = overrides.watch_safety_margin {
+            self.watch_safety_margin = Some(watch_safety_margin);
+        }
+        Ok(())
+    }
+
+    /// Builds the configuration with the precedence: command line > environment variables > config file > defaults.
+    /// The config file is `config_file` if given, otherwise `$MERCURY_CONFIG`, otherwise `Settings.toml` if it exists.
+    /// The database is the one of the named wallet in use, if any.
+    pub fn load(config_file: Option<&str>, cli_overrides: ConfigOverrides) -> Result<Self, CError> {
+
+        let config_file = config_file.map(|path| path.to_string()).or(env::var(CONFIG_FILE_ENV).ok());
+
+        let file_overrides = match config_file {
+            Some(path) => ConfigOverrides::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => ConfigOverrides::from_file(DEFAULT_CONFIG_FILE)?,
+            None => ConfigOverrides::default(),
+        };
+
+        Config::from_sources(vec![file_overrides, ConfigOverrides::from_env()?, cli_overrides], wallets::get_selected_wallet)
+    }
+
+    /// Applies `sources` over the defaults, each one taking precedence over the previous ones.
+    /// `selected_wallet` reads the wallet selected in a wallets directory. It is only used when
+    /// no source gives a wallet or a database file.
+    pub fn from_sources<F>(sources: Vec<ConfigOverrides>, selected_wallet: F) -> Result<Self, CError>
+    where F: FnOnce(&str) -> Result<Option<String>, CError> {
+
+        let mut config = Config::default();
+
+        let database_file_given = sources.iter().any(|overrides| overrides.database_file.is_some());
+
+        for overrides in sources {
+            config.apply(overrides)?;
+        }
+
+        if config.wallet.is_none() && !database_file_given {
+            config.wallet = selected_wallet(&config.wallets_dir)?;
+        }
+
+        if let Some(wallet) = &config.wallet {
+            config.database_file = wallets::wallet_database_file(&config.wallets_dir, wallet)?;
+        }
+
+        Ok(config)
+    }
+}
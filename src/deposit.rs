@@ -1,106 +1,548 @@
-use std::{str::FromStr, thread, time::Duration};
+use std::{fmt, str::FromStr, time::{Duration, Instant}};
 
-use bitcoin::{Network, secp256k1, hashes::sha256, Address, TxOut, Txid};
-use electrum_client::ListUnspentRes;
+use bitcoin::{Network, secp256k1, hashes::sha256, Address, OutPoint, TxOut, Txid};
 use secp256k1_zkp::{Secp256k1, Message, PublicKey, musig::MusigKeyAggCache, SecretKey, XOnlyPublicKey, schnorr::Signature};
 use serde::{Serialize, Deserialize};
 use sqlx::{Sqlite, Row};
 
-use crate::{key_derivation, error::CError, electrum};
+use crate::{account, chain::{self, ChainBackend, Utxo}, encryption::WalletCipher, key_store, key_derivation::{self, AddressData}, error::CError, server::{StatechainServer, DepositRequestPayload, DepositCancelRequestPayload}, token, wallet::{self, StatecoinStatus}};
+
+/// Interval between two lookups of the deposit address while waiting for the funds
+const FUNDING_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Progress of a deposit. Each state is stored in `signer_data.deposit_status` as soon as it is reached,
+/// so an interrupted deposit can be continued with `resume`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepositStatus {
+    /// Keys derived, the server has not created the statecoin yet
+    KeysGenerated,
+    /// The server created the statecoin and sent its public key share
+    ServerInitialised,
+    /// The aggregated address is known and the wallet is waiting for the deposit transaction
+    AwaitingFunds,
+    /// The deposit transaction was found but does not have the required number of confirmations yet
+    FundedUnconfirmed,
+    /// The deposit transaction is confirmed but the backup transaction is not signed yet
+    Funded,
+    /// The backup transaction is signed and stored. The deposit is complete.
+    BackupSigned,
+    /// The deposit was abandoned before being funded. Its keys are reused by the next deposit.
+    Cancelled,
+}
+
+impl DepositStatus {
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DepositStatus::KeysGenerated => "KEYS_GENERATED",
+            DepositStatus::ServerInitialised => "SERVER_INITIALISED",
+            DepositStatus::AwaitingFunds => "AWAITING_FUNDS",
+            DepositStatus::FundedUnconfirmed => "FUNDED_UNCONFIRMED",
+            DepositStatus::Funded => "FUNDED",
+            DepositStatus::BackupSigned => "BACKUP_SIGNED",
+            DepositStatus::Cancelled => "CANCELLED",
+        }
+    }
+
+    /// Lifecycle status of the statecoin while the deposit is in this state
+    fn statecoin_status(&self) -> Option<StatecoinStatus> {
+        match self {
+            DepositStatus::KeysGenerated | DepositStatus::Cancelled => None,
+            DepositStatus::ServerInitialised | DepositStatus::AwaitingFunds => Some(StatecoinStatus::Initialised),
+            DepositStatus::FundedUnconfirmed => Some(StatecoinStatus::InMempool),
+            DepositStatus::Funded | DepositStatus::BackupSigned => Some(StatecoinStatus::Confirmed),
+        }
+    }
+}
+
+impl FromStr for DepositStatus {
+    type Err = CError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "KEYS_GENERATED" => Ok(DepositStatus::KeysGenerated),
+            "SERVER_INITIALISED" => Ok(DepositStatus::ServerInitialised),
+            "AWAITING_FUNDS" => Ok(DepositStatus::AwaitingFunds),
+            "FUNDED_UNCONFIRMED" => Ok(DepositStatus::FundedUnconfirmed),
+            "FUNDED" => Ok(DepositStatus::Funded),
+            "BACKUP_SIGNED" => Ok(DepositStatus::BackupSigned),
+            "CANCELLED" => Ok(DepositStatus::Cancelled),
+            _ => Err(CError::Database(format!("Unknown deposit status: {}", s))),
+        }
+    }
+}
 
-const TX_SIZE: u64 = 112; // virtual size one input P2TR and one output P2TR
-// 163 is the real size one input P2TR and one output P2TR
+impl fmt::Display for DepositStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DepositRequestPayload {
+/// Deposit data stored in `signer_data`. The optional fields are set as the deposit progresses.
+struct Deposit {
+    status: DepositStatus,
     amount: u64,
-    auth_key: String,
-    token_id: String,
-    signed_token_id: String,
+    client_pubkey: PublicKey,
+    backup_address: Address,
+    server_pubkey: Option<PublicKey>,
+    aggregated_pubkey: Option<XOnlyPublicKey>,
+    p2tr_agg_address: Option<Address>,
+    funding_txid: Option<Txid>,
+    funding_vout: Option<u32>,
+}
+
+fn required<T>(value: Option<T>, field: &str, statechain_id: &str) -> Result<T, CError> {
+    value.ok_or(CError::Database(format!("Deposit {} has no {}", statechain_id, field)))
 }
 
-pub async fn execute(pool: &sqlx::Pool<Sqlite>, token_id: uuid::Uuid, amount: u64, network: Network) -> Result<String, CError> {
+async fn get_deposit(pool: &sqlx::Pool<Sqlite>, statechain_id: &str, network: Network) -> Result<Deposit, CError> {
+
+    let query = "\
+        SELECT deposit_status, amount, client_pubkey_share, backup_address, \
+        server_pubkey_share, aggregated_pubkey, p2tr_agg_address, funding_txid, funding_vout \
+        FROM signer_data \
+        WHERE statechain_id = $1 AND deposit_status IS NOT NULL";
+
+    let row = sqlx::query(query)
+        .bind(statechain_id)
+        .fetch_optional(pool)
+        .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Err(CError::UserInput(format!("No deposit found for statecoin {}", statechain_id))),
+    };
+
+    let server_pubkey = match row.try_get::<Option<Vec<u8>>, _>("server_pubkey_share")? {
+        Some(bytes) => Some(PublicKey::from_slice(&bytes)?),
+        None => None,
+    };
+
+    let aggregated_pubkey = match row.try_get::<Option<Vec<u8>>, _>("aggregated_pubkey")? {
+        Some(bytes) => Some(XOnlyPublicKey::from_slice(&bytes)?),
+        None => None,
+    };
+
+    let p2tr_agg_address = match row.try_get::<Option<String>, _>("p2tr_agg_address")? {
+        Some(address) => Some(wallet::parse_address(&address, network)?),
+        None => None,
+    };
+
+    let funding_txid = match row.try_get::<Option<String>, _>("funding_txid")? {
+        Some(txid) => Some(Txid::from_str(&txid).map_err(|e| CError::Database(format!("Invalid funding txid {}: {}", txid, e)))?),
+        None => None,
+    };
 
-    let (statechain_id, client_secret_key, client_pubkey_share, to_address, server_pubkey_share, signed_statechain_id) = init(pool, token_id, amount, network).await;
-    let (aggregate_pub_key, address) = create_agg_pub_key(pool, &client_pubkey_share, &server_pubkey_share, network).await?;
+    Ok(Deposit {
+        status: DepositStatus::from_str(&row.try_get::<String, _>("deposit_status")?)?,
+        amount: row.try_get::<i64, _>("amount")? as u64,
+        client_pubkey: PublicKey::from_slice(&row.try_get::<Vec<u8>, _>("client_pubkey_share")?)?,
+        backup_address: wallet::parse_address(&row.try_get::<String, _>("backup_address")?, network)?,
+        server_pubkey,
+        aggregated_pubkey,
+        p2tr_agg_address,
+        funding_txid,
+        funding_vout: row.try_get::<Option<u32>, _>("funding_vout")?,
+    })
+}
 
-    let client = electrum_client::Client::new("tcp://127.0.0.1:50001").unwrap();
+async fn update_deposit_status(pool: &sqlx::Pool<Sqlite>, statechain_id: &str, status: DepositStatus) -> Result<(), CError> {
 
-    println!("address: {}", address.to_string());
+    let query = "\
+        UPDATE signer_data \
+        SET deposit_status = $1 \
+        WHERE statechain_id = $2";
 
-    println!("waiting for deposit ....");
+    let _ = sqlx::query(query)
+        .bind(status.as_str())
+        .bind(statechain_id)
+        .execute(pool)
+        .await?;
 
-    let delay = Duration::from_secs(5);
+    Ok(())
+}
 
-    let mut utxo: Option<ListUnspentRes> = None;
+/// Reported by `execute` and `resume` while they wait for the deposit transaction
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "progress", rename_all = "snake_case")]
+pub enum DepositProgress {
+    /// Waiting for `amount` sats to be sent to the deposit address
+    AwaitingFunds { statechain_id: String, address: String, amount: u64 },
+    /// The deposit transaction was found, waiting for its confirmations
+    AwaitingConfirmations { statechain_id: String, txid: Txid, min_confirmations: u32 },
+    /// The deposit transaction is no longer in the chain or the mempool, waiting for the funds again
+    FundingDropped { statechain_id: String, txid: Txid },
+}
+
+fn sign_statechain_id(auth_seckey: &SecretKey, statechain_id: &str) -> Result<Signature, CError> {
+    let secp = Secp256k1::new();
+    let keypair = secp256k1::KeyPair::from_seckey_slice(&secp, auth_seckey.as_ref())?;
+    let msg = Message::from_hashed_data::<sha256::Hash>(statechain_id.as_bytes());
+    Ok(secp.sign_schnorr(&msg, &keypair))
+}
+
+/// Creates a new deposit paid with `token_id` and runs it until the backup transaction is signed.
+/// If it is interrupted or times out waiting for the funds, it can be continued with `resume`.
+/// `on_progress` is told what the deposit is waiting for.
+pub async fn execute(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, chain: &dyn ChainBackend, server: &StatechainServer, token_id: uuid::Uuid, amount: u64, network: Network, min_confirmations: u32, timeout: Duration, anchor: bool, on_progress: &mut dyn FnMut(DepositProgress)) -> Result<String, CError> {
+
+    token::validate_unspent(pool, server, &token_id).await?;
+
+    let (statechain_id, _, _, _, _, _) = init(pool, cipher, server, token_id, amount, network).await?;
+
+    token::mark_spent(pool, &token_id).await?;
+
+    resume(pool, cipher, chain, server, &statechain_id, network, min_confirmations, timeout, anchor, on_progress).await
+}
+
+/// Continues a deposit from its last stored state until the backup transaction is signed.
+/// The backup transaction is only signed once the deposit transaction has `min_confirmations` confirmations.
+/// `timeout` bounds the time spent waiting for the deposit transaction and its confirmations.
+/// With `anchor`, the backup transaction gets an anchor output for fee bumping.
+/// `on_progress` is told what the deposit is waiting for.
+pub async fn resume(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, chain: &dyn ChainBackend, server: &StatechainServer, statechain_id: &str, network: Network, min_confirmations: u32, timeout: Duration, anchor: bool, on_progress: &mut dyn FnMut(DepositProgress)) -> Result<String, CError> {
+
+    let deadline = Instant::now() + timeout;
 
     loop {
-        let utxo_list = electrum::get_script_list_unspent(&client, &address);
+        let deposit = get_deposit(pool, statechain_id, network).await?;
+
+        let next_status = match deposit.status {
+            DepositStatus::KeysGenerated => {
+                return Err(CError::Database(format!("Deposit {} has a statechain id but was not initialised by the server", statechain_id)));
+            },
+            DepositStatus::ServerInitialised => {
+                let server_pubkey = required(deposit.server_pubkey, "server public key", statechain_id)?;
+                create_agg_pub_key(pool, &deposit.client_pubkey, &server_pubkey, network).await?;
+                DepositStatus::AwaitingFunds
+            },
+            DepositStatus::AwaitingFunds => {
+                let address = required(deposit.p2tr_agg_address, "deposit address", statechain_id)?;
+                on_progress(DepositProgress::AwaitingFunds { statechain_id: statechain_id.to_string(), address: address.to_string(), amount: deposit.amount });
+                let utxo = wait_for_funds(chain, &address, deposit.amount, deadline, statechain_id).await?;
+                update_funding_tx_outpoint(pool, Some(&utxo.txid), Some(utxo.vout), statechain_id).await?;
+                DepositStatus::FundedUnconfirmed
+            },
+            DepositStatus::FundedUnconfirmed => {
+                let address = required(deposit.p2tr_agg_address, "deposit address", statechain_id)?;
+                let funding_txid = required(deposit.funding_txid, "funding txid", statechain_id)?;
+                let funding_vout = required(deposit.funding_vout, "funding vout", statechain_id)?;
+
+                on_progress(DepositProgress::AwaitingConfirmations { statechain_id: statechain_id.to_string(), txid: funding_txid, min_confirmations });
+                if wait_for_confirmations(chain, &address, &funding_txid, funding_vout, min_confirmations, deadline, statechain_id).await? {
+                    DepositStatus::Funded
+                } else {
+                    on_progress(DepositProgress::FundingDropped { statechain_id: statechain_id.to_string(), txid: funding_txid });
+                    update_funding_tx_outpoint(pool, None, None, statechain_id).await?;
+                    DepositStatus::AwaitingFunds
+                }
+            },
+            DepositStatus::Funded => {
+                let address = required(deposit.p2tr_agg_address.clone(), "deposit address", statechain_id)?;
+                let funding_txid = required(deposit.funding_txid, "funding txid", statechain_id)?;
+                let funding_vout = required(deposit.funding_vout, "funding vout", statechain_id)?;
+
+                // The deposit transaction may have been reorganised out of the chain since it was confirmed
+                match funding_confirmations(chain, &address, &funding_txid, funding_vout).await? {
+                    Some(confirmations) if confirmations >= min_confirmations => {
+                        sign_backup_tx(pool, cipher, chain, server, statechain_id, deposit, network, anchor).await?;
+                        DepositStatus::BackupSigned
+                    },
+                    Some(_) => DepositStatus::FundedUnconfirmed,
+                    None => {
+                        update_funding_tx_outpoint(pool, None, None, statechain_id).await?;
+                        DepositStatus::AwaitingFunds
+                    },
+                }
+            },
+            DepositStatus::BackupSigned => return Ok(statechain_id.to_string()),
+            DepositStatus::Cancelled => return Err(CError::UserInput(format!("Deposit {} was cancelled", statechain_id))),
+        };
+
+        if let Some(status) = next_status.statecoin_status() {
+            wallet::update_status(pool, statechain_id, status).await?;
+        }
+
+        update_deposit_status(pool, statechain_id, next_status).await?;
+    }
+}
+
+fn timeout_error(statechain_id: &str, reason: String) -> CError {
+    CError::Timeout(format!(
+        "{} before the deposit timeout. Run deposit-resume {} to keep waiting or deposit-cancel {} to abandon it",
+        reason, statechain_id, statechain_id))
+}
+
+async fn wait_for_funds(chain: &dyn ChainBackend, address: &Address, amount: u64, deadline: Instant, statechain_id: &str) -> Result<Utxo, CError> {
 
-        for unspent in utxo_list {
-            if unspent.value == amount {
-                utxo = Some(unspent);
-                break;
-            }
+    loop {
+        let utxo_list = chain.list_unspent(address).await?;
+
+        if let Some(unspent) = utxo_list.into_iter().find(|unspent| unspent.value == amount) {
+            return Ok(unspent);
         }
 
-        if utxo.is_some() {
-            break;
+        let now = Instant::now();
+
+        if now >= deadline {
+            return Err(timeout_error(statechain_id, format!("No deposit of {} sats to {}", amount, address)));
         }
 
-        thread::sleep(delay);
+        tokio::time::sleep(FUNDING_POLL_INTERVAL.min(deadline - now)).await;
     }
+}
+
+/// Number of confirmations of the funding output, or `None` if it is no longer unspent in the chain or the mempool
+async fn funding_confirmations(chain: &dyn ChainBackend, address: &Address, txid: &Txid, vout: u32) -> Result<Option<u32>, CError> {
 
-    let utxo = utxo.unwrap();
+    let utxo_list = chain.list_unspent(address).await?;
 
-    update_funding_tx_outpoint(pool, &utxo.tx_hash, utxo.tx_pos as u32, &statechain_id).await;
+    let utxo = match utxo_list.into_iter().find(|utxo| utxo.txid == *txid && utxo.vout == vout) {
+        Some(utxo) => utxo,
+        None => return Ok(None),
+    };
 
-    let fee_rate_btc_per_kb = electrum::estimate_fee(&client, 3);
-    let fee_rate_sats_per_byte = (fee_rate_btc_per_kb * 100000.0) as u64;
+    let tip_height = chain.get_tip_height().await?;
+
+    Ok(Some(chain::confirmations(tip_height, utxo.height)))
+}
 
-    let absolute_fee: u64 = TX_SIZE * fee_rate_sats_per_byte; 
-    let amount_out = utxo.value - absolute_fee;
+/// Waits until the funding output has `min_confirmations` confirmations.
+/// Returns false if the output disappears, e.g. because of a reorg or a double spend of the deposit transaction.
+async fn wait_for_confirmations(chain: &dyn ChainBackend, address: &Address, txid: &Txid, vout: u32, min_confirmations: u32, deadline: Instant, statechain_id: &str) -> Result<bool, CError> {
 
-    let tx_out = TxOut { value: amount_out, script_pubkey: to_address.script_pubkey() };
+    loop {
+        let confirmations = match funding_confirmations(chain, address, txid, vout).await? {
+            Some(confirmations) => confirmations,
+            None => return Ok(false),
+        };
 
-    let block_header = electrum::block_headers_subscribe_raw(&client);
-    let mut block_height = block_header.height;
+        if confirmations >= min_confirmations {
+            return Ok(true);
+        }
 
-    block_height = block_height + 12000;
+        let now = Instant::now();
 
-    crate::transaction::new(pool, &statechain_id,).await.unwrap();
+        if now >= deadline {
+            return Err(timeout_error(statechain_id, format!("Deposit transaction {} has {} of {} confirmations", txid, confirmations, min_confirmations)));
+        }
+
+        tokio::time::sleep(FUNDING_POLL_INTERVAL.min(deadline - now)).await;
+    }
+}
+
+async fn sign_backup_tx(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, chain: &dyn ChainBackend, server: &StatechainServer, statechain_id: &str, deposit: Deposit, network: Network, anchor: bool) -> Result<(), CError> {
+
+    let server_pubkey_share = required(deposit.server_pubkey, "server public key", statechain_id)?;
+    let aggregate_pub_key = required(deposit.aggregated_pubkey, "aggregated public key", statechain_id)?;
+    let address = required(deposit.p2tr_agg_address, "deposit address", statechain_id)?;
+    let funding_txid = required(deposit.funding_txid, "funding txid", statechain_id)?;
+    let funding_vout = required(deposit.funding_vout, "funding vout", statechain_id)?;
+
+    let keys = wallet::get_statecoin_keys(pool, cipher, statechain_id, network).await?;
+
+    let signed_statechain_id = sign_statechain_id(&keys.auth_seckey, statechain_id)?;
+
+    let fee_rate_sats_per_byte = chain.estimate_fee_rate(3).await?;
+
+    let funding_outpoint = OutPoint { txid: funding_txid, vout: funding_vout };
+    let outputs = crate::transaction::with_anchor(TxOut { value: deposit.amount, script_pubkey: deposit.backup_address.script_pubkey() }, anchor)?;
+
+    let absolute_fee: u64 = crate::transaction::estimate_vsize(funding_outpoint, &outputs) * fee_rate_sats_per_byte;
+
+    if absolute_fee >= deposit.amount {
+        return Err(CError::UserInput(format!("Deposit amount {} does not cover the backup transaction fee {}", deposit.amount, absolute_fee)));
+    }
+
+    let amount_out = deposit.amount - absolute_fee;
+
+    let tx_out = TxOut { value: amount_out, script_pubkey: deposit.backup_address.script_pubkey() };
+
+    let block_height = crate::transaction::get_new_block_height(pool, chain, server, statechain_id).await?;
 
     let (tx, client_pub_nonce, blinding_factor) = crate::transaction::create(
-        block_height as u32,
-        &statechain_id,
+        server,
+        block_height,
+        statechain_id,
         &signed_statechain_id,
-        &client_secret_key,
-        &client_pubkey_share,
+        &keys.client_seckey,
+        &deposit.client_pubkey,
         &server_pubkey_share,
-        utxo.tx_hash, 
-        utxo.tx_pos as u32, 
+        funding_txid, 
+        funding_vout, 
         &aggregate_pub_key, 
         &address.script_pubkey(), 
-        utxo.value, 
-        tx_out).await.unwrap();
+        deposit.amount, 
+        tx_out,
+        anchor).await?;
 
     let tx_bytes = bitcoin::consensus::encode::serialize(&tx);
 
-    crate::transaction::insert_transaction(pool, &tx_bytes, &client_pub_nonce.serialize(), blinding_factor.as_bytes(), &statechain_id).await;
+    crate::transaction::insert_transaction(pool, &tx_bytes, &client_pub_nonce.serialize(), blinding_factor.as_bytes(), statechain_id).await?;
+
+    Ok(())
+}
+
+/// Progress of a deposit as reported to the user
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DepositInfo {
+    pub statechain_id: String,
+    pub status: String,
+    pub amount: u64,
+    pub deposit_address: Option<String>,
+    pub funding_txid: Option<Txid>,
+    pub funding_vout: Option<u32>,
+    /// Confirmations of the deposit transaction, `None` if it is unknown or no longer unspent
+    pub confirmations: Option<u32>,
+}
+
+pub async fn info(pool: &sqlx::Pool<Sqlite>, chain: &dyn ChainBackend, statechain_id: &str, network: Network) -> Result<DepositInfo, CError> {
+
+    let deposit = get_deposit(pool, statechain_id, network).await?;
+
+    let confirmations = match (&deposit.p2tr_agg_address, &deposit.funding_txid, deposit.funding_vout) {
+        (Some(address), Some(txid), Some(vout)) => funding_confirmations(chain, address, txid, vout).await?,
+        _ => None,
+    };
+
+    Ok(DepositInfo {
+        statechain_id: statechain_id.to_string(),
+        status: deposit.status.to_string(),
+        amount: deposit.amount,
+        deposit_address: deposit.p2tr_agg_address.map(|address| address.to_string()),
+        funding_txid: deposit.funding_txid,
+        funding_vout: deposit.funding_vout,
+        confirmations,
+    })
+}
+
+/// Abandons a deposit that has not been funded. The server is told to delete the statecoin
+/// and the keys of the deposit are kept for the next one.
+pub async fn cancel(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, chain: &dyn ChainBackend, server: &StatechainServer, statechain_id: &str, network: Network) -> Result<(), CError> {
+
+    let deposit = get_deposit(pool, statechain_id, network).await?;
+
+    match deposit.status {
+        DepositStatus::KeysGenerated | DepositStatus::ServerInitialised | DepositStatus::AwaitingFunds => {},
+        DepositStatus::Cancelled => return Err(CError::UserInput(format!("Deposit {} is already cancelled", statechain_id))),
+        DepositStatus::FundedUnconfirmed | DepositStatus::Funded | DepositStatus::BackupSigned => {
+            return Err(CError::UserInput(format!("Deposit {} is funded and can no longer be cancelled", statechain_id)));
+        },
+    }
+
+    // The funding transaction may have arrived since the last time the deposit was resumed
+    if let Some(address) = &deposit.p2tr_agg_address {
+        if !chain.list_unspent(address).await?.is_empty() {
+            return Err(CError::UserInput(format!("Deposit address {} has received funds. Run deposit-resume {} instead", address, statechain_id)));
+        }
+    }
+
+    let keys = wallet::get_statecoin_keys(pool, cipher, statechain_id, network).await?;
+
+    let signed_statechain_id = sign_statechain_id(&keys.auth_seckey, statechain_id)?;
+
+    let payload = DepositCancelRequestPayload {
+        statechain_id: statechain_id.to_string(),
+        signed_statechain_id: signed_statechain_id.to_string(),
+    };
+
+    match server.deposit_cancel(&payload).await {
+        Ok(()) => {},
+        // The server no longer knows the statecoin, there is nothing left to cancel on its side
+        Err(CError::ServerStatus { status: 404, .. }) => {},
+        Err(err) => return Err(err),
+    }
+
+    free_key_slot(pool, statechain_id).await
+}
+
+async fn free_key_slot(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Result<(), CError> {
+
+    let query = "\
+        UPDATE signer_data \
+        SET statechain_id = NULL, token_id = NULL, amount = NULL, server_pubkey_share = NULL, \
+        aggregated_pubkey = NULL, p2tr_agg_address = NULL, deposit_status = $1, status = NULL \
+        WHERE statechain_id = $2";
+
+    let _ = sqlx::query(query)
+        .bind(DepositStatus::Cancelled.as_str())
+        .bind(statechain_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Returns the keys of a cancelled deposit of the selected account, or of one the server never initialised, assigned to the new token.
+async fn get_free_key_slot(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, token_id: uuid::Uuid, amount: u64, network: Network) -> Result<Option<AddressData>, CError> {
+
+    let query = "\
+        SELECT client_pubkey_share, agg_key_derivation_path, auth_pubkey, auth_derivation_path, backup_address, transfer_address \
+        FROM signer_data \
+        WHERE statechain_id IS NULL AND deposit_status IN ($1, $2) AND account_index = $3 \
+        ORDER BY address_index \
+        LIMIT 1";
+
+    let row = sqlx::query(query)
+        .bind(DepositStatus::KeysGenerated.as_str())
+        .bind(DepositStatus::Cancelled.as_str())
+        .bind(account::get_selected_account(pool).await?)
+        .fetch_optional(pool)
+        .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let client_pubkey_share = PublicKey::from_slice(&row.try_get::<Vec<u8>, _>("client_pubkey_share")?)?;
+    let auth_pubkey = PublicKey::from_slice(&row.try_get::<Vec<u8>, _>("auth_pubkey")?)?;
 
-    Ok(statechain_id)
+    let query = "\
+        UPDATE signer_data \
+        SET token_id = $1, amount = $2 \
+        WHERE client_pubkey_share = $3";
 
+    let _ = sqlx::query(query)
+        .bind(token_id.to_string())
+        .bind(amount as i64)
+        .bind(&client_pubkey_share.serialize().to_vec())
+        .execute(pool)
+        .await?;
+
+    Ok(Some(AddressData {
+        client_secret_key: key_store::get_secret_key(pool, cipher, &client_pubkey_share, row.try_get::<Option<String>, _>("agg_key_derivation_path")?.as_deref(), network).await?,
+        client_pubkey_share,
+        auth_secret_key: key_store::get_secret_key(pool, cipher, &auth_pubkey, row.try_get::<Option<String>, _>("auth_derivation_path")?.as_deref(), network).await?,
+        auth_xonly_pubkey: auth_pubkey.x_only_public_key().0,
+        backup_address: wallet::parse_address(&row.try_get::<String, _>("backup_address")?, network)?,
+        transfer_address: row.try_get::<String, _>("transfer_address")?,
+    }))
 }
 
-pub async fn init(pool: &sqlx::Pool<Sqlite>, token_id: uuid::Uuid, amount: u64, network: Network) -> (String, SecretKey, PublicKey, Address, PublicKey, Signature) {
+pub async fn init(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, server: &StatechainServer, token_id: uuid::Uuid, amount: u64, network: Network) -> Result<(String, SecretKey, PublicKey, Address, PublicKey, Signature), CError> {
+
+    let address_data = match get_free_key_slot(pool, cipher, token_id, amount, network).await? {
+        Some(address_data) => address_data,
+        None => key_derivation::get_new_address(pool, cipher, Some(token_id), Some(amount), network).await?,
+    };
+
+    let query = "\
+        UPDATE signer_data \
+        SET deposit_status = $1 \
+        WHERE client_pubkey_share = $2";
 
-    let address_data = key_derivation::get_new_address(pool, Some(token_id), Some(amount), network).await;
+    let _ = sqlx::query(query)
+        .bind(DepositStatus::KeysGenerated.as_str())
+        .bind(&address_data.client_pubkey_share.serialize().to_vec())
+        .execute(pool)
+        .await?;
 
     let msg = Message::from_hashed_data::<sha256::Hash>(token_id.to_string().as_bytes());
 
     let secp = Secp256k1::new();
     let auth_secret_key = address_data.auth_secret_key;
-    let keypair = secp256k1::KeyPair::from_seckey_slice(&secp, auth_secret_key.as_ref()).unwrap();
+    let keypair = secp256k1::KeyPair::from_seckey_slice(&secp, auth_secret_key.as_ref())?;
     let signed_token_id = secp.sign_schnorr(&msg, &keypair);
     
     let deposit_request_payload = DepositRequestPayload {
@@ -110,55 +552,36 @@ pub async fn init(pool: &sqlx::Pool<Sqlite>, token_id: uuid::Uuid, amount: u64,
         signed_token_id: signed_token_id.to_string(),
     };
 
-    let endpoint = "http://127.0.0.1:8000";
-    let path = "deposit/init/pod";
+    let response = server.deposit_init(&deposit_request_payload).await?;
 
-    let client: reqwest::Client = reqwest::Client::new();
-    let request = client.post(&format!("{}/{}", endpoint, path));
+    let server_pubkey_share = PublicKey::from_str(&response.server_pubkey)
+        .map_err(|e| CError::Protocol(format!("Invalid server public key: {}", e)))?;
 
-    let value = match request.json(&deposit_request_payload).send().await {
-        Ok(response) => {
-            let text = response.text().await.unwrap();
-            text
-        },
-        Err(err) => {
-            // return Err(CError::Generic(err.to_string()));
-            panic!("error: {}", err);
-        },
-    };
-
-    #[derive(Serialize, Deserialize)]
-    pub struct PublicNonceRequestPayload<'r> {
-        server_pubkey: &'r str,
-        statechain_id: &'r str,
-    }
+    let statechain_id = response.statechain_id;
 
-    let response: PublicNonceRequestPayload = serde_json::from_str(value.as_str()).expect(&format!("failed to parse: {}", value.as_str()));
+    update_statechain_id(pool, statechain_id.clone(), &server_pubkey_share, &address_data.client_pubkey_share).await?;
 
-    let server_pubkey_share = PublicKey::from_str(&response.server_pubkey).unwrap();
+    let signed_statechain_id = sign_statechain_id(&auth_secret_key, &statechain_id)?;
 
-    let statechain_id = response.statechain_id.to_string();
-
-    update_statechain_id(pool, statechain_id.clone(), &address_data.client_pubkey_share).await;
-
-    let msg = Message::from_hashed_data::<sha256::Hash>(statechain_id.to_string().as_bytes());
-    let signed_statechain_id = secp.sign_schnorr(&msg, &keypair);
-
-    (statechain_id, address_data.client_secret_key, address_data.client_pubkey_share, address_data.backup_address, server_pubkey_share, signed_statechain_id)
+    Ok((statechain_id, address_data.client_secret_key, address_data.client_pubkey_share, address_data.backup_address, server_pubkey_share, signed_statechain_id))
 }
 
-pub async fn update_statechain_id(pool: &sqlx::Pool<Sqlite>, statechain_id: String, client_pubkey: &PublicKey) {
+pub async fn update_statechain_id(pool: &sqlx::Pool<Sqlite>, statechain_id: String, server_pubkey: &PublicKey, client_pubkey: &PublicKey) -> Result<(), CError> {
     let query = "\
         UPDATE signer_data \
-        SET statechain_id = $1 \
-        WHERE client_pubkey_share = $2";
+        SET statechain_id = $1, server_pubkey_share = $2, deposit_status = $3, status = $4 \
+        WHERE client_pubkey_share = $5";
 
     let _ = sqlx::query(query)
         .bind(&statechain_id)
+        .bind(&server_pubkey.serialize().to_vec())
+        .bind(DepositStatus::ServerInitialised.as_str())
+        .bind(StatecoinStatus::Initialised.as_str())
         .bind(&client_pubkey.serialize().to_vec())
         .execute(pool)
-        .await
-        .unwrap();
+        .await?;
+
+    Ok(())
 }
 
 pub async fn create_agg_pub_key(pool: &sqlx::Pool<Sqlite>, client_pubkey: &PublicKey, server_pubkey: &PublicKey, network: Network) -> Result<(XOnlyPublicKey, Address), CError> {
@@ -181,15 +604,14 @@ pub async fn create_agg_pub_key(pool: &sqlx::Pool<Sqlite>, client_pubkey: &Publi
         .bind(&address.to_string())
         .bind(&client_pubkey.serialize().to_vec())
         .execute(pool)
-        .await
-        .unwrap();
+        .await?;
 
     Ok((agg_pk,address))
 
 }
 
 
- async fn update_funding_tx_outpoint(pool: &sqlx::Pool<Sqlite>, txid: &Txid, vout: u32, statechain_id: &str) {
+ async fn update_funding_tx_outpoint(pool: &sqlx::Pool<Sqlite>, txid: Option<&Txid>, vout: Option<u32>, statechain_id: &str) -> Result<(), CError> {
 
     let query = "\
         UPDATE signer_data \
@@ -197,16 +619,19 @@ pub async fn create_agg_pub_key(pool: &sqlx::Pool<Sqlite>, client_pubkey: &Publi
         WHERE statechain_id = $3";
 
     let _ = sqlx::query(query)
-        .bind(&txid.to_string())
+        .bind(txid.map(|txid| txid.to_string()))
         .bind(vout)
         .bind(statechain_id)
         .execute(pool)
-        .await
-        .unwrap();
+        .await?;
+
+    Ok(())
  }
 
-pub async fn broadcast_backup_tx(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Txid {
-    
+pub async fn broadcast_backup_tx(pool: &sqlx::Pool<Sqlite>, chain: &dyn ChainBackend, statechain_id: &str) -> Result<Txid, CError> {
+
+    wallet::check_transition(pool, statechain_id, StatecoinStatus::BackupBroadcast).await?;
+
     let query = "\
         SELECT backup_tx \
         FROM backup_transaction \
@@ -215,15 +640,19 @@ pub async fn broadcast_backup_tx(pool: &sqlx::Pool<Sqlite>, statechain_id: &str)
 
     let row = sqlx::query(query)
         .bind(statechain_id)
-        .fetch_one(pool)
-        .await
-        .unwrap();
+        .fetch_optional(pool)
+        .await?;
 
-    let tx_bytes = row.get::<Vec<u8>, _>("backup_tx");
+    let row = match row {
+        Some(row) => row,
+        None => return Err(CError::UserInput(format!("No backup transaction found for statecoin {}", statechain_id))),
+    };
 
-    let client = electrum_client::Client::new("tcp://127.0.0.1:50001").unwrap();
-    
-    let txid = electrum::transaction_broadcast_raw(&client, &tx_bytes);
+    let tx_bytes = row.try_get::<Vec<u8>, _>("backup_tx")?;
+
+    let txid = chain.broadcast(&tx_bytes).await?;
+
+    wallet::update_status(pool, statechain_id, StatecoinStatus::BackupBroadcast).await?;
 
-    txid
+    Ok(txid)
 }
\ No newline at end of file
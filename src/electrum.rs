@@ -1,27 +1,74 @@
-use bitcoin::Txid;
-use electrum_client::{GetBalanceRes, ElectrumApi, GetHistoryRes, ListUnspentRes, RawHeaderNotification};
+use async_trait::async_trait;
+use bitcoin::{Address, Transaction, Txid};
+use electrum_client::ElectrumApi;
 
-/// return balance of address
-pub fn get_address_balance(electrum_client: &electrum_client::Client, address: &bitcoin::Address) -> GetBalanceRes {
-    electrum_client.script_get_balance(&address.script_pubkey()).unwrap()
-}
+use crate::{chain::{ChainBackend, AddressBalance, HistoryItem, Utxo, FALLBACK_FEE_RATE}, error::CError};
 
-pub fn get_address_history(electrum_client: &electrum_client::Client, address: &bitcoin::Address) -> Vec<GetHistoryRes> {
-    electrum_client.script_get_history(&address.script_pubkey()).unwrap()
+pub struct ElectrumBackend {
+    client: electrum_client::Client,
 }
 
-pub fn get_script_list_unspent(electrum_client: &electrum_client::Client, address: &bitcoin::Address) -> Vec<ListUnspentRes> {    
-    electrum_client.script_list_unspent(&address.script_pubkey()).unwrap()
+impl ElectrumBackend {
+    pub fn new(electrum_server: &str) -> Result<Self, CError> {
+        let client = electrum_client::Client::new(electrum_server)?;
+        Ok(ElectrumBackend { client })
+    }
 }
 
-pub fn transaction_broadcast_raw(electrum_client: &electrum_client::Client, raw_tx: &[u8]) -> Txid {    
-    electrum_client.transaction_broadcast_raw(raw_tx).unwrap()
-}
+#[async_trait]
+impl ChainBackend for ElectrumBackend {
 
-pub fn block_headers_subscribe_raw(electrum_client: &electrum_client::Client) -> RawHeaderNotification {    
-    electrum_client.block_headers_subscribe_raw().unwrap()
-}
+    async fn get_address_balance(&self, address: &Address) -> Result<AddressBalance, CError> {
+        let balance = self.client.script_get_balance(&address.script_pubkey())?;
+        Ok(AddressBalance { confirmed: balance.confirmed, unconfirmed: balance.unconfirmed })
+    }
+
+    async fn get_address_history(&self, address: &Address) -> Result<Vec<HistoryItem>, CError> {
+        let history = self.client.script_get_history(&address.script_pubkey())?;
+        // Electrum reports mempool transactions with a height of 0 or -1
+        Ok(history.into_iter().map(|item| HistoryItem {
+            txid: item.tx_hash,
+            height: if item.height > 0 { item.height as u32 } else { 0 },
+        }).collect())
+    }
+
+    async fn list_unspent(&self, address: &Address) -> Result<Vec<Utxo>, CError> {
+        let utxos = self.client.script_list_unspent(&address.script_pubkey())?;
+        Ok(utxos.into_iter().map(|utxo| Utxo {
+            txid: utxo.tx_hash,
+            vout: utxo.tx_pos as u32,
+            value: utxo.value,
+            height: utxo.height as u32,
+        }).collect())
+    }
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<Transaction, CError> {
+        Ok(self.client.transaction_get(txid)?)
+    }
+
+    async fn find_transaction(&self, txid: &Txid) -> Result<Option<Transaction>, CError> {
+        match self.client.transaction_get(txid) {
+            Ok(tx) => Ok(Some(tx)),
+            // Electrum servers relay the error of bitcoind, e.g. "No such mempool or blockchain transaction"
+            Err(electrum_client::Error::Protocol(error)) if error.to_string().contains("No such mempool") => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn broadcast(&self, raw_tx: &[u8]) -> Result<Txid, CError> {
+        Ok(self.client.transaction_broadcast_raw(raw_tx)?)
+    }
+
+    async fn get_tip_height(&self) -> Result<u32, CError> {
+        Ok(self.client.block_headers_subscribe_raw()?.height as u32)
+    }
 
-pub fn estimate_fee(electrum_client: &electrum_client::Client, number: usize) -> f64 {
-    electrum_client.estimate_fee(number).unwrap()
+    async fn estimate_fee_rate(&self, blocks: usize) -> Result<u64, CError> {
+        // Electrum returns BTC per kB, or -1 if it has no estimate
+        let fee_rate_btc_per_kb = self.client.estimate_fee(blocks)?;
+        if fee_rate_btc_per_kb <= 0.0 {
+            return Ok(FALLBACK_FEE_RATE);
+        }
+        Ok(((fee_rate_btc_per_kb * 100000.0) as u64).max(FALLBACK_FEE_RATE))
+    }
 }
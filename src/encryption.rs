@@ -0,0 +1,325 @@
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::{Aead, AeadCore, OsRng, Payload}};
+use argon2::{Algorithm, Argon2, Params, Version};
+use secp256k1_zkp::{PublicKey, Secp256k1, SecretKey};
+use sqlx::{Sqlite, Row};
+
+use crate::{error::CError, key_derivation::{self, WalletSeed}};
+
+const NONCE_SIZE: usize = 12;
+const KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+
+/// Associated data of the seed ciphertext. Secret keys use their public key instead, so a ciphertext cannot be moved to another row.
+pub const SEED_AAD: &[u8] = b"signer_seed";
+
+/// Associated data of the BIP39 passphrase ciphertext
+pub const BIP39_PASSPHRASE_AAD: &[u8] = b"bip39_passphrase";
+
+/// Associated data of the wallet key wrapped with the passphrase key
+const WALLET_KEY_AAD: &[u8] = b"wallet_key";
+
+/// Argon2id cost parameters used to derive the key from the passphrase. They are stored with the wallet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Authenticated encryption of the secret columns of the wallet database.
+/// Secrets are encrypted with a random wallet key, itself stored encrypted with a key derived from the passphrase,
+/// so changing the passphrase does not rewrite the secrets.
+pub struct WalletCipher {
+    cipher: Aes256Gcm,
+}
+
+impl WalletCipher {
+
+    fn new(key: &[u8]) -> Result<Self, CError> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| CError::KeyDerivation(e.to_string()))?;
+        Ok(WalletCipher { cipher })
+    }
+
+    /// Returns nonce || ciphertext
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, CError> {
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let ciphertext = self.cipher.encrypt(&nonce, Payload { msg: plaintext, aad })
+            .map_err(|e| CError::KeyDerivation(format!("Encryption failed: {}", e)))?;
+
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+
+        Ok(data)
+    }
+
+    pub fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CError> {
+
+        if data.len() < NONCE_SIZE {
+            return Err(CError::Database("Encrypted value too short".to_string()));
+        }
+
+        let nonce = Nonce::from_slice(&data[..NONCE_SIZE]);
+
+        self.cipher.decrypt(nonce, Payload { msg: &data[NONCE_SIZE..], aad })
+            .map_err(|_| CError::Database("Encrypted value cannot be decrypted with the wallet key".to_string()))
+    }
+
+    pub fn encrypt_secret_key(&self, secret_key: &SecretKey, public_key: &PublicKey) -> Result<Vec<u8>, CError> {
+        self.encrypt(&secret_key.secret_bytes(), &public_key.serialize())
+    }
+
+    pub fn decrypt_secret_key(&self, data: &[u8], public_key: &PublicKey) -> Result<SecretKey, CError> {
+        Ok(SecretKey::from_slice(&self.decrypt(data, &public_key.serialize())?)?)
+    }
+}
+
+fn derive_passphrase_key(passphrase: &str, salt: &[u8], params: KdfParams) -> Result<[u8; KEY_SIZE], CError> {
+
+    let argon2_params = Params::new(params.memory_kib, params.iterations, params.parallelism, Some(KEY_SIZE))
+        .map_err(|e| CError::KeyDerivation(format!("Invalid key derivation parameters: {}", e)))?;
+
+    let mut key = [0u8; KEY_SIZE];
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| CError::KeyDerivation(e.to_string()))?;
+
+    Ok(key)
+}
+
+fn check_passphrase(passphrase: &str) -> Result<(), CError> {
+    if passphrase.is_empty() {
+        return Err(CError::UserInput("The passphrase cannot be empty".to_string()));
+    }
+    Ok(())
+}
+
+/// Wraps `wallet_key` with a key derived from `passphrase` and a new salt, and stores it
+async fn store_wallet_key<'c, E>(executor: E, wallet_key: &[u8], passphrase: &str, params: KdfParams) -> Result<(), CError>
+where E: sqlx::Executor<'c, Database = Sqlite> {
+
+    let mut salt = [0u8; SALT_SIZE];
+    rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut salt);
+
+    let passphrase_key = derive_passphrase_key(passphrase, &salt, params)?;
+    let encrypted_key = WalletCipher::new(&passphrase_key)?.encrypt(wallet_key, WALLET_KEY_AAD)?;
+
+    let query = "\
+        INSERT INTO wallet_encryption (kdf_salt, kdf_memory_kib, kdf_iterations, kdf_parallelism, encrypted_key) \
+        VALUES ($1, $2, $3, $4, $5)";
+
+    let _ = sqlx::query(query)
+        .bind(salt.to_vec())
+        .bind(params.memory_kib)
+        .bind(params.iterations)
+        .bind(params.parallelism)
+        .bind(encrypted_key)
+        .execute(executor)
+        .await?;
+
+    Ok(())
+}
+
+fn new_wallet_key() -> [u8; KEY_SIZE] {
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(&Aes256Gcm::generate_key(&mut OsRng));
+    key
+}
+
+pub async fn is_encrypted(pool: &sqlx::Pool<Sqlite>) -> Result<bool, CError> {
+    let row = sqlx::query("SELECT COUNT(*) AS count FROM wallet_encryption")
+        .fetch_one(pool)
+        .await?;
+    Ok(row.try_get::<i64, _>("count")? > 0)
+}
+
+async fn has_seed(pool: &sqlx::Pool<Sqlite>) -> Result<bool, CError> {
+    let row = sqlx::query("SELECT COUNT(*) AS count FROM signer_seed")
+        .fetch_one(pool)
+        .await?;
+    Ok(row.try_get::<i64, _>("count")? > 0)
+}
+
+/// Whether the wallet has a seed, encrypted or not
+pub async fn wallet_exists(pool: &sqlx::Pool<Sqlite>) -> Result<bool, CError> {
+    Ok(is_encrypted(pool).await? || has_seed(pool).await?)
+}
+
+/// Creates the wallet: a new BIP39 seed with the optional `bip39_passphrase`, encrypted with a key protected by `passphrase`
+pub async fn create_wallet(pool: &sqlx::Pool<Sqlite>, passphrase: &str, params: KdfParams, bip39_passphrase: &str) -> Result<WalletCipher, CError> {
+    new_wallet(pool, passphrase, params, &WalletSeed::generate(bip39_passphrase)).await
+}
+
+/// Creates the wallet from an existing seed, encrypted with a key protected by `passphrase`
+pub async fn restore_wallet(pool: &sqlx::Pool<Sqlite>, passphrase: &str, params: KdfParams, seed: &WalletSeed) -> Result<WalletCipher, CError> {
+    new_wallet(pool, passphrase, params, seed).await
+}
+
+/// Stores a new wallet key protected by `passphrase` and `seed` encrypted with it, if the wallet does not exist yet
+async fn new_wallet(pool: &sqlx::Pool<Sqlite>, passphrase: &str, params: KdfParams, seed: &WalletSeed) -> Result<WalletCipher, CError> {
+
+    check_passphrase(passphrase)?;
+
+    if is_encrypted(pool).await? {
+        return Err(CError::UserInput("The wallet already exists".to_string()));
+    }
+
+    if has_seed(pool).await? {
+        return Err(CError::UserInput("The wallet already exists but is not encrypted. Run encrypt-wallet instead".to_string()));
+    }
+
+    let wallet_key = new_wallet_key();
+    let cipher = WalletCipher::new(&wallet_key)?;
+
+    // A wallet key without a seed would make the wallet unusable
+    let mut tx = pool.begin().await?;
+
+    store_wallet_key(&mut *tx, &wallet_key, passphrase, params).await?;
+    key_derivation::insert_seed(&mut *tx, &cipher, seed).await?;
+
+    tx.commit().await?;
+
+    Ok(cipher)
+}
+
+/// Decrypts the wallet key with `passphrase`
+pub async fn unlock(pool: &sqlx::Pool<Sqlite>, passphrase: &str) -> Result<WalletCipher, CError> {
+    WalletCipher::new(&unwrap_wallet_key(pool, passphrase).await?)
+}
+
+async fn unwrap_wallet_key(pool: &sqlx::Pool<Sqlite>, passphrase: &str) -> Result<Vec<u8>, CError> {
+
+    let query = "\
+        SELECT kdf_salt, kdf_memory_kib, kdf_iterations, kdf_parallelism, encrypted_key \
+        FROM wallet_encryption";
+
+    let row = sqlx::query(query)
+        .fetch_optional(pool)
+        .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => {
+            if has_seed(pool).await? {
+                return Err(CError::UserInput("The wallet is not encrypted. Run encrypt-wallet first".to_string()));
+            }
+            return Err(CError::UserInput("There is no wallet. Run create-wallet first".to_string()));
+        },
+    };
+
+    let params = KdfParams {
+        memory_kib: row.try_get::<u32, _>("kdf_memory_kib")?,
+        iterations: row.try_get::<u32, _>("kdf_iterations")?,
+        parallelism: row.try_get::<u32, _>("kdf_parallelism")?,
+    };
+
+    let passphrase_key = derive_passphrase_key(passphrase, &row.try_get::<Vec<u8>, _>("kdf_salt")?, params)?;
+
+    // The authentication tag of the wallet key tells whether the passphrase is right
+    WalletCipher::new(&passphrase_key)?
+        .decrypt(&row.try_get::<Vec<u8>, _>("encrypted_key")?, WALLET_KEY_AAD)
+        .map_err(|_| CError::UserInput("Wrong passphrase".to_string()))
+}
+
+/// Protects the wallet key with `new_passphrase`. The secrets themselves are not re-encrypted.
+pub async fn change_passphrase(pool: &sqlx::Pool<Sqlite>, passphrase: &str, new_passphrase: &str, params: KdfParams) -> Result<(), CError> {
+
+    check_passphrase(new_passphrase)?;
+
+    let wallet_key = unwrap_wallet_key(pool, passphrase).await?;
+
+    let mut tx = pool.begin().await?;
+
+    let _ = sqlx::query("DELETE FROM wallet_encryption")
+        .execute(&mut *tx)
+        .await?;
+
+    store_wallet_key(&mut *tx, &wallet_key, new_passphrase, params).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Encrypts in place the seed and the secret keys of a wallet created before encryption was supported
+pub async fn encrypt_wallet(pool: &sqlx::Pool<Sqlite>, passphrase: &str, params: KdfParams) -> Result<WalletCipher, CError> {
+
+    check_passphrase(passphrase)?;
+
+    if is_encrypted(pool).await? {
+        return Err(CError::UserInput("The wallet is already encrypted".to_string()));
+    }
+
+    let wallet_key = new_wallet_key();
+    let cipher = WalletCipher::new(&wallet_key)?;
+
+    // All or nothing: a wallet must never be left partly encrypted
+    let mut tx = pool.begin().await?;
+
+    let seed_rows = sqlx::query("SELECT rowid, seed FROM signer_seed")
+        .fetch_all(&mut *tx)
+        .await?;
+
+    for row in seed_rows {
+        let _ = sqlx::query("UPDATE signer_seed SET seed = $1 WHERE rowid = $2")
+            .bind(cipher.encrypt(&row.try_get::<Vec<u8>, _>("seed")?, SEED_AAD)?)
+            .bind(row.try_get::<i64, _>("rowid")?)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let key_rows = sqlx::query("SELECT rowid, client_seckey_share, client_pubkey_share, auth_seckey, auth_pubkey FROM signer_data")
+        .fetch_all(&mut *tx)
+        .await?;
+
+    for row in key_rows {
+
+        // A secret key without its public key gets the derived one, which is the associated data of its ciphertext
+        let encrypt_column = |seckey: Option<Vec<u8>>, pubkey: Option<Vec<u8>>| -> Result<(Option<Vec<u8>>, Option<Vec<u8>>), CError> {
+            match (seckey, pubkey) {
+                (Some(seckey), pubkey) => {
+                    let secret_key = SecretKey::from_slice(&seckey)?;
+                    let public_key = match pubkey {
+                        Some(pubkey) => PublicKey::from_slice(&pubkey)?,
+                        None => PublicKey::from_secret_key(&Secp256k1::new(), &secret_key),
+                    };
+                    Ok((Some(cipher.encrypt_secret_key(&secret_key, &public_key)?), Some(public_key.serialize().to_vec())))
+                },
+                (None, pubkey) => Ok((None, pubkey)),
+            }
+        };
+
+        let (client_seckey, client_pubkey) = encrypt_column(row.try_get("client_seckey_share")?, row.try_get("client_pubkey_share")?)?;
+        let (auth_seckey, auth_pubkey) = encrypt_column(row.try_get("auth_seckey")?, row.try_get("auth_pubkey")?)?;
+
+        let _ = sqlx::query("\
+            UPDATE signer_data \
+            SET client_seckey_share = $1, client_pubkey_share = $2, auth_seckey = $3, auth_pubkey = $4 \
+            WHERE rowid = $5")
+            .bind(client_seckey)
+            .bind(client_pubkey)
+            .bind(auth_seckey)
+            .bind(auth_pubkey)
+            .bind(row.try_get::<i64, _>("rowid")?)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    store_wallet_key(&mut *tx, &wallet_key, passphrase, params).await?;
+
+    tx.commit().await?;
+
+    Ok(cipher)
+}
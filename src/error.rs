@@ -1,7 +1,124 @@
+use std::fmt;
+
 use serde::{Serialize, Deserialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum CError {
-    /// Generic error from string error message
-    Generic(String)
-}
\ No newline at end of file
+    /// Error reading or writing the wallet database
+    Database(String),
+    /// Error communicating with the blockchain backend (Electrum, Esplora or bitcoind)
+    Chain(String),
+    /// The statechain server could not be reached or sent a response that could not be parsed
+    ServerHttp(String),
+    /// The statechain server answered with a non-success status code
+    ServerStatus { status: u16, message: String },
+    /// Data received from the server or the blockchain failed validation
+    Protocol(String),
+    /// Error deriving, parsing or using keys
+    KeyDerivation(String),
+    /// Invalid configuration
+    Config(String),
+    /// Invalid input provided by the user
+    UserInput(String),
+    /// An operation did not complete in the configured time. It can usually be resumed.
+    Timeout(String),
+}
+
+impl CError {
+
+    /// Short name of the error kind, used in the JSON error output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CError::Database(_) => "database",
+            CError::Chain(_) => "chain",
+            CError::ServerHttp(_) => "server_http",
+            CError::ServerStatus { .. } => "server_status",
+            CError::Protocol(_) => "protocol",
+            CError::KeyDerivation(_) => "key_derivation",
+            CError::Config(_) => "config",
+            CError::UserInput(_) => "user_input",
+            CError::Timeout(_) => "timeout",
+        }
+    }
+
+    /// Process exit code of the CLI for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CError::UserInput(_) => 2,
+            CError::Config(_) => 3,
+            CError::Database(_) => 4,
+            CError::Chain(_) => 5,
+            CError::ServerHttp(_) => 6,
+            CError::ServerStatus { .. } => 7,
+            CError::Protocol(_) => 8,
+            CError::KeyDerivation(_) => 9,
+            CError::Timeout(_) => 10,
+        }
+    }
+}
+
+impl fmt::Display for CError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CError::Database(message) => write!(f, "database error: {}", message),
+            CError::Chain(message) => write!(f, "blockchain backend error: {}", message),
+            CError::ServerHttp(message) => write!(f, "server error: {}", message),
+            CError::ServerStatus { status, message } => write!(f, "server returned status {}: {}", status, message),
+            CError::Protocol(message) => write!(f, "protocol error: {}", message),
+            CError::KeyDerivation(message) => write!(f, "key error: {}", message),
+            CError::Config(message) => write!(f, "config error: {}", message),
+            CError::UserInput(message) => write!(f, "invalid input: {}", message),
+            CError::Timeout(message) => write!(f, "timeout: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for CError {}
+
+impl From<sqlx::Error> for CError {
+    fn from(err: sqlx::Error) -> Self {
+        CError::Database(err.to_string())
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for CError {
+    fn from(err: sqlx::migrate::MigrateError) -> Self {
+        CError::Database(err.to_string())
+    }
+}
+
+impl From<electrum_client::Error> for CError {
+    fn from(err: electrum_client::Error) -> Self {
+        CError::Chain(err.to_string())
+    }
+}
+
+impl From<reqwest::Error> for CError {
+    fn from(err: reqwest::Error) -> Self {
+        CError::ServerHttp(err.to_string())
+    }
+}
+
+impl From<bitcoin::bip32::Error> for CError {
+    fn from(err: bitcoin::bip32::Error) -> Self {
+        CError::KeyDerivation(err.to_string())
+    }
+}
+
+impl From<bitcoin::secp256k1::Error> for CError {
+    fn from(err: bitcoin::secp256k1::Error) -> Self {
+        CError::KeyDerivation(err.to_string())
+    }
+}
+
+impl From<hex::FromHexError> for CError {
+    fn from(err: hex::FromHexError) -> Self {
+        CError::Protocol(err.to_string())
+    }
+}
+
+impl From<bitcoin::consensus::encode::Error> for CError {
+    fn from(err: bitcoin::consensus::encode::Error) -> Self {
+        CError::Protocol(err.to_string())
+    }
+}
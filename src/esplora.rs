@@ -0,0 +1,178 @@
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use async_trait::async_trait;
+use bitcoin::{Address, Transaction, Txid};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::{chain::{ChainBackend, AddressBalance, HistoryItem, Utxo, FALLBACK_FEE_RATE}, error::CError};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TxStatus {
+    confirmed: bool,
+    block_height: Option<u32>,
+}
+
+impl TxStatus {
+    fn height(&self) -> u32 {
+        if self.confirmed { self.block_height.unwrap_or(0) } else { 0 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AddressStats {
+    funded_txo_sum: u64,
+    spent_txo_sum: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AddressInfo {
+    chain_stats: AddressStats,
+    mempool_stats: AddressStats,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EsploraTx {
+    txid: Txid,
+    status: TxStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EsploraUtxo {
+    txid: Txid,
+    vout: u32,
+    value: u64,
+    status: TxStatus,
+}
+
+/// Backend for the Esplora HTTP API (Blockstream electrs, mempool.space)
+pub struct EsploraBackend {
+    http_client: reqwest::Client,
+    url: String,
+}
+
+impl EsploraBackend {
+
+    pub fn new(url: &str) -> Result<Self, CError> {
+        let http_client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| CError::Chain(e.to_string()))?;
+
+        Ok(EsploraBackend { http_client, url: url.trim_end_matches('/').to_string() })
+    }
+
+    async fn get_response(&self, path: &str) -> Result<reqwest::Response, CError> {
+        self.get_optional_response(path).await?
+            .ok_or(CError::Chain(format!("esplora {} returned status 404", path)))
+    }
+
+    /// `None` if the resource does not exist
+    async fn get_optional_response(&self, path: &str) -> Result<Option<reqwest::Response>, CError> {
+
+        let response = self.http_client.get(&format!("{}/{}", self.url, path)).send().await
+            .map_err(|e| CError::Chain(e.to_string()))?;
+
+        let status = response.status();
+
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(CError::Chain(format!("esplora {} returned status {}: {}", path, status.as_u16(), message)));
+        }
+
+        Ok(Some(response))
+    }
+
+    async fn get_text(&self, path: &str) -> Result<String, CError> {
+        self.get_response(path).await?.text().await.map_err(|e| CError::Chain(e.to_string()))
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, CError> {
+        let value = self.get_text(path).await?;
+        serde_json::from_str(&value).map_err(|e| CError::Chain(format!("failed to parse esplora {} response: {}", path, e)))
+    }
+}
+
+#[async_trait]
+impl ChainBackend for EsploraBackend {
+
+    async fn get_address_balance(&self, address: &Address) -> Result<AddressBalance, CError> {
+        let info: AddressInfo = self.get_json(&format!("address/{}", address)).await?;
+        Ok(AddressBalance {
+            confirmed: info.chain_stats.funded_txo_sum.saturating_sub(info.chain_stats.spent_txo_sum),
+            unconfirmed: info.mempool_stats.funded_txo_sum as i64 - info.mempool_stats.spent_txo_sum as i64,
+        })
+    }
+
+    async fn get_address_history(&self, address: &Address) -> Result<Vec<HistoryItem>, CError> {
+        let txs: Vec<EsploraTx> = self.get_json(&format!("address/{}/txs", address)).await?;
+        Ok(txs.into_iter().map(|tx| HistoryItem { txid: tx.txid, height: tx.status.height() }).collect())
+    }
+
+    async fn list_unspent(&self, address: &Address) -> Result<Vec<Utxo>, CError> {
+        let utxos: Vec<EsploraUtxo> = self.get_json(&format!("address/{}/utxo", address)).await?;
+        Ok(utxos.into_iter().map(|utxo| Utxo {
+            txid: utxo.txid,
+            vout: utxo.vout,
+            value: utxo.value,
+            height: utxo.status.height(),
+        }).collect())
+    }
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<Transaction, CError> {
+        let response = self.get_response(&format!("tx/{}/raw", txid)).await?;
+        let tx_bytes = response.bytes().await.map_err(|e| CError::Chain(e.to_string()))?;
+        Ok(bitcoin::consensus::deserialize(&tx_bytes)?)
+    }
+
+    async fn find_transaction(&self, txid: &Txid) -> Result<Option<Transaction>, CError> {
+        let response = match self.get_optional_response(&format!("tx/{}/raw", txid)).await? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        let tx_bytes = response.bytes().await.map_err(|e| CError::Chain(e.to_string()))?;
+        Ok(Some(bitcoin::consensus::deserialize(&tx_bytes)?))
+    }
+
+    async fn broadcast(&self, raw_tx: &[u8]) -> Result<Txid, CError> {
+
+        let response = self.http_client.post(&format!("{}/tx", self.url))
+            .body(hex::encode(raw_tx))
+            .send()
+            .await
+            .map_err(|e| CError::Chain(e.to_string()))?;
+
+        let status = response.status();
+        let value = response.text().await.map_err(|e| CError::Chain(e.to_string()))?;
+
+        if !status.is_success() {
+            return Err(CError::Chain(format!("esplora rejected the transaction: {}", value)));
+        }
+
+        Txid::from_str(value.trim()).map_err(|e| CError::Chain(format!("invalid txid {}: {}", value, e)))
+    }
+
+    async fn get_tip_height(&self) -> Result<u32, CError> {
+        let value = self.get_text("blocks/tip/height").await?;
+        value.trim().parse::<u32>().map_err(|e| CError::Chain(format!("invalid tip height {}: {}", value, e)))
+    }
+
+    async fn estimate_fee_rate(&self, blocks: usize) -> Result<u64, CError> {
+        // Map of confirmation target to sats per vbyte
+        let estimates: HashMap<String, f64> = self.get_json("fee-estimates").await?;
+
+        // Use the estimate of the closest target that is not longer than `blocks`
+        let fee_rate = estimates.iter()
+            .filter_map(|(target, fee_rate)| target.parse::<usize>().ok().map(|target| (target, *fee_rate)))
+            .filter(|(target, _)| *target <= blocks)
+            .max_by_key(|(target, _)| *target)
+            .map(|(_, fee_rate)| fee_rate.ceil() as u64);
+
+        Ok(fee_rate.unwrap_or(FALLBACK_FEE_RATE).max(FALLBACK_FEE_RATE))
+    }
+}
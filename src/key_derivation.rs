@@ -7,102 +7,240 @@ use sqlx::{Sqlite, Row};
 use uuid::Uuid;
 use bech32::{self, WriteBase32, FromBase32, ToBase32, Variant};
 
-use crate::error::CError;
+use crate::{account, encryption::{self, WalletCipher}, error::CError};
 
-async fn generate_or_get_seed(pool: &sqlx::Pool<Sqlite>) -> [u8; 32] {
+/// Purpose of the statecoin keys, whose public keys are aggregated with the server key shares
+pub const AGG_KEY_PURPOSE: u32 = 86;
 
-    let rows = sqlx::query("SELECT * FROM signer_seed")
+/// Purpose of the keys that authenticate the owner of a statecoin to the server
+pub const AUTH_KEY_PURPOSE: u32 = 89;
+
+/// BIP44 coin type: 0 on mainnet and 1 on the test networks.
+/// Legacy wallets always used 0, which they keep so that their addresses are derived again identically.
+pub fn coin_type(network: Network, derivation: SeedDerivation) -> u32 {
+    match (network, derivation) {
+        (_, SeedDerivation::Legacy) | (Network::Bitcoin, _) => 0,
+        _ => 1,
+    }
+}
+
+/// Path of the account of `purpose`, e.g. m/86h/1h/0h for the statecoin keys of the first account of a Signet wallet
+pub fn account_path(purpose: u32, coin_type: u32, account_index: u32) -> String {
+    format!("m/{}h/{}h/{}h", purpose, coin_type, account_index)
+}
+
+/// How the BIP32 master key is derived from the entropy stored in `signer_seed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedDerivation {
+    /// Wallets created by earlier versions: the 32 bytes of entropy are the BIP32 seed
+    Legacy,
+    /// The BIP39 seed of the mnemonic and of its optional passphrase, as in any standard wallet
+    Bip39,
+}
+
+impl SeedDerivation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SeedDerivation::Legacy => "LEGACY",
+            SeedDerivation::Bip39 => "BIP39",
+        }
+    }
+}
+
+impl FromStr for SeedDerivation {
+    type Err = CError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "LEGACY" => Ok(SeedDerivation::Legacy),
+            "BIP39" => Ok(SeedDerivation::Bip39),
+            _ => Err(CError::Database(format!("Invalid seed derivation: {}", s))),
+        }
+    }
+}
+
+/// The seed of the wallet: the entropy encoded by the mnemonic and how the keys are derived from it
+pub struct WalletSeed {
+    pub entropy: [u8; 32],
+    pub derivation: SeedDerivation,
+    /// The BIP39 passphrase, or "25th word". Always empty for legacy wallets.
+    pub bip39_passphrase: String,
+}
+
+impl WalletSeed {
+    /// A new random seed with the standard BIP39 derivation
+    pub fn generate(bip39_passphrase: &str) -> Self {
+        let mut entropy = [0u8; 32];  // 256 bits
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut entropy);
+
+        WalletSeed {
+            entropy,
+            derivation: SeedDerivation::Bip39,
+            bip39_passphrase: bip39_passphrase.to_string(),
+        }
+    }
+
+    /// The seed encoded by a mnemonic shown by `get_mnemonic`
+    pub fn from_mnemonic(mnemonic: &str, derivation: SeedDerivation, bip39_passphrase: &str) -> Result<Self, CError> {
+
+        if derivation == SeedDerivation::Legacy && !bip39_passphrase.is_empty() {
+            return Err(CError::UserInput("Legacy wallets have no BIP39 passphrase".to_string()));
+        }
+
+        let mnemonic = Mnemonic::parse_in(Language::English, mnemonic)
+            .map_err(|e| CError::UserInput(format!("Invalid mnemonic: {}", e)))?;
+
+        let entropy = mnemonic.to_entropy();
+
+        if entropy.len() != 32 {
+            return Err(CError::UserInput("Invalid mnemonic: the wallet seed is encoded in 24 words".to_string()));
+        }
+
+        let mut seed = WalletSeed {
+            entropy: [0u8; 32],
+            derivation,
+            bip39_passphrase: bip39_passphrase.to_string(),
+        };
+        seed.entropy.copy_from_slice(&entropy);
+
+        Ok(seed)
+    }
+
+    pub fn mnemonic(&self) -> Result<Mnemonic, CError> {
+        Mnemonic::from_entropy_in(Language::English, &self.entropy).map_err(|e| CError::KeyDerivation(e.to_string()))
+    }
+
+    /// The seed of the BIP32 master key
+    pub fn master_seed(&self) -> Result<Vec<u8>, CError> {
+        match self.derivation {
+            SeedDerivation::Legacy => Ok(self.entropy.to_vec()),
+            SeedDerivation::Bip39 => Ok(self.mnemonic()?.to_seed(self.bip39_passphrase.as_str()).to_vec()),
+        }
+    }
+}
+
+pub async fn get_seed(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher) -> Result<WalletSeed, CError> {
+
+    let rows = sqlx::query("SELECT seed, derivation, bip39_passphrase FROM signer_seed")
         .fetch_all(pool)
-        .await
-        .unwrap();
+        .await?;
 
     if rows.len() > 1 {
-        panic!("More than one seed in database");
+        return Err(CError::Database("More than one seed in database".to_string()));
+    }
+
+    let row = match rows.first() {
+        Some(row) => row,
+        None => return Err(CError::UserInput("The wallet has no seed. Create it with create-wallet or restore".to_string())),
+    };
+
+    let entropy = cipher.decrypt(&row.try_get::<Vec<u8>, _>("seed")?, encryption::SEED_AAD)?;
+    if entropy.len() != 32 {
+        return Err(CError::Database("Invalid seed length in database".to_string()));
     }
 
-    if rows.len() == 1 {
-        let row = rows.get(0).unwrap();
-        let seed = row.get::<Vec<u8>, _>("seed");
-        let mut seed_array = [0u8; 32];
-        seed_array.copy_from_slice(&seed);
-        return seed_array;
+    let bip39_passphrase = match row.try_get::<Option<Vec<u8>>, _>("bip39_passphrase")? {
+        Some(encrypted) => String::from_utf8(cipher.decrypt(&encrypted, encryption::BIP39_PASSPHRASE_AAD)?)
+            .map_err(|e| CError::Database(format!("Invalid BIP39 passphrase in database: {}", e)))?,
+        None => String::new(),
+    };
+
+    let mut seed = WalletSeed {
+        entropy: [0u8; 32],
+        derivation: SeedDerivation::from_str(&row.try_get::<String, _>("derivation")?)?,
+        bip39_passphrase,
+    };
+    seed.entropy.copy_from_slice(&entropy);
+
+    Ok(seed)
+}
+
+pub async fn insert_seed<'c, E>(executor: E, cipher: &WalletCipher, seed: &WalletSeed) -> Result<(), CError>
+where E: sqlx::Executor<'c, Database = Sqlite> {
+
+    // An empty passphrase is not stored, as in legacy wallets
+    let bip39_passphrase = if seed.bip39_passphrase.is_empty() {
+        None
     } else {
-        let mut seed = [0u8; 32];  // 256 bits
-        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut seed);
-        
-        let query = "INSERT INTO signer_seed (seed) VALUES ($1)";
-        let _ = sqlx::query(query)
-            .bind(seed.to_vec())
-            .execute(pool)
-            .await
-            .unwrap();
+        Some(cipher.encrypt(seed.bip39_passphrase.as_bytes(), encryption::BIP39_PASSPHRASE_AAD)?)
+    };
 
-        seed
-    }   
+    let query = "INSERT INTO signer_seed (seed, derivation, bip39_passphrase) VALUES ($1, $2, $3)";
+    let _ = sqlx::query(query)
+        .bind(cipher.encrypt(&seed.entropy, encryption::SEED_AAD)?)
+        .bind(seed.derivation.as_str())
+        .bind(bip39_passphrase)
+        .execute(executor)
+        .await?;
+
+    Ok(())
 }
 
-pub async fn get_next_address_index(pool: &sqlx::Pool<Sqlite>, change_index: u32) -> u32 {
+pub async fn get_next_address_index(pool: &sqlx::Pool<Sqlite>, account_index: u32, change_index: u32) -> Result<u32, CError> {
 
-    let row = sqlx::query("SELECT MAX(address_index) FROM signer_data WHERE change_index = $1")
+    let row = sqlx::query("SELECT MAX(address_index) FROM signer_data WHERE account_index = $1 AND change_index = $2")
+        .bind(account_index)
         .bind(change_index)
         .fetch_one(pool)
-        .await
-        .unwrap();
+        .await?;
 
-    let index = row.get::<Option<u32>, _>(0);
+    let index = row.try_get::<Option<u32>, _>(0)?;
 
-    if index.is_some() {
-        return index.unwrap() + 1;
-    } else {
-        return 0;
+    match index {
+        Some(index) => Ok(index + 1),
+        None => Ok(0),
     }
 }
 
-pub async fn generate_new_key(pool: &sqlx::Pool<Sqlite>, derivation_path: &str, change_index: u32, address_index:u32, network: Network) -> KeyData {
+pub async fn generate_new_key(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, purpose: u32, account_index: u32, change_index: u32, address_index:u32, network: Network) -> Result<KeyData, CError> {
 
-    let seed = generate_or_get_seed(pool).await;
+    let wallet_seed = get_seed(pool, cipher).await?;
+    let seed = wallet_seed.master_seed()?;
+    let derivation_path = account_path(purpose, coin_type(network, wallet_seed.derivation), account_index);
 
     // we need secp256k1 context for key derivation
     let mut buf: Vec<AlignedType> = Vec::new();
     buf.resize(Secp256k1::preallocate_size(), AlignedType::zeroed());
-    let secp = Secp256k1::preallocated_new(buf.as_mut_slice()).unwrap();
+    let secp = Secp256k1::preallocated_new(buf.as_mut_slice()).map_err(|e| CError::KeyDerivation(e.to_string()))?;
 
     // calculate root key from seed
-    let root = ExtendedPrivKey::new_master(network, &seed).unwrap();
+    let root = ExtendedPrivKey::new_master(network, &seed)?;
 
     let fingerprint = root.fingerprint(&secp).to_string();
 
     // derive child xpub
-    let path = DerivationPath::from_str(derivation_path).unwrap();
-    let child = root.derive_priv(&secp, &path).unwrap();
+    let path = DerivationPath::from_str(&derivation_path)?;
+    let child = root.derive_priv(&secp, &path)?;
     let xpub = ExtendedPubKey::from_priv(&secp, &child);
 
     // generate first receiving address at m/0/0
     // manually creating indexes this time
-    let change_index_number = ChildNumber::from_normal_idx(change_index).unwrap();
-    let address_index_number = ChildNumber::from_normal_idx(address_index).unwrap();
+    let change_index_number = ChildNumber::from_normal_idx(change_index)?;
+    let address_index_number = ChildNumber::from_normal_idx(address_index)?;
 
     let derivation_path = format!("{}/{}/{}", derivation_path, change_index, address_index );
 
-    let secret_key = child.derive_priv(&secp, &[change_index_number, address_index_number]).unwrap().private_key;
-    let public_key: secp256k1_zkp::PublicKey = xpub.derive_pub(&secp, &[change_index_number, address_index_number]).unwrap().public_key;
+    let secret_key = child.derive_priv(&secp, &[change_index_number, address_index_number])?.private_key;
+    let public_key: secp256k1_zkp::PublicKey = xpub.derive_pub(&secp, &[change_index_number, address_index_number])?.public_key;
 
-    KeyData {
+    Ok(KeyData {
         amount: None,
         token_id: None,
         secret_key,
         public_key,
         fingerprint,
         derivation_path,
+        account_index,
         change_index,
         address_index,
-    }
+    })
 }
 
-pub async fn insert_agg_key_data(pool: &sqlx::Pool<Sqlite>, key_data: &KeyData, backup_address: &Address)  {
+/// Stores the public data of a statecoin key. The secret key is derived again from the seed when it is needed.
+pub async fn insert_agg_key_data(pool: &sqlx::Pool<Sqlite>, key_data: &KeyData, backup_address: &Address) -> Result<(), CError> {
 
     let query = 
-        "INSERT INTO signer_data (token_id, amount, client_seckey_share, client_pubkey_share, backup_address, fingerprint, agg_key_derivation_path, change_index, address_index) \
+        "INSERT INTO signer_data (token_id, amount, client_pubkey_share, backup_address, fingerprint, agg_key_derivation_path, account_index, change_index, address_index) \
         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)";
 
     let token_id_str = match key_data.token_id {
@@ -118,34 +256,35 @@ pub async fn insert_agg_key_data(pool: &sqlx::Pool<Sqlite>, key_data: &KeyData,
     let _ = sqlx::query(query)
         .bind(token_id_str)
         .bind(amount)
-        .bind(&key_data.secret_key.secret_bytes().to_vec())
         .bind(&key_data.public_key.serialize().to_vec())
         .bind(&backup_address.to_string())
         .bind(&key_data.fingerprint)
         .bind(&key_data.derivation_path)
+        .bind(key_data.account_index)
         .bind(key_data.change_index)
         .bind(key_data.address_index)
         .execute(pool)
-        .await
-        .unwrap();
+        .await?;
+
+    Ok(())
 }
 
-pub async fn update_auth_key_data(pool: &sqlx::Pool<Sqlite>, key_data: &KeyData, client_pubkey_share: &PublicKey, transfer_address: &str)  {
+pub async fn update_auth_key_data(pool: &sqlx::Pool<Sqlite>, key_data: &KeyData, client_pubkey_share: &PublicKey, transfer_address: &str) -> Result<(), CError> {
 
     let query = "\
         UPDATE signer_data \
-        SET auth_derivation_path = $1, auth_seckey = $2, auth_pubkey = $3, transfer_address = $4 \
-        WHERE client_pubkey_share = $5";
+        SET auth_derivation_path = $1, auth_pubkey = $2, transfer_address = $3 \
+        WHERE client_pubkey_share = $4";
 
     let _ = sqlx::query(query)
         .bind(&key_data.derivation_path)
-        .bind(&key_data.secret_key.secret_bytes().to_vec())
         .bind(&key_data.public_key.serialize().to_vec())
         .bind(transfer_address)
         .bind(&client_pubkey_share.serialize().to_vec())
         .execute(pool)
-        .await
-        .unwrap();
+        .await?;
+
+    Ok(())
 }
 
 pub struct KeyData {
@@ -155,16 +294,15 @@ pub struct KeyData {
     pub public_key: PublicKey,
     pub fingerprint: String,
     pub derivation_path: String,
+    pub account_index: u32,
     pub change_index: u32,
     pub address_index: u32,
 }
 
-pub async fn get_mnemonic(pool: &sqlx::Pool<Sqlite>) -> String {
-    let seed = generate_or_get_seed(pool).await;
+pub async fn get_mnemonic(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher) -> Result<String, CError> {
+    let seed = get_seed(pool, cipher).await?;
 
-    let mnemonic = Mnemonic::from_entropy_in(Language::English,&seed).unwrap();
-
-    mnemonic.to_string()
+    Ok(seed.mnemonic()?.to_string())
 }
 
 pub struct AddressData {
@@ -176,7 +314,7 @@ pub struct AddressData {
     pub transfer_address: String,
 }
 
-pub fn encode_transfer_address(user_pubkey: &PublicKey, auth_pubkey: &PublicKey) -> String {
+pub fn encode_transfer_address(user_pubkey: &PublicKey, auth_pubkey: &PublicKey) -> Result<String, CError> {
 
     let hrp = "sc";
     let variant = Variant::Bech32m;
@@ -186,37 +324,51 @@ pub fn encode_transfer_address(user_pubkey: &PublicKey, auth_pubkey: &PublicKey)
     data.append(&mut user_pubkey.clone().serialize().to_vec());
     data.append(&mut auth_pubkey.clone().serialize().to_vec());
 
-    let encoded = bech32::encode(hrp, data.to_base32(), variant).unwrap();
+    let encoded = bech32::encode(hrp, data.to_base32(), variant).map_err(|e| CError::KeyDerivation(e.to_string()))?;
 
-    encoded
+    Ok(encoded)
 }
 
 pub fn decode_transfer_address(sc_address: &str) -> Result<(u8, PublicKey, PublicKey), CError> {
-    let (hrp, data, variant)  = bech32::decode(sc_address).unwrap();
+    let (hrp, data, variant)  = bech32::decode(sc_address)
+        .map_err(|e| CError::UserInput(format!("Invalid address: {}", e)))?;
 
     if hrp != "sc" {
-        return Err(CError::Generic("Invalid address".to_string()));
+        return Err(CError::UserInput("Invalid address".to_string()));
     }
 
     if variant != Variant::Bech32m {
-        return Err(CError::Generic("Invalid address".to_string()));
+        return Err(CError::UserInput("Invalid address".to_string()));
     }
 
-    let decoded_data = Vec::<u8>::from_base32(&data).unwrap();
+    let decoded_data = Vec::<u8>::from_base32(&data)
+        .map_err(|e| CError::UserInput(format!("Invalid address: {}", e)))?;
+
+    if decoded_data.len() != 67 {
+        return Err(CError::UserInput("Invalid address length".to_string()));
+    }
 
     let version = decoded_data[0];
-    let user_pubkey = PublicKey::from_slice(&decoded_data[1..34]).unwrap();
-    let auth_pubkey = PublicKey::from_slice(&decoded_data[34..67]).unwrap();
+    let user_pubkey = PublicKey::from_slice(&decoded_data[1..34])
+        .map_err(|e| CError::UserInput(format!("Invalid address: {}", e)))?;
+    let auth_pubkey = PublicKey::from_slice(&decoded_data[34..67])
+        .map_err(|e| CError::UserInput(format!("Invalid address: {}", e)))?;
 
     Ok((version, user_pubkey, auth_pubkey))
 }
 
 
-pub async fn get_new_address(pool: &sqlx::Pool<Sqlite>, token_id: Option<uuid::Uuid>, amount: Option<u64>, network: Network) -> AddressData {
-    let derivation_path = "m/86h/0h/0h";
+/// Derives and stores the next address of the selected account
+pub async fn get_new_address(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, token_id: Option<uuid::Uuid>, amount: Option<u64>, network: Network) -> Result<AddressData, CError> {
+    let account_index = account::get_selected_account(pool).await?;
+    let address_index = get_next_address_index(pool, account_index, 0).await?;
+    insert_address(pool, cipher, account_index, address_index, token_id, amount, network).await
+}
+
+/// Derives the statecoin and auth keys of `account_index` at `address_index` and stores them
+pub async fn insert_address(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, account_index: u32, address_index: u32, token_id: Option<uuid::Uuid>, amount: Option<u64>, network: Network) -> Result<AddressData, CError> {
     let change_index = 0;
-    let address_index = get_next_address_index(pool, change_index).await;
-    let mut agg_key_data = generate_new_key(pool, derivation_path, change_index, address_index, network).await;
+    let mut agg_key_data = generate_new_key(pool, cipher, AGG_KEY_PURPOSE, account_index, change_index, address_index, network).await?;
     agg_key_data.token_id = token_id;
     agg_key_data.amount = amount;
 
@@ -224,28 +376,31 @@ pub async fn get_new_address(pool: &sqlx::Pool<Sqlite>, token_id: Option<uuid::U
     let client_pubkey_share = agg_key_data.public_key;
     let backup_address = Address::p2tr(&Secp256k1::new(), client_pubkey_share.x_only_public_key().0, None, network);
 
-    insert_agg_key_data(pool, &agg_key_data, &backup_address).await;
-
-    let derivation_path = "m/89h/0h/0h";
-    let mut auth_key_data = generate_new_key(pool, derivation_path, change_index, address_index, network).await;
+    let mut auth_key_data = generate_new_key(pool, cipher, AUTH_KEY_PURPOSE, account_index, change_index, address_index, network).await?;
     auth_key_data.token_id = token_id;
     auth_key_data.amount = amount;
 
-    assert!(auth_key_data.fingerprint == agg_key_data.fingerprint);
-    assert!(auth_key_data.address_index == agg_key_data.address_index);
-    assert!(auth_key_data.change_index == agg_key_data.change_index);
-    assert!(auth_key_data.derivation_path != agg_key_data.derivation_path);
+    // Both keys come from the same seed at the same address index, under different purposes
+    if auth_key_data.fingerprint != agg_key_data.fingerprint ||
+        auth_key_data.address_index != agg_key_data.address_index ||
+        auth_key_data.change_index != agg_key_data.change_index ||
+        auth_key_data.derivation_path == agg_key_data.derivation_path {
+        return Err(CError::KeyDerivation(format!(
+            "Auth key {} does not match statecoin key {}", auth_key_data.derivation_path, agg_key_data.derivation_path)));
+    }
+
+    insert_agg_key_data(pool, &agg_key_data, &backup_address).await?;
 
-    let transfer_address = encode_transfer_address(&client_pubkey_share, &auth_key_data.public_key);
+    let transfer_address = encode_transfer_address(&client_pubkey_share, &auth_key_data.public_key)?;
 
-    update_auth_key_data(pool, &auth_key_data, &client_pubkey_share, &transfer_address).await;
+    update_auth_key_data(pool, &auth_key_data, &client_pubkey_share, &transfer_address).await?;
 
-    AddressData {
+    Ok(AddressData {
         client_secret_key,
         client_pubkey_share,
         auth_secret_key: auth_key_data.secret_key,
         auth_xonly_pubkey: auth_key_data.public_key.x_only_public_key().0,
         backup_address,
         transfer_address,
-    }
+    })
 }
\ No newline at end of file
@@ -0,0 +1,134 @@
+use std::str::FromStr;
+
+use bitcoin::{Network, bip32::{DerivationPath, ExtendedPrivKey}};
+use secp256k1_zkp::{PublicKey, Secp256k1, SecretKey};
+use sqlx::{Sqlite, Row};
+
+use crate::{encryption::WalletCipher, error::CError, key_derivation};
+
+/// Secret columns of `signer_data` written by earlier versions, with their public key and derivation path columns
+const STORED_SECRET_COLUMNS: [(&str, &str, &str); 2] = [
+    ("client_seckey_share", "client_pubkey_share", "agg_key_derivation_path"),
+    ("auth_seckey", "auth_pubkey", "auth_derivation_path"),
+];
+
+fn derive_from_seed(seed: &[u8], derivation_path: &str, network: Network) -> Result<SecretKey, CError> {
+    let root = ExtendedPrivKey::new_master(network, seed)?;
+    let path = DerivationPath::from_str(derivation_path)?;
+    Ok(root.derive_priv(&Secp256k1::new(), &path)?.private_key)
+}
+
+/// Secret key at the full `derivation_path`, derived from the wallet seed
+pub async fn derive_secret_key(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, derivation_path: &str, network: Network) -> Result<SecretKey, CError> {
+    let seed = key_derivation::get_seed(pool, cipher).await?.master_seed()?;
+    derive_from_seed(&seed, derivation_path, network)
+}
+
+/// Secret key of `public_key`. Imported keys are looked up first, then the key is derived at `derivation_path`.
+/// The derived key is checked against `public_key`, so a wrong path never signs with an unrelated key.
+pub async fn get_secret_key(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, public_key: &PublicKey, derivation_path: Option<&str>, network: Network) -> Result<SecretKey, CError> {
+
+    if let Some(secret_key) = get_imported_key(pool, cipher, public_key).await? {
+        return Ok(secret_key);
+    }
+
+    let derivation_path = derivation_path.ok_or(CError::Database(format!("No secret key for public key {}", public_key)))?;
+
+    let secret_key = derive_secret_key(pool, cipher, derivation_path, network).await?;
+
+    if secret_key.public_key(&Secp256k1::new()) != *public_key {
+        return Err(CError::Database(format!("Key derived at {} does not match public key {}", derivation_path, public_key)));
+    }
+
+    Ok(secret_key)
+}
+
+async fn get_imported_key(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, public_key: &PublicKey) -> Result<Option<SecretKey>, CError> {
+
+    let row = sqlx::query("SELECT encrypted_seckey FROM imported_key WHERE public_key = $1")
+        .bind(&public_key.serialize().to_vec())
+        .fetch_optional(pool)
+        .await?;
+
+    match row {
+        Some(row) => Ok(Some(cipher.decrypt_secret_key(&row.try_get::<Vec<u8>, _>("encrypted_seckey")?, public_key)?)),
+        None => Ok(None),
+    }
+}
+
+/// Stores a secret key that cannot be derived from the seed
+pub async fn insert_imported_key<'c, E>(executor: E, cipher: &WalletCipher, secret_key: &SecretKey, public_key: &PublicKey) -> Result<(), CError>
+where E: sqlx::Executor<'c, Database = Sqlite> {
+
+    let query = "\
+        INSERT INTO imported_key (public_key, encrypted_seckey) \
+        VALUES ($1, $2) \
+        ON CONFLICT (public_key) DO NOTHING";
+
+    let _ = sqlx::query(query)
+        .bind(&public_key.serialize().to_vec())
+        .bind(cipher.encrypt_secret_key(secret_key, public_key)?)
+        .execute(executor)
+        .await?;
+
+    Ok(())
+}
+
+/// Empties the secret columns of `signer_data` written by earlier versions.
+/// A secret is only dropped once it matches the key derived at its path. Any other secret is moved to the imported keys.
+/// Returns the number of secrets removed from `signer_data`.
+pub async fn migrate_stored_secrets(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, network: Network) -> Result<u32, CError> {
+
+    let query = "\
+        SELECT rowid, client_seckey_share, client_pubkey_share, agg_key_derivation_path, auth_seckey, auth_pubkey, auth_derivation_path \
+        FROM signer_data \
+        WHERE client_seckey_share IS NOT NULL OR auth_seckey IS NOT NULL";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await?;
+
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    let seed = key_derivation::get_seed(pool, cipher).await?.master_seed()?;
+
+    let mut migrated = 0;
+
+    // All or nothing, so that no secret is dropped before its copy is stored
+    let mut tx = pool.begin().await?;
+
+    for row in rows {
+
+        for (seckey_column, pubkey_column, path_column) in STORED_SECRET_COLUMNS {
+
+            let (encrypted_seckey, public_key) = match (row.try_get::<Option<Vec<u8>>, _>(seckey_column)?, row.try_get::<Option<Vec<u8>>, _>(pubkey_column)?) {
+                (Some(encrypted_seckey), Some(public_key)) => (encrypted_seckey, PublicKey::from_slice(&public_key)?),
+                _ => continue,
+            };
+
+            let secret_key = cipher.decrypt_secret_key(&encrypted_seckey, &public_key)?;
+
+            let derived_key = match row.try_get::<Option<String>, _>(path_column)? {
+                Some(derivation_path) => Some(derive_from_seed(&seed, &derivation_path, network)?),
+                None => None,
+            };
+
+            if derived_key != Some(secret_key) {
+                insert_imported_key(&mut *tx, cipher, &secret_key, &public_key).await?;
+            }
+
+            let _ = sqlx::query(&format!("UPDATE signer_data SET {} = NULL WHERE rowid = $1", seckey_column))
+                .bind(row.try_get::<i64, _>("rowid")?)
+                .execute(&mut *tx)
+                .await?;
+
+            migrated += 1;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(migrated)
+}
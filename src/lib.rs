@@ -0,0 +1,37 @@
+pub mod client;
+pub mod config;
+pub mod deposit;
+pub mod token;
+pub mod key_derivation;
+pub mod account;
+pub mod encryption;
+pub mod key_store;
+pub mod restore;
+pub mod error;
+pub mod chain;
+pub mod electrum;
+pub mod esplora;
+pub mod bitcoind;
+pub mod server;
+pub mod wallet;
+pub mod wallet_metadata;
+pub mod wallets;
+pub mod statecoin_info;
+pub mod transaction;
+pub mod send_backup;
+pub mod bump_backup;
+pub mod verify_backups;
+pub mod watch;
+pub mod transfer;
+pub mod transfer_sender;
+pub mod transfer_receiver;
+pub mod withdraw;
+#[cfg(feature = "mock-server")]
+pub mod mock_server;
+#[cfg(feature = "simulated-chain")]
+pub mod simulated_chain;
+
+pub use client::{MercuryClient, Balance, WalletBalance};
+pub use chain::ChainBackend;
+pub use config::Config;
+pub use error::CError;
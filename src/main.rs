@@ -1,170 +1,468 @@
-mod deposit;
-mod key_derivation;
-mod error;
-mod electrum;
-mod wallet;
-mod transaction;
-mod send_backup;
-
-use std::str::FromStr;
-
-use bitcoin::Address;
 use clap::{Parser, Subcommand};
-use electrum_client::ListUnspentRes;
-use serde::{Serialize, Deserialize};
+use mercury_client::{MercuryClient, CError, config::{Config, ConfigOverrides}, wallets, key_derivation::{SeedDerivation, WalletSeed}, restore::DEFAULT_GAP_LIMIT, watch::WatchEvent, deposit::DepositProgress};
 use serde_json::json;
-use sqlx::{Sqlite, migrate::MigrateDatabase, SqlitePool};
-
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    /// Path of the TOML config file [default: Settings.toml]
+    #[arg(long, global = true)]
+    config: Option<String>,
+    /// Bitcoin network: bitcoin (or mainnet), testnet, signet or regtest
+    #[arg(long, global = true)]
+    network: Option<String>,
+    /// Blockchain backend: electrum, esplora or bitcoind
+    #[arg(long, global = true)]
+    chain_backend: Option<String>,
+    /// Electrum server URL
+    #[arg(long, global = true)]
+    electrum_server: Option<String>,
+    /// Esplora API URL
+    #[arg(long, global = true)]
+    esplora_url: Option<String>,
+    /// bitcoind JSON-RPC URL. The credentials are read from the config file or the environment.
+    #[arg(long, global = true)]
+    bitcoind_rpc_url: Option<String>,
+    /// Statechain server URL
+    #[arg(long, global = true)]
+    statechain_entity: Option<String>,
+    /// Path of the wallet database
+    #[arg(long, global = true)]
+    database_file: Option<String>,
+    /// Directory of the named wallets [default: wallets]
+    #[arg(long, global = true)]
+    wallets_dir: Option<String>,
+    /// Named wallet to use instead of the selected one
+    #[arg(long, global = true)]
+    wallet: Option<String>,
+    /// Seconds a deposit waits for its funding transaction, or a token for its payment
+    #[arg(long, global = true)]
+    deposit_timeout: Option<u64>,
+    /// Confirmations of the deposit transaction required before signing the backup transaction
+    #[arg(long, global = true)]
+    min_confirmations: Option<u32>,
+    /// Add an anchor output to new backup transactions: true or false
+    #[arg(long, global = true)]
+    backup_anchor: Option<bool>,
+    /// Seconds between two chain tip checks in watch mode
+    #[arg(long, global = true)]
+    watch_interval: Option<u64>,
+    /// Blocks to keep before the backup transaction of the previous owner becomes final when broadcasting in watch mode
+    #[arg(long, global = true)]
+    watch_safety_margin: Option<u32>,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Create a new wallet encrypted with a passphrase
+    CreateWallet {
+        /// Protect the mnemonic with a BIP39 passphrase, read from MERCURY_BIP39_PASSPHRASE or prompted for
+        #[arg(long)]
+        bip39_passphrase: bool,
+    },
+    /// Encrypt the seed and secret keys of a wallet created without a passphrase
+    EncryptWallet { },
+    /// Change the passphrase of the wallet
+    ChangePassphrase { },
+    /// Recreate the wallet from its mnemonic and recover its statecoins from the server
+    Restore {
+        /// The 24 words shown by show-mnemonic
+        #[arg(long)]
+        mnemonic: String,
+        /// The mnemonic has a BIP39 passphrase, read from MERCURY_BIP39_PASSPHRASE or prompted for
+        #[arg(long)]
+        bip39_passphrase: bool,
+        /// The mnemonic was shown by a wallet with the legacy derivation
+        #[arg(long, conflicts_with = "bip39_passphrase")]
+        legacy: bool,
+        /// Number of consecutive unused addresses after which the scan stops [default: 20]
+        #[arg(long)]
+        gap_limit: Option<u32>,
+        /// Number of accounts to scan, in addition to the accounts already in the wallet [default: 1]
+        #[arg(long)]
+        accounts: Option<u32>,
+    },
+    /// List the named wallets
+    ListWallets { },
+    /// Select the named wallet used without --wallet, or the database file if no name is given
+    SelectWallet { name: Option<String> },
+    /// Add an account to the wallet
+    CreateAccount { name: String },
+    /// List the accounts of the wallet
+    ListAccounts { },
+    /// Select the account of new addresses and deposits
+    SelectAccount { name: String },
     /// Show mnemonic
     ShowMnemonic { },
+    /// Request a deposit token from the server
+    NewToken { },
+    /// Wait until a deposit token is paid
+    WaitTokenPayment { token_id: String },
+    /// List the deposit tokens of the wallet
+    ListTokens { },
     /// Create Aggregated Public Key
     Deposit { token_id: String, amount: u64 },
+    /// Continue an interrupted deposit
+    DepositResume { statechain_id: String },
+    /// Show the progress of a deposit
+    DepositStatus { statechain_id: String },
+    /// Cancel a deposit that has not been funded
+    DepositCancel { statechain_id: String },
     /// Get a wallet balance
     GetBalance { },
+    /// List the statecoins of the wallet
+    ListStatecoins { },
+    /// Show the details of a statecoin
+    StatecoinInfo { statechain_id: String },
     /// Broadcast the backup transaction to the network
     BroadcastBackupTransaction { statechain_id: String },
+    /// Check the stored backup transactions of a statecoin, or of all statecoins
+    VerifyBackups { statechain_id: Option<String> },
+    /// Broadcast the backup transaction with a CPFP child spending its anchor output
+    BumpBackup { statechain_id: String, fee_rate: Option<u64> },
+    /// Broadcast backup transactions when they are due and monitor the funding outpoints
+    Watch {
+        /// Check the current chain tip once and exit
+        #[arg(long)]
+        once: bool,
+    },
+    /// Show the events recorded by the watchtower
+    WatchEvents { statechain_id: Option<String> },
     /// Send all backup funds to the address provided
     SendBackup { address: String, fee_rate: Option<u64> },
     /// Generate a transfer address to receive funds
     NewTransferAddress { },
     /// Send a statechain coin to a transfer address
-    TransferSend { recipient_address: String, statechain_id: String }
+    TransferSend { recipient_address: String, statechain_id: String },
+    /// Retrieve coins from server
+    TransferReceive { },
+    /// Withdraw funds from a statechain coin to a bitcoin address
+    Withdraw { statechain_id: String, address: String, fee_rate: Option<u64> },
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    // let network = bitcoin::Network::Bitcoin;
-    let network = bitcoin::Network::Signet;
+impl Commands {
+    /// Commands that may open a named wallet whose database does not exist yet
+    fn creates_wallet(&self) -> bool {
+        matches!(self, Commands::CreateWallet { .. } | Commands::Restore { .. })
+    }
 
-    let client = electrum_client::Client::new("tcp://127.0.0.1:50001").unwrap();
+    /// Commands that read the seed or a secret key, and so need the wallet to be unlocked
+    fn needs_unlock(&self) -> bool {
+        matches!(self,
+            Commands::ShowMnemonic { } |
+            Commands::Deposit { .. } |
+            Commands::DepositResume { .. } |
+            Commands::DepositCancel { .. } |
+            Commands::BumpBackup { .. } |
+            Commands::SendBackup { .. } |
+            Commands::NewTransferAddress { } |
+            Commands::TransferSend { .. } |
+            Commands::TransferReceive { } |
+            Commands::Withdraw { .. })
+    }
+}
 
-    let cli = Cli::parse();
+/// Reads the passphrase from `env_var`, or prompts for it on the terminal
+fn read_passphrase(env_var: &str, prompt: &str) -> Result<String, CError> {
+    if let Ok(passphrase) = std::env::var(env_var) {
+        return Ok(passphrase);
+    }
+    rpassword::prompt_password(prompt).map_err(|e| CError::UserInput(format!("Cannot read the passphrase: {}", e)))
+}
 
-    if !Sqlite::database_exists("wallet.db").await.unwrap_or(false) {
-        match Sqlite::create_database("wallet.db").await {
-            Ok(_) => println!("Create db success"),
-            Err(error) => panic!("error: {}", error),
-        }
+/// Reads a new passphrase from `env_var`, or prompts for it twice on the terminal
+fn read_new_passphrase(env_var: &str) -> Result<String, CError> {
+    if let Ok(passphrase) = std::env::var(env_var) {
+        return Ok(passphrase);
     }
 
-    let pool = SqlitePool::connect("wallet.db").await.unwrap();
+    let passphrase = read_passphrase(env_var, "New passphrase: ")?;
 
-    sqlx::migrate!("./migrations")
-        .run(&pool)
-        .await
-        .unwrap();
+    if read_passphrase(env_var, "Repeat the new passphrase: ")? != passphrase {
+        return Err(CError::UserInput("The passphrases do not match".to_string()));
+    }
 
-    match cli.command {
-        Commands::ShowMnemonic { } => {
-            let mnemonic = key_derivation::get_mnemonic(&pool).await;
-            println!("{}", serde_json::to_string_pretty(&json!({
-                "mnemonic": mnemonic,
-            })).unwrap());
-        },
-        Commands::Deposit { token_id, amount } => {
-            let token_id = uuid::Uuid::new_v4() ; // uuid::Uuid::parse_str(&token_id).unwrap();
-            let statechain_id = deposit::execute(&pool, token_id, amount, network).await.unwrap();
-            println!("{}", serde_json::to_string_pretty(&json!({
-                "statechain_id": statechain_id,
-            })).unwrap());
-        },
-        Commands::GetBalance {  } => {
+    Ok(passphrase)
+}
 
-            #[derive(Serialize, Deserialize, Debug)]
-            struct Balance {
-                address: String,
-                balance: u64,
-                unconfirmed_balance: i64,
-            }
+/// Reads the BIP39 passphrase if `enabled`. Without it, the passphrase is empty.
+fn read_bip39_passphrase(enabled: bool, prompt: &str) -> Result<String, CError> {
+    if enabled {
+        read_passphrase("MERCURY_BIP39_PASSPHRASE", prompt)
+    } else {
+        Ok(String::new())
+    }
+}
 
-            let (agg_addresses, backup_addresses) = wallet::get_all_addresses(&pool, network).await;
+fn parse_token_id(token_id: &str) -> Result<uuid::Uuid, CError> {
+    uuid::Uuid::parse_str(token_id).map_err(|e| CError::UserInput(format!("Invalid token id {}: {}", token_id, e)))
+}
 
-            let agg_result: Vec<Balance> = agg_addresses.iter().map(|address| {
-                let balance_res = electrum::get_address_balance(&client, &address);
-                Balance {
-                    address: address.to_string(),
-                    balance: balance_res.confirmed,
-                    unconfirmed_balance: balance_res.unconfirmed,
-                }
-            }).collect();
-
-            let backup_result: Vec<Balance> = backup_addresses.iter().map(|address| {
-                let balance_res = electrum::get_address_balance(&client, &address);
-                Balance {
-                    address: address.to_string(),
-                    balance: balance_res.confirmed,
-                    unconfirmed_balance: balance_res.unconfirmed,
-                }
-            }).collect();
+async fn run(cli: Cli) -> Result<serde_json::Value, CError> {
 
-            println!("{}", serde_json::to_string_pretty(&json!({
-                "statecoins": agg_result,
-                "backup addresses": backup_result,
-            })).unwrap());
-        },
-        Commands::BroadcastBackupTransaction { statechain_id } => {
+    let cli_overrides = ConfigOverrides {
+        network: cli.network,
+        chain_backend: cli.chain_backend,
+        electrum_server: cli.electrum_server,
+        esplora_url: cli.esplora_url,
+        bitcoind_rpc_url: cli.bitcoind_rpc_url,
+        bitcoind_rpc_user: None,
+        bitcoind_rpc_password: None,
+        statechain_entity: cli.statechain_entity,
+        database_file: cli.database_file,
+        wallets_dir: cli.wallets_dir,
+        wallet: cli.wallet,
+        deposit_timeout: cli.deposit_timeout,
+        min_confirmations: cli.min_confirmations,
+        backup_anchor: cli.backup_anchor,
+        watch_interval: cli.watch_interval,
+        watch_safety_margin: cli.watch_safety_margin,
+    };
 
-            let txid = deposit::broadcast_backup_tx(&pool, &statechain_id).await;
+    let config = Config::load(cli.config.as_deref(), cli_overrides)?;
 
-            println!("{}", serde_json::to_string_pretty(&json!({
-                "txid": txid,
-            })).unwrap());
+    // The named wallets are managed without opening a wallet
+    match &cli.command {
+        Commands::ListWallets { } => {
+            return wallets::list_wallets(&config.wallets_dir).map(|wallets| json!({
+                "wallets": wallets,
+            }));
         },
-        Commands::SendBackup { address, fee_rate } => {
+        Commands::SelectWallet { name } => {
+            return wallets::select_wallet(&config.wallets_dir, name.as_deref()).map(|_| json!({
+                "selected_wallet": name,
+            }));
+        },
+        _ => {},
+    }
+
+    if let Some(wallet) = &config.wallet {
+        if !cli.command.creates_wallet() && !wallets::wallet_exists(&config.wallets_dir, wallet)? {
+            return Err(CError::UserInput(format!("Wallet {} not found. Create it with --wallet {} create-wallet", wallet, wallet)));
+        }
+    }
 
-            let to_address = bitcoin::Address::from_str(&address).unwrap().require_network(network).unwrap();
+    let mut client = MercuryClient::new(&config).await?;
 
-            let fee_rate = match fee_rate {
-                Some(fee_rate) => fee_rate,
-                None => {
-                    let fee_rate_btc_per_kb = electrum::estimate_fee(&client, 1);
-                    let fee_rate_sats_per_byte = (fee_rate_btc_per_kb * 100000.0) as u64;
-                    fee_rate_sats_per_byte
-                },
-            };
+    if cli.command.needs_unlock() {
+        let unlocked = match read_passphrase("MERCURY_PASSPHRASE", "Passphrase: ") {
+            Ok(passphrase) => client.unlock(&passphrase).await,
+            Err(err) => Err(err),
+        };
 
-            let (_, backup_addresses) = wallet::get_all_addresses(&pool, network).await;
+        if let Err(err) = unlocked {
+            client.close().await;
+            return Err(err);
+        }
+    }
 
-            let mut list_unspent = Vec::<(ListUnspentRes, Address)>::new(); 
+    let result = match cli.command {
+        Commands::CreateWallet { bip39_passphrase } => {
+            let passphrases = read_new_passphrase("MERCURY_PASSPHRASE")
+                .and_then(|passphrase| Ok((passphrase, read_bip39_passphrase(bip39_passphrase, "BIP39 passphrase: ")?)));
 
-            for address in backup_addresses {
-                let address_utxos = electrum::get_script_list_unspent(&client, &address);
-                for utxo in address_utxos {
-                    list_unspent.push((utxo, address.clone()));
-                }
+            match passphrases {
+                Ok((passphrase, bip39_passphrase)) => client.create_wallet(&passphrase, &bip39_passphrase).await.map(|_| json!({
+                    "created": true,
+                })),
+                Err(err) => Err(err),
             }
+        },
+        Commands::EncryptWallet { } => {
+            match read_new_passphrase("MERCURY_PASSPHRASE") {
+                Ok(passphrase) => client.encrypt_wallet(&passphrase).await.map(|_| json!({
+                    "encrypted": true,
+                })),
+                Err(err) => Err(err),
+            }
+        },
+        Commands::Restore { mnemonic, bip39_passphrase, legacy, gap_limit, accounts } => {
+            let derivation = if legacy { SeedDerivation::Legacy } else { SeedDerivation::Bip39 };
 
-            let list_utxo = send_backup::get_address_info(&pool, list_unspent).await;
+            let seed = read_bip39_passphrase(bip39_passphrase, "BIP39 passphrase: ")
+                .and_then(|bip39_passphrase| WalletSeed::from_mnemonic(&mnemonic, derivation, &bip39_passphrase));
 
+            // An interrupted restore is continued with the passphrase given the first time
+            let seed_and_passphrase = match seed {
+                Ok(seed) => match client.wallet_exists().await {
+                    Ok(true) => read_passphrase("MERCURY_PASSPHRASE", "Passphrase: ").map(|passphrase| (seed, passphrase)),
+                    Ok(false) => read_new_passphrase("MERCURY_PASSPHRASE").map(|passphrase| (seed, passphrase)),
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err),
+            };
 
-            send_backup::send_all_funds(&list_utxo, &to_address, fee_rate);
+            match seed_and_passphrase {
+                Ok((seed, passphrase)) => client.restore(&seed, &passphrase, gap_limit.unwrap_or(DEFAULT_GAP_LIMIT), accounts.unwrap_or(1)).await.map(|report| json!(report)),
+                Err(err) => Err(err),
+            }
+        },
+        Commands::ChangePassphrase { } => {
+            let passphrases = read_passphrase("MERCURY_PASSPHRASE", "Current passphrase: ")
+                .and_then(|passphrase| Ok((passphrase, read_new_passphrase("MERCURY_NEW_PASSPHRASE")?)));
+
+            match passphrases {
+                Ok((passphrase, new_passphrase)) => client.change_passphrase(&passphrase, &new_passphrase).await.map(|_| json!({
+                    "passphrase_changed": true,
+                })),
+                Err(err) => Err(err),
+            }
+        },
+        Commands::ListWallets { } | Commands::SelectWallet { .. } => unreachable!("handled before the wallet is opened"),
+        Commands::CreateAccount { name } => {
+            client.create_account(&name).await.map(|account| json!(account))
+        },
+        Commands::ListAccounts { } => {
+            client.list_accounts().await.map(|accounts| json!({
+                "accounts": accounts,
+            }))
+        },
+        Commands::SelectAccount { name } => {
+            client.select_account(&name).await.map(|account| json!(account))
+        },
+        Commands::ShowMnemonic { } => {
+            client.show_mnemonic().await.map(|(mnemonic, derivation)| json!({
+                "mnemonic": mnemonic,
+                "derivation": derivation.as_str(),
+            }))
+        },
+        Commands::NewToken { } => {
+            client.new_token().await.map(|token| json!(token))
+        },
+        Commands::WaitTokenPayment { token_id } => {
+            match parse_token_id(&token_id) {
+                Ok(token_id) => client.wait_for_token_payment(token_id).await.map(|token| json!(token)),
+                Err(err) => Err(err),
+            }
+        },
+        Commands::ListTokens { } => {
+            client.list_tokens().await.map(|tokens| json!({
+                "tokens": tokens,
+            }))
+        },
+        Commands::Deposit { token_id, amount } => {
+            match parse_token_id(&token_id) {
+                Ok(token_id) => client.deposit(token_id, amount, &mut print_deposit_progress).await.map(|statechain_id| json!({
+                    "statechain_id": statechain_id,
+                })),
+                Err(err) => Err(err),
+            }
+        },
+        Commands::DepositResume { statechain_id } => {
+            client.deposit_resume(&statechain_id, &mut print_deposit_progress).await.map(|statechain_id| json!({
+                "statechain_id": statechain_id,
+            }))
+        },
+        Commands::DepositStatus { statechain_id } => {
+            client.deposit_status(&statechain_id).await.map(|info| json!(info))
+        },
+        Commands::DepositCancel { statechain_id } => {
+            client.deposit_cancel(&statechain_id).await.map(|_| json!({
+                "cancelled": statechain_id,
+            }))
+        },
+        Commands::GetBalance {  } => {
+            client.get_balance().await.map(|balance| json!({
+                "statecoins": balance.statecoins,
+                "backup addresses": balance.backup_addresses,
+            }))
+        },
+        Commands::ListStatecoins { } => {
+            client.list_statecoins().await.map(|statecoins| json!({
+                "statecoins": statecoins,
+            }))
+        },
+        Commands::StatecoinInfo { statechain_id } => {
+            client.statecoin_info(&statechain_id).await.map(|info| json!(info))
+        },
+        Commands::BroadcastBackupTransaction { statechain_id } => {
+            client.broadcast_backup_transaction(&statechain_id).await.map(|txid| json!({
+                "txid": txid,
+            }))
+        },
+        Commands::VerifyBackups { statechain_id } => {
+            client.verify_backups(statechain_id.as_deref()).await.map(|reports| json!({
+                "valid": reports.iter().all(|report| report.valid),
+                "statecoins": reports,
+            }))
+        },
+        Commands::BumpBackup { statechain_id, fee_rate } => {
+            client.bump_backup(&statechain_id, fee_rate).await.map(|txid| json!({
+                "child_txid": txid,
+            }))
+        },
+        Commands::Watch { once } => {
+            // When running continuously, events and retried backend errors are printed as JSON lines as they happen
+            let mut print_update = |update: Result<&WatchEvent, &CError>| {
+                if once {
+                    return;
+                }
+                match update {
+                    Ok(event) => println!("{}", json!(event)),
+                    Err(err) => println!("{}", json!({ "error": { "kind": err.kind(), "message": err.to_string() } })),
+                }
+            };
+            client.watch(once, &mut print_update).await.map(|events| json!({
+                "events": events,
+            }))
+        },
+        Commands::WatchEvents { statechain_id } => {
+            client.watch_events(statechain_id.as_deref()).await.map(|events| json!({
+                "events": events,
+            }))
+        },
+        Commands::SendBackup { address, fee_rate } => {
+            client.send_backup(&address, fee_rate).await.map(|txid| json!({
+                "txid": txid,
+            }))
         },
         Commands::NewTransferAddress { } => {
-            let address_data = key_derivation::get_new_address(&pool, None, None, network).await;
-            println!("{}", serde_json::to_string_pretty(&json!({
-                "transfer_address": address_data.transfer_address,
-            })).unwrap());
+            client.new_transfer_address().await.map(|transfer_address| json!({
+                "transfer_address": transfer_address,
+            }))
         },
         Commands::TransferSend { recipient_address, statechain_id } => {
+            client.transfer_send(&recipient_address, &statechain_id).await.map(|txid| json!({
+                "backup_txid": txid,
+            }))
+        },
+        Commands::TransferReceive { } => {
+            client.transfer_receive().await.map(|report| json!(report))
+        },
+        Commands::Withdraw { statechain_id, address, fee_rate } => {
+            client.withdraw(&statechain_id, &address, fee_rate).await.map(|result| json!(result))
+        }
+    };
 
-            let (_, new_user_pubkey, new_auth_pubkey) = key_derivation::decode_transfer_address(&recipient_address).unwrap();
-            println!("new_user_pubkey: {}", new_user_pubkey);
-            println!("new_auth_pubkey: {}", new_auth_pubkey);
+    client.close().await;
 
+    result
+}
 
-        }
-    };
+/// Progress goes to stderr so that stdout only holds the result
+fn print_deposit_progress(progress: DepositProgress) {
+    eprintln!("{}", json!(progress));
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let cli = Cli::parse();
 
-    pool.close().await;
+    match run(cli).await {
+        Ok(value) => {
+            println!("{}", serde_json::to_string_pretty(&value).unwrap());
+        },
+        Err(err) => {
+            println!("{}", serde_json::to_string_pretty(&json!({
+                "error": {
+                    "kind": err.kind(),
+                    "message": err.to_string(),
+                },
+            })).unwrap());
+            std::process::exit(err.exit_code());
+        }
+    }
 }
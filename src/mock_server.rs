@@ -0,0 +1,480 @@
+//! In-process statechain server for offline testing.
+//! Implements `token/token_init`, `token/token_verify`, `deposit/init/pod`, `deposit/cancel`, `info/config`, `info/statechain`,
+//! `sign/first`, `sign/second`, `transfer/sender`, `transfer/update_msg`, `transfer/get_msg_addr`, `transfer/receiver`,
+//! `withdraw/complete` and `recover/statechains`,
+//! including the server half of the blinded MuSig2 signing and of the key update of a transfer.
+//! Enabled by the `mock-server` feature.
+
+use std::{collections::{HashMap, HashSet}, convert::Infallible, net::SocketAddr, str::FromStr, sync::{Arc, Mutex}};
+
+use bitcoin::hashes::sha256;
+use hyper::{Body, Method, Request, Response, Server, StatusCode, service::{make_service_fn, service_fn}};
+use secp256k1_zkp::{Secp256k1, Message, PublicKey, SecretKey, XOnlyPublicKey, KeyPair, Scalar, schnorr::Signature, new_musig_nonce_pair, musig::{MusigSessionId, MusigSecNonce, MusigSession, MusigKeyAggCoef}};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::sync::oneshot;
+
+use crate::{error::CError, server::{ServerConfig, TokenInitResponsePayload, TokenVerifyResponsePayload, DepositRequestPayload, DepositResponsePayload, DepositCancelRequestPayload, SignFirstRequestPayload, SignFirstResponsePayload, PartialSignatureRequestPayload, PartialSignatureResponsePayload, StatechainInfoResponsePayload, TransferSenderRequestPayload, TransferSenderResponsePayload, TransferUpdateMsgRequestPayload, GetMsgAddrResponsePayload, TransferReceiverRequestPayload, TransferReceiverResponsePayload, WithdrawCompleteRequestPayload, RecoverStatechainsRequestPayload, RecoverStatechainsResponsePayload, RecoveredStatechain}};
+
+type MockResult<T> = Result<T, (StatusCode, String)>;
+
+/// Transfer started by `transfer/sender`, completed by `transfer/receiver`
+struct MockTransfer {
+    x1: SecretKey,
+    new_auth_key: PublicKey,
+    /// Set by `transfer/update_msg`
+    enc_transfer_msg: Option<String>,
+}
+
+struct MockStatecoin {
+    server_seckey: SecretKey,
+    server_pubkey: PublicKey,
+    auth_key: XOnlyPublicKey,
+    amount: u64,
+    /// Secret nonce generated in `sign/first`, consumed by `sign/second`
+    sec_nonce: Option<MusigSecNonce>,
+    num_sigs: u32,
+    transfer: Option<MockTransfer>,
+    withdrawn: bool,
+}
+
+struct MockState {
+    config: ServerConfig,
+    /// Token id to its status
+    tokens: HashMap<String, TokenVerifyResponsePayload>,
+    statecoins: HashMap<String, MockStatecoin>,
+    /// Paths whose next request fails before being handled
+    failures: HashSet<String>,
+}
+
+fn bad_request<E: ToString>(err: E) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, err.to_string())
+}
+
+fn decode_hex(value: &str) -> MockResult<Vec<u8>> {
+    hex::decode(value.trim_start_matches("0x")).map_err(bad_request)
+}
+
+/// Checks the schnorr signature of sha256(`data`) by `auth_key`, the same way the client signs it
+fn verify_auth_signature(auth_key: &XOnlyPublicKey, data: &str, signature: &str) -> MockResult<()> {
+
+    let secp = Secp256k1::new();
+
+    let signature = Signature::from_str(signature).map_err(bad_request)?;
+    let msg = Message::from_hashed_data::<sha256::Hash>(data.as_bytes());
+
+    secp.verify_schnorr(&signature, &msg, auth_key)
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid auth signature".to_string()))
+}
+
+impl MockState {
+
+    fn statecoin(&mut self, statechain_id: &str, signed_statechain_id: &str) -> MockResult<&mut MockStatecoin> {
+
+        let statecoin = self.statecoins.get_mut(statechain_id)
+            .ok_or((StatusCode::NOT_FOUND, format!("Statechain {} not found", statechain_id)))?;
+
+        verify_auth_signature(&statecoin.auth_key, statechain_id, signed_statechain_id)?;
+
+        if statecoin.withdrawn {
+            return Err(bad_request(format!("Statechain {} has been withdrawn", statechain_id)));
+        }
+
+        Ok(statecoin)
+    }
+
+    fn token_init(&mut self) -> MockResult<TokenInitResponsePayload> {
+
+        let token_id = uuid::Uuid::new_v4().to_string();
+
+        self.tokens.insert(token_id.clone(), TokenVerifyResponsePayload { confirmed: false, spent: false });
+
+        Ok(TokenInitResponsePayload {
+            lightning_invoice: format!("lnbcrt10u1mock{}", token_id.replace('-', "")),
+            btc_payment_address: String::new(),
+            fee: 1000,
+            token_id,
+        })
+    }
+
+    fn token_verify(&self, token_id: &str) -> MockResult<TokenVerifyResponsePayload> {
+        self.tokens.get(token_id)
+            .cloned()
+            .ok_or((StatusCode::NOT_FOUND, format!("Token {} not found", token_id)))
+    }
+
+    fn deposit_init(&mut self, payload: DepositRequestPayload) -> MockResult<DepositResponsePayload> {
+
+        let auth_key = XOnlyPublicKey::from_str(&payload.auth_key).map_err(bad_request)?;
+
+        verify_auth_signature(&auth_key, &payload.token_id, &payload.signed_token_id)?;
+
+        let token = self.tokens.get_mut(&payload.token_id)
+            .ok_or(bad_request(format!("Token {} not found", payload.token_id)))?;
+
+        if !token.confirmed || token.spent {
+            return Err(bad_request(format!("Token {} is not paid or already spent", payload.token_id)));
+        }
+
+        token.spent = true;
+
+        let secp = Secp256k1::new();
+        let (server_seckey, server_pubkey) = secp.generate_keypair(&mut rand::thread_rng());
+
+        let statechain_id = uuid::Uuid::new_v4().simple().to_string();
+
+        self.statecoins.insert(statechain_id.clone(), MockStatecoin {
+            server_seckey,
+            server_pubkey,
+            auth_key,
+            amount: payload.amount,
+            sec_nonce: None,
+            num_sigs: 0,
+            transfer: None,
+            withdrawn: false,
+        });
+
+        Ok(DepositResponsePayload {
+            server_pubkey: server_pubkey.to_string(),
+            statechain_id,
+        })
+    }
+
+    fn deposit_cancel(&mut self, payload: DepositCancelRequestPayload) -> MockResult<()> {
+
+        let statecoin = self.statecoin(&payload.statechain_id, &payload.signed_statechain_id)?;
+
+        if statecoin.num_sigs > 0 {
+            return Err(bad_request("a statecoin with a signed backup transaction cannot be cancelled"));
+        }
+
+        self.statecoins.remove(&payload.statechain_id);
+
+        Ok(())
+    }
+
+    fn recover_statechains(&self, payload: RecoverStatechainsRequestPayload) -> MockResult<RecoverStatechainsResponsePayload> {
+
+        let auth_key = XOnlyPublicKey::from_str(&payload.auth_key).map_err(bad_request)?;
+
+        verify_auth_signature(&auth_key, &payload.auth_key, &payload.signed_auth_key)?;
+
+        // The mock server does not keep the backup transactions it signs
+        let statechains = self.statecoins.iter()
+            .filter(|(_, statecoin)| statecoin.auth_key == auth_key && !statecoin.withdrawn)
+            .map(|(statechain_id, statecoin)| RecoveredStatechain {
+                statechain_id: statechain_id.clone(),
+                server_pubkey: statecoin.server_pubkey.to_string(),
+                amount: statecoin.amount,
+                backup_transactions: Vec::new(),
+            })
+            .collect();
+
+        Ok(RecoverStatechainsResponsePayload { statechains })
+    }
+
+    fn sign_first(&mut self, payload: SignFirstRequestPayload) -> MockResult<SignFirstResponsePayload> {
+
+        let statecoin = self.statecoin(&payload.statechain_id, &payload.signed_statechain_id)?;
+
+        let secp = Secp256k1::new();
+        let session_id = MusigSessionId::new(&mut rand::thread_rng());
+
+        let (sec_nonce, pub_nonce) = new_musig_nonce_pair(&secp, session_id, None, Some(statecoin.server_seckey), statecoin.server_pubkey, None, None)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+        // A new call discards any nonce left by an unfinished signing session
+        statecoin.sec_nonce = Some(sec_nonce);
+
+        Ok(SignFirstResponsePayload {
+            server_pubnonce: hex::encode(pub_nonce.serialize()),
+        })
+    }
+
+    fn sign_second(&mut self, payload: PartialSignatureRequestPayload) -> MockResult<PartialSignatureResponsePayload> {
+
+        let statecoin = self.statecoin(&payload.statechain_id, &payload.signed_statechain_id)?;
+
+        let sec_nonce = statecoin.sec_nonce.take()
+            .ok_or((StatusCode::BAD_REQUEST, "sign/first must be called before sign/second".to_string()))?;
+
+        let key_agg_coef = MusigKeyAggCoef::from_slice(&decode_hex(&payload.keyaggcoef)?).map_err(bad_request)?;
+        let session = MusigSession::from_slice(&decode_hex(&payload.session)?).map_err(bad_request)?;
+
+        let negate_seckey = match payload.negate_seckey {
+            0 => false,
+            1 => true,
+            _ => return Err(bad_request("negate_seckey must be 0 or 1")),
+        };
+
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::from_secret_key(&secp, &statecoin.server_seckey);
+
+        let partial_sig = secp256k1_zkp::blinded_musig_partial_sign(&secp, sec_nonce, &keypair, &session, &key_agg_coef, negate_seckey)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+        statecoin.num_sigs += 1;
+
+        Ok(PartialSignatureResponsePayload {
+            partial_sig: hex::encode(partial_sig.serialize()),
+        })
+    }
+
+    fn statechain_info(&self, statechain_id: &str) -> MockResult<StatechainInfoResponsePayload> {
+        self.statecoins.get(statechain_id)
+            .map(|statecoin| StatechainInfoResponsePayload { num_sigs: statecoin.num_sigs })
+            .ok_or((StatusCode::NOT_FOUND, format!("Statechain {} not found", statechain_id)))
+    }
+
+    fn transfer_sender(&mut self, payload: TransferSenderRequestPayload) -> MockResult<TransferSenderResponsePayload> {
+
+        let statecoin = self.statecoin(&payload.statechain_id, &payload.signed_statechain_id)?;
+
+        if XOnlyPublicKey::from_str(&payload.user_auth_key).map_err(bad_request)? != statecoin.auth_key {
+            return Err((StatusCode::UNAUTHORIZED, "user_auth_key is not the auth key of the statechain".to_string()));
+        }
+
+        let new_auth_key = PublicKey::from_str(&payload.new_user_auth_key).map_err(bad_request)?;
+
+        // A new call replaces a transfer that was not completed
+        let x1 = SecretKey::new(&mut rand::thread_rng());
+
+        statecoin.transfer = Some(MockTransfer {
+            x1,
+            new_auth_key,
+            enc_transfer_msg: None,
+        });
+
+        Ok(TransferSenderResponsePayload {
+            x1: hex::encode(x1.secret_bytes()),
+        })
+    }
+
+    fn transfer_update_msg(&mut self, payload: TransferUpdateMsgRequestPayload) -> MockResult<()> {
+
+        let statecoin = self.statecoin(&payload.statechain_id, &payload.auth_sign)?;
+
+        let transfer = statecoin.transfer.as_mut()
+            .ok_or(bad_request("transfer/sender must be called before transfer/update_msg"))?;
+
+        if PublicKey::from_str(&payload.new_user_auth_key).map_err(bad_request)? != transfer.new_auth_key {
+            return Err(bad_request("new_user_auth_key does not match transfer/sender"));
+        }
+
+        transfer.enc_transfer_msg = Some(payload.enc_transfer_msg);
+
+        Ok(())
+    }
+
+    fn get_msg_addr(&self, auth_key: &str) -> MockResult<GetMsgAddrResponsePayload> {
+
+        let auth_key = PublicKey::from_str(auth_key).map_err(bad_request)?;
+
+        let list_enc_transfer_msg = self.statecoins.values()
+            .filter_map(|statecoin| statecoin.transfer.as_ref())
+            .filter(|transfer| transfer.new_auth_key == auth_key)
+            .filter_map(|transfer| transfer.enc_transfer_msg.clone())
+            .collect();
+
+        Ok(GetMsgAddrResponsePayload { list_enc_transfer_msg })
+    }
+
+    /// Replaces the server key share so that the new client share adds up to the same aggregated secret:
+    /// s2 = s1 + t2 - x1 = s1 + o1 - o2, since t2 = t1 - o2 and t1 = o1 + x1
+    fn transfer_receiver(&mut self, payload: TransferReceiverRequestPayload) -> MockResult<TransferReceiverResponsePayload> {
+
+        let statecoin = self.statecoins.get_mut(&payload.statechain_id)
+            .ok_or((StatusCode::NOT_FOUND, format!("Statechain {} not found", payload.statechain_id)))?;
+
+        let transfer = match &statecoin.transfer {
+            Some(transfer) if transfer.enc_transfer_msg.is_some() => transfer,
+            _ => return Err(bad_request(format!("Statechain {} has no transfer to receive", payload.statechain_id))),
+        };
+
+        let new_auth_key = transfer.new_auth_key.x_only_public_key().0;
+
+        verify_auth_signature(&new_auth_key, &payload.statechain_id, &payload.auth_sig)?;
+
+        let t2 = SecretKey::from_slice(&decode_hex(&payload.t2)?).map_err(bad_request)?;
+
+        let server_seckey = statecoin.server_seckey
+            .add_tweak(&Scalar::from(t2)).map_err(bad_request)?
+            .add_tweak(&Scalar::from(transfer.x1.negate())).map_err(bad_request)?;
+
+        statecoin.server_seckey = server_seckey;
+        statecoin.server_pubkey = server_seckey.public_key(&Secp256k1::new());
+        statecoin.auth_key = new_auth_key;
+        statecoin.sec_nonce = None;
+        statecoin.transfer = None;
+
+        Ok(TransferReceiverResponsePayload {
+            server_pubkey: statecoin.server_pubkey.to_string(),
+        })
+    }
+
+    fn withdraw_complete(&mut self, payload: WithdrawCompleteRequestPayload) -> MockResult<()> {
+
+        let statecoin = self.statecoin(&payload.statechain_id, &payload.signed_statechain_id)?;
+
+        statecoin.withdrawn = true;
+        statecoin.transfer = None;
+
+        Ok(())
+    }
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> MockResult<T> {
+    serde_json::from_slice(body).map_err(bad_request)
+}
+
+fn to_json<T: Serialize>(value: T) -> MockResult<String> {
+    serde_json::to_string(&value).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+async fn handle(state: Arc<Mutex<MockState>>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+
+    let method = request.method().clone();
+    let path = request.uri().path().trim_start_matches('/').to_string();
+
+    let result = match hyper::body::to_bytes(request.into_body()).await {
+        Err(err) => Err(bad_request(err)),
+        Ok(body) => {
+            let mut state = state.lock().unwrap();
+
+            if state.failures.remove(&path) {
+                return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Injected failure of {}", path)));
+            }
+
+            match (method, path.as_str()) {
+                (Method::GET, "token/token_init") => state.token_init().and_then(to_json),
+                (Method::GET, path) if path.starts_with("token/token_verify/") => {
+                    state.token_verify(path.trim_start_matches("token/token_verify/")).and_then(to_json)
+                },
+                (Method::POST, "deposit/init/pod") => parse(&body).and_then(|payload| state.deposit_init(payload)).and_then(to_json),
+                (Method::POST, "deposit/cancel") => parse(&body).and_then(|payload| state.deposit_cancel(payload)).and_then(to_json),
+                (Method::GET, "info/config") => to_json(&state.config),
+                (Method::GET, path) if path.starts_with("info/statechain/") => {
+                    state.statechain_info(path.trim_start_matches("info/statechain/")).and_then(to_json)
+                },
+                (Method::POST, "sign/first") => parse(&body).and_then(|payload| state.sign_first(payload)).and_then(to_json),
+                (Method::POST, "sign/second") => parse(&body).and_then(|payload| state.sign_second(payload)).and_then(to_json),
+                (Method::POST, "transfer/sender") => parse(&body).and_then(|payload| state.transfer_sender(payload)).and_then(to_json),
+                (Method::POST, "transfer/update_msg") => parse(&body).and_then(|payload| state.transfer_update_msg(payload)).and_then(to_json),
+                (Method::GET, path) if path.starts_with("transfer/get_msg_addr/") => {
+                    state.get_msg_addr(path.trim_start_matches("transfer/get_msg_addr/")).and_then(to_json)
+                },
+                (Method::POST, "transfer/receiver") => parse(&body).and_then(|payload| state.transfer_receiver(payload)).and_then(to_json),
+                (Method::POST, "withdraw/complete") => parse(&body).and_then(|payload| state.withdraw_complete(payload)).and_then(to_json),
+                (Method::POST, "recover/statechains") => parse(&body).and_then(|payload| state.recover_statechains(payload)).and_then(to_json),
+                _ => Err((StatusCode::NOT_FOUND, format!("{} not found", path))),
+            }
+        },
+    };
+
+    let (status, body) = match result {
+        Ok(body) => (StatusCode::OK, body),
+        Err(err) => err,
+    };
+
+    Ok(json_response(status, body))
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Statechain server listening on a random local port. It stops when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+
+    /// Starts the server on the tokio runtime of the caller
+    pub async fn start(config: ServerConfig) -> Result<Self, CError> {
+
+        let state = Arc::new(Mutex::new(MockState {
+            config,
+            tokens: HashMap::new(),
+            statecoins: HashMap::new(),
+            failures: HashSet::new(),
+        }));
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| handle(state.clone(), request)))
+            }
+        });
+
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .map_err(|e| CError::ServerHttp(e.to_string()))?
+            .serve(make_service);
+
+        let addr = server.local_addr();
+
+        let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+
+        tokio::spawn(server.with_graceful_shutdown(async {
+            shutdown_signal.await.ok();
+        }));
+
+        Ok(MockServer {
+            addr,
+            state,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    /// URL to use as the statechain entity
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Marks a token as paid, as the server does when its invoice is settled
+    pub fn pay_token(&self, token_id: &uuid::Uuid) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(token) = state.tokens.get_mut(&token_id.to_string()) {
+            token.confirmed = true;
+        }
+    }
+
+    /// Issues a token that is already paid
+    pub fn paid_token(&self) -> uuid::Uuid {
+        let token_id = uuid::Uuid::new_v4();
+        let mut state = self.state.lock().unwrap();
+        state.tokens.insert(token_id.to_string(), TokenVerifyResponsePayload { confirmed: true, spent: false });
+        token_id
+    }
+
+    /// Number of partial signatures the server has produced for a statecoin
+    pub fn num_sigs(&self, statechain_id: &str) -> Option<u32> {
+        let state = self.state.lock().unwrap();
+        state.statecoins.get(statechain_id).map(|statecoin| statecoin.num_sigs)
+    }
+
+    /// Makes the next request to `path` (e.g. "transfer/update_msg") fail with a 500 status, without handling it
+    pub fn fail_next(&self, path: &str) {
+        self.state.lock().unwrap().failures.insert(path.to_string());
+    }
+
+    /// Whether `withdraw/complete` has been called for a statecoin
+    pub fn withdrawn(&self, statechain_id: &str) -> Option<bool> {
+        let state = self.state.lock().unwrap();
+        state.statecoins.get(statechain_id).map(|statecoin| statecoin.withdrawn)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::str::FromStr;
+
+use bitcoin::{Network, Address, OutPoint, Transaction, Txid, hashes::sha256, secp256k1};
+use secp256k1_zkp::{Secp256k1, Message, PublicKey, SecretKey, XOnlyPublicKey, musig::MusigKeyAggCache};
+use serde::{Serialize, Deserialize};
+use sqlx::Sqlite;
+
+use crate::{account, chain::ChainBackend, deposit::DepositStatus, encryption::{self, KdfParams, WalletCipher}, error::CError, key_derivation::{self, WalletSeed}, server::{StatechainServer, RecoverStatechainsRequestPayload, RecoveredStatechain}, transaction, wallet::StatecoinStatus};
+
+/// Number of consecutive unused addresses after which the scan stops
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RestoredStatecoin {
+    pub statechain_id: String,
+    pub amount: u64,
+    pub status: StatecoinStatus,
+    pub funding_txid: Option<Txid>,
+    pub funding_vout: Option<u32>,
+    pub backup_tx_count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RestoreReport {
+    /// Number of addresses stored in the wallet, in all its accounts. New addresses are derived after them.
+    pub address_count: u32,
+    pub statecoins: Vec<RestoredStatecoin>,
+    /// Statecoins registered to the wallet keys whose funding output has already been spent
+    pub closed_statechain_ids: Vec<String>,
+}
+
+enum RestoreOutcome {
+    Restored(RestoredStatecoin),
+    Closed,
+    /// The statecoin was restored by a previous run
+    AlreadyInWallet,
+}
+
+/// Creates the wallet from `seed`. If the wallet already exists, e.g. because a previous restore was interrupted,
+/// it is unlocked and must have the same seed, derivation and BIP39 passphrase.
+pub async fn open_wallet(pool: &sqlx::Pool<Sqlite>, seed: &WalletSeed, passphrase: &str, params: KdfParams) -> Result<WalletCipher, CError> {
+
+    if !encryption::wallet_exists(pool).await? {
+        return encryption::restore_wallet(pool, passphrase, params, seed).await;
+    }
+
+    let cipher = encryption::unlock(pool, passphrase).await?;
+
+    let wallet_seed = key_derivation::get_seed(pool, &cipher).await?;
+
+    if wallet_seed.entropy != seed.entropy {
+        return Err(CError::UserInput("The mnemonic does not match the seed of the wallet".to_string()));
+    }
+
+    if wallet_seed.derivation != seed.derivation || wallet_seed.bip39_passphrase != seed.bip39_passphrase {
+        return Err(CError::UserInput("The wallet was restored with another derivation or BIP39 passphrase".to_string()));
+    }
+
+    Ok(cipher)
+}
+
+/// Derives the addresses of every account of the wallet until `gap_limit` consecutive ones are unused, stores them,
+/// and rebuilds the statecoins the server has registered to their auth keys.
+/// An address is used if it owns a statecoin or has a transfer waiting to be received.
+pub async fn execute(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, chain: &dyn ChainBackend, server: &StatechainServer, gap_limit: u32, network: Network) -> Result<RestoreReport, CError> {
+
+    if gap_limit == 0 {
+        return Err(CError::UserInput("The gap limit must be at least 1".to_string()));
+    }
+
+    let mut found = Vec::<(PublicKey, RecoveredStatechain)>::new();
+    let mut address_count = 0;
+
+    for account in account::list_accounts(pool).await? {
+        found.append(&mut scan_account(pool, cipher, server, account.index, gap_limit, network).await?);
+        address_count += key_derivation::get_next_address_index(pool, account.index, 0).await?;
+    }
+
+    let mut statecoins = Vec::<RestoredStatecoin>::new();
+    let mut closed_statechain_ids = Vec::<String>::new();
+
+    for (client_pubkey, statechain) in found {
+        match restore_statecoin(pool, chain, &client_pubkey, &statechain, network).await? {
+            RestoreOutcome::Restored(statecoin) => statecoins.push(statecoin),
+            RestoreOutcome::Closed => closed_statechain_ids.push(statechain.statechain_id),
+            RestoreOutcome::AlreadyInWallet => {},
+        }
+    }
+
+    Ok(RestoreReport {
+        address_count,
+        statecoins,
+        closed_statechain_ids,
+    })
+}
+
+/// Scans the addresses of `account_index`, stores them up to the last used one and returns the statechains found
+async fn scan_account(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, server: &StatechainServer, account_index: u32, gap_limit: u32, network: Network) -> Result<Vec<(PublicKey, RecoveredStatechain)>, CError> {
+
+    let mut found = Vec::<(PublicKey, RecoveredStatechain)>::new();
+    let mut address_count = 0;
+    let mut address_index = 0;
+    let mut unused = 0;
+
+    while unused < gap_limit {
+
+        let agg_key = key_derivation::generate_new_key(pool, cipher, key_derivation::AGG_KEY_PURPOSE, account_index, 0, address_index, network).await?;
+        let auth_key = key_derivation::generate_new_key(pool, cipher, key_derivation::AUTH_KEY_PURPOSE, account_index, 0, address_index, network).await?;
+
+        let statechain = get_statechain(server, &auth_key.secret_key, &auth_key.public_key.x_only_public_key().0).await?;
+        let pending_transfers = server.get_msg_addr(&auth_key.public_key.to_string()).await?.list_enc_transfer_msg;
+
+        if statechain.is_none() && pending_transfers.is_empty() {
+            unused += 1;
+        } else {
+            unused = 0;
+            address_count = address_index + 1;
+        }
+
+        if let Some(statechain) = statechain {
+            found.push((agg_key.public_key, statechain));
+        }
+
+        address_index += 1;
+    }
+
+    // Addresses already in the wallet are kept as they are
+    for address_index in key_derivation::get_next_address_index(pool, account_index, 0).await?..address_count {
+        key_derivation::insert_address(pool, cipher, account_index, address_index, None, None, network).await?;
+    }
+
+    Ok(found)
+}
+
+/// Asks the server for the statechain owned by `auth_pubkey`. The wallet uses each auth key for a single statecoin.
+async fn get_statechain(server: &StatechainServer, auth_seckey: &SecretKey, auth_pubkey: &XOnlyPublicKey) -> Result<Option<RecoveredStatechain>, CError> {
+
+    let secp = Secp256k1::new();
+    let keypair = secp256k1::KeyPair::from_seckey_slice(&secp, auth_seckey.as_ref())?;
+
+    let auth_key = auth_pubkey.to_string();
+    let msg = Message::from_hashed_data::<sha256::Hash>(auth_key.as_bytes());
+
+    let payload = RecoverStatechainsRequestPayload {
+        signed_auth_key: secp.sign_schnorr(&msg, &keypair).to_string(),
+        auth_key,
+    };
+
+    let mut statechains = server.recover_statechains(&payload).await?.statechains;
+
+    if statechains.len() > 1 {
+        return Err(CError::Protocol(format!("Server returned {} statechains for the auth key {}", statechains.len(), auth_pubkey)));
+    }
+
+    Ok(statechains.pop())
+}
+
+async fn restore_statecoin(pool: &sqlx::Pool<Sqlite>, chain: &dyn ChainBackend, client_pubkey: &PublicKey, statechain: &RecoveredStatechain, network: Network) -> Result<RestoreOutcome, CError> {
+
+    let server_pubkey = PublicKey::from_str(&statechain.server_pubkey)
+        .map_err(|e| CError::Protocol(format!("Invalid server public key: {}", e)))?;
+
+    let secp = Secp256k1::new();
+
+    let aggregated_pubkey = MusigKeyAggCache::new(&secp, &[client_pubkey.to_owned(), server_pubkey]).agg_pk();
+    let p2tr_agg_address = Address::p2tr(&secp, aggregated_pubkey, None, network);
+
+    // The backup transactions spend the funding outpoint. Without them, it is the output of the statecoin amount at the deposit address.
+    let funding_outpoint = match statechain.backup_transactions.first() {
+        Some(backup_tx) => {
+            let tx: Transaction = bitcoin::consensus::deserialize(&hex::decode(&backup_tx.tx)?)?;
+            tx.input.first().map(|input| input.previous_output)
+        },
+        None => None,
+    };
+
+    let utxo = chain.list_unspent(&p2tr_agg_address).await?.into_iter().find(|utxo| match funding_outpoint {
+        Some(outpoint) => OutPoint { txid: utxo.txid, vout: utxo.vout } == outpoint,
+        None => utxo.value == statechain.amount,
+    });
+
+    let (deposit_status, status) = match &utxo {
+        Some(utxo) if utxo.height > 0 => {
+            let deposit_status = if statechain.backup_transactions.is_empty() { DepositStatus::Funded } else { DepositStatus::BackupSigned };
+            (deposit_status, StatecoinStatus::Confirmed)
+        },
+        Some(_) => (DepositStatus::FundedUnconfirmed, StatecoinStatus::InMempool),
+        None => {
+            // The deposit address has been used, so the statecoin is closed rather than waiting for its deposit
+            if funding_outpoint.is_some() || !chain.get_address_history(&p2tr_agg_address).await?.is_empty() {
+                return Ok(RestoreOutcome::Closed);
+            }
+            (DepositStatus::AwaitingFunds, StatecoinStatus::Initialised)
+        },
+    };
+
+    let query = "\
+        UPDATE signer_data \
+        SET statechain_id = $1, amount = $2, server_pubkey_share = $3, aggregated_pubkey = $4, p2tr_agg_address = $5, \
+        funding_txid = $6, funding_vout = $7, deposit_status = $8, status = $9 \
+        WHERE client_pubkey_share = $10 AND statechain_id IS NULL";
+
+    let result = sqlx::query(query)
+        .bind(&statechain.statechain_id)
+        .bind(statechain.amount as i64)
+        .bind(&server_pubkey.serialize().to_vec())
+        .bind(&aggregated_pubkey.serialize().to_vec())
+        .bind(&p2tr_agg_address.to_string())
+        .bind(utxo.as_ref().map(|utxo| utxo.txid.to_string()))
+        .bind(utxo.as_ref().map(|utxo| utxo.vout))
+        .bind(deposit_status.as_str())
+        .bind(status.as_str())
+        .bind(&client_pubkey.serialize().to_vec())
+        .execute(pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(RestoreOutcome::AlreadyInWallet);
+    }
+
+    if !statechain.backup_transactions.is_empty() {
+        transaction::insert_backup_transactions(pool, &statechain.backup_transactions, &statechain.statechain_id).await?;
+    }
+
+    Ok(RestoreOutcome::Restored(RestoredStatecoin {
+        statechain_id: statechain.statechain_id.clone(),
+        amount: statechain.amount,
+        status,
+        funding_txid: utxo.as_ref().map(|utxo| utxo.txid),
+        funding_vout: utxo.as_ref().map(|utxo| utxo.vout),
+        backup_tx_count: statechain.backup_transactions.len(),
+    }))
+}
@@ -1,11 +1,10 @@
 use std::{collections::{HashMap, BTreeMap}, str::FromStr};
 
-use bitcoin::{Address, TxOut, Transaction, OutPoint, TxIn, ScriptBuf, Witness, absolute, psbt::{Psbt, Input, PsbtSighashType, self}, bip32::{Fingerprint, DerivationPath}, Amount, sighash::{TapSighashType, SighashCache, self, TapSighash}, taproot::{TapLeafHash, self}, secp256k1, key::TapTweak};
-use electrum_client::ListUnspentRes;
+use bitcoin::{Address, Txid, TxOut, Transaction, OutPoint, TxIn, ScriptBuf, Witness, absolute, psbt::{Psbt, Input, PsbtSighashType, self}, bip32::{Fingerprint, DerivationPath}, Amount, sighash::{TapSighashType, SighashCache, self, TapSighash}, taproot::{TapLeafHash, self}, secp256k1, key::TapTweak};
 use secp256k1_zkp::{SecretKey, XOnlyPublicKey, PublicKey, Secp256k1};
 use sqlx::{Sqlite, Row};
 
-use crate::electrum;
+use crate::{chain::{ChainBackend, Utxo}, encryption::WalletCipher, error::CError, key_store};
 
 #[derive(Debug)]
 pub struct AddressInfo {
@@ -24,30 +23,29 @@ pub struct AddressInfo {
     pub value: u64,
 }
 
-pub async fn get_address_info(pool: &sqlx::Pool<Sqlite>, list_utxo: Vec::<(ListUnspentRes, Address)>) -> Vec::<AddressInfo> {
+pub async fn get_address_info(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, list_utxo: Vec::<(Utxo, Address)>, network: Network) -> Result<Vec::<AddressInfo>, CError> {
 
     let mut list_unspent = Vec::<AddressInfo>::new(); 
 
     for (utxo, backup_address) in list_utxo {
 
-        let query = "SELECT client_seckey_share, client_pubkey_share, fingerprint, agg_key_derivation_path \
+        let query = "SELECT client_pubkey_share, fingerprint, agg_key_derivation_path \
             FROM signer_data \
             WHERE backup_address = $1";
 
         let row = sqlx::query(query)
             .bind(&backup_address.to_string())
             .fetch_one(pool)
-            .await
-            .unwrap();
+            .await?;
 
-        let secret_key_bytes = row.get::<Vec<u8>, _>("client_seckey_share");
-        let secret_key = SecretKey::from_slice(&secret_key_bytes).unwrap();
+        let public_key_bytes = row.try_get::<Vec<u8>, _>("client_pubkey_share")?;
+        let public_key = PublicKey::from_slice(&public_key_bytes)?;
+        let xonly_public_key = public_key.x_only_public_key().0;
 
-        let public_key_bytes = row.get::<Vec<u8>, _>("client_pubkey_share");
-        let xonly_public_key = PublicKey::from_slice(&public_key_bytes).unwrap().x_only_public_key().0;
+        let fingerprint = row.try_get::<String, _>("fingerprint")?;
+        let derivation_path = row.try_get::<String, _>("agg_key_derivation_path")?;
 
-        let fingerprint = row.get::<String, _>("fingerprint");
-        let derivation_path = row.get::<String, _>("agg_key_derivation_path");
+        let secret_key = key_store::get_secret_key(pool, cipher, &public_key, Some(&derivation_path), network).await?;
 
         list_unspent.push(AddressInfo {
             address: backup_address,
@@ -55,18 +53,26 @@ pub async fn get_address_info(pool: &sqlx::Pool<Sqlite>, list_utxo: Vec::<(ListU
             xonly_public_key,
             fingerprint,
             derivation_path,
-            height: utxo.height,
-            tx_hash: utxo.tx_hash,
-            tx_pos: utxo.tx_pos,
+            height: utxo.height as usize,
+            tx_hash: utxo.txid,
+            tx_pos: utxo.vout as usize,
             value: utxo.value,
         });
     }
 
-    list_unspent
+    Ok(list_unspent)
 
 }
 
-pub fn send_all_funds(list_utxo: &Vec::<AddressInfo>, to_address: &Address, fee_rate_sats_per_byte: u64) {
+pub async fn send_all_funds(chain: &dyn ChainBackend, list_utxo: &Vec::<AddressInfo>, to_address: &Address, fee_rate_sats_per_byte: u64) -> Result<Txid, CError> {
+
+    let absolute_fee: u64 = sweep_vsize(list_utxo, to_address)? * fee_rate_sats_per_byte;
+
+    send_all_funds_with_fee(chain, list_utxo, to_address, absolute_fee).await
+}
+
+/// Virtual size of the transaction sending all of `list_utxo` to `to_address`
+pub fn sweep_vsize(list_utxo: &Vec::<AddressInfo>, to_address: &Address) -> Result<u64, CError> {
 
     let input_amount: u64 = list_utxo.iter().map(|s| s.value).sum();
 
@@ -74,9 +80,19 @@ pub fn send_all_funds(list_utxo: &Vec::<AddressInfo>, to_address: &Address, fee_
         TxOut { value: input_amount, script_pubkey: to_address.script_pubkey() },
     ];
 
-    let tx = create_transaction(list_utxo, &outputs).unwrap();
+    let tx = create_transaction(list_utxo, &outputs)?;
+
+    Ok(tx.vsize() as u64)
+}
+
+/// Sends all of `list_utxo` to `to_address`, paying `absolute_fee`
+pub async fn send_all_funds_with_fee(chain: &dyn ChainBackend, list_utxo: &Vec::<AddressInfo>, to_address: &Address, absolute_fee: u64) -> Result<Txid, CError> {
+
+    let input_amount: u64 = list_utxo.iter().map(|s| s.value).sum();
 
-    let absolute_fee: u64 = tx.vsize() as u64 * fee_rate_sats_per_byte;
+    if absolute_fee >= input_amount {
+        return Err(CError::UserInput(format!("Backup funds {} do not cover the fee {}", input_amount, absolute_fee)));
+    }
 
     let amount_out = input_amount - absolute_fee;
 
@@ -84,19 +100,15 @@ pub fn send_all_funds(list_utxo: &Vec::<AddressInfo>, to_address: &Address, fee_
             TxOut { value: amount_out, script_pubkey: to_address.script_pubkey() },
     ];
 
-    let tx = create_transaction(list_utxo, &outputs).unwrap();
-   
-    let client = electrum_client::Client::new("tcp://127.0.0.1:50001").unwrap();
+    let tx = create_transaction(list_utxo, &outputs)?;
 
     let tx_bytes = bitcoin::consensus::encode::serialize(&tx);
-    let txid = electrum::transaction_broadcast_raw(&client, &tx_bytes);
-
-    println!("--> txid sent: {}", txid);
-
+    let txid = chain.broadcast(&tx_bytes).await?;
 
+    Ok(txid)
 }
 
-fn create_transaction(inputs_info: &Vec::<AddressInfo>, outputs: &Vec<TxOut>) -> Result<Transaction, Box<dyn std::error::Error>> {
+fn create_transaction(inputs_info: &Vec::<AddressInfo>, outputs: &Vec<TxOut>) -> Result<Transaction, CError> {
 
     let secp = Secp256k1::new();
 
@@ -125,7 +137,7 @@ fn create_transaction(inputs_info: &Vec::<AddressInfo>, outputs: &Vec<TxOut>) ->
         input: tx_inputs,
         output: outputs.clone(),
     };
-    let mut psbt = Psbt::from_unsigned_tx(tx1).unwrap();
+    let mut psbt = Psbt::from_unsigned_tx(tx1).map_err(|e| CError::Protocol(e.to_string()))?;
 
     let mut origins = BTreeMap::new();
     for input in inputs_info {
@@ -134,8 +146,8 @@ fn create_transaction(inputs_info: &Vec::<AddressInfo>, outputs: &Vec<TxOut>) ->
             (
                 vec![],
                 (
-                    Fingerprint::from_str(&input.fingerprint).unwrap(),
-                    DerivationPath::from_str(&input.derivation_path).unwrap(),
+                    Fingerprint::from_str(&input.fingerprint).map_err(|e| CError::KeyDerivation(e.to_string()))?,
+                    DerivationPath::from_str(&input.derivation_path)?,
                 ),
             ),
         );
@@ -154,7 +166,7 @@ fn create_transaction(inputs_info: &Vec::<AddressInfo>, outputs: &Vec<TxOut>) ->
             tap_key_origins: origins.clone(),
             ..Default::default()
         };
-        let ty = PsbtSighashType::from_str("SIGHASH_ALL").unwrap();
+        let ty = PsbtSighashType::from(TapSighashType::All);
         input.sighash_type = Some(ty);
         input.tap_internal_key = Some(input_info.xonly_public_key);
         psbt_inputs.push(input);
@@ -170,7 +182,7 @@ fn create_transaction(inputs_info: &Vec::<AddressInfo>, outputs: &Vec<TxOut>) ->
         input_txouts.push(TxOut { value: input_info.value, script_pubkey: input_info.address.script_pubkey() });
     }
 
-    psbt.inputs.iter_mut().enumerate().try_for_each::<_, Result<(), Box<dyn std::error::Error>>>(
+    psbt.inputs.iter_mut().enumerate().try_for_each::<_, Result<(), CError>>(
         |(vout, input)| {
 
             let hash_ty = input
@@ -182,29 +194,32 @@ fn create_transaction(inputs_info: &Vec::<AddressInfo>, outputs: &Vec<TxOut>) ->
                 vout,
                 &sighash::Prevouts::All(&input_txouts.as_slice()),
                 hash_ty,
-            ).unwrap();
+            ).map_err(|e| CError::Protocol(e.to_string()))?;
 
-            let secret_key = secret_keys.get(&input.tap_internal_key.ok_or("Internal key missing in PSBT")?).unwrap();
+            let internal_key = input.tap_internal_key.ok_or(CError::Protocol("Internal key missing in PSBT".to_string()))?;
+            let secret_key = secret_keys.get(&internal_key).ok_or(CError::KeyDerivation("Secret key not found for PSBT input".to_string()))?;
 
             sign_psbt_taproot(
                 &secret_key,
-                input.tap_internal_key.unwrap(),
+                internal_key,
                 None,
                 input,
                 hash,
                 hash_ty,
                 &secp,
-            );
+            )?;
 
             Ok(())
         },
-    ).unwrap();
+    )?;
 
     // FINALIZER
     psbt.inputs.iter_mut().for_each(|input| {
-        let mut script_witness: Witness = Witness::new();
-        script_witness.push(input.tap_key_sig.unwrap().to_vec());
-        input.final_script_witness = Some(script_witness);
+        if let Some(tap_key_sig) = input.tap_key_sig {
+            let mut script_witness: Witness = Witness::new();
+            script_witness.push(tap_key_sig.to_vec());
+            input.final_script_witness = Some(script_witness);
+        }
 
         // Clear all the data fields as per the spec.
         input.partial_sigs = BTreeMap::new();
@@ -216,20 +231,15 @@ fn create_transaction(inputs_info: &Vec::<AddressInfo>, outputs: &Vec<TxOut>) ->
 
     let tx = psbt.extract_tx();
     
-    //let mut prev_out_verify = Vec::<bitcoin::TxOut>::new();
-    for input in inputs_info {
-        let script_pubkey_hex = input.address.script_pubkey().to_hex_string();
-        let amount = Amount::from_sat(input.value);
-
-        //prev_out_verify.push(TxOut { value: amount.to_sat(), script_pubkey });
-        tx.verify(|_| {
-            Some(TxOut { 
-                value: amount.to_sat(), 
-                script_pubkey: ScriptBuf::from_hex(&script_pubkey_hex).unwrap() 
-            })
-        })
-        .expect("failed to verify transaction");
-    }
+    let prevouts: HashMap<OutPoint, TxOut> = inputs_info.iter().map(|input| {
+        (
+            OutPoint { txid: input.tx_hash, vout: input.tx_pos as u32 },
+            TxOut { value: input.value, script_pubkey: input.address.script_pubkey() },
+        )
+    }).collect();
+
+    tx.verify(|outpoint| prevouts.get(outpoint).cloned())
+        .map_err(|e| CError::Protocol(format!("failed to verify transaction: {}", e)))?;
 
     Ok(tx)
 }
@@ -242,8 +252,8 @@ fn sign_psbt_taproot(
     hash: TapSighash,
     hash_ty: TapSighashType,
     secp: &Secp256k1<secp256k1::All>,
-) {
-    let keypair = secp256k1::KeyPair::from_seckey_slice(secp, secret_key.as_ref()).unwrap();
+) -> Result<(), CError> {
+    let keypair = secp256k1::KeyPair::from_seckey_slice(secp, secret_key.as_ref())?;
     let keypair = match leaf_hash {
         None => keypair.tap_tweak(secp, psbt_input.tap_merkle_root).to_inner(),
         Some(_) => keypair, // no tweak for script spend
@@ -258,4 +268,6 @@ fn sign_psbt_taproot(
     } else {
         psbt_input.tap_key_sig = Some(final_signature);
     }
+
+    Ok(())
 }
\ No newline at end of file
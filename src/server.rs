@@ -0,0 +1,319 @@
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::{error::CError, transaction::BackupTx};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of times an idempotent request is retried after the first attempt fails
+const MAX_RETRIES: u32 = 3;
+/// Delay before the first retry. It doubles on every following one.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DepositRequestPayload {
+    pub amount: u64,
+    pub auth_key: String,
+    pub token_id: String,
+    pub signed_token_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DepositResponsePayload {
+    pub server_pubkey: String,
+    pub statechain_id: String,
+}
+
+/// Response of `token/token_init`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenInitResponsePayload {
+    pub token_id: String,
+    /// Price of the token in satoshis
+    pub fee: u64,
+    pub lightning_invoice: String,
+    pub btc_payment_address: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenVerifyResponsePayload {
+    /// The token has been paid
+    pub confirmed: bool,
+    /// The token has been used by a deposit
+    pub spent: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DepositCancelRequestPayload {
+    pub statechain_id: String,
+    pub signed_statechain_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerConfig {
+    /// Number of blocks between the deposit and the locktime of the first backup transaction
+    pub initlock: u32,
+    /// Number of blocks each new backup transaction locktime is decremented by
+    pub interval: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignFirstRequestPayload {
+    pub statechain_id: String,
+    pub r2_commitment: String,
+    pub blind_commitment: String,
+    pub signed_statechain_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignFirstResponsePayload {
+    pub server_pubnonce: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartialSignatureRequestPayload {
+    pub statechain_id: String,
+    pub keyaggcoef: String,
+    pub negate_seckey: u8,
+    pub session: String,
+    pub signed_statechain_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartialSignatureResponsePayload {
+    pub partial_sig: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferSenderRequestPayload {
+    pub statechain_id: String,
+    pub user_auth_key: String,
+    pub new_user_auth_key: String,
+    pub batch_id: Option<String>,
+    pub signed_statechain_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferSenderResponsePayload {
+    pub x1: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferUpdateMsgRequestPayload {
+    pub statechain_id: String,
+    pub auth_sign: String,
+    pub new_user_auth_key: String,
+    pub enc_transfer_msg: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetMsgAddrResponsePayload {
+    pub list_enc_transfer_msg: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatechainInfoResponsePayload {
+    pub num_sigs: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferReceiverRequestPayload {
+    pub statechain_id: String,
+    pub batch_data: Option<String>,
+    pub t2: String,
+    pub auth_sig: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferReceiverResponsePayload {
+    pub server_pubkey: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WithdrawCompleteRequestPayload {
+    pub statechain_id: String,
+    pub signed_statechain_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecoverStatechainsRequestPayload {
+    pub auth_key: String,
+    /// Signature of `auth_key` by the auth key itself, so only its owner can list its statechains
+    pub signed_auth_key: String,
+}
+
+/// Statechain currently owned by an auth key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecoveredStatechain {
+    pub statechain_id: String,
+    pub server_pubkey: String,
+    pub amount: u64,
+    /// Backup transactions held by the server. Empty if it does not keep them.
+    pub backup_transactions: Vec<BackupTx>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecoverStatechainsResponsePayload {
+    pub statechains: Vec<RecoveredStatechain>,
+}
+
+/// Client of the statechain server API.
+/// Clones share the same underlying connection pool.
+#[derive(Debug, Clone)]
+pub struct StatechainServer {
+    http_client: reqwest::Client,
+    endpoint: String,
+}
+
+impl StatechainServer {
+
+    pub fn new(endpoint: &str) -> Result<Self, CError> {
+
+        let http_client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        Ok(StatechainServer {
+            http_client,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+        })
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.endpoint, path)
+    }
+
+    /// Checks the status code and returns the body of a successful response
+    async fn response_text(response: reqwest::Response) -> Result<String, CError> {
+
+        let status = response.status();
+        let value = response.text().await?;
+
+        if !status.is_success() {
+            return Err(CError::ServerStatus { status: status.as_u16(), message: value });
+        }
+
+        Ok(value)
+    }
+
+    fn parse<T: DeserializeOwned>(path: &str, value: &str) -> Result<T, CError> {
+        serde_json::from_str(value)
+            .map_err(|e| CError::ServerHttp(format!("failed to parse {} response {}: {}", path, value, e)))
+    }
+
+    /// Whether a failed idempotent request is worth sending again
+    fn is_retryable(err: &CError) -> bool {
+        match err {
+            CError::ServerHttp(_) => true,
+            CError::ServerStatus { status, .. } => *status >= 500 || *status == 429,
+            _ => false,
+        }
+    }
+
+    /// Idempotent GET requests are retried with exponential backoff
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, CError> {
+
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+
+        loop {
+            match self.get_text(path).await {
+                Ok(value) => return Self::parse(path, &value),
+                Err(err) if attempt < MAX_RETRIES && Self::is_retryable(&err) => {
+                    attempt += 1;
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                },
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// GET requests that change the server state are sent only once
+    async fn get_once<T: DeserializeOwned>(&self, path: &str) -> Result<T, CError> {
+        let value = self.get_text(path).await?;
+        Self::parse(path, &value)
+    }
+
+    async fn get_text(&self, path: &str) -> Result<String, CError> {
+        let response = self.http_client.get(&self.url(path)).send().await?;
+        Self::response_text(response).await
+    }
+
+    /// POST requests change the server state and are sent only once
+    async fn post<P: Serialize, T: DeserializeOwned>(&self, path: &str, payload: &P) -> Result<T, CError> {
+        let value = self.post_raw(path, payload).await?;
+        Self::parse(path, &value)
+    }
+
+    async fn post_raw<P: Serialize>(&self, path: &str, payload: &P) -> Result<String, CError> {
+        let response = self.http_client.post(&self.url(path)).json(payload).send().await?;
+        Self::response_text(response).await
+    }
+
+    /// Each call creates a new token and invoice, so a lost response is not retried
+    pub async fn token_init(&self) -> Result<TokenInitResponsePayload, CError> {
+        self.get_once("token/token_init").await
+    }
+
+    pub async fn token_verify(&self, token_id: &str) -> Result<TokenVerifyResponsePayload, CError> {
+        self.get(&format!("token/token_verify/{}", token_id)).await
+    }
+
+    pub async fn deposit_init(&self, payload: &DepositRequestPayload) -> Result<DepositResponsePayload, CError> {
+        self.post("deposit/init/pod", payload).await
+    }
+
+    pub async fn deposit_cancel(&self, payload: &DepositCancelRequestPayload) -> Result<(), CError> {
+        self.post_raw("deposit/cancel", payload).await?;
+        Ok(())
+    }
+
+    pub async fn get_config(&self) -> Result<ServerConfig, CError> {
+        self.get("info/config").await
+    }
+
+    pub async fn get_statechain_info(&self, statechain_id: &str) -> Result<StatechainInfoResponsePayload, CError> {
+        self.get(&format!("info/statechain/{}", statechain_id)).await
+    }
+
+    pub async fn sign_first(&self, payload: &SignFirstRequestPayload) -> Result<SignFirstResponsePayload, CError> {
+        self.post("sign/first", payload).await
+    }
+
+    pub async fn sign_second(&self, payload: &PartialSignatureRequestPayload) -> Result<PartialSignatureResponsePayload, CError> {
+        self.post("sign/second", payload).await
+    }
+
+    pub async fn transfer_sender(&self, payload: &TransferSenderRequestPayload) -> Result<TransferSenderResponsePayload, CError> {
+        self.post("transfer/sender", payload).await
+    }
+
+    pub async fn transfer_update_msg(&self, payload: &TransferUpdateMsgRequestPayload) -> Result<(), CError> {
+        self.post_raw("transfer/update_msg", payload).await?;
+        Ok(())
+    }
+
+    pub async fn get_msg_addr(&self, auth_pubkey: &str) -> Result<GetMsgAddrResponsePayload, CError> {
+        self.get(&format!("transfer/get_msg_addr/{}", auth_pubkey)).await
+    }
+
+    pub async fn transfer_receiver(&self, payload: &TransferReceiverRequestPayload) -> Result<TransferReceiverResponsePayload, CError> {
+        self.post("transfer/receiver", payload).await
+    }
+
+    pub async fn recover_statechains(&self, payload: &RecoverStatechainsRequestPayload) -> Result<RecoverStatechainsResponsePayload, CError> {
+        self.post("recover/statechains", payload).await
+    }
+
+    pub async fn withdraw_complete(&self, payload: &WithdrawCompleteRequestPayload) -> Result<(), CError> {
+        self.post_raw("withdraw/complete", payload).await?;
+        Ok(())
+    }
+}
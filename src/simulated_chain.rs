@@ -0,0 +1,433 @@
+//! In-memory blockchain for deterministic tests.
+//! Keeps a UTXO set and a mempool, mines blocks on demand and checks transactions the way a node would:
+//! inputs must exist and be unspent, absolute and relative locktimes must be satisfied and scripts must verify.
+//! Enabled by the `simulated-chain` feature.
+
+use std::{collections::{HashMap, HashSet}, sync::Mutex};
+
+use async_trait::async_trait;
+use bitcoin::{Address, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness, absolute, secp256k1::{self, Secp256k1, XOnlyPublicKey}, sighash::{Prevouts, SighashCache}, taproot};
+
+use crate::{chain::{ChainBackend, AddressBalance, HistoryItem, Utxo, FALLBACK_FEE_RATE}, error::CError};
+
+const GENESIS_TIME: u32 = 1600000000;
+const BLOCK_INTERVAL: u32 = 600;
+
+/// Locktimes below this value are block heights, above it unix timestamps
+const LOCK_TIME_THRESHOLD: u32 = 500000000;
+
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+struct ChainState {
+    /// Timestamp of every block, indexed by height. The tip height is `len - 1`.
+    block_times: Vec<u32>,
+    /// Every transaction accepted by the chain with its confirmation height, 0 while in the mempool
+    transactions: HashMap<Txid, (Transaction, u32)>,
+    /// Unconfirmed transactions, parents before children
+    mempool: Vec<Txid>,
+    /// Confirmed unspent outputs with their confirmation height
+    utxos: HashMap<OutPoint, (TxOut, u32)>,
+    fee_rate: u64,
+    faucet_count: u32,
+}
+
+fn chain_error<E: ToString>(err: E) -> CError {
+    CError::Chain(err.to_string())
+}
+
+impl ChainState {
+
+    fn tip(&self) -> u32 {
+        (self.block_times.len() - 1) as u32
+    }
+
+    /// Median time of the 11 blocks ending at `height`
+    fn median_time_past(&self, height: u32) -> u32 {
+        let end = height as usize + 1;
+        let start = end.saturating_sub(11);
+        let mut times = self.block_times[start..end].to_vec();
+        times.sort();
+        times[times.len() / 2]
+    }
+
+    fn mempool_txs(&self) -> impl Iterator<Item = &Transaction> {
+        self.mempool.iter().map(|txid| &self.transactions[txid].0)
+    }
+
+    fn mempool_spends(&self) -> HashSet<OutPoint> {
+        self.mempool_txs().flat_map(|tx| tx.input.iter().map(|input| input.previous_output)).collect()
+    }
+
+    /// Output spent by `outpoint` and its confirmation height, 0 if it is in the mempool
+    fn find_prevout(&self, outpoint: &OutPoint) -> Option<(TxOut, u32)> {
+
+        if let Some(utxo) = self.utxos.get(outpoint) {
+            return Some(utxo.clone());
+        }
+
+        match self.transactions.get(&outpoint.txid) {
+            Some((tx, 0)) => tx.output.get(outpoint.vout as usize).map(|txout| (txout.clone(), 0)),
+            _ => None,
+        }
+    }
+
+    /// Output created by any known transaction, spent or not
+    fn find_output(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+        self.transactions.get(&outpoint.txid).and_then(|(tx, _)| tx.output.get(outpoint.vout as usize))
+    }
+
+    /// nLockTime rule: the transaction must be final in the next block
+    fn check_final(&self, tx: &Transaction) -> Result<(), CError> {
+
+        let lock_time = tx.lock_time.to_consensus_u32();
+
+        if lock_time == 0 || tx.input.iter().all(|input| input.sequence == Sequence::MAX) {
+            return Ok(());
+        }
+
+        let next_height = self.tip() + 1;
+
+        let is_final = if lock_time < LOCK_TIME_THRESHOLD {
+            lock_time < next_height
+        } else {
+            lock_time < self.median_time_past(self.tip())
+        };
+
+        if !is_final {
+            return Err(CError::Chain(format!("non-final transaction: locktime {}, next block height {}", lock_time, next_height)));
+        }
+
+        Ok(())
+    }
+
+    /// BIP68 rule: relative locktimes of version 2 transactions
+    fn check_sequence_lock(&self, tx: &Transaction, input: &TxIn, prevout_height: u32) -> Result<(), CError> {
+
+        let sequence = input.sequence.0;
+
+        if tx.version < 2 || sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return Ok(());
+        }
+
+        let next_height = self.tip() + 1;
+        // Outputs in the mempool count as confirmed in the next block
+        let coin_height = if prevout_height == 0 { next_height } else { prevout_height };
+        let value = sequence & SEQUENCE_LOCKTIME_MASK;
+
+        let is_locked = if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            let min_time = self.median_time_past(coin_height.saturating_sub(1)) + (value << SEQUENCE_LOCKTIME_GRANULARITY);
+            min_time > self.median_time_past(self.tip())
+        } else {
+            coin_height + value > next_height
+        };
+
+        if is_locked {
+            return Err(CError::Chain(format!("non-BIP68-final transaction: input {} is still locked", input.previous_output)));
+        }
+
+        Ok(())
+    }
+
+    fn accept(&mut self, tx: Transaction) -> Result<Txid, CError> {
+
+        let txid = tx.txid();
+
+        if self.transactions.contains_key(&txid) {
+            return Err(CError::Chain(format!("transaction {} already in the chain or the mempool", txid)));
+        }
+
+        if tx.input.is_empty() || tx.output.is_empty() {
+            return Err(CError::Chain("transaction has no inputs or no outputs".to_string()));
+        }
+
+        let mempool_spends = self.mempool_spends();
+        let mut prevouts = Vec::<TxOut>::new();
+
+        for input in &tx.input {
+
+            if mempool_spends.contains(&input.previous_output) {
+                return Err(CError::Chain(format!("input {} already spent by a mempool transaction", input.previous_output)));
+            }
+
+            let (prevout, prevout_height) = self.find_prevout(&input.previous_output)
+                .ok_or(CError::Chain(format!("input {} is missing or spent", input.previous_output)))?;
+
+            self.check_sequence_lock(&tx, input, prevout_height)?;
+
+            prevouts.push(prevout);
+        }
+
+        let input_amount: u64 = prevouts.iter().map(|prevout| prevout.value).sum();
+        let output_amount: u64 = tx.output.iter().map(|output| output.value).sum();
+
+        if output_amount > input_amount {
+            return Err(CError::Chain(format!("outputs {} exceed inputs {}", output_amount, input_amount)));
+        }
+
+        self.check_final(&tx)?;
+
+        let prevout_map: HashMap<OutPoint, TxOut> = tx.input.iter()
+            .map(|input| input.previous_output)
+            .zip(prevouts.iter().cloned())
+            .collect();
+
+        tx.verify(|outpoint| prevout_map.get(outpoint).cloned())
+            .map_err(|e| CError::Chain(format!("script verification failed: {}", e)))?;
+
+        verify_taproot_key_spends(&tx, &prevouts)?;
+
+        self.transactions.insert(txid, (tx, 0));
+        self.mempool.push(txid);
+
+        Ok(txid)
+    }
+
+    fn mine_block(&mut self) {
+
+        let height = self.tip() + 1;
+        self.block_times.push(GENESIS_TIME + BLOCK_INTERVAL * height);
+
+        for txid in std::mem::take(&mut self.mempool) {
+
+            let tx = match self.transactions.get_mut(&txid) {
+                Some((tx, tx_height)) => {
+                    *tx_height = height;
+                    tx.clone()
+                },
+                None => continue,
+            };
+
+            for input in &tx.input {
+                self.utxos.remove(&input.previous_output);
+            }
+
+            for (vout, output) in tx.output.iter().enumerate() {
+                self.utxos.insert(OutPoint { txid, vout: vout as u32 }, (output.clone(), height));
+            }
+        }
+    }
+
+    fn transaction_touches(&self, tx: &Transaction, script_pubkey: &ScriptBuf) -> bool {
+        tx.output.iter().any(|output| &output.script_pubkey == script_pubkey) ||
+            tx.input.iter().any(|input| {
+                self.find_output(&input.previous_output).map_or(false, |output| &output.script_pubkey == script_pubkey)
+            })
+    }
+}
+
+/// libbitcoinconsensus predates taproot, so the signatures of key path spends are checked here
+fn verify_taproot_key_spends(tx: &Transaction, prevouts: &[TxOut]) -> Result<(), CError> {
+
+    let secp = Secp256k1::verification_only();
+    let mut sighash_cache = SighashCache::new(tx);
+
+    for (index, (input, prevout)) in tx.input.iter().zip(prevouts).enumerate() {
+
+        // Script path spends have more than one witness element
+        if !prevout.script_pubkey.is_v1_p2tr() || input.witness.len() != 1 {
+            continue;
+        }
+
+        let signature_bytes = input.witness.nth(0).unwrap_or_default();
+        let signature = taproot::Signature::from_slice(signature_bytes)
+            .map_err(|e| CError::Chain(format!("input {} has an invalid signature encoding: {}", index, e)))?;
+
+        let output_key = XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..]).map_err(chain_error)?;
+
+        let sighash = sighash_cache.taproot_key_spend_signature_hash(index, &Prevouts::All(prevouts), signature.hash_ty)
+            .map_err(chain_error)?;
+
+        let msg: secp256k1::Message = sighash.into();
+
+        secp.verify_schnorr(&signature.sig, &msg, &output_key)
+            .map_err(|_| CError::Chain(format!("input {} has an invalid schnorr signature", index)))?;
+    }
+
+    Ok(())
+}
+
+/// Blockchain kept in memory. Starts with only the genesis block at height 0.
+pub struct SimulatedChain {
+    state: Mutex<ChainState>,
+}
+
+impl Default for SimulatedChain {
+    fn default() -> Self {
+        SimulatedChain::new()
+    }
+}
+
+impl SimulatedChain {
+
+    pub fn new() -> Self {
+        SimulatedChain {
+            state: Mutex::new(ChainState {
+                block_times: vec![GENESIS_TIME],
+                transactions: HashMap::new(),
+                mempool: Vec::new(),
+                utxos: HashMap::new(),
+                fee_rate: FALLBACK_FEE_RATE,
+                faucet_count: 0,
+            }),
+        }
+    }
+
+    /// Adds a transaction paying `amount` to `address` to the mempool, without inputs to validate
+    pub fn fund_address(&self, address: &Address, amount: u64) -> OutPoint {
+
+        let mut state = self.state.lock().unwrap();
+
+        state.faucet_count += 1;
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                // Makes every faucet transaction unique
+                script_sig: ScriptBuf::from(state.faucet_count.to_le_bytes().to_vec()),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut { value: amount, script_pubkey: address.script_pubkey() }],
+        };
+
+        let txid = tx.txid();
+
+        state.transactions.insert(txid, (tx, 0));
+        state.mempool.push(txid);
+
+        OutPoint { txid, vout: 0 }
+    }
+
+    /// Mines `blocks` blocks. The first one confirms the whole mempool.
+    pub fn mine(&self, blocks: u32) {
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..blocks {
+            state.mine_block();
+        }
+    }
+
+    pub fn set_fee_rate(&self, fee_rate: u64) {
+        self.state.lock().unwrap().fee_rate = fee_rate;
+    }
+
+    /// Confirmation height of a transaction, 0 if it is in the mempool and `None` if it is unknown
+    pub fn transaction_height(&self, txid: &Txid) -> Option<u32> {
+        self.state.lock().unwrap().transactions.get(txid).map(|(_, height)| *height)
+    }
+
+    pub fn mempool(&self) -> Vec<Txid> {
+        self.state.lock().unwrap().mempool.clone()
+    }
+
+    /// Removes an unconfirmed transaction as if the mempool had evicted it
+    pub fn evict(&self, txid: &Txid) {
+        let mut state = self.state.lock().unwrap();
+        if state.mempool.contains(txid) {
+            state.mempool.retain(|mempool_txid| mempool_txid != txid);
+            state.transactions.remove(txid);
+        }
+    }
+}
+
+#[async_trait]
+impl ChainBackend for SimulatedChain {
+
+    async fn get_address_balance(&self, address: &Address) -> Result<AddressBalance, CError> {
+
+        let state = self.state.lock().unwrap();
+        let script_pubkey = address.script_pubkey();
+
+        let confirmed: u64 = state.utxos.values()
+            .filter(|(output, _)| output.script_pubkey == script_pubkey)
+            .map(|(output, _)| output.value)
+            .sum();
+
+        // As in Electrum, the unconfirmed balance is what the mempool adds minus what it spends
+        let mut unconfirmed: i64 = 0;
+
+        for tx in state.mempool_txs() {
+            for output in tx.output.iter().filter(|output| output.script_pubkey == script_pubkey) {
+                unconfirmed += output.value as i64;
+            }
+            for input in &tx.input {
+                if let Some(output) = state.find_output(&input.previous_output) {
+                    if output.script_pubkey == script_pubkey {
+                        unconfirmed -= output.value as i64;
+                    }
+                }
+            }
+        }
+
+        Ok(AddressBalance { confirmed, unconfirmed })
+    }
+
+    async fn get_address_history(&self, address: &Address) -> Result<Vec<HistoryItem>, CError> {
+
+        let state = self.state.lock().unwrap();
+        let script_pubkey = address.script_pubkey();
+
+        let mut history: Vec<HistoryItem> = state.transactions.iter()
+            .filter(|(_, (tx, _))| state.transaction_touches(tx, &script_pubkey))
+            .map(|(txid, (_, height))| HistoryItem { txid: *txid, height: *height })
+            .collect();
+
+        // Confirmed transactions first, in block order
+        history.sort_by_key(|item| (item.height == 0, item.height, item.txid));
+
+        Ok(history)
+    }
+
+    async fn list_unspent(&self, address: &Address) -> Result<Vec<Utxo>, CError> {
+
+        let state = self.state.lock().unwrap();
+        let script_pubkey = address.script_pubkey();
+        let mempool_spends = state.mempool_spends();
+
+        let mut utxos: Vec<Utxo> = state.utxos.iter()
+            .filter(|(outpoint, (output, _))| output.script_pubkey == script_pubkey && !mempool_spends.contains(outpoint))
+            .map(|(outpoint, (output, height))| Utxo { txid: outpoint.txid, vout: outpoint.vout, value: output.value, height: *height })
+            .collect();
+
+        for tx in state.mempool_txs() {
+            let txid = tx.txid();
+            for (vout, output) in tx.output.iter().enumerate() {
+                let outpoint = OutPoint { txid, vout: vout as u32 };
+                if output.script_pubkey == script_pubkey && !mempool_spends.contains(&outpoint) {
+                    utxos.push(Utxo { txid, vout: vout as u32, value: output.value, height: 0 });
+                }
+            }
+        }
+
+        Ok(utxos)
+    }
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<Transaction, CError> {
+        let state = self.state.lock().unwrap();
+        state.transactions.get(txid)
+            .map(|(tx, _)| tx.clone())
+            .ok_or(CError::Chain(format!("transaction {} not found", txid)))
+    }
+
+    async fn find_transaction(&self, txid: &Txid) -> Result<Option<Transaction>, CError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.transactions.get(txid).map(|(tx, _)| tx.clone()))
+    }
+
+    async fn broadcast(&self, raw_tx: &[u8]) -> Result<Txid, CError> {
+        let tx: Transaction = bitcoin::consensus::deserialize(raw_tx).map_err(chain_error)?;
+        self.state.lock().unwrap().accept(tx)
+    }
+
+    async fn get_tip_height(&self) -> Result<u32, CError> {
+        Ok(self.state.lock().unwrap().tip())
+    }
+
+    async fn estimate_fee_rate(&self, _blocks: usize) -> Result<u64, CError> {
+        Ok(self.state.lock().unwrap().fee_rate)
+    }
+}
@@ -0,0 +1,118 @@
+use std::str::FromStr;
+
+use bitcoin::{Network, Transaction, Txid};
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, Row};
+
+use crate::{chain::{self, ChainBackend}, error::CError, transaction, wallet::{self, StatecoinStatus}};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatecoinInfo {
+    pub statechain_id: String,
+    pub amount: u64,
+    pub status: Option<StatecoinStatus>,
+    pub funding_txid: Option<Txid>,
+    pub funding_vout: Option<u32>,
+    /// Confirmations of the deposit transaction, `None` if the blockchain backend does not know it
+    pub confirmations: Option<u32>,
+    pub backup_tx_count: usize,
+    /// Locktime of the latest backup transaction
+    pub backup_tx_lock_time: Option<u32>,
+    /// Blocks left until the latest backup transaction can be broadcast, 0 if it already can
+    pub blocks_until_backup_valid: Option<u32>,
+    pub transfer_address: Option<String>,
+}
+
+/// Every statecoin of the wallet, including the ones sent, withdrawn or closed, oldest first
+pub async fn list(pool: &sqlx::Pool<Sqlite>, chain: &dyn ChainBackend, network: Network) -> Result<Vec<StatecoinInfo>, CError> {
+
+    let query = "\
+        SELECT statechain_id, amount, status, funding_txid, funding_vout, p2tr_agg_address, transfer_address \
+        FROM signer_data \
+        WHERE statechain_id IS NOT NULL \
+        ORDER BY created_at";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await?;
+
+    let tip_height = chain.get_tip_height().await?;
+
+    let mut statecoins = Vec::<StatecoinInfo>::new();
+
+    for row in rows {
+        statecoins.push(row_to_info(pool, chain, &row, tip_height, network).await?);
+    }
+
+    Ok(statecoins)
+}
+
+pub async fn info(pool: &sqlx::Pool<Sqlite>, chain: &dyn ChainBackend, statechain_id: &str, network: Network) -> Result<StatecoinInfo, CError> {
+
+    let query = "\
+        SELECT statechain_id, amount, status, funding_txid, funding_vout, p2tr_agg_address, transfer_address \
+        FROM signer_data \
+        WHERE statechain_id = $1";
+
+    let row = sqlx::query(query)
+        .bind(statechain_id)
+        .fetch_optional(pool)
+        .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Err(CError::UserInput(format!("Statecoin {} not found", statechain_id))),
+    };
+
+    let tip_height = chain.get_tip_height().await?;
+
+    row_to_info(pool, chain, &row, tip_height, network).await
+}
+
+async fn row_to_info(pool: &sqlx::Pool<Sqlite>, chain: &dyn ChainBackend, row: &sqlx::sqlite::SqliteRow, tip_height: u32, network: Network) -> Result<StatecoinInfo, CError> {
+
+    let statechain_id = row.try_get::<String, _>("statechain_id")?;
+
+    let funding_txid = row.try_get::<Option<String>, _>("funding_txid")?
+        .map(|txid| Txid::from_str(&txid).map_err(|e| CError::Database(e.to_string())))
+        .transpose()?;
+
+    let status = row.try_get::<Option<String>, _>("status")?
+        .map(|status| StatecoinStatus::from_str(&status))
+        .transpose()?;
+
+    // The history still holds the deposit transaction once the funding output is spent
+    let confirmations = match (funding_txid, row.try_get::<Option<String>, _>("p2tr_agg_address")?) {
+        (Some(funding_txid), Some(address)) => {
+            let address = wallet::parse_address(&address, network)?;
+            chain.get_address_history(&address).await?.iter()
+                .find(|item| item.txid == funding_txid)
+                .map(|item| chain::confirmations(tip_height, item.height))
+        },
+        _ => None,
+    };
+
+    let backup_txs = transaction::get_backup_transactions(pool, &statechain_id).await?;
+
+    let backup_tx_lock_time = match backup_txs.last() {
+        Some(backup_tx) => {
+            let tx: Transaction = bitcoin::consensus::deserialize(&hex::decode(&backup_tx.tx)?)?;
+            Some(tx.lock_time.to_consensus_u32())
+        },
+        None => None,
+    };
+
+    Ok(StatecoinInfo {
+        statechain_id,
+        amount: row.try_get::<i64, _>("amount")? as u64,
+        status,
+        funding_txid,
+        funding_vout: row.try_get::<Option<u32>, _>("funding_vout")?,
+        confirmations,
+        backup_tx_count: backup_txs.len(),
+        backup_tx_lock_time,
+        // A transaction is final once its locktime is below the height of the next block
+        blocks_until_backup_valid: backup_tx_lock_time.map(|lock_time| lock_time.saturating_sub(tip_height)),
+        transfer_address: row.try_get::<Option<String>, _>("transfer_address")?,
+    })
+}
@@ -0,0 +1,171 @@
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, Row};
+
+use crate::{error::CError, server::StatechainServer};
+
+/// Interval between two payment checks while waiting for a token to be paid
+const PAYMENT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Deposit token bought from the statechain server. Every deposit consumes one paid token.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Token {
+    pub token_id: uuid::Uuid,
+    /// Price of the token in satoshis. The payment details are unknown for tokens not requested by this wallet.
+    pub fee: Option<u64>,
+    pub lightning_invoice: Option<String>,
+    pub btc_payment_address: Option<String>,
+    /// The token has been paid
+    pub confirmed: bool,
+    /// The token has been used by a deposit
+    pub spent: bool,
+}
+
+fn parse_token_id(token_id: &str) -> Result<uuid::Uuid, CError> {
+    uuid::Uuid::parse_str(token_id).map_err(|e| CError::Protocol(format!("Invalid token id {}: {}", token_id, e)))
+}
+
+/// Requests a new token from the server. It must be paid with the returned invoice or address before it can be used.
+pub async fn new_token(pool: &sqlx::Pool<Sqlite>, server: &StatechainServer) -> Result<Token, CError> {
+
+    let response = server.token_init().await?;
+
+    let token = Token {
+        token_id: parse_token_id(&response.token_id)?,
+        fee: Some(response.fee),
+        lightning_invoice: Some(response.lightning_invoice),
+        btc_payment_address: Some(response.btc_payment_address),
+        confirmed: false,
+        spent: false,
+    };
+
+    let query = "\
+        INSERT INTO token (token_id, fee, lightning_invoice, btc_payment_address, confirmed, spent) \
+        VALUES ($1, $2, $3, $4, $5, $6)";
+
+    let _ = sqlx::query(query)
+        .bind(token.token_id.to_string())
+        .bind(token.fee.map(|fee| fee as i64))
+        .bind(&token.lightning_invoice)
+        .bind(&token.btc_payment_address)
+        .bind(token.confirmed)
+        .bind(token.spent)
+        .execute(pool)
+        .await?;
+
+    Ok(token)
+}
+
+pub async fn get_token(pool: &sqlx::Pool<Sqlite>, token_id: &uuid::Uuid) -> Result<Option<Token>, CError> {
+
+    let query = "\
+        SELECT token_id, fee, lightning_invoice, btc_payment_address, confirmed, spent \
+        FROM token \
+        WHERE token_id = $1";
+
+    let row = sqlx::query(query)
+        .bind(token_id.to_string())
+        .fetch_optional(pool)
+        .await?;
+
+    match row {
+        Some(row) => Ok(Some(row_to_token(&row)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn list_tokens(pool: &sqlx::Pool<Sqlite>) -> Result<Vec<Token>, CError> {
+
+    let query = "\
+        SELECT token_id, fee, lightning_invoice, btc_payment_address, confirmed, spent \
+        FROM token \
+        ORDER BY created_at";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await?;
+
+    rows.iter().map(row_to_token).collect()
+}
+
+fn row_to_token(row: &sqlx::sqlite::SqliteRow) -> Result<Token, CError> {
+    Ok(Token {
+        token_id: parse_token_id(&row.try_get::<String, _>("token_id")?)?,
+        fee: row.try_get::<Option<i64>, _>("fee")?.map(|fee| fee as u64),
+        lightning_invoice: row.try_get::<Option<String>, _>("lightning_invoice")?,
+        btc_payment_address: row.try_get::<Option<String>, _>("btc_payment_address")?,
+        confirmed: row.try_get::<bool, _>("confirmed")?,
+        spent: row.try_get::<bool, _>("spent")?,
+    })
+}
+
+/// Stores the status of the token reported by the server. Tokens obtained outside the wallet are added to the table.
+async fn update_token_status(pool: &sqlx::Pool<Sqlite>, token_id: &uuid::Uuid, confirmed: bool, spent: bool) -> Result<(), CError> {
+
+    let query = "\
+        INSERT INTO token (token_id, confirmed, spent) \
+        VALUES ($1, $2, $3) \
+        ON CONFLICT (token_id) DO UPDATE SET confirmed = excluded.confirmed, spent = excluded.spent";
+
+    let _ = sqlx::query(query)
+        .bind(token_id.to_string())
+        .bind(confirmed)
+        .bind(spent)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Polls the server until the token is paid, for at most `timeout`
+pub async fn wait_for_payment(pool: &sqlx::Pool<Sqlite>, server: &StatechainServer, token_id: &uuid::Uuid, timeout: Duration) -> Result<(), CError> {
+
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let status = server.token_verify(&token_id.to_string()).await?;
+
+        update_token_status(pool, token_id, status.confirmed, status.spent).await?;
+
+        if status.confirmed {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+
+        if now >= deadline {
+            return Err(CError::Timeout(format!("Token {} was not paid after {} seconds", token_id, timeout.as_secs())));
+        }
+
+        tokio::time::sleep(PAYMENT_POLL_INTERVAL.min(deadline - now)).await;
+    }
+}
+
+/// Checks with the server that the token is paid and has not been used by another deposit
+pub async fn validate_unspent(pool: &sqlx::Pool<Sqlite>, server: &StatechainServer, token_id: &uuid::Uuid) -> Result<(), CError> {
+
+    if let Some(token) = get_token(pool, token_id).await? {
+        if token.spent {
+            return Err(CError::UserInput(format!("Token {} has already been used", token_id)));
+        }
+    }
+
+    let status = server.token_verify(&token_id.to_string()).await?;
+
+    update_token_status(pool, token_id, status.confirmed, status.spent).await?;
+
+    if status.spent {
+        return Err(CError::UserInput(format!("Token {} has already been used", token_id)));
+    }
+
+    if !status.confirmed {
+        return Err(CError::UserInput(format!("Token {} has not been paid", token_id)));
+    }
+
+    Ok(())
+}
+
+pub async fn mark_spent(pool: &sqlx::Pool<Sqlite>, token_id: &uuid::Uuid) -> Result<(), CError> {
+    update_token_status(pool, token_id, true, true).await
+}
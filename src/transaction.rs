@@ -5,53 +5,130 @@ use secp256k1_zkp::{SecretKey, PublicKey, XOnlyPublicKey, Secp256k1, schnorr::Si
 use serde::{Serialize, Deserialize};
 use sqlx::{Sqlite, Row};
 
-use crate::error::CError;
+use crate::{chain::ChainBackend, error::CError, server::{StatechainServer, SignFirstRequestPayload, PartialSignatureRequestPayload}};
 
-async fn count_backup_tx(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> u32 {
+/// Value of the anchor output of backup transactions, the dust limit of a P2TR output.
+/// It pays to the owner's backup address so the owner can bump the fee with a CPFP child.
+pub const ANCHOR_VALUE: u64 = 330;
+
+/// Outputs of a backup transaction paying `output`. With `anchor`, the anchor output is split off `output`.
+pub fn with_anchor(output: TxOut, anchor: bool) -> Result<Vec<TxOut>, CError> {
+
+    if !anchor {
+        return Ok(vec![output]);
+    }
+
+    if output.value <= 2 * ANCHOR_VALUE {
+        return Err(CError::UserInput(format!("Backup output of {} sats is too small for an anchor output", output.value)));
+    }
+
+    let anchor_output = TxOut { value: ANCHOR_VALUE, script_pubkey: output.script_pubkey.clone() };
+
+    Ok(vec![TxOut { value: output.value - ANCHOR_VALUE, script_pubkey: output.script_pubkey }, anchor_output])
+}
+
+/// Index of the anchor output of a backup transaction paying to `script_pubkey`, if it has one
+pub fn anchor_vout(tx: &Transaction, script_pubkey: &ScriptBuf) -> Option<u32> {
+    if tx.output.len() != 2 {
+        return None;
+    }
+    let anchor = &tx.output[1];
+    if anchor.value == ANCHOR_VALUE && anchor.script_pubkey == *script_pubkey {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+/// Virtual size of a transaction spending `input` with a key-path signature, computed with a dummy signature in place
+pub fn estimate_vsize(input: OutPoint, outputs: &[TxOut]) -> u64 {
+
+    let mut witness = Witness::new();
+    witness.push([0u8; 64]);
+
+    let tx = Transaction {
+        version: 2,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: input,
+            script_sig: ScriptBuf::new(),
+            sequence: bitcoin::Sequence::ENABLE_LOCKTIME_NO_RBF,
+            witness,
+        }],
+        output: outputs.to_vec(),
+    };
+
+    tx.vsize() as u64
+}
+
+async fn count_backup_tx(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Result<u32, CError> {
 
     let row = sqlx::query("SELECT count(*) FROM backup_transaction WHERE statechain_id = $1")
         .bind(statechain_id)
         .fetch_one(pool)
-        .await
-        .unwrap();
+        .await?;
 
-    let count = row.get::<u32, _>(0);
+    let count = row.try_get::<u32, _>(0)?;
 
-    count
+    Ok(count)
 }
 
-pub async fn new(pool: &sqlx::Pool<Sqlite>, statechain_id: &str,) -> Result<(), CError> {
-    let endpoint = "http://127.0.0.1:8000";
-    let path = "info/config";
+async fn get_first_backup_tx_lock_time(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Result<Option<u32>, CError> {
 
-    let client: reqwest::Client = reqwest::Client::new();
-    let request = client.get(&format!("{}/{}", endpoint, path));
+    let query = "\
+        SELECT backup_tx \
+        FROM backup_transaction \
+        WHERE tx_n = (SELECT MIN(tx_n) FROM backup_transaction WHERE statechain_id = $1)
+        AND statechain_id = $1";
 
-    let value = match request.send().await {
-        Ok(response) => {
-            let text = response.text().await.unwrap();
-            text
-        },
-        Err(err) => {
-            return Err(CError::Generic(err.to_string()));
+    let row = sqlx::query(query)
+        .bind(statechain_id)
+        .fetch_optional(pool)
+        .await?;
+
+    match row {
+        Some(row) => {
+            let tx_bytes = row.try_get::<Vec<u8>, _>("backup_tx")?;
+            let tx: Transaction = bitcoin::consensus::deserialize(&tx_bytes)?;
+            Ok(Some(tx.lock_time.to_consensus_u32()))
         },
-    };
+        None => Ok(None),
+    }
+}
 
-    let value: serde_json::Value = serde_json::from_str(value.as_str()).expect(&format!("failed to parse: {}", value.as_str()));
+/// Returns the locktime of the next backup transaction of the statecoin.
+/// The first backup transaction is locked `initlock` blocks above the current height and
+/// every following one `interval` blocks below the first, so that the newest owner can always broadcast first.
+pub async fn get_new_block_height(pool: &sqlx::Pool<Sqlite>, chain: &dyn ChainBackend, server: &StatechainServer, statechain_id: &str) -> Result<u32, CError> {
 
-    let initlock = value.get("initlock").unwrap().as_u64().unwrap();
-    let interval = value.get("interval").unwrap().as_u64().unwrap();
-    let qt_backup_tx = count_backup_tx(pool, statechain_id).await;
+    let server_config = server.get_config().await?;
 
-    println!("initlock {}", initlock);
-    println!("interval {}", interval);
-    println!("qt_backup_tx {}", qt_backup_tx);
+    let initlock = server_config.initlock;
+    let interval = server_config.interval;
+    let qt_backup_tx = count_backup_tx(pool, statechain_id).await?;
 
-    Ok(())
+    let current_block_height = chain.get_tip_height().await?;
 
+    let block_height = match get_first_backup_tx_lock_time(pool, statechain_id).await? {
+        None => current_block_height + initlock,
+        Some(first_lock_time) => {
+            let decrement = interval * qt_backup_tx;
+            if decrement >= first_lock_time {
+                return Err(CError::Protocol("Backup transaction locktime cannot be decremented any further".to_string()));
+            }
+            first_lock_time - decrement
+        },
+    };
+
+    if block_height <= current_block_height {
+        return Err(CError::Protocol(format!("New backup transaction locktime {} is not above the current block height {}", block_height, current_block_height)));
+    }
+
+    Ok(block_height)
 }
 
 pub async fn create(
+    server: &StatechainServer,
     block_height: u32,
     statechain_id: &str,
     signed_statechain_id: &Signature,
@@ -63,11 +140,13 @@ pub async fn create(
     input_pubkey: &XOnlyPublicKey, 
     input_scriptpubkey: &ScriptBuf, 
     input_amount: u64, 
-    output: TxOut) -> Result<(Transaction, MusigPubNonce, BlindingFactor), Box<dyn std::error::Error>> {
+    output: TxOut,
+    anchor: bool) -> Result<(Transaction, MusigPubNonce, BlindingFactor), CError> {
 
-    let outputs = [output].to_vec();
+    let outputs = with_anchor(output, anchor)?;
 
-    let lock_time = absolute::LockTime::from_height(block_height).expect("valid height");
+    let lock_time = absolute::LockTime::from_height(block_height)
+        .map_err(|e| CError::Protocol(format!("Invalid locktime {}: {}", block_height, e)))?;
 
     let tx1 = Transaction {
         version: 2,
@@ -75,18 +154,18 @@ pub async fn create(
         input: vec![TxIn {
             previous_output: OutPoint { txid: input_txid, vout: input_vout },
             script_sig: ScriptBuf::new(),
-            sequence: bitcoin::Sequence(0xFFFFFFFF), // Ignore nSequence.
+            sequence: bitcoin::Sequence::ENABLE_LOCKTIME_NO_RBF, // nLockTime is only enforced if an input sequence is not final
             witness: Witness::default(),
         }],
         output: outputs,
     };
-    let mut psbt = Psbt::from_unsigned_tx(tx1)?;
+    let mut psbt = Psbt::from_unsigned_tx(tx1).map_err(|e| CError::Protocol(e.to_string()))?;
 
     let mut input = Input {
         witness_utxo: Some(TxOut { value: input_amount, script_pubkey: input_scriptpubkey.to_owned() }),
         ..Default::default()
     };
-    let ty = PsbtSighashType::from_str("SIGHASH_ALL")?;
+    let ty = PsbtSighashType::from_str("SIGHASH_ALL").map_err(|e| CError::Protocol(e.to_string()))?;
     input.sighash_type = Some(ty);
     input.tap_internal_key = Some(input_pubkey.to_owned());
     psbt.inputs = vec![input];
@@ -95,50 +174,50 @@ pub async fn create(
     
     let unsigned_tx = psbt.unsigned_tx.clone();
 
-    // There must not be more than one input.
-    // The input is the funding transaction and the output the backup address.
-    // If there is more than one input, the UPDATE command below will rewrite the client_sec_nonce and blinding_factor.
-    assert!(psbt.inputs.len() == 1);
-
-    // for (vout, input) in psbt.inputs.iter_mut().enumerate() {
-
-        let vout = 0;
-        let input = psbt.inputs.iter_mut().nth(vout).unwrap();
-
-        let hash_ty = input
-            .sighash_type
-            .and_then(|psbt_sighash_type| psbt_sighash_type.taproot_hash_ty().ok())
-            .unwrap_or(TapSighashType::All);
-
-        let hash = SighashCache::new(&unsigned_tx).taproot_key_spend_signature_hash(
-            vout,
-            &sighash::Prevouts::All(&[TxOut {
-                value: input.witness_utxo.as_ref().unwrap().value,
-                script_pubkey: input.witness_utxo.as_ref().unwrap().script_pubkey.clone(),
-            }]),
-            hash_ty,
-        ).unwrap();
-
-        let (sig, client_pub_nonce, blinding_factor) = musig_sign_psbt_taproot(
-            statechain_id,
-            signed_statechain_id,
-            client_seckey,
-            client_pubkey,
-            server_pubkey,
-            input_pubkey,
-            hash,
-            &secp,
-        ).await.unwrap();
-
-        let final_signature = taproot::Signature { sig, hash_ty };
-
-        input.tap_key_sig = Some(final_signature);
-    // }
+    // The only input is the funding output, the outputs pay the backup or withdrawal address
+    if psbt.inputs.len() != 1 {
+        return Err(CError::Protocol(format!("Expected a single input, found {}", psbt.inputs.len())));
+    }
+
+    let vout = 0;
+    let input = &mut psbt.inputs[vout];
+
+    let hash_ty = input
+        .sighash_type
+        .and_then(|psbt_sighash_type| psbt_sighash_type.taproot_hash_ty().ok())
+        .unwrap_or(TapSighashType::All);
+
+    let hash = SighashCache::new(&unsigned_tx).taproot_key_spend_signature_hash(
+        vout,
+        &sighash::Prevouts::All(&[TxOut {
+            value: input_amount,
+            script_pubkey: input_scriptpubkey.to_owned(),
+        }]),
+        hash_ty,
+    ).map_err(|e| CError::Protocol(e.to_string()))?;
+
+    let (sig, client_pub_nonce, blinding_factor) = musig_sign_psbt_taproot(
+        server,
+        statechain_id,
+        signed_statechain_id,
+        client_seckey,
+        client_pubkey,
+        server_pubkey,
+        input_pubkey,
+        hash,
+        &secp,
+    ).await?;
+
+    let final_signature = taproot::Signature { sig, hash_ty };
+
+    input.tap_key_sig = Some(final_signature);
 
     // FINALIZER
     psbt.inputs.iter_mut().for_each(|input| {
         let mut script_witness: Witness = Witness::new();
-        script_witness.push(input.tap_key_sig.unwrap().to_vec());
+        if let Some(tap_key_sig) = input.tap_key_sig {
+            script_witness.push(tap_key_sig.to_vec());
+        }
         input.final_script_witness = Some(script_witness);
 
         // Clear all the data fields as per the spec.
@@ -157,59 +236,14 @@ pub async fn create(
             script_pubkey: input_scriptpubkey.to_owned(),
         })
     })
-    .expect("failed to verify transaction");
+    .map_err(|e| CError::Protocol(format!("failed to verify transaction: {}", e)))?;
 
 
     Ok((tx, client_pub_nonce, blinding_factor))
 }
 
-
-#[derive(Serialize, Deserialize)]
-pub struct SignFirstRequestPayload<'r> {
-    statechain_id: &'r str,
-    r2_commitment: &'r str,
-    blind_commitment: &'r str,
-    signed_statechain_id: &'r str,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ServerPublicNonceResponsePayload<'r> {
-    server_pubnonce: &'r str,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct PartialSignatureRequestPayload<'r> {
-    statechain_id: &'r str,
-    keyaggcoef: &'r str,
-    negate_seckey: u8,
-    session: &'r str,
-    signed_statechain_id: &'r str,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct PartialSignatureResponsePayload<'r> {
-    partial_sig: &'r str,
-}
-
-/*
-async fn update_commitments(pool: &sqlx::Pool<Sqlite>, client_sec_nonce: &[u8; 132], blinding_factor: &[u8; 32], client_pubkey: &PublicKey) {
-
-    let query = "\
-        UPDATE signer_data \
-        SET client_sec_nonce = $1, blinding_factor = $2 \
-        WHERE client_pubkey_share = $3";
-
-    let _ = sqlx::query(query)
-        .bind(client_sec_nonce.to_vec())
-        .bind(blinding_factor.to_vec())
-        .bind(&client_pubkey.serialize().to_vec())
-        .execute(pool)
-        .await
-        .unwrap();
-}
- */
-
 async fn musig_sign_psbt_taproot(
+    server: &StatechainServer,
     statechain_id: &str,
     signed_statechain_id: &Signature,
     client_seckey: &SecretKey,
@@ -223,39 +257,22 @@ async fn musig_sign_psbt_taproot(
 
     let client_session_id = MusigSessionId::new(&mut rand::thread_rng());
 
-    let (client_sec_nonce, client_pub_nonce) = new_musig_nonce_pair(&secp, client_session_id, None, Some(client_seckey.to_owned()), client_pubkey.to_owned(), None, None).unwrap();
+    let (client_sec_nonce, client_pub_nonce) = new_musig_nonce_pair(&secp, client_session_id, None, Some(client_seckey.to_owned()), client_pubkey.to_owned(), None, None)
+        .map_err(|e| CError::KeyDerivation(e.to_string()))?;
 
     let r2_commitment = sha256::Hash::hash(&client_sec_nonce.serialize());
 
     let blinding_factor = BlindingFactor::new(&mut rand::thread_rng());
     let blind_commitment = sha256::Hash::hash(blinding_factor.as_bytes());
 
-    // update_commitments(pool, &client_sec_nonce.serialize(), blinding_factor.as_bytes(), client_pubkey).await;
-
-    let endpoint = "http://127.0.0.1:8000";
-    let path = "sign/first";
-
-    let client: reqwest::Client = reqwest::Client::new();
-    let request = client.post(&format!("{}/{}", endpoint, path));
-
     let sign_first_request_payload = SignFirstRequestPayload {
-        statechain_id,
-        r2_commitment: &r2_commitment.to_string(),
-        blind_commitment: &blind_commitment.to_string(),
-        signed_statechain_id: &signed_statechain_id.to_string(),
+        statechain_id: statechain_id.to_string(),
+        r2_commitment: r2_commitment.to_string(),
+        blind_commitment: blind_commitment.to_string(),
+        signed_statechain_id: signed_statechain_id.to_string(),
     };
 
-    let value = match request.json(&sign_first_request_payload).send().await {
-        Ok(response) => {
-            let text = response.text().await.unwrap();
-            text
-        },
-        Err(err) => {
-            return Err(CError::Generic(err.to_string()));
-        },
-    };
-
-    let response: ServerPublicNonceResponsePayload = serde_json::from_str(value.as_str()).expect(&format!("failed to parse: {}", value.as_str()));
+    let response = server.sign_first(&sign_first_request_payload).await?;
 
     let mut server_pubnonce_hex = response.server_pubnonce.to_string();
 
@@ -263,9 +280,10 @@ async fn musig_sign_psbt_taproot(
         server_pubnonce_hex = server_pubnonce_hex[2..].to_string();
     }
 
-    let server_pub_nonce_bytes = hex::decode(server_pubnonce_hex).unwrap();
+    let server_pub_nonce_bytes = hex::decode(server_pubnonce_hex)?;
     
-    let server_pub_nonce = MusigPubNonce::from_slice(server_pub_nonce_bytes.as_slice()).unwrap();
+    let server_pub_nonce = MusigPubNonce::from_slice(server_pub_nonce_bytes.as_slice())
+        .map_err(|e| CError::Protocol(format!("Invalid server public nonce: {}", e)))?;
 
     let mut key_agg_cache = MusigKeyAggCache::new(&secp, &[client_pubkey.to_owned(), server_pubkey.to_owned()]);
 
@@ -273,9 +291,10 @@ async fn musig_sign_psbt_taproot(
     let tap_tweak_bytes = tap_tweak.as_byte_array();
 
     // tranform tweak: Scalar to SecretKey
-    let tweak = SecretKey::from_slice(tap_tweak_bytes).unwrap();
+    let tweak = SecretKey::from_slice(tap_tweak_bytes)?;
 
-    let tweaked_pubkey = key_agg_cache.pubkey_xonly_tweak_add(secp, tweak).unwrap();
+    let tweaked_pubkey = key_agg_cache.pubkey_xonly_tweak_add(secp, tweak)
+        .map_err(|e| CError::KeyDerivation(e.to_string()))?;
 
     let aggnonce = MusigAggNonce::new(&secp, &[client_pub_nonce, server_pub_nonce]);
 
@@ -294,15 +313,17 @@ async fn musig_sign_psbt_taproot(
         client_sec_nonce,
         &client_keypair,
         &key_agg_cache,
-    ).unwrap();
+    ).map_err(|e| CError::KeyDerivation(e.to_string()))?;
 
-    assert!(session.partial_verify(
+    if !session.partial_verify(
         &secp,
         &key_agg_cache,
         client_partial_sig,
         client_pub_nonce,
         client_pubkey.to_owned(),
-    ));
+    ) {
+        return Err(CError::KeyDerivation("Invalid client partial signature".to_string()));
+    }
 
     let (key_agg_coef, negate_seckey) = session.get_keyaggcoef_and_negation_seckey(&secp, &key_agg_cache, &server_pubkey);
 
@@ -312,30 +333,14 @@ async fn musig_sign_psbt_taproot(
     };
 
     let payload = PartialSignatureRequestPayload {
-        statechain_id,
-        keyaggcoef: &hex::encode(key_agg_coef.serialize()),
+        statechain_id: statechain_id.to_string(),
+        keyaggcoef: hex::encode(key_agg_coef.serialize()),
         negate_seckey,
-        session: &hex::encode(session.serialize()),
-        signed_statechain_id: &signed_statechain_id.to_string(),
-    };
-
-    let endpoint = "http://127.0.0.1:8000";
-    let path = "sign/second";
-
-    let client: reqwest::Client = reqwest::Client::new();
-    let request = client.post(&format!("{}/{}", endpoint, path));
-
-    let value = match request.json(&payload).send().await {
-        Ok(response) => {
-            let text = response.text().await.unwrap();
-            text
-        },
-        Err(err) => {
-            return Err(CError::Generic(err.to_string()));
-        },
+        session: hex::encode(session.serialize()),
+        signed_statechain_id: signed_statechain_id.to_string(),
     };
 
-    let response: PartialSignatureResponsePayload = serde_json::from_str(value.as_str()).expect(&format!("failed to parse: {}", value.as_str()));
+    let response = server.sign_second(&payload).await?;
 
     let mut server_partial_sig_hex = response.partial_sig.to_string();
 
@@ -343,39 +348,43 @@ async fn musig_sign_psbt_taproot(
         server_partial_sig_hex = server_partial_sig_hex[2..].to_string();
     }
 
-    let server_partial_sig_bytes = hex::decode(server_partial_sig_hex).unwrap();
+    let server_partial_sig_bytes = hex::decode(server_partial_sig_hex)?;
 
-    let server_partial_sig = MusigPartialSignature::from_slice(server_partial_sig_bytes.as_slice()).unwrap();
+    let server_partial_sig = MusigPartialSignature::from_slice(server_partial_sig_bytes.as_slice())
+        .map_err(|e| CError::Protocol(format!("Invalid server partial signature: {}", e)))?;
 
-    assert!(session.partial_verify(
+    if !session.partial_verify(
         &secp,
         &key_agg_cache,
         server_partial_sig,
         server_pub_nonce,
         server_pubkey.to_owned(),
-    ));
+    ) {
+        return Err(CError::Protocol("Server partial signature does not verify".to_string()));
+    }
 
     let sig = session.partial_sig_agg(&[client_partial_sig, server_partial_sig]);
     let agg_pk = key_agg_cache.agg_pk();
 
-    assert!(agg_pk.eq(aggregated_pubkey));
+    if !agg_pk.eq(aggregated_pubkey) {
+        return Err(CError::Protocol("Key shares do not aggregate to the statecoin public key".to_string()));
+    }
 
-    assert!(secp.verify_schnorr(&sig, &msg, &tweaked_pubkey.x_only_public_key().0).is_ok());
+    if secp.verify_schnorr(&sig, &msg, &tweaked_pubkey.x_only_public_key().0).is_err() {
+        return Err(CError::Protocol("Aggregated signature does not verify".to_string()));
+    }
    
     Ok((sig, client_pub_nonce, blinding_factor))
 }
 
-pub async fn insert_transaction(pool: &sqlx::Pool<Sqlite>, tx_bytes: &Vec<u8>, client_pub_nonce: &[u8; 66], blinding_factor: &[u8; 32], statechain_id: &str) { 
+pub async fn insert_transaction(pool: &sqlx::Pool<Sqlite>, tx_bytes: &Vec<u8>, client_pub_nonce: &[u8; 66], blinding_factor: &[u8; 32], statechain_id: &str) -> Result<(), CError> { 
 
     let row = sqlx::query("SELECT MAX(tx_n) FROM backup_transaction WHERE statechain_id = $1")
         .bind(statechain_id)
         .fetch_one(pool)
-        .await
-        .unwrap();
+        .await?;
 
-    let mut tx_n = row.get::<u32, _>(0);
-
-    tx_n = tx_n + 1;
+    let tx_n = row.try_get::<Option<u32>, _>(0)?.unwrap_or(0) + 1;
 
     let query = "INSERT INTO backup_transaction (tx_n, statechain_id, client_public_nonce, blinding_factor, backup_tx) \
         VALUES ($1, $2, $3, $4, $5)";
@@ -386,7 +395,83 @@ pub async fn insert_transaction(pool: &sqlx::Pool<Sqlite>, tx_bytes: &Vec<u8>, c
             .bind(blinding_factor.to_vec())
             .bind(tx_bytes)
             .execute(pool)
-            .await
-            .unwrap();
+            .await?;
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupTx {
+    pub tx_n: u32,
+    pub tx: String,
+    pub client_public_nonce: String,
+    pub blinding_factor: String,
+}
+
+pub async fn get_backup_transactions(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Result<Vec<BackupTx>, CError> {
+
+    let query = "\
+        SELECT tx_n, client_public_nonce, blinding_factor, backup_tx \
+        FROM backup_transaction \
+        WHERE statechain_id = $1 \
+        ORDER BY tx_n ASC";
+
+    let rows = sqlx::query(query)
+        .bind(statechain_id)
+        .fetch_all(pool)
+        .await?;
+
+    let mut backup_txs = Vec::<BackupTx>::new();
+
+    for row in rows {
+        backup_txs.push(BackupTx {
+            tx_n: row.try_get::<u32, _>("tx_n")?,
+            tx: hex::encode(row.try_get::<Vec<u8>, _>("backup_tx")?),
+            client_public_nonce: hex::encode(row.try_get::<Vec<u8>, _>("client_public_nonce")?),
+            blinding_factor: hex::encode(row.try_get::<Vec<u8>, _>("blinding_factor")?),
+        });
+    }
+
+    Ok(backup_txs)
+}
+
+pub async fn update_sent_to(pool: &sqlx::Pool<Sqlite>, statechain_id: &str, sent_to: &str) -> Result<(), CError> {
+
+    let query = "\
+        UPDATE backup_transaction \
+        SET sent_to = $1 \
+        WHERE tx_n = (SELECT MAX(tx_n) FROM backup_transaction WHERE statechain_id = $2)
+        AND statechain_id = $2";
+
+    let _ = sqlx::query(query)
+        .bind(sent_to)
+        .bind(statechain_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn insert_backup_transactions(pool: &sqlx::Pool<Sqlite>, backup_txs: &Vec<BackupTx>, statechain_id: &str) -> Result<(), CError> {
+
+    let _ = sqlx::query("DELETE FROM backup_transaction WHERE statechain_id = $1")
+        .bind(statechain_id)
+        .execute(pool)
+        .await?;
+
+    for backup_tx in backup_txs {
+
+        let query = "INSERT INTO backup_transaction (tx_n, statechain_id, client_public_nonce, blinding_factor, backup_tx) \
+            VALUES ($1, $2, $3, $4, $5)";
+        let _ = sqlx::query(query)
+            .bind(backup_tx.tx_n)
+            .bind(statechain_id)
+            .bind(hex::decode(&backup_tx.client_public_nonce)?)
+            .bind(hex::decode(&backup_tx.blinding_factor)?)
+            .bind(hex::decode(&backup_tx.tx)?)
+            .execute(pool)
+            .await?;
+    }
 
-}
\ No newline at end of file
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::{Aead, AeadCore, OsRng}};
+use bitcoin::hashes::{sha256, Hash};
+use secp256k1_zkp::{PublicKey, SecretKey, Secp256k1, ecdh::SharedSecret};
+use serde::{Serialize, Deserialize};
+
+use crate::{error::CError, transaction::BackupTx};
+
+/// Message sent (encrypted) from the current owner of a statecoin to the new owner
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferMsg {
+    pub statechain_id: String,
+    /// Signature of the statechain_id by the sender's auth key
+    pub transfer_signature: String,
+    /// Full history of backup transactions, the last one paying to the receiver
+    pub backup_transactions: Vec<BackupTx>,
+    /// t1 = o1 + x1, hex encoded
+    pub t1: String,
+    /// Sender's auth key, x-only, against which `transfer_signature` is verified
+    pub user_public_key: String,
+    pub aggregated_pubkey: String,
+    pub funding_txid: String,
+    pub funding_vout: u32,
+    pub amount: u64,
+}
+
+const NONCE_SIZE: usize = 12;
+const PUBKEY_SIZE: usize = 33;
+
+fn derive_encryption_key(shared_secret: &SharedSecret, ephemeral_pubkey: &PublicKey) -> [u8; 32] {
+    let mut data = shared_secret.secret_bytes().to_vec();
+    data.extend_from_slice(&ephemeral_pubkey.serialize());
+    sha256::Hash::hash(&data).to_byte_array()
+}
+
+/// Encrypts the transfer message to the receiver's auth pubkey.
+/// The result is hex(ephemeral_pubkey || nonce || ciphertext).
+pub fn encrypt_transfer_msg(transfer_msg: &TransferMsg, auth_pubkey: &PublicKey) -> Result<String, CError> {
+
+    let secp = Secp256k1::new();
+
+    let (ephemeral_seckey, ephemeral_pubkey) = secp.generate_keypair(&mut rand::thread_rng());
+
+    let shared_secret = SharedSecret::new(auth_pubkey, &ephemeral_seckey);
+    let key = derive_encryption_key(&shared_secret, &ephemeral_pubkey);
+
+    let cipher = Aes256Gcm::new_from_slice(&key).map_err(|e| CError::Protocol(e.to_string()))?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let plaintext = serde_json::to_vec(transfer_msg).map_err(|e| CError::Protocol(e.to_string()))?;
+
+    let ciphertext = cipher.encrypt(&nonce, plaintext.as_slice()).map_err(|e| CError::Protocol(e.to_string()))?;
+
+    let mut data = Vec::<u8>::new();
+    data.extend_from_slice(&ephemeral_pubkey.serialize());
+    data.extend_from_slice(nonce.as_slice());
+    data.extend_from_slice(&ciphertext);
+
+    Ok(hex::encode(data))
+}
+
+/// Decrypts a transfer message with the receiver's auth secret key.
+pub fn decrypt_transfer_msg(enc_transfer_msg: &str, auth_seckey: &SecretKey) -> Result<TransferMsg, CError> {
+
+    let data = hex::decode(enc_transfer_msg)?;
+
+    if data.len() < PUBKEY_SIZE + NONCE_SIZE {
+        return Err(CError::Protocol("Encrypted transfer message too short".to_string()));
+    }
+
+    let ephemeral_pubkey = PublicKey::from_slice(&data[..PUBKEY_SIZE]).map_err(|e| CError::Protocol(e.to_string()))?;
+    let nonce = Nonce::from_slice(&data[PUBKEY_SIZE..PUBKEY_SIZE + NONCE_SIZE]);
+    let ciphertext = &data[PUBKEY_SIZE + NONCE_SIZE..];
+
+    let shared_secret = SharedSecret::new(&ephemeral_pubkey, auth_seckey);
+    let key = derive_encryption_key(&shared_secret, &ephemeral_pubkey);
+
+    let cipher = Aes256Gcm::new_from_slice(&key).map_err(|e| CError::Protocol(e.to_string()))?;
+
+    let plaintext = cipher.decrypt(nonce, ciphertext).map_err(|e| CError::Protocol(e.to_string()))?;
+
+    serde_json::from_slice(&plaintext).map_err(|e| CError::Protocol(e.to_string()))
+}
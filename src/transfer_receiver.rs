@@ -0,0 +1,454 @@
+use std::str::FromStr;
+
+use bitcoin::{Network, Address, Transaction, Txid, OutPoint, TxOut, hashes::sha256, secp256k1};
+use secp256k1_zkp::{Secp256k1, Message, PublicKey, SecretKey, XOnlyPublicKey, Scalar, schnorr::Signature, musig::MusigKeyAggCache};
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, Row, sqlite::SqliteRow};
+
+use crate::{chain::ChainBackend, encryption::WalletCipher, error::CError, key_store, server::{StatechainServer, TransferReceiverRequestPayload}, transaction, transfer::{self, TransferMsg}, wallet::StatecoinStatus};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferFailure {
+    /// None when the message could not be decrypted
+    pub statechain_id: Option<String>,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferReceiveReport {
+    pub received_statechain_ids: Vec<String>,
+    /// Transfer messages that could not be received, the others are still processed
+    pub failures: Vec<TransferFailure>,
+}
+
+struct TransferAddressKeys {
+    client_seckey: SecretKey,
+    client_pubkey: PublicKey,
+    auth_seckey: SecretKey,
+    auth_pubkey: PublicKey,
+}
+
+/// Transfer message whose key update has been started, see `migrations/0015_pending_receive.sql`
+struct PendingReceive {
+    auth_pubkey: PublicKey,
+    transfer_msg: TransferMsg,
+    t2: SecretKey,
+    /// Set once the server has rotated its key share
+    server_pubkey: Option<PublicKey>,
+}
+
+async fn get_transfer_keys(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, row: &SqliteRow, network: Network) -> Result<TransferAddressKeys, CError> {
+
+    let client_pubkey = PublicKey::from_slice(&row.try_get::<Vec<u8>, _>("client_pubkey_share")?)?;
+    let auth_pubkey = PublicKey::from_slice(&row.try_get::<Vec<u8>, _>("auth_pubkey")?)?;
+
+    Ok(TransferAddressKeys {
+        client_seckey: key_store::get_secret_key(pool, cipher, &client_pubkey, row.try_get::<Option<String>, _>("agg_key_derivation_path")?.as_deref(), network).await?,
+        client_pubkey,
+        auth_seckey: key_store::get_secret_key(pool, cipher, &auth_pubkey, row.try_get::<Option<String>, _>("auth_derivation_path")?.as_deref(), network).await?,
+        auth_pubkey,
+    })
+}
+
+/// Transfer addresses that have not received a statecoin and have no pending receive
+async fn get_unused_transfer_keys(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, network: Network) -> Result<Vec<TransferAddressKeys>, CError> {
+
+    let query = "\
+        SELECT client_pubkey_share, agg_key_derivation_path, auth_pubkey, auth_derivation_path \
+        FROM signer_data \
+        WHERE statechain_id IS NULL AND auth_pubkey IS NOT NULL AND deposit_status IS NULL \
+        AND auth_pubkey NOT IN (SELECT auth_pubkey FROM pending_receive)";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await?;
+
+    let mut keys = Vec::<TransferAddressKeys>::new();
+
+    for row in rows {
+        keys.push(get_transfer_keys(pool, cipher, &row, network).await?);
+    }
+
+    Ok(keys)
+}
+
+async fn get_pending_receive_keys(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, auth_pubkey: &PublicKey, network: Network) -> Result<TransferAddressKeys, CError> {
+
+    let query = "\
+        SELECT client_pubkey_share, agg_key_derivation_path, auth_pubkey, auth_derivation_path \
+        FROM signer_data \
+        WHERE auth_pubkey = $1";
+
+    let row = sqlx::query(query)
+        .bind(&auth_pubkey.serialize().to_vec())
+        .fetch_one(pool)
+        .await?;
+
+    get_transfer_keys(pool, cipher, &row, network).await
+}
+
+async fn get_pending_receives(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher) -> Result<Vec<PendingReceive>, CError> {
+
+    let query = "\
+        SELECT auth_pubkey, transfer_msg, t2, server_pubkey \
+        FROM pending_receive \
+        ORDER BY created_at";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await?;
+
+    let mut pending_receives = Vec::<PendingReceive>::new();
+
+    for row in rows {
+
+        let auth_pubkey = PublicKey::from_slice(&row.try_get::<Vec<u8>, _>("auth_pubkey")?)?;
+
+        let transfer_msg_bytes = cipher.decrypt(&row.try_get::<Vec<u8>, _>("transfer_msg")?, &auth_pubkey.serialize())?;
+        let transfer_msg: TransferMsg = serde_json::from_slice(&transfer_msg_bytes).map_err(|e| CError::Database(e.to_string()))?;
+
+        let server_pubkey = match row.try_get::<Option<Vec<u8>>, _>("server_pubkey")? {
+            Some(server_pubkey) => Some(PublicKey::from_slice(&server_pubkey)?),
+            None => None,
+        };
+
+        pending_receives.push(PendingReceive {
+            auth_pubkey,
+            transfer_msg,
+            t2: cipher.decrypt_secret_key(&row.try_get::<Vec<u8>, _>("t2")?, &auth_pubkey)?,
+            server_pubkey,
+        });
+    }
+
+    Ok(pending_receives)
+}
+
+async fn insert_pending_receive(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, pending: &PendingReceive) -> Result<(), CError> {
+
+    let transfer_msg_bytes = serde_json::to_vec(&pending.transfer_msg).map_err(|e| CError::Database(e.to_string()))?;
+
+    let query = "\
+        INSERT INTO pending_receive (auth_pubkey, statechain_id, transfer_msg, t2) \
+        VALUES ($1, $2, $3, $4)";
+
+    let _ = sqlx::query(query)
+        .bind(&pending.auth_pubkey.serialize().to_vec())
+        .bind(&pending.transfer_msg.statechain_id)
+        .bind(cipher.encrypt(&transfer_msg_bytes, &pending.auth_pubkey.serialize())?)
+        .bind(cipher.encrypt_secret_key(&pending.t2, &pending.auth_pubkey)?)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+async fn update_pending_server_pubkey(pool: &sqlx::Pool<Sqlite>, auth_pubkey: &PublicKey, server_pubkey: &PublicKey) -> Result<(), CError> {
+
+    let _ = sqlx::query("UPDATE pending_receive SET server_pubkey = $1 WHERE auth_pubkey = $2")
+        .bind(&server_pubkey.serialize().to_vec())
+        .bind(&auth_pubkey.serialize().to_vec())
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+async fn has_pending_receive(pool: &sqlx::Pool<Sqlite>, auth_pubkey: &PublicKey) -> Result<bool, CError> {
+
+    let row = sqlx::query("SELECT 1 FROM pending_receive WHERE auth_pubkey = $1")
+        .bind(&auth_pubkey.serialize().to_vec())
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+async fn delete_pending_receive(pool: &sqlx::Pool<Sqlite>, auth_pubkey: &PublicKey) -> Result<(), CError> {
+
+    let _ = sqlx::query("DELETE FROM pending_receive WHERE auth_pubkey = $1")
+        .bind(&auth_pubkey.serialize().to_vec())
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Checks that `transfer_signature` signs the statechain_id with the sender's auth key
+fn verify_transfer_signature(transfer_msg: &TransferMsg) -> Result<(), CError> {
+
+    let secp = Secp256k1::new();
+
+    let sender_auth_pubkey = XOnlyPublicKey::from_str(&transfer_msg.user_public_key).map_err(|e| CError::Protocol(e.to_string()))?;
+    let signature = Signature::from_str(&transfer_msg.transfer_signature).map_err(|e| CError::Protocol(e.to_string()))?;
+    let msg = Message::from_hashed_data::<sha256::Hash>(transfer_msg.statechain_id.as_bytes());
+
+    secp.verify_schnorr(&signature, &msg, &sender_auth_pubkey)
+        .map_err(|_| CError::Protocol("Transfer signature is not valid for the sender's auth key".to_string()))
+}
+
+/// Checks that the funding output exists on-chain, is locked to the aggregated key and has not been spent.
+async fn verify_funding_outpoint(chain: &dyn ChainBackend, transfer_msg: &TransferMsg, p2tr_agg_address: &Address) -> Result<OutPoint, CError> {
+
+    let funding_txid = Txid::from_str(&transfer_msg.funding_txid).map_err(|e| CError::Protocol(e.to_string()))?;
+    let funding_tx = chain.get_transaction(&funding_txid).await?;
+
+    let funding_output = match funding_tx.output.get(transfer_msg.funding_vout as usize) {
+        Some(output) => output,
+        None => return Err(CError::Protocol("Funding output does not exist".to_string())),
+    };
+
+    if funding_output.script_pubkey != p2tr_agg_address.script_pubkey() {
+        return Err(CError::Protocol("Funding output is not locked to the aggregated public key".to_string()));
+    }
+
+    if funding_output.value != transfer_msg.amount {
+        return Err(CError::Protocol("Funding output amount does not match the statecoin amount".to_string()));
+    }
+
+    let utxo_list = chain.list_unspent(p2tr_agg_address).await?;
+
+    let is_unspent = utxo_list.iter().any(|utxo| utxo.txid == funding_txid && utxo.vout == transfer_msg.funding_vout);
+
+    if !is_unspent {
+        return Err(CError::Protocol("Funding output has already been spent".to_string()));
+    }
+
+    Ok(OutPoint { txid: funding_txid, vout: transfer_msg.funding_vout })
+}
+
+/// Checks that every backup transaction spends the funding outpoint with a valid signature,
+/// that the locktimes strictly decrease, and that the latest one pays to the receiver.
+fn verify_backup_transactions(transfer_msg: &TransferMsg, funding_outpoint: &OutPoint, p2tr_agg_address: &Address, backup_address: &Address) -> Result<(), CError> {
+
+    if transfer_msg.backup_transactions.is_empty() {
+        return Err(CError::Protocol("Transfer message has no backup transactions".to_string()));
+    }
+
+    let funding_txout = TxOut { value: transfer_msg.amount, script_pubkey: p2tr_agg_address.script_pubkey() };
+
+    let mut previous_lock_time: Option<u32> = None;
+    let mut previous_tx_n: Option<u32> = None;
+
+    for backup_tx in &transfer_msg.backup_transactions {
+
+        let tx_bytes = hex::decode(&backup_tx.tx)?;
+        let tx: Transaction = bitcoin::consensus::deserialize(&tx_bytes)?;
+
+        if tx.input.len() != 1 || tx.input[0].previous_output != *funding_outpoint {
+            return Err(CError::Protocol(format!("Backup transaction {} does not spend the funding outpoint", backup_tx.tx_n)));
+        }
+
+        tx.verify(|_| Some(funding_txout.clone()))
+            .map_err(|e| CError::Protocol(format!("Backup transaction {} has an invalid signature: {}", backup_tx.tx_n, e)))?;
+
+        let lock_time = tx.lock_time.to_consensus_u32();
+
+        if let Some(previous_lock_time) = previous_lock_time {
+            if lock_time >= previous_lock_time {
+                return Err(CError::Protocol(format!("Backup transaction {} locktime does not decrease", backup_tx.tx_n)));
+            }
+        }
+
+        if let Some(previous_tx_n) = previous_tx_n {
+            if backup_tx.tx_n <= previous_tx_n {
+                return Err(CError::Protocol("Backup transactions are out of order".to_string()));
+            }
+        }
+
+        previous_lock_time = Some(lock_time);
+        previous_tx_n = Some(backup_tx.tx_n);
+    }
+
+    let latest_backup_tx = &transfer_msg.backup_transactions[transfer_msg.backup_transactions.len() - 1];
+    let latest_tx: Transaction = bitcoin::consensus::deserialize(&hex::decode(&latest_backup_tx.tx)?)?;
+
+    // Both the backup output and the anchor output, if any, must pay to the receiver
+    if latest_tx.output.is_empty() || latest_tx.output.iter().any(|output| output.script_pubkey != backup_address.script_pubkey()) {
+        return Err(CError::Protocol("Latest backup transaction does not pay to the receiver".to_string()));
+    }
+
+    Ok(())
+}
+
+async fn key_update(server: &StatechainServer, statechain_id: &str, t2: &SecretKey, keys: &TransferAddressKeys) -> Result<PublicKey, CError> {
+
+    let secp = Secp256k1::new();
+
+    let keypair = secp256k1::KeyPair::from_seckey_slice(&secp, keys.auth_seckey.as_ref())?;
+    let msg = Message::from_hashed_data::<sha256::Hash>(statechain_id.as_bytes());
+    let auth_sig = secp.sign_schnorr(&msg, &keypair);
+
+    let transfer_receiver_request_payload = TransferReceiverRequestPayload {
+        statechain_id: statechain_id.to_string(),
+        batch_data: None,
+        t2: hex::encode(t2.secret_bytes()),
+        auth_sig: auth_sig.to_string(),
+    };
+
+    let response = server.transfer_receiver(&transfer_receiver_request_payload).await?;
+
+    PublicKey::from_str(&response.server_pubkey).map_err(|e| CError::Protocol(e.to_string()))
+}
+
+async fn update_received_statecoin(pool: &sqlx::Pool<Sqlite>, transfer_msg: &TransferMsg, server_pubkey: &PublicKey, aggregated_pubkey: &XOnlyPublicKey, p2tr_agg_address: &Address, auth_pubkey: &PublicKey) -> Result<(), CError> {
+
+    let query = "\
+        UPDATE signer_data \
+        SET statechain_id = $1, amount = $2, funding_txid = $3, funding_vout = $4, \
+        server_pubkey_share = $5, aggregated_pubkey = $6, p2tr_agg_address = $7, status = $8 \
+        WHERE auth_pubkey = $9";
+
+    let _ = sqlx::query(query)
+        .bind(&transfer_msg.statechain_id)
+        .bind(transfer_msg.amount as i64)
+        .bind(&transfer_msg.funding_txid)
+        .bind(transfer_msg.funding_vout)
+        .bind(&server_pubkey.serialize().to_vec())
+        .bind(&aggregated_pubkey.serialize().to_vec())
+        .bind(&p2tr_agg_address.to_string())
+        .bind(StatecoinStatus::Confirmed.as_str())
+        .bind(&auth_pubkey.serialize().to_vec())
+        .execute(pool)
+        .await?;
+
+    transaction::insert_backup_transactions(pool, &transfer_msg.backup_transactions, &transfer_msg.statechain_id).await
+}
+
+/// Verifies the transfer message, then saves it with t2 before the key update so that a rerun can complete the transfer
+async fn process_transfer_msg(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, chain: &dyn ChainBackend, server: &StatechainServer, transfer_msg: &TransferMsg, keys: &TransferAddressKeys, network: Network) -> Result<(), CError> {
+
+    let secp = Secp256k1::new();
+
+    let aggregated_pubkey = XOnlyPublicKey::from_str(&transfer_msg.aggregated_pubkey).map_err(|e| CError::Protocol(e.to_string()))?;
+    let p2tr_agg_address = Address::p2tr(&secp, aggregated_pubkey, None, network);
+    let backup_address = Address::p2tr(&secp, keys.client_pubkey.x_only_public_key().0, None, network);
+
+    verify_transfer_signature(transfer_msg)?;
+
+    let statechain_info = server.get_statechain_info(&transfer_msg.statechain_id).await?;
+
+    if statechain_info.num_sigs as usize != transfer_msg.backup_transactions.len() {
+        return Err(CError::Protocol("Number of backup transactions does not match the number of server signatures".to_string()));
+    }
+
+    let funding_outpoint = verify_funding_outpoint(chain, transfer_msg, &p2tr_agg_address).await?;
+
+    verify_backup_transactions(transfer_msg, &funding_outpoint, &p2tr_agg_address, &backup_address)?;
+
+    let t1_bytes = hex::decode(&transfer_msg.t1)?;
+    let t1 = SecretKey::from_slice(&t1_bytes).map_err(|e| CError::Protocol(e.to_string()))?;
+
+    // t2 = t1 - o2
+    let t2 = t1.add_tweak(&Scalar::from(keys.client_seckey.negate()))?;
+
+    let pending = PendingReceive {
+        auth_pubkey: keys.auth_pubkey,
+        transfer_msg: transfer_msg.clone(),
+        t2,
+        server_pubkey: None,
+    };
+
+    insert_pending_receive(pool, cipher, &pending).await?;
+
+    complete_pending_receive(pool, server, &pending, keys, network).await
+}
+
+/// Runs the key update unless the server already answered it, then adds the statecoin to the wallet
+async fn complete_pending_receive(pool: &sqlx::Pool<Sqlite>, server: &StatechainServer, pending: &PendingReceive, keys: &TransferAddressKeys, network: Network) -> Result<(), CError> {
+
+    let secp = Secp256k1::new();
+
+    let transfer_msg = &pending.transfer_msg;
+
+    let aggregated_pubkey = XOnlyPublicKey::from_str(&transfer_msg.aggregated_pubkey).map_err(|e| CError::Protocol(e.to_string()))?;
+    let p2tr_agg_address = Address::p2tr(&secp, aggregated_pubkey, None, network);
+
+    let server_pubkey = match pending.server_pubkey {
+        Some(server_pubkey) => server_pubkey,
+        None => {
+            let server_pubkey = key_update(server, &transfer_msg.statechain_id, &pending.t2, keys).await?;
+            update_pending_server_pubkey(pool, &keys.auth_pubkey, &server_pubkey).await?;
+            server_pubkey
+        },
+    };
+
+    let key_agg_cache = MusigKeyAggCache::new(&secp, &[keys.client_pubkey, server_pubkey]);
+
+    if key_agg_cache.agg_pk() != aggregated_pubkey {
+        return Err(CError::Protocol("New key shares do not aggregate to the statecoin public key".to_string()));
+    }
+
+    update_received_statecoin(pool, transfer_msg, &server_pubkey, &aggregated_pubkey, &p2tr_agg_address, &keys.auth_pubkey).await?;
+    delete_pending_receive(pool, &keys.auth_pubkey).await?;
+
+    Ok(())
+}
+
+pub async fn execute(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, chain: &dyn ChainBackend, server: &StatechainServer, network: Network) -> Result<TransferReceiveReport, CError> {
+
+    let mut received_statechain_ids = Vec::<String>::new();
+    let mut failures = Vec::<TransferFailure>::new();
+
+    // Transfers interrupted after their message was saved are completed first, the server no longer serves their message
+    for pending in get_pending_receives(pool, cipher).await? {
+
+        let statechain_id = pending.transfer_msg.statechain_id.clone();
+
+        let result = match get_pending_receive_keys(pool, cipher, &pending.auth_pubkey, network).await {
+            Ok(keys) => complete_pending_receive(pool, server, &pending, &keys, network).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(()) => received_statechain_ids.push(statechain_id),
+            Err(err) => failures.push(TransferFailure {
+                statechain_id: Some(statechain_id),
+                error: err.to_string(),
+            }),
+        }
+    }
+
+    let keys_list = get_unused_transfer_keys(pool, cipher, network).await?;
+
+    for keys in keys_list {
+
+        let enc_msgs = server.get_msg_addr(&keys.auth_pubkey.to_string()).await?.list_enc_transfer_msg;
+
+        for enc_msg in enc_msgs {
+
+            let transfer_msg = match transfer::decrypt_transfer_msg(&enc_msg, &keys.auth_seckey) {
+                Ok(transfer_msg) => transfer_msg,
+                Err(err) => {
+                    failures.push(TransferFailure {
+                        statechain_id: None,
+                        error: format!("Failed to decrypt transfer message: {}", err),
+                    });
+                    continue;
+                }
+            };
+
+            match process_transfer_msg(pool, cipher, chain, server, &transfer_msg, &keys, network).await {
+                Ok(()) => {
+                    received_statechain_ids.push(transfer_msg.statechain_id.clone());
+                    // Each transfer address receives a single statecoin
+                    break;
+                },
+                Err(err) => {
+                    failures.push(TransferFailure {
+                        statechain_id: Some(transfer_msg.statechain_id.clone()),
+                        error: err.to_string(),
+                    });
+                    // The message was saved before the key update failed, the next run completes it
+                    if has_pending_receive(pool, &keys.auth_pubkey).await? {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(TransferReceiveReport {
+        received_statechain_ids,
+        failures,
+    })
+}
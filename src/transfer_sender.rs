@@ -0,0 +1,277 @@
+use bitcoin::{Network, Address, TxOut, Txid, Transaction, hashes::sha256, secp256k1};
+use secp256k1_zkp::{Secp256k1, Message, PublicKey, SecretKey, XOnlyPublicKey, Scalar, schnorr::Signature};
+use sqlx::{Sqlite, Row};
+
+use crate::{chain::ChainBackend, encryption::WalletCipher, key_derivation, error::CError, server::{StatechainServer, TransferSenderRequestPayload, TransferUpdateMsgRequestPayload}, transaction::{self, BackupTx}, transfer::{self, TransferMsg}, wallet::{self, StatecoinStatus}};
+
+async fn get_x1(server: &StatechainServer, statechain_id: &str, signed_statechain_id: &Signature, auth_pubkey: &XOnlyPublicKey, new_auth_pubkey: &PublicKey) -> Result<SecretKey, CError> {
+
+    let transfer_sender_request_payload = TransferSenderRequestPayload {
+        statechain_id: statechain_id.to_string(),
+        user_auth_key: auth_pubkey.to_string(),
+        new_user_auth_key: new_auth_pubkey.to_string(),
+        batch_id: None,
+        signed_statechain_id: signed_statechain_id.to_string(),
+    };
+
+    let response = server.transfer_sender(&transfer_sender_request_payload).await?;
+
+    let mut x1_hex = response.x1;
+
+    if x1_hex.starts_with("0x") {
+        x1_hex = x1_hex[2..].to_string();
+    }
+
+    let x1_bytes = hex::decode(x1_hex)?;
+
+    SecretKey::from_slice(&x1_bytes).map_err(|e| CError::Protocol(format!("Invalid x1 received from server: {}", e)))
+}
+
+/// Backup transaction signed for a transfer that the server has not accepted yet
+struct PendingTransfer {
+    recipient_address: String,
+    tx: Transaction,
+    client_public_nonce: [u8; 66],
+    blinding_factor: [u8; 32],
+}
+
+async fn get_pending_transfer(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Result<Option<PendingTransfer>, CError> {
+
+    let query = "\
+        SELECT recipient_address, backup_tx, client_public_nonce, blinding_factor \
+        FROM pending_transfer \
+        WHERE statechain_id = $1";
+
+    let row = sqlx::query(query)
+        .bind(statechain_id)
+        .fetch_optional(pool)
+        .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let client_public_nonce = row.try_get::<Vec<u8>, _>("client_public_nonce")?.try_into()
+        .map_err(|_| CError::Database(format!("Invalid client public nonce in the pending transfer of {}", statechain_id)))?;
+    let blinding_factor = row.try_get::<Vec<u8>, _>("blinding_factor")?.try_into()
+        .map_err(|_| CError::Database(format!("Invalid blinding factor in the pending transfer of {}", statechain_id)))?;
+
+    Ok(Some(PendingTransfer {
+        recipient_address: row.try_get::<String, _>("recipient_address")?,
+        tx: bitcoin::consensus::deserialize(&row.try_get::<Vec<u8>, _>("backup_tx")?)?,
+        client_public_nonce,
+        blinding_factor,
+    }))
+}
+
+async fn insert_pending_transfer(pool: &sqlx::Pool<Sqlite>, statechain_id: &str, pending: &PendingTransfer) -> Result<(), CError> {
+
+    let query = "\
+        INSERT INTO pending_transfer (statechain_id, recipient_address, backup_tx, client_public_nonce, blinding_factor) \
+        VALUES ($1, $2, $3, $4, $5)";
+
+    let _ = sqlx::query(query)
+        .bind(statechain_id)
+        .bind(&pending.recipient_address)
+        .bind(bitcoin::consensus::encode::serialize(&pending.tx))
+        .bind(pending.client_public_nonce.to_vec())
+        .bind(pending.blinding_factor.to_vec())
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+async fn delete_pending_transfer(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Result<(), CError> {
+
+    let _ = sqlx::query("DELETE FROM pending_transfer WHERE statechain_id = $1")
+        .bind(statechain_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Sends the statecoin to `recipient_address`.
+/// The new backup transaction co-signed by the server is stored as a pending transfer until the server accepts the transfer message,
+/// so that running it again after a failure reuses it instead of asking the server for another signature.
+pub async fn execute(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, chain: &dyn ChainBackend, server: &StatechainServer, recipient_address: &str, statechain_id: &str, network: Network, anchor: bool) -> Result<Txid, CError> {
+
+    let (_, new_user_pubkey, new_auth_pubkey) = key_derivation::decode_transfer_address(recipient_address)?;
+
+    let statecoin = wallet::get_statecoin(pool, statechain_id, network).await?;
+
+    if statecoin.coin_sent {
+        return Err(CError::UserInput(format!("Statecoin {} has already been sent", statechain_id)));
+    }
+
+    if statecoin.coin_withdrawn {
+        return Err(CError::UserInput(format!("Statecoin {} has already been withdrawn", statechain_id)));
+    }
+
+    wallet::check_transition(pool, statechain_id, StatecoinStatus::InTransfer).await?;
+
+    let pending = get_pending_transfer(pool, statechain_id).await?;
+
+    if let Some(pending) = &pending {
+        if pending.recipient_address != recipient_address {
+            return Err(CError::UserInput(format!(
+                "Statecoin {} has a pending transfer to {}. Run transfer-send again with that address",
+                statechain_id, pending.recipient_address)));
+        }
+    }
+
+    let backup_txs = transaction::get_backup_transactions(pool, statechain_id).await?;
+
+    let latest_backup_tx = match backup_txs.last() {
+        Some(backup_tx) => backup_tx,
+        None => return Err(CError::UserInput(format!("No backup transaction found for statecoin {}", statechain_id))),
+    };
+
+    let latest_tx: Transaction = bitcoin::consensus::deserialize(&hex::decode(&latest_backup_tx.tx)?)?;
+
+    // The pending backup transaction already has its locktime
+    let block_height = match &pending {
+        Some(pending) => pending.tx.lock_time.to_consensus_u32(),
+        None => transaction::get_new_block_height(pool, chain, server, statechain_id).await?,
+    };
+
+    if block_height >= latest_tx.lock_time.to_consensus_u32() {
+        return Err(CError::Protocol("New backup transaction locktime must be lower than the current one".to_string()));
+    }
+
+    let keys = wallet::get_statecoin_keys(pool, cipher, statechain_id, network).await?;
+
+    let secp = Secp256k1::new();
+
+    let keypair = secp256k1::KeyPair::from_seckey_slice(&secp, keys.auth_seckey.as_ref())?;
+    let auth_xonly_pubkey = keypair.x_only_public_key().0;
+
+    let msg = Message::from_hashed_data::<sha256::Hash>(statechain_id.to_string().as_bytes());
+    let signed_statechain_id = secp.sign_schnorr(&msg, &keypair);
+
+    wallet::update_status(pool, statechain_id, StatecoinStatus::InTransfer).await?;
+
+    // The backup transaction is only added to the statecoin once the server accepts the transfer message.
+    // If it does not, the statecoin is still owned by the wallet and the signed transaction stays pending.
+    let result: Result<PendingTransfer, CError> = async {
+
+        let x1 = get_x1(server, statechain_id, &signed_statechain_id, &auth_xonly_pubkey, &new_auth_pubkey).await?;
+
+        let pending = match pending {
+            Some(pending) => pending,
+            None => {
+                let pending = sign_new_backup_tx(server, recipient_address, &latest_tx, block_height, statechain_id, &signed_statechain_id, &keys.client_seckey, &statecoin, &new_user_pubkey, network, anchor).await?;
+                insert_pending_transfer(pool, statechain_id, &pending).await?;
+                pending
+            },
+        };
+
+        let mut backup_transactions = backup_txs.clone();
+        backup_transactions.push(BackupTx {
+            tx_n: latest_backup_tx.tx_n + 1,
+            tx: hex::encode(bitcoin::consensus::encode::serialize(&pending.tx)),
+            client_public_nonce: hex::encode(pending.client_public_nonce),
+            blinding_factor: hex::encode(pending.blinding_factor),
+        });
+
+        // t1 = o1 + x1
+        let t1 = keys.client_seckey.add_tweak(&Scalar::from(x1))?;
+
+        let transfer_msg = TransferMsg {
+            statechain_id: statechain_id.to_string(),
+            transfer_signature: signed_statechain_id.to_string(),
+            backup_transactions,
+            t1: hex::encode(t1.secret_bytes()),
+            user_public_key: auth_xonly_pubkey.to_string(),
+            aggregated_pubkey: statecoin.aggregated_pubkey.to_string(),
+            funding_txid: statecoin.funding_txid.to_string(),
+            funding_vout: statecoin.funding_vout,
+            amount: statecoin.amount,
+        };
+
+        let enc_transfer_msg = transfer::encrypt_transfer_msg(&transfer_msg, &new_auth_pubkey)?;
+
+        let transfer_update_msg_request_payload = TransferUpdateMsgRequestPayload {
+            statechain_id: statechain_id.to_string(),
+            auth_sign: signed_statechain_id.to_string(),
+            new_user_auth_key: new_auth_pubkey.to_string(),
+            enc_transfer_msg,
+        };
+
+        server.transfer_update_msg(&transfer_update_msg_request_payload).await?;
+
+        Ok(pending)
+    }.await;
+
+    let pending = match result {
+        Ok(pending) => pending,
+        Err(err) => {
+            wallet::update_status(pool, statechain_id, StatecoinStatus::Confirmed).await?;
+            return Err(err);
+        },
+    };
+
+    let tx_bytes = bitcoin::consensus::encode::serialize(&pending.tx);
+
+    transaction::insert_transaction(pool, &tx_bytes, &pending.client_public_nonce, &pending.blinding_factor, statechain_id).await?;
+    transaction::update_sent_to(pool, statechain_id, recipient_address).await?;
+
+    update_coin_sent(pool, statechain_id).await?;
+    delete_pending_transfer(pool, statechain_id).await?;
+    wallet::update_status(pool, statechain_id, StatecoinStatus::Transferred).await?;
+
+    Ok(pending.tx.txid())
+}
+
+/// Asks the server to co-sign the backup transaction paying the recipient, with the same fee as the latest backup transaction
+async fn sign_new_backup_tx(server: &StatechainServer, recipient_address: &str, latest_tx: &Transaction, block_height: u32, statechain_id: &str, signed_statechain_id: &Signature, client_seckey: &SecretKey, statecoin: &wallet::Statecoin, new_user_pubkey: &PublicKey, network: Network, anchor: bool) -> Result<PendingTransfer, CError> {
+
+    let secp = Secp256k1::new();
+
+    // The fee is kept equal to the one paid by the previous backup transaction, anchor output included
+    let amount_out: u64 = latest_tx.output.iter().map(|output| output.value).sum();
+
+    let new_backup_address = Address::p2tr(&secp, new_user_pubkey.x_only_public_key().0, None, network);
+
+    let tx_out = TxOut { value: amount_out, script_pubkey: new_backup_address.script_pubkey() };
+
+    let (tx, client_pub_nonce, blinding_factor) = transaction::create(
+        server,
+        block_height,
+        statechain_id,
+        signed_statechain_id,
+        client_seckey,
+        &statecoin.client_pubkey,
+        &statecoin.server_pubkey,
+        statecoin.funding_txid,
+        statecoin.funding_vout,
+        &statecoin.aggregated_pubkey,
+        &statecoin.p2tr_agg_address.script_pubkey(),
+        statecoin.amount,
+        tx_out,
+        anchor).await?;
+
+    Ok(PendingTransfer {
+        recipient_address: recipient_address.to_string(),
+        tx,
+        client_public_nonce: client_pub_nonce.serialize(),
+        blinding_factor: *blinding_factor.as_bytes(),
+    })
+}
+
+async fn update_coin_sent(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Result<(), CError> {
+
+    let query = "\
+        UPDATE signer_data \
+        SET coin_sent = TRUE \
+        WHERE statechain_id = $1";
+
+    let _ = sqlx::query(query)
+        .bind(statechain_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use bitcoin::{Network, Address, OutPoint, Transaction, TxOut, Txid, key::TapTweak, secp256k1::{self, Secp256k1, XOnlyPublicKey}, sighash::{Prevouts, SighashCache}, taproot};
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, Row};
+
+use crate::{error::CError, transaction::{self, BackupTx}, wallet::{self, Statecoin}};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackupTxReport {
+    pub tx_n: u32,
+    pub txid: Option<Txid>,
+    pub lock_time: Option<u32>,
+    /// Discrepancies found in this transaction. Empty if it is valid.
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatecoinBackupReport {
+    pub statechain_id: String,
+    /// True if neither the statecoin nor any of its backup transactions has errors
+    pub valid: bool,
+    /// Discrepancies that concern the statecoin as a whole
+    pub errors: Vec<String>,
+    pub backup_transactions: Vec<BackupTxReport>,
+}
+
+/// Checks the stored backup transactions of `statechain_id`, or of every funded statecoin if it is `None`
+pub async fn execute(pool: &sqlx::Pool<Sqlite>, statechain_id: Option<&str>, network: Network) -> Result<Vec<StatecoinBackupReport>, CError> {
+
+    let statechain_ids = match statechain_id {
+        Some(statechain_id) => vec![statechain_id.to_string()],
+        None => get_funded_statechain_ids(pool).await?,
+    };
+
+    let mut reports = Vec::<StatecoinBackupReport>::new();
+
+    for statechain_id in statechain_ids {
+        let statecoin = wallet::get_statecoin(pool, &statechain_id, network).await?;
+        let backup_txs = transaction::get_backup_transactions(pool, &statechain_id).await?;
+        reports.push(verify_statecoin(&statechain_id, &statecoin, &backup_txs, network)?);
+    }
+
+    Ok(reports)
+}
+
+async fn get_funded_statechain_ids(pool: &sqlx::Pool<Sqlite>) -> Result<Vec<String>, CError> {
+
+    let query = "\
+        SELECT statechain_id \
+        FROM signer_data \
+        WHERE statechain_id IS NOT NULL AND funding_txid IS NOT NULL \
+        ORDER BY created_at";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await?;
+
+    rows.iter().map(|row| Ok(row.try_get::<String, _>("statechain_id")?)).collect()
+}
+
+fn verify_statecoin(statechain_id: &str, statecoin: &Statecoin, backup_txs: &[BackupTx], network: Network) -> Result<StatecoinBackupReport, CError> {
+
+    let secp = Secp256k1::verification_only();
+
+    let funding_outpoint = OutPoint { txid: statecoin.funding_txid, vout: statecoin.funding_vout };
+
+    // The funding output is a key path only P2TR output of the aggregated key
+    let aggregated_pubkey = XOnlyPublicKey::from_slice(&statecoin.aggregated_pubkey.serialize())?;
+    let output_key = aggregated_pubkey.tap_tweak(&secp, None).0.to_inner();
+    let funding_txout = TxOut { value: statecoin.amount, script_pubkey: Address::p2tr(&secp, aggregated_pubkey, None, network).script_pubkey() };
+
+    let backup_address = Address::p2tr(&secp, XOnlyPublicKey::from_slice(&statecoin.client_pubkey.x_only_public_key().0.serialize())?, None, network);
+
+    let mut errors = Vec::<String>::new();
+
+    if funding_txout.script_pubkey != statecoin.p2tr_agg_address.script_pubkey() {
+        errors.push(format!("Deposit address {} does not match the aggregated public key", statecoin.p2tr_agg_address));
+    }
+
+    if backup_txs.is_empty() {
+        errors.push("No backup transactions".to_string());
+    }
+
+    let mut reports = Vec::<BackupTxReport>::new();
+    let mut previous_lock_time: Option<u32> = None;
+    let mut latest_tx: Option<Transaction> = None;
+
+    for backup_tx in backup_txs {
+
+        let mut report = BackupTxReport { tx_n: backup_tx.tx_n, txid: None, lock_time: None, errors: Vec::new() };
+
+        let tx: Transaction = match hex::decode(&backup_tx.tx).ok().and_then(|bytes| bitcoin::consensus::deserialize(&bytes).ok()) {
+            Some(tx) => tx,
+            None => {
+                report.errors.push("Cannot be deserialized".to_string());
+                reports.push(report);
+                latest_tx = None;
+                continue;
+            },
+        };
+
+        let lock_time = tx.lock_time.to_consensus_u32();
+
+        report.txid = Some(tx.txid());
+        report.lock_time = Some(lock_time);
+
+        if tx.input.len() != 1 || tx.input[0].previous_output != funding_outpoint {
+            report.errors.push(format!("Does not spend the funding outpoint {}", funding_outpoint));
+        } else if let Err(err) = verify_key_spend_signature(&secp, &tx, &funding_txout, &output_key) {
+            report.errors.push(err);
+        }
+
+        if let Some(previous_lock_time) = previous_lock_time {
+            if lock_time >= previous_lock_time {
+                report.errors.push(format!("Locktime {} is not lower than the locktime {} of the previous backup transaction", lock_time, previous_lock_time));
+            }
+        }
+
+        previous_lock_time = Some(lock_time);
+        latest_tx = Some(tx);
+        reports.push(report);
+    }
+
+    // Once the coin has been sent, the latest backup transaction pays the new owner
+    if let (false, Some(latest_tx), Some(latest_report)) = (statecoin.coin_sent, latest_tx, reports.last_mut()) {
+        if latest_tx.output.is_empty() || latest_tx.output.iter().any(|output| output.script_pubkey != backup_address.script_pubkey()) {
+            latest_report.errors.push(format!("Does not pay to the backup address {}", backup_address));
+        }
+    }
+
+    let valid = errors.is_empty() && reports.iter().all(|report| report.errors.is_empty());
+
+    Ok(StatecoinBackupReport {
+        statechain_id: statechain_id.to_string(),
+        valid,
+        errors,
+        backup_transactions: reports,
+    })
+}
+
+fn verify_key_spend_signature(secp: &Secp256k1<secp256k1::VerifyOnly>, tx: &Transaction, funding_txout: &TxOut, output_key: &XOnlyPublicKey) -> Result<(), String> {
+
+    if tx.input[0].witness.len() != 1 {
+        return Err("Is not a key path spend".to_string());
+    }
+
+    let signature_bytes = tx.input[0].witness.nth(0).unwrap_or_default();
+    let signature = taproot::Signature::from_slice(signature_bytes)
+        .map_err(|e| format!("Invalid signature encoding: {}", e))?;
+
+    let sighash = SighashCache::new(tx)
+        .taproot_key_spend_signature_hash(0, &Prevouts::All(&[funding_txout.clone()]), signature.hash_ty)
+        .map_err(|e| format!("Cannot compute the signature hash: {}", e))?;
+
+    let msg: secp256k1::Message = sighash.into();
+
+    secp.verify_schnorr(&signature.sig, &msg, output_key)
+        .map_err(|_| "Schnorr signature is not valid for the aggregated public key".to_string())
+}
@@ -1,29 +1,322 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
-use bitcoin::{Network, Address};
+use bitcoin::{Network, Address, Txid};
+use secp256k1_zkp::{PublicKey, SecretKey, XOnlyPublicKey};
+use serde::{Serialize, Deserialize};
 use sqlx::{Sqlite, Row};
 
-pub async fn get_all_addresses(pool: &sqlx::Pool<Sqlite>, network: Network) -> (Vec::<Address>, Vec::<Address>){
+use crate::{encryption::WalletCipher, error::CError, key_store};
+
+/// Parses an address stored in the database and checks it belongs to `network`
+pub fn parse_address(address: &str, network: Network) -> Result<Address, CError> {
+    Address::from_str(address)
+        .map_err(|e| CError::UserInput(format!("Invalid address {}: {}", address, e)))?
+        .require_network(network)
+        .map_err(|e| CError::UserInput(format!("Invalid address {}: {}", address, e)))
+}
+
+pub async fn get_all_addresses(pool: &sqlx::Pool<Sqlite>, network: Network) -> Result<(Vec::<Address>, Vec::<Address>), CError> {
     let query = "SELECT p2tr_agg_address, backup_address FROM signer_data";
 
     let rows = sqlx::query(query)
         .fetch_all(pool)
-        .await
-        .unwrap();
+        .await?;
 
     let mut agg_addresses = Vec::<Address>::new();
     let mut backup_addresses = Vec::<Address>::new();
 
     for row in rows {
 
-        let p2tr_agg_address = row.get::<String, _>("p2tr_agg_address");
-        let agg_address = Address::from_str(&p2tr_agg_address).unwrap().require_network(network).unwrap();
-        agg_addresses.push(agg_address);
+        // p2tr_agg_address is only set once the server has been contacted (it is NULL for transfer addresses)
+        if let Some(p2tr_agg_address) = row.try_get::<Option<String>, _>("p2tr_agg_address")? {
+            let agg_address = parse_address(&p2tr_agg_address, network)?;
+            agg_addresses.push(agg_address);
+        }
 
-        let backup_address_str = row.get::<String, _>("backup_address");
-        let backup_address = Address::from_str(&backup_address_str).unwrap().require_network(network).unwrap();
+        let backup_address_str = row.try_get::<String, _>("backup_address")?;
+        let backup_address = parse_address(&backup_address_str, network)?;
         backup_addresses.push(backup_address);
     }
 
-    (agg_addresses, backup_addresses)
-}
\ No newline at end of file
+    Ok((agg_addresses, backup_addresses))
+}
+
+/// Lifecycle of a statecoin, stored in `signer_data.status`.
+/// Rows without a statechain id (unused keys, cancelled deposits) have no status.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum StatecoinStatus {
+    /// The server created the statecoin, the deposit transaction has not been seen yet
+    Initialised,
+    /// The deposit transaction does not have the required number of confirmations yet
+    InMempool,
+    /// The statecoin is owned by the wallet and can be transferred or withdrawn
+    Confirmed,
+    /// A transfer has been started. It is complete once the transfer message is sent to the server.
+    InTransfer,
+    /// The statecoin has been sent to another owner
+    Transferred,
+    /// The withdrawal transaction has been broadcast
+    Withdrawing,
+    /// The withdrawal transaction is confirmed
+    Withdrawn,
+    /// The statecoin was closed on-chain with its latest backup transaction
+    BackupBroadcast,
+    /// The funding output was spent by a transaction that does not pay the wallet, e.g. the backup transaction of a previous owner
+    Expired,
+}
+
+impl StatecoinStatus {
+    pub const ALL: [StatecoinStatus; 9] = [
+        StatecoinStatus::Initialised,
+        StatecoinStatus::InMempool,
+        StatecoinStatus::Confirmed,
+        StatecoinStatus::InTransfer,
+        StatecoinStatus::Transferred,
+        StatecoinStatus::Withdrawing,
+        StatecoinStatus::Withdrawn,
+        StatecoinStatus::BackupBroadcast,
+        StatecoinStatus::Expired,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StatecoinStatus::Initialised => "INITIALISED",
+            StatecoinStatus::InMempool => "IN_MEMPOOL",
+            StatecoinStatus::Confirmed => "CONFIRMED",
+            StatecoinStatus::InTransfer => "IN_TRANSFER",
+            StatecoinStatus::Transferred => "TRANSFERRED",
+            StatecoinStatus::Withdrawing => "WITHDRAWING",
+            StatecoinStatus::Withdrawn => "WITHDRAWN",
+            StatecoinStatus::BackupBroadcast => "BACKUP_BROADCAST",
+            StatecoinStatus::Expired => "EXPIRED",
+        }
+    }
+
+    /// Whether a statecoin in this status may move to `next`. Staying in the same status is always allowed.
+    /// `None` is the status of a row that has no statecoin yet.
+    pub fn can_transition(current: Option<StatecoinStatus>, next: StatecoinStatus) -> bool {
+        use StatecoinStatus::*;
+
+        if current == Some(next) {
+            return true;
+        }
+
+        match current {
+            // Deposits start as initialised, received statecoins as confirmed
+            None => matches!(next, Initialised | Confirmed),
+            // The deposit transaction can be reorganised out of the chain or dropped from the mempool
+            Some(Initialised) | Some(InMempool) => matches!(next, Initialised | InMempool | Confirmed),
+            Some(Confirmed) => matches!(next, Initialised | InMempool | InTransfer | Withdrawing | BackupBroadcast | Expired),
+            // A transfer the server did not accept leaves the statecoin with the wallet
+            Some(InTransfer) => matches!(next, Confirmed | Transferred | Expired),
+            // The withdrawal transaction can be dropped from the mempool or reorganised out of the chain
+            Some(Withdrawing) => matches!(next, Confirmed | Withdrawn | Expired),
+            Some(BackupBroadcast) => matches!(next, Expired),
+            Some(Transferred) | Some(Withdrawn) | Some(Expired) => false,
+        }
+    }
+}
+
+impl FromStr for StatecoinStatus {
+    type Err = CError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "INITIALISED" => Ok(StatecoinStatus::Initialised),
+            "IN_MEMPOOL" => Ok(StatecoinStatus::InMempool),
+            "CONFIRMED" => Ok(StatecoinStatus::Confirmed),
+            "IN_TRANSFER" => Ok(StatecoinStatus::InTransfer),
+            "TRANSFERRED" => Ok(StatecoinStatus::Transferred),
+            "WITHDRAWING" => Ok(StatecoinStatus::Withdrawing),
+            "WITHDRAWN" => Ok(StatecoinStatus::Withdrawn),
+            "BACKUP_BROADCAST" => Ok(StatecoinStatus::BackupBroadcast),
+            "EXPIRED" => Ok(StatecoinStatus::Expired),
+            _ => Err(CError::Database(format!("Unknown statecoin status: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for StatecoinStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+pub async fn get_status(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Result<Option<StatecoinStatus>, CError> {
+
+    let query = "\
+        SELECT status \
+        FROM signer_data \
+        WHERE statechain_id = $1";
+
+    let row = sqlx::query(query)
+        .bind(statechain_id)
+        .fetch_optional(pool)
+        .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Err(CError::UserInput(format!("Statecoin {} not found", statechain_id))),
+    };
+
+    row.try_get::<Option<String>, _>("status")?
+        .map(|status| StatecoinStatus::from_str(&status))
+        .transpose()
+}
+
+/// Fails if the statecoin cannot move from its current status to `next`.
+/// Called before an operation starts, so that nothing is sent to the server or the network for a statecoin in the wrong status.
+pub async fn check_transition(pool: &sqlx::Pool<Sqlite>, statechain_id: &str, next: StatecoinStatus) -> Result<(), CError> {
+
+    let current = get_status(pool, statechain_id).await?;
+
+    if !StatecoinStatus::can_transition(current, next) {
+        let current = current.map(|status| status.as_str()).unwrap_or("NONE");
+        return Err(CError::UserInput(format!("Statecoin {} is {} and cannot become {}", statechain_id, current, next)));
+    }
+
+    Ok(())
+}
+
+/// Moves the statecoin to `next`, if the transition is allowed.
+/// The current status is checked by the update itself, so a concurrent change of status cannot be overwritten.
+pub async fn update_status(pool: &sqlx::Pool<Sqlite>, statechain_id: &str, next: StatecoinStatus) -> Result<(), CError> {
+
+    let sources = StatecoinStatus::ALL.iter()
+        .filter(|status| StatecoinStatus::can_transition(Some(**status), next))
+        .collect::<Vec<_>>();
+
+    // $1 is the new status, $2 the statechain id, $3 whether a row without status can move to `next`
+    let placeholders = (0..sources.len())
+        .map(|index| format!("${}", index + 4))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let query = format!("\
+        UPDATE signer_data \
+        SET status = $1 \
+        WHERE statechain_id = $2 \
+        AND (status IN ({}) OR (status IS NULL AND $3))", placeholders);
+
+    let mut update = sqlx::query(&query)
+        .bind(next.as_str())
+        .bind(statechain_id)
+        .bind(StatecoinStatus::can_transition(None, next));
+
+    for status in sources {
+        update = update.bind(status.as_str());
+    }
+
+    let result = update
+        .execute(pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        // Reports whether the statecoin is missing or in a status that cannot move to `next`
+        check_transition(pool, statechain_id, next).await?;
+        return Err(CError::UserInput(format!("Statecoin {} changed status and cannot become {}", statechain_id, next)));
+    }
+
+    Ok(())
+}
+
+pub struct Statecoin {
+    pub client_pubkey: PublicKey,
+    pub server_pubkey: PublicKey,
+    pub aggregated_pubkey: XOnlyPublicKey,
+    pub p2tr_agg_address: Address,
+    pub funding_txid: Txid,
+    pub funding_vout: u32,
+    pub amount: u64,
+    pub coin_sent: bool,
+    pub coin_withdrawn: bool,
+    pub withdrawal_txid: Option<Txid>,
+    pub status: Option<StatecoinStatus>,
+}
+
+pub async fn get_statecoin(pool: &sqlx::Pool<Sqlite>, statechain_id: &str, network: Network) -> Result<Statecoin, CError> {
+
+    let query = "\
+        SELECT client_pubkey_share, server_pubkey_share, aggregated_pubkey, p2tr_agg_address, \
+        funding_txid, funding_vout, amount, coin_sent, coin_withdrawn, withdrawal_txid, status \
+        FROM signer_data \
+        WHERE statechain_id = $1";
+
+    let row = sqlx::query(query)
+        .bind(statechain_id)
+        .fetch_optional(pool)
+        .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Err(CError::UserInput(format!("Statecoin {} not found", statechain_id))),
+    };
+
+    let funding_txid = match row.try_get::<Option<String>, _>("funding_txid")? {
+        Some(funding_txid) => funding_txid,
+        None => return Err(CError::UserInput(format!("Statecoin {} has not been funded", statechain_id))),
+    };
+
+    let client_pubkey = PublicKey::from_slice(&row.try_get::<Vec<u8>, _>("client_pubkey_share")?)?;
+    let server_pubkey = PublicKey::from_slice(&row.try_get::<Vec<u8>, _>("server_pubkey_share")?)?;
+    let aggregated_pubkey = XOnlyPublicKey::from_slice(&row.try_get::<Vec<u8>, _>("aggregated_pubkey")?)?;
+    let p2tr_agg_address = parse_address(&row.try_get::<String, _>("p2tr_agg_address")?, network)?;
+    let funding_txid = Txid::from_str(&funding_txid).map_err(|e| CError::Database(e.to_string()))?;
+    let funding_vout = row.try_get::<u32, _>("funding_vout")?;
+    let amount = row.try_get::<i64, _>("amount")? as u64;
+    let coin_sent = row.try_get::<bool, _>("coin_sent")?;
+    let coin_withdrawn = row.try_get::<bool, _>("coin_withdrawn")?;
+    let withdrawal_txid = row.try_get::<Option<String>, _>("withdrawal_txid")?
+        .map(|txid| Txid::from_str(&txid).map_err(|e| CError::Database(e.to_string())))
+        .transpose()?;
+    let status = row.try_get::<Option<String>, _>("status")?
+        .map(|status| StatecoinStatus::from_str(&status))
+        .transpose()?;
+
+    Ok(Statecoin {
+        client_pubkey,
+        server_pubkey,
+        aggregated_pubkey,
+        p2tr_agg_address,
+        funding_txid,
+        funding_vout,
+        amount,
+        coin_sent,
+        coin_withdrawn,
+        withdrawal_txid,
+        status,
+    })
+}
+
+/// Secret keys of a statecoin, only read when they are needed to sign
+pub struct StatecoinKeys {
+    pub client_seckey: SecretKey,
+    pub auth_seckey: SecretKey,
+}
+
+pub async fn get_statecoin_keys(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, statechain_id: &str, network: Network) -> Result<StatecoinKeys, CError> {
+
+    let query = "\
+        SELECT client_pubkey_share, agg_key_derivation_path, auth_pubkey, auth_derivation_path \
+        FROM signer_data \
+        WHERE statechain_id = $1";
+
+    let row = sqlx::query(query)
+        .bind(statechain_id)
+        .fetch_optional(pool)
+        .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Err(CError::UserInput(format!("Statecoin {} not found", statechain_id))),
+    };
+
+    let client_pubkey = PublicKey::from_slice(&row.try_get::<Vec<u8>, _>("client_pubkey_share")?)?;
+    let auth_pubkey = PublicKey::from_slice(&row.try_get::<Vec<u8>, _>("auth_pubkey")?)?;
+
+    Ok(StatecoinKeys {
+        client_seckey: key_store::get_secret_key(pool, cipher, &client_pubkey, row.try_get::<Option<String>, _>("agg_key_derivation_path")?.as_deref(), network).await?,
+        auth_seckey: key_store::get_secret_key(pool, cipher, &auth_pubkey, row.try_get::<Option<String>, _>("auth_derivation_path")?.as_deref(), network).await?,
+    })
+}
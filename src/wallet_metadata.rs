@@ -0,0 +1,73 @@
+use std::str::FromStr;
+
+use bitcoin::{Address, Network};
+use sqlx::{Sqlite, Row};
+
+use crate::error::CError;
+
+const NETWORK_KEY: &str = "network";
+
+pub async fn get_value(pool: &sqlx::Pool<Sqlite>, key: &str) -> Result<Option<String>, CError> {
+
+    let row = sqlx::query("SELECT value FROM wallet_metadata WHERE key = $1")
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+
+    match row {
+        Some(row) => Ok(Some(row.try_get::<String, _>("value")?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn set_value(pool: &sqlx::Pool<Sqlite>, key: &str, value: &str) -> Result<(), CError> {
+
+    let query = "\
+        INSERT INTO wallet_metadata (key, value) \
+        VALUES ($1, $2) \
+        ON CONFLICT (key) DO UPDATE SET value = excluded.value";
+
+    let _ = sqlx::query(query)
+        .bind(key)
+        .bind(value)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// The network the wallet was created for, if it has been recorded
+pub async fn get_network(pool: &sqlx::Pool<Sqlite>) -> Result<Option<Network>, CError> {
+    get_value(pool, NETWORK_KEY).await?
+        .map(|network| Network::from_str(&network).map_err(|_| CError::Database(format!("Invalid network in database: {}", network))))
+        .transpose()
+}
+
+/// Fails if the wallet belongs to another network than `network`. A wallet without a recorded network is pinned to `network`,
+/// once its stored addresses are checked against it. Testnet and Signet addresses cannot be told apart, so such a wallet is pinned to the first of them it is opened with.
+pub async fn check_network(pool: &sqlx::Pool<Sqlite>, network: Network) -> Result<(), CError> {
+
+    if let Some(wallet_network) = get_network(pool).await? {
+        if wallet_network != network {
+            return Err(CError::Config(format!("The wallet was created for {}, not {}", wallet_network, network)));
+        }
+        return Ok(());
+    }
+
+    let rows = sqlx::query("SELECT p2tr_agg_address, backup_address FROM signer_data")
+        .fetch_all(pool)
+        .await?;
+
+    for row in rows {
+        for column in ["p2tr_agg_address", "backup_address"] {
+            if let Some(address) = row.try_get::<Option<String>, _>(column)? {
+                let address = Address::from_str(&address).map_err(|e| CError::Database(format!("Invalid address {}: {}", address, e)))?;
+                if !address.is_valid_for_network(network) {
+                    return Err(CError::Config(format!("The wallet holds addresses of another network than {}", network)));
+                }
+            }
+        }
+    }
+
+    set_value(pool, NETWORK_KEY, &network.to_string()).await
+}
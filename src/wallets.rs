@@ -0,0 +1,101 @@
+use std::{fs, io, path::Path};
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::CError;
+
+/// File of the wallets directory holding the name of the selected wallet
+const SELECTED_WALLET_FILE: &str = "selected_wallet";
+
+/// Extension of the wallet databases in the wallets directory
+const WALLET_EXTENSION: &str = "db";
+
+/// Wallet stored in the wallets directory, with its own database and seed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NamedWallet {
+    pub name: String,
+    pub selected: bool,
+}
+
+/// Wallet names are used as file names: letters, digits, '-' and '_' only
+pub fn check_wallet_name(name: &str) -> Result<(), CError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(CError::UserInput(format!("Invalid wallet name {}: use letters, digits, '-' and '_'", name)));
+    }
+    Ok(())
+}
+
+/// Path of the database of the wallet `name`
+pub fn wallet_database_file(wallets_dir: &str, name: &str) -> Result<String, CError> {
+    check_wallet_name(name)?;
+    Ok(Path::new(wallets_dir).join(format!("{}.{}", name, WALLET_EXTENSION)).to_string_lossy().into_owned())
+}
+
+pub fn wallet_exists(wallets_dir: &str, name: &str) -> Result<bool, CError> {
+    Ok(Path::new(&wallet_database_file(wallets_dir, name)?).exists())
+}
+
+/// The wallets of `wallets_dir`, sorted by name
+pub fn list_wallets(wallets_dir: &str) -> Result<Vec<NamedWallet>, CError> {
+
+    let entries = match fs::read_dir(wallets_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(CError::Config(format!("Cannot read the wallets directory {}: {}", wallets_dir, e))),
+    };
+
+    let selected = get_selected_wallet(wallets_dir)?;
+
+    let mut wallets = Vec::<NamedWallet>::new();
+
+    for entry in entries {
+        let path = entry.map_err(|e| CError::Config(format!("Cannot read the wallets directory {}: {}", wallets_dir, e)))?.path();
+
+        if path.extension().and_then(|extension| extension.to_str()) != Some(WALLET_EXTENSION) {
+            continue;
+        }
+
+        if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+            if check_wallet_name(name).is_ok() {
+                wallets.push(NamedWallet {
+                    name: name.to_string(),
+                    selected: selected.as_deref() == Some(name),
+                });
+            }
+        }
+    }
+
+    wallets.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(wallets)
+}
+
+/// The wallet used when none is given with `--wallet`
+pub fn get_selected_wallet(wallets_dir: &str) -> Result<Option<String>, CError> {
+    match fs::read_to_string(Path::new(wallets_dir).join(SELECTED_WALLET_FILE)) {
+        Ok(name) => Ok(Some(name.trim().to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(CError::Config(format!("Cannot read the selected wallet: {}", e))),
+    }
+}
+
+/// Selects the wallet used when none is given with `--wallet`. Without a name, `database_file` is used again.
+pub fn select_wallet(wallets_dir: &str, name: Option<&str>) -> Result<(), CError> {
+
+    let path = Path::new(wallets_dir).join(SELECTED_WALLET_FILE);
+
+    let result = match name {
+        Some(name) => {
+            if !wallet_exists(wallets_dir, name)? {
+                return Err(CError::UserInput(format!("Wallet {} not found. Create it with --wallet {} create-wallet", name, name)));
+            }
+            fs::write(&path, name)
+        },
+        None => match fs::remove_file(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        },
+    };
+
+    result.map_err(|e| CError::Config(format!("Cannot write the selected wallet: {}", e)))
+}
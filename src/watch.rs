@@ -0,0 +1,424 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use bitcoin::{Network, Address, OutPoint, Transaction, Txid};
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, Row};
+
+use crate::{chain::ChainBackend, error::CError, server::StatechainServer, transaction, wallet::{self, StatecoinStatus}, withdraw};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum WatchEventKind {
+    /// The latest backup transaction was accepted by the backend
+    BackupBroadcast,
+    /// The latest backup transaction is due but the backend refused it
+    BackupBroadcastFailed,
+    /// The funding outpoint was spent by the latest backup transaction
+    SpentByBackup,
+    /// The funding outpoint was spent by the withdrawal transaction
+    SpentByWithdrawal,
+    /// The funding outpoint was spent by the backup transaction of a previous owner
+    SpentByPreviousBackup,
+    /// The funding outpoint was spent by a transaction the wallet does not know, or the backend cannot find the spending transaction
+    SpentUnexpectedly,
+    /// The withdrawal transaction is no longer in the mempool or the chain. It is broadcast again,
+    /// or, for a withdrawal made before the transaction was stored, the statecoin is owned again.
+    WithdrawalDropped,
+    /// The server was told with `withdraw/complete` that the confirmed withdrawal closed the statecoin
+    WithdrawalCompleted,
+    /// `withdraw/complete` failed, it is sent again at the next block
+    WithdrawalCompleteFailed,
+}
+
+impl WatchEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WatchEventKind::BackupBroadcast => "BACKUP_BROADCAST",
+            WatchEventKind::BackupBroadcastFailed => "BACKUP_BROADCAST_FAILED",
+            WatchEventKind::SpentByBackup => "SPENT_BY_BACKUP",
+            WatchEventKind::SpentByWithdrawal => "SPENT_BY_WITHDRAWAL",
+            WatchEventKind::SpentByPreviousBackup => "SPENT_BY_PREVIOUS_BACKUP",
+            WatchEventKind::SpentUnexpectedly => "SPENT_UNEXPECTEDLY",
+            WatchEventKind::WithdrawalDropped => "WITHDRAWAL_DROPPED",
+            WatchEventKind::WithdrawalCompleted => "WITHDRAWAL_COMPLETED",
+            WatchEventKind::WithdrawalCompleteFailed => "WITHDRAWAL_COMPLETE_FAILED",
+        }
+    }
+}
+
+impl FromStr for WatchEventKind {
+    type Err = CError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "BACKUP_BROADCAST" => Ok(WatchEventKind::BackupBroadcast),
+            "BACKUP_BROADCAST_FAILED" => Ok(WatchEventKind::BackupBroadcastFailed),
+            "SPENT_BY_BACKUP" => Ok(WatchEventKind::SpentByBackup),
+            "SPENT_BY_WITHDRAWAL" => Ok(WatchEventKind::SpentByWithdrawal),
+            "SPENT_BY_PREVIOUS_BACKUP" => Ok(WatchEventKind::SpentByPreviousBackup),
+            "SPENT_UNEXPECTEDLY" => Ok(WatchEventKind::SpentUnexpectedly),
+            "WITHDRAWAL_DROPPED" => Ok(WatchEventKind::WithdrawalDropped),
+            "WITHDRAWAL_COMPLETED" => Ok(WatchEventKind::WithdrawalCompleted),
+            "WITHDRAWAL_COMPLETE_FAILED" => Ok(WatchEventKind::WithdrawalCompleteFailed),
+            _ => Err(CError::Database(format!("Unknown watch event: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for WatchEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WatchEvent {
+    pub statechain_id: String,
+    pub event: WatchEventKind,
+    pub txid: Option<Txid>,
+    /// Chain tip when the event was recorded, or confirmation height of the spending transaction (0 in the mempool)
+    pub block_height: u32,
+    pub message: Option<String>,
+}
+
+/// When the latest backup transaction of a statecoin is broadcast
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchPolicy {
+    /// Blocks kept between the broadcast and the moment the backup transaction of the previous owner becomes final.
+    /// If `None`, the backup transaction is broadcast as soon as it is final.
+    pub safety_margin: Option<u32>,
+}
+
+impl WatchPolicy {
+
+    /// Height from which the backup transaction with locktime `lock_time` is broadcast.
+    /// `previous_lock_time` is the locktime of the backup transaction of the previous owner, if any.
+    pub fn broadcast_height(&self, lock_time: u32, previous_lock_time: Option<u32>) -> u32 {
+        match (self.safety_margin, previous_lock_time) {
+            (Some(safety_margin), Some(previous_lock_time)) => previous_lock_time.saturating_sub(safety_margin).max(lock_time),
+            _ => lock_time,
+        }
+    }
+}
+
+/// Follows the chain tip and checks every statecoin at each new block.
+/// Runs until a non blockchain error occurs, or after the first check if `once` is set. Returns the events recorded during the run.
+/// `on_update` is called with each event as it is recorded and, when running continuously, with the blockchain backend errors that are retried.
+pub async fn execute(pool: &sqlx::Pool<Sqlite>, chain: &dyn ChainBackend, server: &StatechainServer, policy: WatchPolicy, poll_interval: Duration, once: bool, network: Network, on_update: &mut dyn FnMut(Result<&WatchEvent, &CError>)) -> Result<Vec<WatchEvent>, CError> {
+
+    let mut events = Vec::<WatchEvent>::new();
+    let mut last_tip: Option<u32> = None;
+
+    loop {
+        match check_new_tip(pool, chain, server, policy, last_tip, network).await {
+            Ok((tip, new_events)) => {
+                for event in &new_events {
+                    on_update(Ok(event));
+                }
+                events.extend(new_events);
+                last_tip = Some(tip);
+            },
+            Err(err @ CError::Chain(_)) if !once => on_update(Err(&err)),
+            Err(err) => return Err(err),
+        }
+
+        if once {
+            return Ok(events);
+        }
+
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+/// Checks every watched statecoin if the tip has changed since `last_tip`
+async fn check_new_tip(pool: &sqlx::Pool<Sqlite>, chain: &dyn ChainBackend, server: &StatechainServer, policy: WatchPolicy, last_tip: Option<u32>, network: Network) -> Result<(u32, Vec<WatchEvent>), CError> {
+
+    let tip = chain.get_tip_height().await?;
+
+    let mut events = Vec::<WatchEvent>::new();
+
+    if last_tip != Some(tip) {
+        for statechain_id in get_watched_statechain_ids(pool).await? {
+            if let Some(event) = check_statecoin(pool, chain, &statechain_id, tip, policy, network).await? {
+                events.push(event);
+            }
+        }
+
+        for statechain_id in get_uncompleted_withdrawal_ids(pool).await? {
+            if let Some(event) = complete_withdrawal(pool, server, &statechain_id, tip).await? {
+                events.push(event);
+            }
+        }
+    }
+
+    Ok((tip, events))
+}
+
+/// Statecoins whose withdrawal is confirmed but not yet acknowledged by the server
+async fn get_uncompleted_withdrawal_ids(pool: &sqlx::Pool<Sqlite>) -> Result<Vec<String>, CError> {
+
+    let query = "\
+        SELECT statechain_id \
+        FROM signer_data \
+        WHERE status = $1 \
+        AND withdrawal_signed_statechain_id IS NOT NULL \
+        AND statechain_id NOT IN (SELECT statechain_id FROM watch_event WHERE event = $2) \
+        ORDER BY created_at";
+
+    let rows = sqlx::query(query)
+        .bind(StatecoinStatus::Withdrawn.as_str())
+        .bind(WatchEventKind::WithdrawalCompleted.as_str())
+        .fetch_all(pool)
+        .await?;
+
+    rows.iter().map(|row| Ok(row.try_get::<String, _>("statechain_id")?)).collect()
+}
+
+/// Sends `withdraw/complete`. A server error is recorded and the request is sent again at the next block.
+async fn complete_withdrawal(pool: &sqlx::Pool<Sqlite>, server: &StatechainServer, statechain_id: &str, tip: u32) -> Result<Option<WatchEvent>, CError> {
+
+    let (event, message) = match withdraw::complete(pool, server, statechain_id).await {
+        Ok(()) => (WatchEventKind::WithdrawalCompleted, None),
+        Err(err @ (CError::ServerHttp(_) | CError::ServerStatus { .. })) => (WatchEventKind::WithdrawalCompleteFailed, Some(err.to_string())),
+        Err(err) => return Err(err),
+    };
+
+    let event = WatchEvent { statechain_id: statechain_id.to_string(), event, txid: None, block_height: tip, message };
+
+    record_event(pool, event).await
+}
+
+/// Statecoins owned by the wallet, or being withdrawn, whose funding outpoint has not been spent in a confirmed transaction
+async fn get_watched_statechain_ids(pool: &sqlx::Pool<Sqlite>) -> Result<Vec<String>, CError> {
+
+    let query = "\
+        SELECT statechain_id \
+        FROM signer_data \
+        WHERE status IN ($1, $2, $3) \
+        AND statechain_id IN (SELECT statechain_id FROM backup_transaction) \
+        AND statechain_id NOT IN (\
+            SELECT statechain_id FROM watch_event \
+            WHERE event IN ($4, $5, $6, $7) AND block_height > 0) \
+        ORDER BY created_at";
+
+    let rows = sqlx::query(query)
+        .bind(StatecoinStatus::Confirmed.as_str())
+        .bind(StatecoinStatus::Withdrawing.as_str())
+        .bind(StatecoinStatus::BackupBroadcast.as_str())
+        .bind(WatchEventKind::SpentByBackup.as_str())
+        .bind(WatchEventKind::SpentByWithdrawal.as_str())
+        .bind(WatchEventKind::SpentByPreviousBackup.as_str())
+        .bind(WatchEventKind::SpentUnexpectedly.as_str())
+        .fetch_all(pool)
+        .await?;
+
+    rows.iter().map(|row| Ok(row.try_get::<String, _>("statechain_id")?)).collect()
+}
+
+async fn check_statecoin(pool: &sqlx::Pool<Sqlite>, chain: &dyn ChainBackend, statechain_id: &str, tip: u32, policy: WatchPolicy, network: Network) -> Result<Option<WatchEvent>, CError> {
+
+    let statecoin = wallet::get_statecoin(pool, statechain_id, network).await?;
+    let funding_outpoint = OutPoint { txid: statecoin.funding_txid, vout: statecoin.funding_vout };
+
+    let backup_txs = transaction::get_backup_transactions(pool, statechain_id).await?.iter()
+        .map(|backup_tx| Ok(bitcoin::consensus::deserialize::<Transaction>(&hex::decode(&backup_tx.tx)?)?))
+        .collect::<Result<Vec<Transaction>, CError>>()?;
+
+    let latest_tx = match backup_txs.last() {
+        Some(latest_tx) => latest_tx,
+        None => return Ok(None),
+    };
+
+    let funding_unspent = chain.list_unspent(&statecoin.p2tr_agg_address).await?.iter()
+        .any(|utxo| utxo.txid == funding_outpoint.txid && utxo.vout == funding_outpoint.vout);
+
+    if !funding_unspent {
+        let event = match find_spending_tx(chain, &statecoin.p2tr_agg_address, &funding_outpoint).await? {
+            Some((txid, height)) => {
+                let kind = if Some(txid) == statecoin.withdrawal_txid {
+                    WatchEventKind::SpentByWithdrawal
+                } else if txid == latest_tx.txid() {
+                    WatchEventKind::SpentByBackup
+                } else if backup_txs.iter().any(|tx| tx.txid() == txid) {
+                    WatchEventKind::SpentByPreviousBackup
+                } else {
+                    WatchEventKind::SpentUnexpectedly
+                };
+                WatchEvent { statechain_id: statechain_id.to_string(), event: kind, txid: Some(txid), block_height: height, message: None }
+            },
+            // Some backends only index unspent outputs, so the spending transaction cannot be identified.
+            // Without the spender the statecoin may still be ours (e.g. spent by the withdrawal), so its status is left unchanged.
+            None => {
+                let event = WatchEvent {
+                    statechain_id: statechain_id.to_string(),
+                    event: WatchEventKind::SpentUnexpectedly,
+                    txid: None,
+                    block_height: tip,
+                    message: Some("The spending transaction could not be found, the status is unchanged".to_string()),
+                };
+                return record_event(pool, event).await;
+            },
+        };
+
+        // The statecoin is closed once the spend is confirmed
+        if event.block_height > 0 {
+            let status = match event.event {
+                WatchEventKind::SpentByWithdrawal => StatecoinStatus::Withdrawn,
+                WatchEventKind::SpentByBackup if statecoin.status != Some(StatecoinStatus::Withdrawing) => StatecoinStatus::BackupBroadcast,
+                _ => StatecoinStatus::Expired,
+            };
+            wallet::update_status(pool, statechain_id, status).await?;
+        }
+
+        return record_event(pool, event).await;
+    }
+
+    // A withdrawal is only waited for, its backup transaction must not be broadcast
+    if statecoin.status == Some(StatecoinStatus::Withdrawing) {
+
+        // Only a transaction the backend definitely does not know is dropped, not one it failed to look up
+        let withdrawal_known = match statecoin.withdrawal_txid {
+            Some(txid) => chain.find_transaction(&txid).await?.is_some(),
+            None => false,
+        };
+
+        if withdrawal_known {
+            return Ok(None);
+        }
+
+        let message = match withdraw::get_stored_withdrawal(pool, statechain_id).await? {
+            // Dropped from the mempool or reorganised out of the chain, the server signature is still valid
+            Some(withdrawal) => match chain.broadcast(&bitcoin::consensus::encode::serialize(&withdrawal.tx)).await {
+                Ok(_) => "The withdrawal transaction was no longer in the mempool or the chain and was broadcast again".to_string(),
+                Err(err) => format!("The withdrawal transaction is no longer in the mempool or the chain and could not be broadcast again: {}", err),
+            },
+            // Withdrawn before the transaction was stored, the backup transaction protects the statecoin again
+            None => {
+                withdraw::reset_coin_withdrawn(pool, statechain_id).await?;
+                wallet::update_status(pool, statechain_id, StatecoinStatus::Confirmed).await?;
+                "The withdrawal transaction is no longer in the mempool or the chain, the statecoin can be withdrawn again".to_string()
+            },
+        };
+
+        let event = WatchEvent {
+            statechain_id: statechain_id.to_string(),
+            event: WatchEventKind::WithdrawalDropped,
+            txid: statecoin.withdrawal_txid,
+            block_height: tip,
+            message: Some(message),
+        };
+
+        return record_event(pool, event).await;
+    }
+
+    let lock_time = latest_tx.lock_time.to_consensus_u32();
+    let previous_lock_time = backup_txs.iter().rev().nth(1).map(|tx| tx.lock_time.to_consensus_u32());
+
+    if tip < policy.broadcast_height(lock_time, previous_lock_time) {
+        return Ok(None);
+    }
+
+    let txid = latest_tx.txid();
+
+    let event = match chain.broadcast(&bitcoin::consensus::encode::serialize(latest_tx)).await {
+        Ok(_) => {
+            wallet::update_status(pool, statechain_id, StatecoinStatus::BackupBroadcast).await?;
+            WatchEvent { statechain_id: statechain_id.to_string(), event: WatchEventKind::BackupBroadcast, txid: Some(txid), block_height: tip, message: None }
+        },
+        Err(err) => {
+            // Already in the mempool from a previous block
+            if chain.get_transaction(&txid).await.is_ok() {
+                return Ok(None);
+            }
+            WatchEvent { statechain_id: statechain_id.to_string(), event: WatchEventKind::BackupBroadcastFailed, txid: Some(txid), block_height: tip, message: Some(err.to_string()) }
+        },
+    };
+
+    record_event(pool, event).await
+}
+
+/// Looks for the transaction spending `outpoint` in the history of `address`. Returns its txid and confirmation height.
+async fn find_spending_tx(chain: &dyn ChainBackend, address: &Address, outpoint: &OutPoint) -> Result<Option<(Txid, u32)>, CError> {
+
+    for item in chain.get_address_history(address).await? {
+
+        if item.txid == outpoint.txid {
+            continue;
+        }
+
+        let tx = chain.get_transaction(&item.txid).await?;
+
+        if tx.input.iter().any(|input| input.previous_output == *outpoint) {
+            return Ok(Some((item.txid, item.height)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Stores the event unless the same one has already been recorded. Returns it if it is new.
+async fn record_event(pool: &sqlx::Pool<Sqlite>, event: WatchEvent) -> Result<Option<WatchEvent>, CError> {
+
+    // A spend is recorded once in the mempool and once confirmed, a broadcast once per statecoin
+    let query = "\
+        SELECT COUNT(*) AS count \
+        FROM watch_event \
+        WHERE statechain_id = $1 AND event = $2 AND txid IS $3 AND ($4 OR block_height = $5)";
+
+    let row = sqlx::query(query)
+        .bind(&event.statechain_id)
+        .bind(event.event.as_str())
+        .bind(event.txid.map(|txid| txid.to_string()))
+        .bind(event.event == WatchEventKind::BackupBroadcast)
+        .bind(event.block_height)
+        .fetch_one(pool)
+        .await?;
+
+    if row.try_get::<i64, _>("count")? > 0 {
+        return Ok(None);
+    }
+
+    let query = "\
+        INSERT INTO watch_event (statechain_id, event, txid, block_height, message) \
+        VALUES ($1, $2, $3, $4, $5)";
+
+    let _ = sqlx::query(query)
+        .bind(&event.statechain_id)
+        .bind(event.event.as_str())
+        .bind(event.txid.map(|txid| txid.to_string()))
+        .bind(event.block_height)
+        .bind(&event.message)
+        .execute(pool)
+        .await?;
+
+    Ok(Some(event))
+}
+
+/// Events recorded for `statechain_id`, or for every statecoin if it is `None`, oldest first
+pub async fn get_events(pool: &sqlx::Pool<Sqlite>, statechain_id: Option<&str>) -> Result<Vec<WatchEvent>, CError> {
+
+    let query = "\
+        SELECT statechain_id, event, txid, block_height, message \
+        FROM watch_event \
+        WHERE $1 IS NULL OR statechain_id = $1 \
+        ORDER BY rowid";
+
+    let rows = sqlx::query(query)
+        .bind(statechain_id)
+        .fetch_all(pool)
+        .await?;
+
+    rows.iter().map(|row| {
+        let event = WatchEventKind::from_str(&row.try_get::<String, _>("event")?)?;
+        let txid = row.try_get::<Option<String>, _>("txid")?
+            .map(|txid| Txid::from_str(&txid).map_err(|e| CError::Database(e.to_string())))
+            .transpose()?;
+        Ok(WatchEvent {
+            statechain_id: row.try_get::<String, _>("statechain_id")?,
+            event,
+            txid,
+            block_height: row.try_get::<u32, _>("block_height")?,
+            message: row.try_get::<Option<String>, _>("message")?,
+        })
+    }).collect()
+}
@@ -0,0 +1,155 @@
+use bitcoin::{Network, Address, Transaction, TxOut, Txid, OutPoint, hashes::sha256, secp256k1};
+use secp256k1_zkp::{Secp256k1, Message};
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, Row};
+
+use crate::{chain::ChainBackend, encryption::WalletCipher, error::CError, server::{StatechainServer, WithdrawCompleteRequestPayload}, transaction, wallet::{self, StatecoinStatus}};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WithdrawResult {
+    pub txid: Txid,
+}
+
+/// Withdrawal transaction stored when it is broadcast
+pub struct StoredWithdrawal {
+    pub tx: Transaction,
+    pub signed_statechain_id: String,
+}
+
+/// Broadcasts a transaction spending the statecoin to `to_address`. The statecoin stays WITHDRAWING until `watch` sees the
+/// transaction confirm. `watch` also broadcasts it again if it leaves the mempool, and sends `withdraw/complete` once it is confirmed.
+
+pub async fn execute(pool: &sqlx::Pool<Sqlite>, cipher: &WalletCipher, chain: &dyn ChainBackend, server: &StatechainServer, statechain_id: &str, to_address: &Address, fee_rate_sats_per_byte: u64, network: Network) -> Result<WithdrawResult, CError> {
+
+    let statecoin = wallet::get_statecoin(pool, statechain_id, network).await?;
+
+    if statecoin.coin_sent {
+        return Err(CError::UserInput(format!("Statecoin {} has already been sent", statechain_id)));
+    }
+
+    if statecoin.coin_withdrawn {
+        return Err(CError::UserInput(format!("Statecoin {} has already been withdrawn", statechain_id)));
+    }
+
+    wallet::check_transition(pool, statechain_id, StatecoinStatus::Withdrawing).await?;
+
+    let funding_outpoint = OutPoint { txid: statecoin.funding_txid, vout: statecoin.funding_vout };
+
+    let absolute_fee = transaction::estimate_vsize(funding_outpoint, &[TxOut { value: statecoin.amount, script_pubkey: to_address.script_pubkey() }]) * fee_rate_sats_per_byte;
+
+    if absolute_fee >= statecoin.amount {
+        return Err(CError::UserInput("Fee is larger than the statecoin amount".to_string()));
+    }
+
+    let amount_out = statecoin.amount - absolute_fee;
+
+    let tx_out = TxOut { value: amount_out, script_pubkey: to_address.script_pubkey() };
+
+    let keys = wallet::get_statecoin_keys(pool, cipher, statechain_id, network).await?;
+
+    let secp = Secp256k1::new();
+
+    let keypair = secp256k1::KeyPair::from_seckey_slice(&secp, keys.auth_seckey.as_ref())?;
+    let msg = Message::from_hashed_data::<sha256::Hash>(statechain_id.to_string().as_bytes());
+    let signed_statechain_id = secp.sign_schnorr(&msg, &keypair);
+
+    // A block height of zero means the transaction is not timelocked
+    let (tx, _, _) = transaction::create(
+        server,
+        0,
+        statechain_id,
+        &signed_statechain_id,
+        &keys.client_seckey,
+        &statecoin.client_pubkey,
+        &statecoin.server_pubkey,
+        statecoin.funding_txid,
+        statecoin.funding_vout,
+        &statecoin.aggregated_pubkey,
+        &statecoin.p2tr_agg_address.script_pubkey(),
+        statecoin.amount,
+        tx_out,
+        false).await?;
+
+    let tx_bytes = bitcoin::consensus::encode::serialize(&tx);
+
+    let txid = chain.broadcast(&tx_bytes).await?;
+
+    update_coin_withdrawn(pool, statechain_id, &txid, &tx_bytes, &signed_statechain_id.to_string()).await?;
+    wallet::update_status(pool, statechain_id, StatecoinStatus::Withdrawing).await?;
+
+    Ok(WithdrawResult { txid })
+}
+
+async fn update_coin_withdrawn(pool: &sqlx::Pool<Sqlite>, statechain_id: &str, txid: &Txid, tx_bytes: &[u8], signed_statechain_id: &str) -> Result<(), CError> {
+
+    let query = "\
+        UPDATE signer_data \
+        SET coin_withdrawn = TRUE, withdrawal_txid = $1, withdrawal_tx = $2, withdrawal_signed_statechain_id = $3 \
+        WHERE statechain_id = $4";
+
+    let _ = sqlx::query(query)
+        .bind(&txid.to_string())
+        .bind(tx_bytes)
+        .bind(signed_statechain_id)
+        .bind(statechain_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// The withdrawal transaction of the statecoin, `None` if it was withdrawn before the transaction was stored
+pub async fn get_stored_withdrawal(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Result<Option<StoredWithdrawal>, CError> {
+
+    let query = "\
+        SELECT withdrawal_tx, withdrawal_signed_statechain_id \
+        FROM signer_data \
+        WHERE statechain_id = $1";
+
+    let row = sqlx::query(query)
+        .bind(statechain_id)
+        .fetch_one(pool)
+        .await?;
+
+    let tx_bytes = row.try_get::<Option<Vec<u8>>, _>("withdrawal_tx")?;
+    let signed_statechain_id = row.try_get::<Option<String>, _>("withdrawal_signed_statechain_id")?;
+
+    match (tx_bytes, signed_statechain_id) {
+        (Some(tx_bytes), Some(signed_statechain_id)) => Ok(Some(StoredWithdrawal {
+            tx: bitcoin::consensus::deserialize(&tx_bytes)?,
+            signed_statechain_id,
+        })),
+        _ => Ok(None),
+    }
+}
+
+/// Tells the server the statecoin is withdrawn, so that it stops co-signing for it.
+/// Sent once the withdrawal transaction is confirmed: before that, the statecoin may still need its backup transaction.
+pub async fn complete(pool: &sqlx::Pool<Sqlite>, server: &StatechainServer, statechain_id: &str) -> Result<(), CError> {
+
+    let withdrawal = get_stored_withdrawal(pool, statechain_id).await?
+        .ok_or(CError::Database(format!("Statecoin {} has no stored withdrawal", statechain_id)))?;
+
+    let withdraw_complete_request_payload = WithdrawCompleteRequestPayload {
+        statechain_id: statechain_id.to_string(),
+        signed_statechain_id: withdrawal.signed_statechain_id,
+    };
+
+    server.withdraw_complete(&withdraw_complete_request_payload).await
+}
+
+/// Lets the statecoin be withdrawn again once its withdrawal transaction has been dropped
+pub async fn reset_coin_withdrawn(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Result<(), CError> {
+
+    let query = "\
+        UPDATE signer_data \
+        SET coin_withdrawn = FALSE, withdrawal_txid = NULL \
+        WHERE statechain_id = $1";
+
+    let _ = sqlx::query(query)
+        .bind(statechain_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::path::Path;
+
+use bitcoin::Network;
+use mercury_client::{CError, Config, config::ConfigOverrides};
+
+fn no_selected_wallet(_wallets_dir: &str) -> Result<Option<String>, CError> {
+    Ok(None)
+}
+
+fn treasury_selected(_wallets_dir: &str) -> Result<Option<String>, CError> {
+    Ok(Some("treasury".to_string()))
+}
+
+fn database_file(wallets_dir: &str, name: &str) -> String {
+    Path::new(wallets_dir).join(format!("{}.db", name)).to_string_lossy().into_owned()
+}
+
+#[test]
+fn defaults_apply_without_sources() {
+
+    let config = Config::from_sources(Vec::new(), no_selected_wallet).unwrap();
+
+    assert_eq!(config.network, Network::Signet);
+    assert_eq!(config.database_file, "wallet.db");
+    assert_eq!(config.min_confirmations, 1);
+    assert_eq!(config.watch_safety_margin, None);
+}
+
+#[test]
+fn command_line_overrides_environment_overrides_file() {
+
+    let file = ConfigOverrides::from_toml("\
+        network = \"regtest\"\n\
+        min_confirmations = 3\n\
+        watch_interval = 10\n\
+        statechain_entity = \"http://file:8000\"\n").unwrap();
+
+    let env = ConfigOverrides {
+        min_confirmations: Some(2),
+        statechain_entity: Some("http://env:8000".to_string()),
+        ..ConfigOverrides::default()
+    };
+
+    let cli = ConfigOverrides {
+        statechain_entity: Some("http://cli:8000".to_string()),
+        ..ConfigOverrides::default()
+    };
+
+    let config = Config::from_sources(vec![file, env, cli], no_selected_wallet).unwrap();
+
+    assert_eq!(config.network, Network::Regtest);
+    assert_eq!(config.watch_interval, 10);
+    assert_eq!(config.min_confirmations, 2);
+    assert_eq!(config.statechain_entity, "http://cli:8000");
+    // Untouched by every source
+    assert_eq!(config.deposit_timeout, 3600);
+}
+
+#[test]
+fn invalid_values_are_config_errors() {
+
+    assert!(matches!(ConfigOverrides::from_toml("min_confirmations = \"two\""), Err(CError::Config(_))));
+
+    let env = ConfigOverrides { network: Some("moonnet".to_string()), ..ConfigOverrides::default() };
+    assert!(matches!(Config::from_sources(vec![env], no_selected_wallet), Err(CError::Config(_))));
+
+    let env = ConfigOverrides { chain_backend: Some("carrier-pigeon".to_string()), ..ConfigOverrides::default() };
+    assert!(Config::from_sources(vec![env], no_selected_wallet).is_err());
+}
+
+#[test]
+fn selected_wallet_is_used_only_without_explicit_database() {
+
+    let config = Config::from_sources(Vec::new(), treasury_selected).unwrap();
+    assert_eq!(config.wallet.as_deref(), Some("treasury"));
+    assert_eq!(config.database_file, database_file("wallets", "treasury"));
+
+    // A database file from any source wins over the selected wallet
+    for index in 0..3 {
+        let mut sources = vec![ConfigOverrides::default(), ConfigOverrides::default(), ConfigOverrides::default()];
+        sources[index].database_file = Some("explicit.db".to_string());
+
+        let config = Config::from_sources(sources, treasury_selected).unwrap();
+        assert_eq!(config.wallet, None);
+        assert_eq!(config.database_file, "explicit.db");
+    }
+
+    // An explicit wallet wins over both, in its wallets directory
+    let file = ConfigOverrides { database_file: Some("explicit.db".to_string()), ..ConfigOverrides::default() };
+    let cli = ConfigOverrides {
+        wallet: Some("operations".to_string()),
+        wallets_dir: Some("my-wallets".to_string()),
+        ..ConfigOverrides::default()
+    };
+
+    let config = Config::from_sources(vec![file, cli], treasury_selected).unwrap();
+    assert_eq!(config.wallet.as_deref(), Some("operations"));
+    assert_eq!(config.database_file, database_file("my-wallets", "operations"));
+
+    let cli = ConfigOverrides { wallet: Some("../treasury".to_string()), ..ConfigOverrides::default() };
+    assert!(matches!(Config::from_sources(vec![cli], no_selected_wallet), Err(CError::UserInput(_))));
+}
@@ -0,0 +1,146 @@
+use std::{str::FromStr, time::Duration};
+
+use bitcoin::{Network, TxOut, Txid};
+use mercury_client::{CError, encryption::{self, KdfParams, WalletCipher}, deposit, token, transaction, mock_server::MockServer, server::{ServerConfig, StatechainServer}};
+use sqlx::{Sqlite, sqlite::SqlitePoolOptions};
+
+const FUNDING_TXID: &str = "6a1e4bbd0a5e3f39cc1f26a2ff0d3a50e4bd0ac3a1b8e69b0c2b6ed7d5f6f7a1";
+
+const PASSPHRASE: &str = "correct horse battery staple";
+
+/// Cheap Argon2 parameters, the defaults take too long for tests
+const TEST_KDF_PARAMS: KdfParams = KdfParams { memory_kib: 8, iterations: 1, parallelism: 1 };
+
+async fn setup() -> (MockServer, StatechainServer, sqlx::Pool<Sqlite>, WalletCipher) {
+
+    let mock = MockServer::start(ServerConfig { initlock: 1000, interval: 10 }).await.unwrap();
+    let server = StatechainServer::new(&mock.endpoint()).unwrap();
+
+    // A single connection, otherwise every connection opens its own in-memory database
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+    let cipher = encryption::create_wallet(&pool, PASSPHRASE, TEST_KDF_PARAMS, "").await.unwrap();
+
+    (mock, server, pool, cipher)
+}
+
+#[tokio::test]
+async fn deposit_and_sign_backup_transaction() {
+
+    let (mock, server, pool, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    let (statechain_id, client_seckey, client_pubkey, backup_address, server_pubkey, signed_statechain_id) =
+        deposit::init(&pool, &cipher, &server, mock.paid_token(), amount, network).await.unwrap();
+
+    let (aggregated_pubkey, p2tr_agg_address) = deposit::create_agg_pub_key(&pool, &client_pubkey, &server_pubkey, network).await.unwrap();
+
+    let tx_out = TxOut { value: amount - 1000, script_pubkey: backup_address.script_pubkey() };
+
+    // create() checks the aggregated signature against the funding output before returning
+    let (tx, _, _) = transaction::create(
+        &server,
+        1000,
+        &statechain_id,
+        &signed_statechain_id,
+        &client_seckey,
+        &client_pubkey,
+        &server_pubkey,
+        Txid::from_str(FUNDING_TXID).unwrap(),
+        0,
+        &aggregated_pubkey,
+        &p2tr_agg_address.script_pubkey(),
+        amount,
+        tx_out,
+        false).await.unwrap();
+
+    assert_eq!(tx.lock_time.to_consensus_u32(), 1000);
+    assert_eq!(mock.num_sigs(&statechain_id), Some(1));
+}
+
+#[tokio::test]
+async fn server_config_is_served() {
+
+    let (_mock, server, _pool, _cipher) = setup().await;
+
+    let config = server.get_config().await.unwrap();
+
+    assert_eq!(config.initlock, 1000);
+    assert_eq!(config.interval, 10);
+}
+
+#[tokio::test]
+async fn signing_unknown_statecoin_is_rejected() {
+
+    let (mock, server, pool, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    let (_, client_seckey, client_pubkey, backup_address, server_pubkey, signed_statechain_id) =
+        deposit::init(&pool, &cipher, &server, mock.paid_token(), amount, network).await.unwrap();
+
+    let (aggregated_pubkey, p2tr_agg_address) = deposit::create_agg_pub_key(&pool, &client_pubkey, &server_pubkey, network).await.unwrap();
+
+    let tx_out = TxOut { value: amount - 1000, script_pubkey: backup_address.script_pubkey() };
+
+    let result = transaction::create(
+        &server,
+        1000,
+        "unknown",
+        &signed_statechain_id,
+        &client_seckey,
+        &client_pubkey,
+        &server_pubkey,
+        Txid::from_str(FUNDING_TXID).unwrap(),
+        0,
+        &aggregated_pubkey,
+        &p2tr_agg_address.script_pubkey(),
+        amount,
+        tx_out,
+        false).await;
+
+    assert!(matches!(result, Err(CError::ServerStatus { status: 404, .. })));
+}
+
+#[tokio::test]
+async fn deposit_token_must_be_paid_and_unspent() {
+
+    let (mock, server, pool, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    let token = token::new_token(&pool, &server).await.unwrap();
+    assert!(!token.confirmed);
+
+    let result = token::validate_unspent(&pool, &server, &token.token_id).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+
+    let result = token::wait_for_payment(&pool, &server, &token.token_id, Duration::ZERO).await;
+    assert!(matches!(result, Err(CError::Timeout(_))));
+
+    mock.pay_token(&token.token_id);
+
+    token::wait_for_payment(&pool, &server, &token.token_id, Duration::ZERO).await.unwrap();
+    token::validate_unspent(&pool, &server, &token.token_id).await.unwrap();
+
+    deposit::init(&pool, &cipher, &server, token.token_id, amount, network).await.unwrap();
+    token::mark_spent(&pool, &token.token_id).await.unwrap();
+
+    let result = token::validate_unspent(&pool, &server, &token.token_id).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+
+    let tokens = token::list_tokens(&pool).await.unwrap();
+    assert_eq!(tokens.len(), 1);
+    assert!(tokens[0].confirmed && tokens[0].spent);
+    assert_eq!(tokens[0].lightning_invoice, token.lightning_invoice);
+}
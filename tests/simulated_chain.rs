@@ -0,0 +1,811 @@
+use std::{str::FromStr, time::Duration};
+
+use bitcoin::{Address, Network, TxOut, secp256k1::Secp256k1};
+use mercury_client::{CError, account, encryption::{self, KdfParams, WalletCipher}, ChainBackend, bump_backup, deposit::{self, DepositProgress}, key_derivation::{self, SeedDerivation, WalletSeed}, restore, send_backup::{self, AddressInfo}, statecoin_info, transaction, transfer_receiver, transfer_sender, verify_backups, wallet::{self, StatecoinStatus}, watch::{self, WatchEventKind, WatchPolicy}, withdraw, mock_server::MockServer, server::{ServerConfig, StatechainServer}, simulated_chain::SimulatedChain};
+use sqlx::{Sqlite, sqlite::SqlitePoolOptions};
+
+const INITLOCK: u32 = 10;
+
+const PASSPHRASE: &str = "correct horse battery staple";
+
+/// Cheap Argon2 parameters, the defaults take too long for tests
+const TEST_KDF_PARAMS: KdfParams = KdfParams { memory_kib: 8, iterations: 1, parallelism: 1 };
+
+async fn new_wallet() -> (sqlx::Pool<Sqlite>, WalletCipher) {
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+    let cipher = encryption::create_wallet(&pool, PASSPHRASE, TEST_KDF_PARAMS, "").await.unwrap();
+
+    (pool, cipher)
+}
+
+async fn setup() -> (MockServer, StatechainServer, sqlx::Pool<Sqlite>, SimulatedChain, WalletCipher) {
+
+    let mock = MockServer::start(ServerConfig { initlock: INITLOCK, interval: 2 }).await.unwrap();
+    let server = StatechainServer::new(&mock.endpoint()).unwrap();
+
+    let (pool, cipher) = new_wallet().await;
+
+    (mock, server, pool, SimulatedChain::new(), cipher)
+}
+
+/// Starts a deposit of `amount` and lets it time out waiting for the funds. Returns its statechain id and deposit address.
+async fn awaiting_deposit(mock: &MockServer, server: &StatechainServer, pool: &sqlx::Pool<Sqlite>, chain: &SimulatedChain, cipher: &WalletCipher, amount: u64, network: Network) -> (String, Address) {
+
+    let mut awaiting_funds = None;
+
+    let result = deposit::execute(pool, cipher, chain, server, mock.paid_token(), amount, network, 1, Duration::ZERO, false, &mut |progress| {
+        if let DepositProgress::AwaitingFunds { statechain_id, address, .. } = progress {
+            awaiting_funds = Some((statechain_id, address));
+        }
+    }).await;
+    assert!(matches!(result, Err(CError::Timeout(_))));
+
+    let (statechain_id, address) = awaiting_funds.unwrap();
+    let address = Address::from_str(&address).unwrap().require_network(network).unwrap();
+
+    (statechain_id, address)
+}
+
+/// Deposits `amount`, confirms the deposit transaction and signs the first backup transaction, with an anchor output if `anchor` is set
+async fn confirmed_deposit(mock: &MockServer, server: &StatechainServer, pool: &sqlx::Pool<Sqlite>, chain: &SimulatedChain, cipher: &WalletCipher, amount: u64, network: Network, anchor: bool) -> String {
+
+    let (statechain_id, p2tr_agg_address) = awaiting_deposit(mock, server, pool, chain, cipher, amount, network).await;
+
+    chain.fund_address(&p2tr_agg_address, amount);
+    chain.mine(1);
+
+    deposit::resume(pool, cipher, chain, server, &statechain_id, network, 1, Duration::ZERO, anchor, &mut |_| {}).await.unwrap();
+
+    statechain_id
+}
+
+#[tokio::test]
+async fn backup_transaction_is_broadcast_only_after_locktime() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    let (statechain_id, client_seckey, client_pubkey, backup_address, server_pubkey, signed_statechain_id) =
+        deposit::init(&pool, &cipher, &server, mock.paid_token(), amount, network).await.unwrap();
+
+    let (aggregated_pubkey, p2tr_agg_address) = deposit::create_agg_pub_key(&pool, &client_pubkey, &server_pubkey, network).await.unwrap();
+
+    let funding_outpoint = chain.fund_address(&p2tr_agg_address, amount);
+    chain.mine(1);
+    wallet::update_status(&pool, &statechain_id, StatecoinStatus::Confirmed).await.unwrap();
+
+    let block_height = transaction::get_new_block_height(&pool, &chain, &server, &statechain_id).await.unwrap();
+    assert_eq!(block_height, 1 + INITLOCK);
+
+    let tx_out = TxOut { value: amount - 1000, script_pubkey: backup_address.script_pubkey() };
+
+    let (tx, client_pub_nonce, blinding_factor) = transaction::create(
+        &server,
+        block_height,
+        &statechain_id,
+        &signed_statechain_id,
+        &client_seckey,
+        &client_pubkey,
+        &server_pubkey,
+        funding_outpoint.txid,
+        funding_outpoint.vout,
+        &aggregated_pubkey,
+        &p2tr_agg_address.script_pubkey(),
+        amount,
+        tx_out,
+        false).await.unwrap();
+
+    let tx_bytes = bitcoin::consensus::encode::serialize(&tx);
+    transaction::insert_transaction(&pool, &tx_bytes, &client_pub_nonce.serialize(), blinding_factor.as_bytes(), &statechain_id).await.unwrap();
+
+    // The transaction is final once its locktime is below the height of the next block
+    chain.mine(INITLOCK - 1);
+    assert_eq!(chain.get_tip_height().await.unwrap(), block_height - 1);
+
+    let result = deposit::broadcast_backup_tx(&pool, &chain, &statechain_id).await;
+    assert!(matches!(result, Err(CError::Chain(_))));
+
+    chain.mine(1);
+
+    let txid = deposit::broadcast_backup_tx(&pool, &chain, &statechain_id).await.unwrap();
+    assert_eq!(txid, tx.txid());
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::BackupBroadcast));
+
+    chain.mine(1);
+
+    assert_eq!(chain.transaction_height(&txid), Some(block_height + 1));
+    assert_eq!(chain.get_address_balance(&backup_address).await.unwrap().confirmed, amount - 1000);
+    assert!(chain.list_unspent(&p2tr_agg_address).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn send_all_funds_sweeps_backup_outputs() {
+
+    let chain = SimulatedChain::new();
+    let secp = Secp256k1::new();
+    let network = Network::Regtest;
+
+    let (secret_key, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+    let xonly_public_key = public_key.x_only_public_key().0;
+    let backup_address = Address::p2tr(&secp, xonly_public_key, None, network);
+
+    let (_, destination_pubkey) = secp.generate_keypair(&mut rand::thread_rng());
+    let destination = Address::p2tr(&secp, destination_pubkey.x_only_public_key().0, None, network);
+
+    chain.fund_address(&backup_address, 50000);
+    chain.fund_address(&backup_address, 30000);
+    chain.mine(1);
+
+    let list_utxo: Vec<AddressInfo> = chain.list_unspent(&backup_address).await.unwrap().into_iter().map(|utxo| AddressInfo {
+        address: backup_address.clone(),
+        secret_key,
+        xonly_public_key,
+        fingerprint: "00000000".to_string(),
+        derivation_path: "m/86'/0'/0'/0/0".to_string(),
+        height: utxo.height as usize,
+        tx_hash: utxo.txid,
+        tx_pos: utxo.vout as usize,
+        value: utxo.value,
+    }).collect();
+
+    assert_eq!(list_utxo.len(), 2);
+
+    let fee_rate = 2;
+    let txid = send_backup::send_all_funds(&chain, &list_utxo, &destination, fee_rate).await.unwrap();
+
+    assert_eq!(chain.mempool(), vec![txid]);
+    assert_eq!(chain.get_address_balance(&backup_address).await.unwrap().unconfirmed, -80000);
+
+    chain.mine(1);
+
+    let tx = chain.get_transaction(&txid).await.unwrap();
+    let received = chain.get_address_balance(&destination).await.unwrap().confirmed;
+
+    assert_eq!(received, 80000 - tx.vsize() as u64 * fee_rate);
+    assert!(chain.list_unspent(&backup_address).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn timed_out_deposit_is_resumed_once_funded() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    let mut progress = Vec::<DepositProgress>::new();
+    let result = deposit::execute(&pool, &cipher, &chain, &server, mock.paid_token(), amount, network, 1, Duration::ZERO, false, &mut |update| progress.push(update)).await;
+    assert!(matches!(result, Err(CError::Timeout(_))));
+
+    let (agg_addresses, _) = mercury_client::wallet::get_all_addresses(&pool, network).await.unwrap();
+    assert_eq!(agg_addresses.len(), 1);
+
+    // The deposit address is reported while waiting for the funds
+    assert!(matches!(&progress[..], [DepositProgress::AwaitingFunds { address, amount: 100000, .. }] if *address == agg_addresses[0].to_string()));
+
+    chain.fund_address(&agg_addresses[0], amount);
+    chain.mine(1);
+
+    let statechain_id = sqlx::query_scalar::<_, String>("SELECT statechain_id FROM signer_data WHERE deposit_status = 'AWAITING_FUNDS'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+    let resumed = deposit::resume(&pool, &cipher, &chain, &server, &statechain_id, network, 1, Duration::ZERO, false, &mut |_| {}).await.unwrap();
+    assert_eq!(resumed, statechain_id);
+    assert_eq!(mock.num_sigs(&statechain_id), Some(1));
+
+    let backup_txs = transaction::get_backup_transactions(&pool, &statechain_id).await.unwrap();
+    assert_eq!(backup_txs.len(), 1);
+
+    // Resuming a completed deposit does not sign again
+    deposit::resume(&pool, &cipher, &chain, &server, &statechain_id, network, 1, Duration::ZERO, false, &mut |_| {}).await.unwrap();
+    assert_eq!(mock.num_sigs(&statechain_id), Some(1));
+}
+
+#[tokio::test]
+async fn cancelled_deposit_frees_its_keys() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    let (statechain_id, _, client_pubkey, _, server_pubkey, _) =
+        deposit::init(&pool, &cipher, &server, mock.paid_token(), amount, network).await.unwrap();
+    deposit::create_agg_pub_key(&pool, &client_pubkey, &server_pubkey, network).await.unwrap();
+
+    deposit::cancel(&pool, &cipher, &chain, &server, &statechain_id, network).await.unwrap();
+    assert_eq!(mock.num_sigs(&statechain_id), None);
+
+    let result = deposit::resume(&pool, &cipher, &chain, &server, &statechain_id, network, 1, Duration::ZERO, false, &mut |_| {}).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+
+    let (new_statechain_id, _, new_client_pubkey, _, _, _) =
+        deposit::init(&pool, &cipher, &server, mock.paid_token(), amount, network).await.unwrap();
+
+    assert_ne!(new_statechain_id, statechain_id);
+    assert_eq!(new_client_pubkey, client_pubkey);
+}
+
+#[tokio::test]
+async fn backup_transaction_waits_for_confirmations() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+    let min_confirmations = 2;
+
+    let (statechain_id, p2tr_agg_address) = awaiting_deposit(&mock, &server, &pool, &chain, &cipher, amount, network).await;
+
+    let funding_outpoint = chain.fund_address(&p2tr_agg_address, amount);
+
+    let result = deposit::resume(&pool, &cipher, &chain, &server, &statechain_id, network, min_confirmations, Duration::ZERO, false, &mut |_| {}).await;
+    assert!(matches!(result, Err(CError::Timeout(_))));
+
+    let info = deposit::info(&pool, &chain, &statechain_id, network).await.unwrap();
+    assert_eq!(info.status, "FUNDED_UNCONFIRMED");
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::InMempool));
+    assert_eq!(info.funding_txid, Some(funding_outpoint.txid));
+    assert_eq!(info.confirmations, Some(0));
+    assert_eq!(mock.num_sigs(&statechain_id), Some(0));
+
+    chain.mine(1);
+
+    let result = deposit::resume(&pool, &cipher, &chain, &server, &statechain_id, network, min_confirmations, Duration::ZERO, false, &mut |_| {}).await;
+    assert!(matches!(result, Err(CError::Timeout(_))));
+    assert_eq!(deposit::info(&pool, &chain, &statechain_id, network).await.unwrap().confirmations, Some(1));
+
+    chain.mine(1);
+
+    deposit::resume(&pool, &cipher, &chain, &server, &statechain_id, network, min_confirmations, Duration::ZERO, false, &mut |_| {}).await.unwrap();
+
+    let info = deposit::info(&pool, &chain, &statechain_id, network).await.unwrap();
+    assert_eq!(info.status, "BACKUP_SIGNED");
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Confirmed));
+    assert_eq!(mock.num_sigs(&statechain_id), Some(1));
+}
+
+#[tokio::test]
+async fn backup_transaction_is_bumped_through_its_anchor() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+    let fee_rate = 5;
+
+    let statechain_id = confirmed_deposit(&mock, &server, &pool, &chain, &cipher, amount, network, true).await;
+
+    let backup_address = sqlx::query_scalar::<_, String>("SELECT backup_address FROM signer_data WHERE statechain_id = $1")
+        .bind(&statechain_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let backup_address = wallet::parse_address(&backup_address, network).unwrap();
+
+    let backup_txs = transaction::get_backup_transactions(&pool, &statechain_id).await.unwrap();
+    let parent: bitcoin::Transaction = bitcoin::consensus::deserialize(&hex::decode(&backup_txs[0].tx).unwrap()).unwrap();
+    assert_eq!(transaction::anchor_vout(&parent, &backup_address.script_pubkey()), Some(1));
+
+    chain.mine(INITLOCK);
+
+    let child_txid = bump_backup::execute(&pool, &cipher, &chain, &statechain_id, fee_rate, network).await.unwrap();
+    assert_eq!(chain.mempool(), vec![parent.txid(), child_txid]);
+
+    chain.mine(1);
+
+    let child = chain.get_transaction(&child_txid).await.unwrap();
+    let parent_fee = amount - parent.output.iter().map(|output| output.value).sum::<u64>();
+    let child_fee = parent.output.iter().map(|output| output.value).sum::<u64>() - child.output[0].value;
+
+    assert!(parent_fee + child_fee >= (parent.vsize() + child.vsize()) as u64 * fee_rate);
+    assert_eq!(chain.get_address_balance(&backup_address).await.unwrap().confirmed, child.output[0].value);
+}
+
+#[tokio::test]
+async fn tampered_backup_transactions_are_reported() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    let statechain_id = confirmed_deposit(&mock, &server, &pool, &chain, &cipher, amount, network, false).await;
+
+    let reports = verify_backups::execute(&pool, None, network).await.unwrap();
+    assert_eq!(reports.len(), 1);
+    assert!(reports[0].valid, "{:?}", reports[0]);
+
+    // Changing the locktime invalidates the signature
+    let backup_txs = transaction::get_backup_transactions(&pool, &statechain_id).await.unwrap();
+    let mut tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&hex::decode(&backup_txs[0].tx).unwrap()).unwrap();
+    tx.lock_time = bitcoin::absolute::LockTime::from_consensus(tx.lock_time.to_consensus_u32() - 1);
+
+    sqlx::query("UPDATE backup_transaction SET backup_tx = $1 WHERE statechain_id = $2")
+        .bind(bitcoin::consensus::encode::serialize(&tx))
+        .bind(&statechain_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let reports = verify_backups::execute(&pool, Some(&statechain_id), network).await.unwrap();
+    assert!(!reports[0].valid);
+    assert_eq!(reports[0].backup_transactions[0].errors.len(), 1);
+}
+
+#[tokio::test]
+async fn statecoins_are_listed_with_their_backup_locktime() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    let statechain_id = confirmed_deposit(&mock, &server, &pool, &chain, &cipher, amount, network, false).await;
+
+    let p2tr_agg_address = wallet::get_statecoin(&pool, &statechain_id, network).await.unwrap().p2tr_agg_address;
+    let funding_utxo = chain.list_unspent(&p2tr_agg_address).await.unwrap().remove(0);
+
+    chain.mine(3);
+
+    let statecoins = statecoin_info::list(&pool, &chain, network).await.unwrap();
+    assert_eq!(statecoins.len(), 1);
+
+    let info = &statecoins[0];
+    assert_eq!(info.statechain_id, statechain_id);
+    assert_eq!(info.amount, amount);
+    assert_eq!(info.status, Some(StatecoinStatus::Confirmed));
+    assert_eq!(info.funding_txid, Some(funding_utxo.txid));
+    assert_eq!(info.funding_vout, Some(funding_utxo.vout));
+    assert_eq!(info.confirmations, Some(4));
+    assert_eq!(info.backup_tx_count, 1);
+    assert_eq!(info.backup_tx_lock_time, Some(1 + INITLOCK));
+    assert_eq!(info.blocks_until_backup_valid, Some(INITLOCK - 3));
+    assert!(info.transfer_address.is_some());
+
+    let result = statecoin_info::info(&pool, &chain, "unknown", network).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+}
+
+#[tokio::test]
+async fn watchtower_broadcasts_final_backup_transaction() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+    let policy = WatchPolicy { safety_margin: None };
+
+    let statechain_id = confirmed_deposit(&mock, &server, &pool, &chain, &cipher, amount, network, false).await;
+
+    let backup_txs = transaction::get_backup_transactions(&pool, &statechain_id).await.unwrap();
+    let backup_tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&hex::decode(&backup_txs[0].tx).unwrap()).unwrap();
+
+    chain.mine(INITLOCK - 1);
+
+    let events = watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert!(events.is_empty());
+    assert!(chain.mempool().is_empty());
+
+    chain.mine(1);
+
+    let events = watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].event, WatchEventKind::BackupBroadcast);
+    assert_eq!(events[0].txid, Some(backup_tx.txid()));
+    assert_eq!(chain.mempool(), vec![backup_tx.txid()]);
+
+    chain.mine(1);
+
+    let events = watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].event, WatchEventKind::SpentByBackup);
+    assert_eq!(events[0].block_height, chain.transaction_height(&backup_tx.txid()).unwrap());
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::BackupBroadcast));
+
+    // A closed statecoin can no longer be withdrawn or transferred
+    let result = wallet::check_transition(&pool, &statechain_id, StatecoinStatus::Withdrawing).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+
+    // The statecoin is no longer watched once the spend is confirmed
+    chain.mine(1);
+    assert!(watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap().is_empty());
+
+    let recorded = watch::get_events(&pool, Some(&statechain_id)).await.unwrap();
+    assert_eq!(recorded.iter().map(|event| event.event).collect::<Vec<_>>(), vec![WatchEventKind::BackupBroadcast, WatchEventKind::SpentByBackup]);
+}
+
+#[tokio::test]
+async fn watchtower_reports_spend_by_previous_backup_transaction() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    let (statechain_id, client_seckey, client_pubkey, backup_address, server_pubkey, signed_statechain_id) =
+        deposit::init(&pool, &cipher, &server, mock.paid_token(), amount, network).await.unwrap();
+
+    let (aggregated_pubkey, p2tr_agg_address) = deposit::create_agg_pub_key(&pool, &client_pubkey, &server_pubkey, network).await.unwrap();
+
+    let funding_outpoint = chain.fund_address(&p2tr_agg_address, amount);
+    chain.mine(1);
+    wallet::update_status(&pool, &statechain_id, StatecoinStatus::Confirmed).await.unwrap();
+
+    // Two backup transactions, the first one standing for the one of a previous owner
+    let mut backup_txs = Vec::<bitcoin::Transaction>::new();
+
+    for _ in 0..2 {
+        let block_height = transaction::get_new_block_height(&pool, &chain, &server, &statechain_id).await.unwrap();
+        let tx_out = TxOut { value: amount - 1000, script_pubkey: backup_address.script_pubkey() };
+
+        let (tx, client_pub_nonce, blinding_factor) = transaction::create(
+            &server,
+            block_height,
+            &statechain_id,
+            &signed_statechain_id,
+            &client_seckey,
+            &client_pubkey,
+            &server_pubkey,
+            funding_outpoint.txid,
+            funding_outpoint.vout,
+            &aggregated_pubkey,
+            &p2tr_agg_address.script_pubkey(),
+            amount,
+            tx_out,
+            false).await.unwrap();
+
+        let tx_bytes = bitcoin::consensus::encode::serialize(&tx);
+        transaction::insert_transaction(&pool, &tx_bytes, &client_pub_nonce.serialize(), blinding_factor.as_bytes(), &statechain_id).await.unwrap();
+        backup_txs.push(tx);
+    }
+
+    let lock_times: Vec<u32> = backup_txs.iter().map(|tx| tx.lock_time.to_consensus_u32()).collect();
+
+    // With a margin, the broadcast waits as long as the previous backup transaction is far enough from being final
+    assert_eq!(WatchPolicy { safety_margin: Some(1) }.broadcast_height(lock_times[1], Some(lock_times[0])), lock_times[0] - 1);
+    assert_eq!(WatchPolicy { safety_margin: Some(10) }.broadcast_height(lock_times[1], Some(lock_times[0])), lock_times[1]);
+
+    chain.mine(lock_times[0] - chain.get_tip_height().await.unwrap());
+    chain.broadcast(&bitcoin::consensus::encode::serialize(&backup_txs[0])).await.unwrap();
+
+    let policy = WatchPolicy { safety_margin: None };
+
+    let events = watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].event, WatchEventKind::SpentByPreviousBackup);
+    assert_eq!(events[0].txid, Some(backup_txs[0].txid()));
+    assert_eq!(events[0].block_height, 0);
+
+    chain.mine(1);
+
+    let events = watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].event, WatchEventKind::SpentByPreviousBackup);
+    assert_eq!(events[0].block_height, lock_times[0] + 1);
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Expired));
+}
+
+#[tokio::test]
+async fn wallet_is_restored_from_its_mnemonic() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    // A funded statecoin at the first address, an unfunded one at the second
+    let statechain_id = confirmed_deposit(&mock, &server, &pool, &chain, &cipher, amount, network, false).await;
+    let deposited = wallet::get_statecoin(&pool, &statechain_id, network).await.unwrap();
+
+    let (unfunded_statechain_id, _, _, _, _, _) =
+        deposit::init(&pool, &cipher, &server, mock.paid_token(), amount, network).await.unwrap();
+
+    let mnemonic = key_derivation::get_mnemonic(&pool, &cipher).await.unwrap();
+
+    let restored_pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+
+    sqlx::migrate!("./migrations").run(&restored_pool).await.unwrap();
+
+    let seed = WalletSeed::from_mnemonic(&mnemonic, SeedDerivation::Bip39, "").unwrap();
+    let restored_cipher = restore::open_wallet(&restored_pool, &seed, PASSPHRASE, TEST_KDF_PARAMS).await.unwrap();
+    let report = restore::execute(&restored_pool, &restored_cipher, &chain, &server, 3, network).await.unwrap();
+
+    assert_eq!(report.address_count, 2);
+    assert_eq!(report.statecoins.len(), 2);
+    assert!(report.closed_statechain_ids.is_empty());
+
+    let funded = report.statecoins.iter().find(|statecoin| statecoin.statechain_id == statechain_id).unwrap();
+    assert_eq!(funded.amount, amount);
+    assert_eq!(funded.status, StatecoinStatus::Confirmed);
+    assert_eq!(funded.funding_txid, Some(deposited.funding_txid));
+    assert_eq!(funded.funding_vout, Some(deposited.funding_vout));
+
+    let unfunded = report.statecoins.iter().find(|statecoin| statecoin.statechain_id == unfunded_statechain_id).unwrap();
+    assert_eq!(unfunded.status, StatecoinStatus::Initialised);
+
+    // The mock server does not keep backup transactions, so a new one is signed
+    deposit::resume(&restored_pool, &restored_cipher, &chain, &server, &statechain_id, network, 1, Duration::ZERO, false, &mut |_| {}).await.unwrap();
+    assert_eq!(transaction::get_backup_transactions(&restored_pool, &statechain_id).await.unwrap().len(), 1);
+
+    let statecoin = wallet::get_statecoin(&restored_pool, &statechain_id, network).await.unwrap();
+    assert_eq!(statecoin.client_pubkey, deposited.client_pubkey);
+    assert_eq!(statecoin.p2tr_agg_address, deposited.p2tr_agg_address);
+
+    // Running it again restores nothing new
+    let report = restore::execute(&restored_pool, &restored_cipher, &chain, &server, 3, network).await.unwrap();
+    assert!(report.statecoins.is_empty());
+    assert_eq!(report.address_count, 2);
+
+    let other_mnemonic = bip39::Mnemonic::from_entropy(&[1u8; 32]).unwrap().to_string();
+    let other_seed = WalletSeed::from_mnemonic(&other_mnemonic, SeedDerivation::Bip39, "").unwrap();
+    let result = restore::open_wallet(&restored_pool, &other_seed, PASSPHRASE, TEST_KDF_PARAMS).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+
+    // The same mnemonic with a BIP39 passphrase is another wallet
+    let other_seed = WalletSeed::from_mnemonic(&mnemonic, SeedDerivation::Bip39, "25th word").unwrap();
+    let result = restore::open_wallet(&restored_pool, &other_seed, PASSPHRASE, TEST_KDF_PARAMS).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+
+    // A statecoin of a second account is restored once the account is scanned
+    account::create_account(&pool, "operations").await.unwrap();
+    account::select_account(&pool, "operations").await.unwrap();
+
+    let (operations_statechain_id, _, _, _, _, _) =
+        deposit::init(&pool, &cipher, &server, mock.paid_token(), amount, network).await.unwrap();
+
+    account::create_missing_accounts(&restored_pool, 2).await.unwrap();
+    let report = restore::execute(&restored_pool, &restored_cipher, &chain, &server, 3, network).await.unwrap();
+
+    assert_eq!(report.address_count, 3);
+    assert_eq!(report.statecoins.len(), 1);
+    assert_eq!(report.statecoins[0].statechain_id, operations_statechain_id);
+}
+
+#[tokio::test]
+async fn transferred_statecoin_is_signed_by_the_receiver() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+    let (receiver_pool, receiver_cipher) = new_wallet().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+    let policy = WatchPolicy { safety_margin: None };
+
+    let statechain_id = confirmed_deposit(&mock, &server, &pool, &chain, &cipher, amount, network, false).await;
+    let statecoin = wallet::get_statecoin(&pool, &statechain_id, network).await.unwrap();
+
+    let transfer_address = key_derivation::get_new_address(&receiver_pool, &receiver_cipher, None, None, network).await.unwrap().transfer_address;
+
+    transfer_sender::execute(&pool, &cipher, &chain, &server, &transfer_address, &statechain_id, network, false).await.unwrap();
+
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Transferred));
+    assert_eq!(mock.num_sigs(&statechain_id), Some(2));
+    assert_eq!(transaction::get_backup_transactions(&pool, &statechain_id).await.unwrap().len(), 2);
+
+    // The receiver checks the key update: t2 = t1 - o2 on the client, s2 = s1 + t2 - x1 on the server
+    let report = transfer_receiver::execute(&receiver_pool, &receiver_cipher, &chain, &server, network).await.unwrap();
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+    assert_eq!(report.received_statechain_ids, vec![statechain_id.clone()]);
+
+    assert_eq!(wallet::get_status(&receiver_pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Confirmed));
+    assert_eq!(transaction::get_backup_transactions(&receiver_pool, &statechain_id).await.unwrap().len(), 2);
+
+    let received = wallet::get_statecoin(&receiver_pool, &statechain_id, network).await.unwrap();
+    assert_eq!(received.aggregated_pubkey, statecoin.aggregated_pubkey);
+    assert_eq!(received.p2tr_agg_address, statecoin.p2tr_agg_address);
+    assert_ne!(received.server_pubkey, statecoin.server_pubkey);
+
+    // The message is consumed
+    let report = transfer_receiver::execute(&receiver_pool, &receiver_cipher, &chain, &server, network).await.unwrap();
+    assert!(report.received_statechain_ids.is_empty());
+    assert!(report.failures.is_empty());
+
+    // The sender no longer owns the statecoin
+    let to_address = key_derivation::get_new_address(&pool, &cipher, None, None, network).await.unwrap().backup_address;
+    let result = withdraw::execute(&pool, &cipher, &chain, &server, &statechain_id, &to_address, 1, network).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+
+    // The new key shares sign a transaction the chain accepts
+    let to_address = key_derivation::get_new_address(&receiver_pool, &receiver_cipher, None, None, network).await.unwrap().backup_address;
+    let result = withdraw::execute(&receiver_pool, &receiver_cipher, &chain, &server, &statechain_id, &to_address, 1, network).await.unwrap();
+    assert_eq!(chain.mempool(), vec![result.txid]);
+
+    chain.mine(1);
+
+    let events = watch::execute(&receiver_pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert_eq!(events.iter().map(|event| event.event).collect::<Vec<_>>(), vec![WatchEventKind::SpentByWithdrawal, WatchEventKind::WithdrawalCompleted]);
+    assert_eq!(wallet::get_status(&receiver_pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Withdrawn));
+    assert_eq!(mock.withdrawn(&statechain_id), Some(true));
+}
+
+#[tokio::test]
+async fn failed_transfer_reuses_its_signed_backup_transaction() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+    let (receiver_pool, receiver_cipher) = new_wallet().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    let statechain_id = confirmed_deposit(&mock, &server, &pool, &chain, &cipher, amount, network, false).await;
+
+    let transfer_address = key_derivation::get_new_address(&receiver_pool, &receiver_cipher, None, None, network).await.unwrap().transfer_address;
+
+    mock.fail_next("transfer/update_msg");
+
+    let result = transfer_sender::execute(&pool, &cipher, &chain, &server, &transfer_address, &statechain_id, network, false).await;
+    assert!(matches!(result, Err(CError::ServerStatus { status: 500, .. })));
+
+    // The server signed the new backup transaction but the statecoin is still owned by the wallet
+    assert_eq!(mock.num_sigs(&statechain_id), Some(2));
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Confirmed));
+    assert_eq!(transaction::get_backup_transactions(&pool, &statechain_id).await.unwrap().len(), 1);
+
+    // The signed transaction is pending for this recipient only
+    let other_address = key_derivation::get_new_address(&pool, &cipher, None, None, network).await.unwrap().transfer_address;
+    let result = transfer_sender::execute(&pool, &cipher, &chain, &server, &other_address, &statechain_id, network, false).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+
+    // The retry does not ask the server for another signature
+    let txid = transfer_sender::execute(&pool, &cipher, &chain, &server, &transfer_address, &statechain_id, network, false).await.unwrap();
+    assert_eq!(mock.num_sigs(&statechain_id), Some(2));
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Transferred));
+
+    let backup_txs = transaction::get_backup_transactions(&pool, &statechain_id).await.unwrap();
+    assert_eq!(backup_txs.len(), 2);
+    let sent_tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&hex::decode(&backup_txs[1].tx).unwrap()).unwrap();
+    assert_eq!(sent_tx.txid(), txid);
+
+    let report = transfer_receiver::execute(&receiver_pool, &receiver_cipher, &chain, &server, network).await.unwrap();
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+    assert_eq!(report.received_statechain_ids, vec![statechain_id.clone()]);
+}
+
+#[tokio::test]
+async fn interrupted_receive_is_completed_by_the_next_run() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+    let (receiver_pool, receiver_cipher) = new_wallet().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+
+    let statechain_id = confirmed_deposit(&mock, &server, &pool, &chain, &cipher, amount, network, false).await;
+
+    let transfer_address = key_derivation::get_new_address(&receiver_pool, &receiver_cipher, None, None, network).await.unwrap().transfer_address;
+
+    transfer_sender::execute(&pool, &cipher, &chain, &server, &transfer_address, &statechain_id, network, false).await.unwrap();
+
+    mock.fail_next("transfer/receiver");
+
+    let report = transfer_receiver::execute(&receiver_pool, &receiver_cipher, &chain, &server, network).await.unwrap();
+    assert!(report.received_statechain_ids.is_empty());
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].statechain_id, Some(statechain_id.clone()));
+    assert!(wallet::get_statecoin(&receiver_pool, &statechain_id, network).await.is_err());
+
+    // The saved message and t2 complete the key update
+    let report = transfer_receiver::execute(&receiver_pool, &receiver_cipher, &chain, &server, network).await.unwrap();
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+    assert_eq!(report.received_statechain_ids, vec![statechain_id.clone()]);
+
+    assert_eq!(wallet::get_status(&receiver_pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Confirmed));
+    assert_eq!(transaction::get_backup_transactions(&receiver_pool, &statechain_id).await.unwrap().len(), 2);
+
+    let report = transfer_receiver::execute(&receiver_pool, &receiver_cipher, &chain, &server, network).await.unwrap();
+    assert!(report.received_statechain_ids.is_empty());
+    assert!(report.failures.is_empty());
+}
+
+#[tokio::test]
+async fn withdrawn_statecoin_is_closed_on_the_server() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+    let policy = WatchPolicy { safety_margin: None };
+
+    let statechain_id = confirmed_deposit(&mock, &server, &pool, &chain, &cipher, amount, network, false).await;
+
+    let to_address = key_derivation::get_new_address(&pool, &cipher, None, None, network).await.unwrap().backup_address;
+    let result = withdraw::execute(&pool, &cipher, &chain, &server, &statechain_id, &to_address, 1, network).await.unwrap();
+
+    // The server is only told once the withdrawal transaction is confirmed
+    assert_eq!(mock.withdrawn(&statechain_id), Some(false));
+    assert_eq!(mock.num_sigs(&statechain_id), Some(2));
+    assert_eq!(chain.mempool(), vec![result.txid]);
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Withdrawing));
+
+    let withdrawal_tx = chain.get_transaction(&result.txid).await.unwrap();
+    assert_eq!(withdrawal_tx.output[0].script_pubkey, to_address.script_pubkey());
+
+    let result_again = withdraw::execute(&pool, &cipher, &chain, &server, &statechain_id, &to_address, 1, network).await;
+    assert!(matches!(result_again, Err(CError::UserInput(_))));
+
+    chain.mine(1);
+
+    let events = watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].event, WatchEventKind::SpentByWithdrawal);
+    assert_eq!(events[0].block_height, 0);
+    assert_eq!(mock.withdrawn(&statechain_id), Some(false));
+
+    chain.mine(1);
+
+    let events = watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert_eq!(events.iter().map(|event| event.event).collect::<Vec<_>>(), vec![WatchEventKind::SpentByWithdrawal, WatchEventKind::WithdrawalCompleted]);
+    assert_eq!(events[0].txid, Some(result.txid));
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Withdrawn));
+    assert_eq!(mock.withdrawn(&statechain_id), Some(true));
+
+    // The server is told once
+    chain.mine(1);
+    assert!(watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn dropped_withdrawal_is_broadcast_again() {
+
+    let (mock, server, pool, chain, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let amount = 100000;
+    let policy = WatchPolicy { safety_margin: None };
+
+    let statechain_id = confirmed_deposit(&mock, &server, &pool, &chain, &cipher, amount, network, false).await;
+
+    let to_address = key_derivation::get_new_address(&pool, &cipher, None, None, network).await.unwrap().backup_address;
+    let result = withdraw::execute(&pool, &cipher, &chain, &server, &statechain_id, &to_address, 1, network).await.unwrap();
+
+    let events = watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].event, WatchEventKind::SpentByWithdrawal);
+    assert_eq!(events[0].block_height, 0);
+
+    chain.evict(&result.txid);
+    chain.mine(1);
+
+    let events = watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].event, WatchEventKind::WithdrawalDropped);
+    assert_eq!(events[0].txid, Some(result.txid));
+    assert_eq!(chain.mempool(), vec![result.txid]);
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Withdrawing));
+    assert_eq!(mock.withdrawn(&statechain_id), Some(false));
+
+    // Back in the mempool, nothing to do
+    assert!(watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap().is_empty());
+
+    // The backup transaction is not broadcast while the withdrawal is pending, even once final
+    chain.evict(&result.txid);
+    chain.mine(INITLOCK);
+
+    let events = watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert_eq!(events.iter().map(|event| event.event).collect::<Vec<_>>(), vec![WatchEventKind::WithdrawalDropped]);
+    assert_eq!(chain.mempool(), vec![result.txid]);
+
+    chain.mine(1);
+
+    let events = watch::execute(&pool, &chain, &server, policy, Duration::ZERO, true, network, &mut |_| {}).await.unwrap();
+    assert_eq!(events.iter().map(|event| event.event).collect::<Vec<_>>(), vec![WatchEventKind::SpentByWithdrawal, WatchEventKind::WithdrawalCompleted]);
+    assert_eq!(wallet::get_status(&pool, &statechain_id).await.unwrap(), Some(StatecoinStatus::Withdrawn));
+    assert_eq!(mock.withdrawn(&statechain_id), Some(true));
+}
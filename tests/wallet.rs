@@ -0,0 +1,343 @@
+use std::str::FromStr;
+
+use bitcoin::{Network, bip32::{DerivationPath, ExtendedPrivKey}, secp256k1::Secp256k1};
+use mercury_client::{CError, account, encryption::{self, KdfParams, WalletCipher}, key_derivation::{self, SeedDerivation, WalletSeed}, key_store, wallet::{self, StatecoinStatus}, wallet_metadata, wallets};
+use sqlx::{Row, Sqlite, sqlite::SqlitePoolOptions};
+
+const PASSPHRASE: &str = "correct horse battery staple";
+
+/// Cheap Argon2 parameters, the defaults take too long for tests
+const TEST_KDF_PARAMS: KdfParams = KdfParams { memory_kib: 8, iterations: 1, parallelism: 1 };
+
+/// Migrated database without a wallet
+async fn new_pool() -> sqlx::Pool<Sqlite> {
+
+    // A single connection, otherwise every connection opens its own in-memory database
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+    pool
+}
+
+async fn setup() -> (sqlx::Pool<Sqlite>, WalletCipher) {
+
+    let pool = new_pool().await;
+
+    let cipher = encryption::create_wallet(&pool, PASSPHRASE, TEST_KDF_PARAMS, "").await.unwrap();
+
+    (pool, cipher)
+}
+
+#[tokio::test]
+async fn wallet_is_unlocked_only_with_its_passphrase() {
+
+    let (pool, cipher) = setup().await;
+
+    let mnemonic = key_derivation::get_mnemonic(&pool, &cipher).await.unwrap();
+
+    let seed = sqlx::query("SELECT seed FROM signer_seed").fetch_one(&pool).await.unwrap().get::<Vec<u8>, _>("seed");
+    assert_ne!(seed.len(), 32);
+
+    assert!(matches!(encryption::unlock(&pool, "wrong passphrase").await, Err(CError::UserInput(_))));
+
+    encryption::change_passphrase(&pool, PASSPHRASE, "new passphrase", TEST_KDF_PARAMS).await.unwrap();
+
+    assert!(matches!(encryption::unlock(&pool, PASSPHRASE).await, Err(CError::UserInput(_))));
+
+    let cipher = encryption::unlock(&pool, "new passphrase").await.unwrap();
+    assert_eq!(key_derivation::get_mnemonic(&pool, &cipher).await.unwrap(), mnemonic);
+}
+
+#[tokio::test]
+async fn plaintext_wallet_is_encrypted_in_place() {
+
+    let pool = new_pool().await;
+
+    let seed = [7u8; 32];
+
+    sqlx::query("INSERT INTO signer_seed (seed) VALUES ($1)")
+        .bind(seed.to_vec())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // A secret key stored without its public key
+    let secret_key = secp256k1_zkp::SecretKey::from_slice(&[3u8; 32]).unwrap();
+
+    sqlx::query("INSERT INTO signer_data (client_seckey_share) VALUES ($1)")
+        .bind(secret_key.secret_bytes().to_vec())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    assert!(matches!(encryption::unlock(&pool, PASSPHRASE).await, Err(CError::UserInput(_))));
+    assert!(matches!(encryption::create_wallet(&pool, PASSPHRASE, TEST_KDF_PARAMS, "").await, Err(CError::UserInput(_))));
+
+    encryption::encrypt_wallet(&pool, PASSPHRASE, TEST_KDF_PARAMS).await.unwrap();
+
+    let cipher = encryption::unlock(&pool, PASSPHRASE).await.unwrap();
+    let expected = bip39::Mnemonic::from_entropy(&seed).unwrap().to_string();
+
+    assert_eq!(key_derivation::get_mnemonic(&pool, &cipher).await.unwrap(), expected);
+    assert_eq!(key_derivation::get_seed(&pool, &cipher).await.unwrap().derivation, SeedDerivation::Legacy);
+
+    // It is encrypted with the derived public key, no secret is left in plaintext
+    let public_key = secret_key.public_key(&secp256k1_zkp::Secp256k1::new());
+
+    let row = sqlx::query("SELECT client_seckey_share, client_pubkey_share FROM signer_data")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+    assert_eq!(row.try_get::<Vec<u8>, _>("client_pubkey_share").unwrap(), public_key.serialize().to_vec());
+    assert_ne!(row.try_get::<Vec<u8>, _>("client_seckey_share").unwrap(), secret_key.secret_bytes().to_vec());
+
+    assert_eq!(key_store::migrate_stored_secrets(&pool, &cipher, Network::Regtest).await.unwrap(), 1);
+    assert_eq!(key_store::get_secret_key(&pool, &cipher, &public_key, None, Network::Regtest).await.unwrap(), secret_key);
+    assert!(matches!(encryption::encrypt_wallet(&pool, PASSPHRASE, TEST_KDF_PARAMS).await, Err(CError::UserInput(_))));
+}
+
+#[tokio::test]
+async fn seed_derivation_follows_the_wallet_version() {
+
+    let secp = Secp256k1::new();
+
+    // BIP39 test vector: entropy 0xff..ff with the passphrase "TREZOR"
+    let mnemonic = "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote";
+    let bip39_root = ExtendedPrivKey::from_str("xprv9s21ZrQH143K2WFF16X85T2QCpndrGwx6GueB72Zf3AHwHJaknRXNF37ZmDrtHrrLSHvbuRejXcnYxoZKvRquTPyp2JiNG3XcjQyzSEgqCB").unwrap();
+    let legacy_root = ExtendedPrivKey::new_master(Network::Bitcoin, &[0xff; 32]).unwrap();
+
+    for (derivation, bip39_passphrase, root) in [(SeedDerivation::Bip39, "TREZOR", bip39_root), (SeedDerivation::Legacy, "", legacy_root)] {
+
+        let pool = new_pool().await;
+
+        let seed = WalletSeed::from_mnemonic(mnemonic, derivation, bip39_passphrase).unwrap();
+        let cipher = encryption::restore_wallet(&pool, PASSPHRASE, TEST_KDF_PARAMS, &seed).await.unwrap();
+
+        let stored_seed = key_derivation::get_seed(&pool, &cipher).await.unwrap();
+        assert_eq!(stored_seed.derivation, derivation);
+        assert_eq!(stored_seed.bip39_passphrase, bip39_passphrase);
+
+        let key_data = key_derivation::generate_new_key(&pool, &cipher, key_derivation::AGG_KEY_PURPOSE, 0, 0, 3, Network::Bitcoin).await.unwrap();
+        assert_eq!(key_data.derivation_path, "m/86h/0h/0h/0/3");
+        let path = DerivationPath::from_str(&key_data.derivation_path).unwrap();
+
+        assert_eq!(key_data.fingerprint, root.fingerprint(&secp).to_string());
+        assert_eq!(key_data.secret_key, root.derive_priv(&secp, &path).unwrap().private_key);
+        assert_eq!(key_store::derive_secret_key(&pool, &cipher, &key_data.derivation_path, Network::Bitcoin).await.unwrap(), key_data.secret_key);
+
+        // Legacy wallets keep the mainnet coin type on the test networks
+        let signet_key_data = key_derivation::generate_new_key(&pool, &cipher, key_derivation::AUTH_KEY_PURPOSE, 0, 0, 3, Network::Signet).await.unwrap();
+        let expected_path = if derivation == SeedDerivation::Bip39 { "m/89h/1h/0h/0/3" } else { "m/89h/0h/0h/0/3" };
+        assert_eq!(signet_key_data.derivation_path, expected_path);
+    }
+
+    assert!(matches!(WalletSeed::from_mnemonic(mnemonic, SeedDerivation::Legacy, "TREZOR"), Err(CError::UserInput(_))));
+}
+
+#[tokio::test]
+async fn wallet_is_pinned_to_its_network() {
+
+    let (pool, cipher) = setup().await;
+
+    key_derivation::get_new_address(&pool, &cipher, None, None, Network::Regtest).await.unwrap();
+
+    // A wallet created before the network was recorded is checked against its addresses
+    assert_eq!(wallet_metadata::get_network(&pool).await.unwrap(), None);
+    assert!(matches!(wallet_metadata::check_network(&pool, Network::Bitcoin).await, Err(CError::Config(_))));
+    assert_eq!(wallet_metadata::get_network(&pool).await.unwrap(), None);
+
+    wallet_metadata::check_network(&pool, Network::Regtest).await.unwrap();
+    assert_eq!(wallet_metadata::get_network(&pool).await.unwrap(), Some(Network::Regtest));
+
+    wallet_metadata::check_network(&pool, Network::Regtest).await.unwrap();
+    assert!(matches!(wallet_metadata::check_network(&pool, Network::Signet).await, Err(CError::Config(_))));
+}
+
+#[tokio::test]
+async fn new_addresses_use_the_selected_account() {
+
+    let (pool, cipher) = setup().await;
+
+    let network = Network::Regtest;
+
+    key_derivation::get_new_address(&pool, &cipher, None, None, network).await.unwrap();
+
+    let operations = account::create_account(&pool, "operations").await.unwrap();
+    assert_eq!(operations.index, 1);
+    assert!(matches!(account::create_account(&pool, "operations").await, Err(CError::UserInput(_))));
+    assert!(matches!(account::select_account(&pool, "treasury").await, Err(CError::UserInput(_))));
+
+    account::select_account(&pool, "operations").await.unwrap();
+
+    let address_data = key_derivation::get_new_address(&pool, &cipher, None, None, network).await.unwrap();
+
+    let row = sqlx::query("SELECT agg_key_derivation_path, auth_derivation_path, account_index FROM signer_data WHERE client_pubkey_share = $1")
+        .bind(&address_data.client_pubkey_share.serialize().to_vec())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+    assert_eq!(row.get::<String, _>("agg_key_derivation_path"), "m/86h/1h/1h/0/0");
+    assert_eq!(row.get::<String, _>("auth_derivation_path"), "m/89h/1h/1h/0/0");
+    assert_eq!(row.get::<u32, _>("account_index"), 1);
+
+    // Each account has its own address indexes
+    assert_eq!(key_derivation::get_next_address_index(&pool, 0, 0).await.unwrap(), 1);
+    assert_eq!(key_derivation::get_next_address_index(&pool, 1, 0).await.unwrap(), 1);
+
+    let accounts = account::list_accounts(&pool).await.unwrap();
+    assert_eq!(accounts.iter().map(|account| (account.index, account.selected)).collect::<Vec<_>>(), vec![(0, false), (1, true)]);
+
+    // A generated name does not collide with a name chosen by the user
+    account::create_account(&pool, "account-3").await.unwrap();
+    account::create_missing_accounts(&pool, 4).await.unwrap();
+
+    let accounts = account::list_accounts(&pool).await.unwrap();
+    assert_eq!(accounts.iter().map(|account| account.name.as_str()).collect::<Vec<_>>(), vec!["default", "operations", "account-3", "account-3-2"]);
+}
+
+#[tokio::test]
+async fn named_wallets_are_listed_and_selected() {
+
+    let wallets_dir = std::env::temp_dir().join(format!("mercury-wallets-{}", uuid::Uuid::new_v4()));
+    let wallets_dir = wallets_dir.to_str().unwrap();
+
+    assert!(wallets::list_wallets(wallets_dir).unwrap().is_empty());
+    assert!(matches!(wallets::wallet_database_file(wallets_dir, "../treasury"), Err(CError::UserInput(_))));
+
+    std::fs::create_dir_all(wallets_dir).unwrap();
+    for name in ["treasury", "operations"] {
+        std::fs::write(wallets::wallet_database_file(wallets_dir, name).unwrap(), b"").unwrap();
+    }
+
+    assert!(matches!(wallets::select_wallet(wallets_dir, Some("payroll")), Err(CError::UserInput(_))));
+
+    wallets::select_wallet(wallets_dir, Some("treasury")).unwrap();
+    assert_eq!(wallets::get_selected_wallet(wallets_dir).unwrap().as_deref(), Some("treasury"));
+
+    let names = wallets::list_wallets(wallets_dir).unwrap().into_iter().map(|wallet| (wallet.name, wallet.selected)).collect::<Vec<_>>();
+    assert_eq!(names, vec![("operations".to_string(), false), ("treasury".to_string(), true)]);
+
+    wallets::select_wallet(wallets_dir, None).unwrap();
+    assert_eq!(wallets::get_selected_wallet(wallets_dir).unwrap(), None);
+
+    std::fs::remove_dir_all(wallets_dir).unwrap();
+}
+
+#[tokio::test]
+async fn stored_secret_keys_are_replaced_by_derivation() {
+
+    let (pool, cipher) = setup().await;
+
+    let network = Network::Regtest;
+    let secp = Secp256k1::new();
+
+    let address_data = key_derivation::get_new_address(&pool, &cipher, None, None, network).await.unwrap();
+
+    let row = sqlx::query("SELECT client_seckey_share, agg_key_derivation_path, auth_derivation_path FROM signer_data")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(row.get::<Option<Vec<u8>>, _>("client_seckey_share").is_none());
+    let agg_key_derivation_path = row.get::<String, _>("agg_key_derivation_path");
+    let auth_derivation_path = row.get::<String, _>("auth_derivation_path");
+
+    // As written by earlier versions: the derived client key, and an auth key that does not come from the seed
+    let (imported_seckey, imported_pubkey) = secp.generate_keypair(&mut rand::thread_rng());
+
+    sqlx::query("UPDATE signer_data SET client_seckey_share = $1, auth_seckey = $2, auth_pubkey = $3")
+        .bind(cipher.encrypt_secret_key(&address_data.client_secret_key, &address_data.client_pubkey_share).unwrap())
+        .bind(cipher.encrypt_secret_key(&imported_seckey, &imported_pubkey).unwrap())
+        .bind(imported_pubkey.serialize().to_vec())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    assert_eq!(key_store::migrate_stored_secrets(&pool, &cipher, network).await.unwrap(), 2);
+    assert_eq!(key_store::migrate_stored_secrets(&pool, &cipher, network).await.unwrap(), 0);
+
+    let row = sqlx::query("SELECT client_seckey_share, auth_seckey FROM signer_data")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(row.get::<Option<Vec<u8>>, _>("client_seckey_share").is_none());
+    assert!(row.get::<Option<Vec<u8>>, _>("auth_seckey").is_none());
+
+    let client_seckey = key_store::get_secret_key(&pool, &cipher, &address_data.client_pubkey_share, Some(&agg_key_derivation_path), network).await.unwrap();
+    assert_eq!(client_seckey, address_data.client_secret_key);
+
+    let auth_seckey = key_store::get_secret_key(&pool, &cipher, &imported_pubkey, Some(&auth_derivation_path), network).await.unwrap();
+    assert_eq!(auth_seckey, imported_seckey);
+
+    // A derivation path never yields a key for another public key
+    let result = key_store::get_secret_key(&pool, &cipher, &address_data.client_pubkey_share, Some(&auth_derivation_path), network).await;
+    assert!(matches!(result, Err(CError::Database(_))));
+}
+
+#[test]
+fn statecoin_status_transitions() {
+    use StatecoinStatus::*;
+
+    // Staying in the same status is always allowed
+    for status in StatecoinStatus::ALL {
+        assert!(StatecoinStatus::can_transition(Some(status), status));
+    }
+
+    assert!(StatecoinStatus::can_transition(None, Initialised));
+    assert!(StatecoinStatus::can_transition(None, Confirmed));
+    assert!(!StatecoinStatus::can_transition(None, Withdrawing));
+
+    assert!(StatecoinStatus::can_transition(Some(InMempool), Confirmed));
+    assert!(StatecoinStatus::can_transition(Some(Confirmed), InMempool));
+    assert!(StatecoinStatus::can_transition(Some(Confirmed), InTransfer));
+    assert!(StatecoinStatus::can_transition(Some(InTransfer), Confirmed));
+    assert!(StatecoinStatus::can_transition(Some(InTransfer), Transferred));
+    assert!(StatecoinStatus::can_transition(Some(Withdrawing), Withdrawn));
+    assert!(StatecoinStatus::can_transition(Some(BackupBroadcast), Expired));
+
+    assert!(!StatecoinStatus::can_transition(Some(Initialised), InTransfer));
+    assert!(!StatecoinStatus::can_transition(Some(InMempool), Withdrawing));
+    assert!(!StatecoinStatus::can_transition(Some(Confirmed), Transferred));
+    assert!(!StatecoinStatus::can_transition(Some(Confirmed), Withdrawn));
+    assert!(!StatecoinStatus::can_transition(Some(InTransfer), Withdrawing));
+    assert!(!StatecoinStatus::can_transition(Some(BackupBroadcast), Confirmed));
+
+    // Final statuses
+    for status in [Transferred, Withdrawn, Expired] {
+        for next in StatecoinStatus::ALL.into_iter().filter(|next| *next != status) {
+            assert!(!StatecoinStatus::can_transition(Some(status), next));
+        }
+    }
+}
+
+#[tokio::test]
+async fn status_is_updated_only_from_an_allowed_status() {
+
+    let (pool, _) = setup().await;
+
+    sqlx::query("INSERT INTO signer_data (statechain_id) VALUES ('received')")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let result = wallet::update_status(&pool, "received", StatecoinStatus::Withdrawing).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+    assert_eq!(wallet::get_status(&pool, "received").await.unwrap(), None);
+
+    wallet::update_status(&pool, "received", StatecoinStatus::Confirmed).await.unwrap();
+    wallet::update_status(&pool, "received", StatecoinStatus::InTransfer).await.unwrap();
+    wallet::update_status(&pool, "received", StatecoinStatus::Transferred).await.unwrap();
+
+    let result = wallet::update_status(&pool, "received", StatecoinStatus::Confirmed).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+    assert_eq!(wallet::get_status(&pool, "received").await.unwrap(), Some(StatecoinStatus::Transferred));
+
+    let result = wallet::update_status(&pool, "unknown", StatecoinStatus::Confirmed).await;
+    assert!(matches!(result, Err(CError::UserInput(_))));
+}